@@ -94,6 +94,19 @@ export const MakuGraphSchema = z.object({
   outputs: z.array(z.string()),
 });
 
+// ========== Error Schema ==========
+
+// Thrown by WasmEngine.run / WasmSession; node, inputs, output and value are graph ids
+export const MakuErrorSchema = z.object({
+  kind: z.string(), // e.g. "ShapeMismatch", "MissingInput", "MissingType", "ParseError"
+  message: z.string(),
+  node: z.string().optional(),
+  op: z.string().optional(),
+  inputs: z.array(z.string()).optional(),
+  output: z.string().optional(),
+  value: z.string().optional(),
+});
+
 // ========== Inferred TypeScript Types ==========
 
 export type MakuTensor = z.infer<typeof MakuTensorSchema>;
@@ -108,3 +121,4 @@ export type ConcatAttrs = z.infer<typeof ConcatAttrsSchema>;
 export type MakuOp = z.infer<typeof MakuOpSchema>;
 export type MakuNode = z.infer<typeof MakuNodeSchema>;
 export type MakuGraph = z.infer<typeof MakuGraphSchema>;
+export type MakuError = z.infer<typeof MakuErrorSchema>;
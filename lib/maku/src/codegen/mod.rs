@@ -0,0 +1,5 @@
+//! Code generators from Loop IR ([`crate::loop_ir`]).
+
+pub mod rust;
+pub mod wasm;
+pub mod wgsl;
@@ -0,0 +1,489 @@
+//! Standalone Rust source for a Loop IR program, for ahead-of-time
+//! compiled inference without `maku` at run time.
+//!
+//! The module defines a `Model` holding a statically sized array for every
+//! intermediate buffer, weights as `static` arrays decoded at compile time
+//! from little-endian byte strings, and `Model::run`, which takes the inputs
+//! and output slots as fixed-size arrays and never allocates. A build script
+//! can lower a graph and write the module to `OUT_DIR`:
+//!
+//! ```ignore
+//! let program = maku::loop_ir::lower(&graph, &input_types)?;
+//! std::fs::write(out_dir.join("model.rs"), maku::codegen::rust::generate(&program))?;
+//! // in the crate: mod model { include!(concat!(env!("OUT_DIR"), "/model.rs")); }
+//! ```
+//!
+//! The code only needs `core`, except that BatchNorm calls `f32::sqrt`.
+
+use crate::loop_ir::{Affine, BinaryOp, Bound, BufferId, BufferKind, Expr, Program, Stmt, UnaryOp};
+
+/// Source of a module running `program`.
+pub fn generate(program: &Program) -> String {
+    let mut w = Writer {
+        program,
+        out: String::new(),
+        depth: 0,
+    };
+    w.line("// Generated by maku; do not edit.");
+    w.line("");
+    w.module();
+    w.out
+}
+
+/// Where the kernels find a buffer
+enum Place {
+    /// `run` parameter
+    Param,
+    /// `Model` field
+    Field,
+    /// `static` weights
+    Static,
+}
+
+struct Writer<'a> {
+    program: &'a Program,
+    out: String,
+    depth: usize,
+}
+
+impl Writer<'_> {
+    fn line(&mut self, text: &str) {
+        if !text.is_empty() {
+            for _ in 0..self.depth {
+                self.out.push_str("    ");
+            }
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn open(&mut self, text: &str) {
+        self.line(text);
+        self.depth += 1;
+    }
+
+    fn close(&mut self, text: &str) {
+        self.depth -= 1;
+        self.line(text);
+    }
+
+    fn is_output(&self, id: BufferId) -> bool {
+        self.program.outputs.iter().any(|&(_, b)| b == id)
+    }
+
+    fn place(&self, id: BufferId) -> Place {
+        match self.program.buffer(id).kind {
+            BufferKind::Input => Place::Param,
+            BufferKind::Constant(_) => Place::Static,
+            BufferKind::Temp if self.is_output(id) => Place::Param,
+            BufferKind::Temp => Place::Field,
+        }
+    }
+
+    fn name(&self, id: BufferId) -> String {
+        let name = &self.program.buffer(id).name;
+        match self.place(id) {
+            Place::Param => name.clone(),
+            Place::Field => format!("self.{}", name),
+            Place::Static => name.to_uppercase(),
+        }
+    }
+
+    /// Outputs that are not written by a kernel get their own parameter
+    fn copied_output(&self, id: BufferId) -> Option<String> {
+        match self.program.buffer(id).kind {
+            BufferKind::Temp => None,
+            _ => Some(format!("out_{}", self.program.buffer(id).name)),
+        }
+    }
+
+    fn module(&mut self) {
+        let ids = (0..self.program.buffers.len() as u32).map(BufferId);
+        let fields: Vec<BufferId> = ids
+            .clone()
+            .filter(|&id| matches!(self.place(id), Place::Field))
+            .collect();
+        let mut outputs: Vec<BufferId> = Vec::new();
+        for &(_, id) in &self.program.outputs {
+            if !outputs.contains(&id) {
+                outputs.push(id);
+            }
+        }
+
+        for id in ids {
+            if let BufferKind::Constant(t) = &self.program.buffer(id).kind {
+                self.weights(id, &t.data);
+            }
+        }
+
+        self.line("/// Intermediate buffers; `Model::new` is const, so a model can live in a");
+        self.line("/// `static` rather than on the stack.");
+        self.open("pub struct Model {");
+        for &id in &fields {
+            let b = self.program.buffer(id);
+            let text = format!("{}: [f32; {}],", b.name, b.len());
+            self.line(&text);
+        }
+        self.close("}");
+        self.line("");
+        self.open("impl Default for Model {");
+        self.open("fn default() -> Self {");
+        self.line("Self::new()");
+        self.close("}");
+        self.close("}");
+        self.line("");
+        self.line("#[allow(unused_assignments, unused_mut, unused_variables, clippy::all)]");
+        self.open("impl Model {");
+        self.open("pub const fn new() -> Self {");
+        self.open("Model {");
+        for &id in &fields {
+            let b = self.program.buffer(id);
+            let text = format!("{}: [0.0; {}],", b.name, b.len());
+            self.line(&text);
+        }
+        self.close("}");
+        self.close("}");
+        self.line("");
+
+        let mut params = Vec::new();
+        let mut docs = Vec::new();
+        for &(_, id) in &self.program.inputs {
+            let b = self.program.buffer(id);
+            params.push(format!("{}: &[f32; {}]", b.name, b.len()));
+            docs.push(format!("/// - `{}`: input f32{:?}", b.name, b.shape));
+        }
+        for &id in &outputs {
+            let b = self.program.buffer(id);
+            let name = self.copied_output(id).unwrap_or_else(|| b.name.clone());
+            params.push(format!("{}: &mut [f32; {}]", name, b.len()));
+            docs.push(format!("/// - `{}`: output f32{:?}", name, b.shape));
+        }
+        self.line("/// Run the model on row-major tensors:");
+        for text in docs {
+            self.line(&text);
+        }
+        self.open(&format!("pub fn run(&mut self, {}) {{", params.join(", ")));
+        let locals = self.program.locals;
+        for l in 0..locals {
+            self.line(&format!("let mut a{}: f32 = 0.0;", l));
+        }
+        for kernel in &self.program.kernels {
+            self.line(&format!("// {}", kernel.name));
+            self.stmts(&kernel.body);
+        }
+        for &id in &outputs {
+            if let Some(name) = self.copied_output(id) {
+                let text = format!("{}.copy_from_slice(&{});", name, self.name(id));
+                self.line(&text);
+            }
+        }
+        self.close("}");
+        self.close("}");
+        self.line("");
+        self.helpers();
+    }
+
+    fn weights(&mut self, id: BufferId, data: &[f32]) {
+        let b = self.program.buffer(id);
+        self.line(&format!("/// f32{:?}", b.shape));
+        self.open(&format!(
+            "static {}: [f32; {}] = decode(",
+            self.name(id),
+            b.len()
+        ));
+        let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let lines: Vec<String> = bytes
+            .chunks(16)
+            .map(|chunk| chunk.iter().map(|b| format!("\\x{:02x}", b)).collect())
+            .collect();
+        // One byte string, continued over lines of 16 bytes
+        let last = lines.len().saturating_sub(1);
+        match lines.is_empty() {
+            true => self.line("b\"\","),
+            false => {
+                for (i, text) in lines.iter().enumerate() {
+                    let open = if i == 0 { "b\"" } else { "" };
+                    let end = if i == last { "\"," } else { "\\" };
+                    self.line(&format!("{}{}{}", open, text, end));
+                }
+            }
+        }
+        self.close(");");
+        self.line("");
+    }
+
+    fn helpers(&mut self) {
+        for text in [
+            "/// Little-endian f32s, at compile time",
+            "#[allow(dead_code)]",
+            "const fn decode<const N: usize>(bytes: &[u8]) -> [f32; N] {",
+            "    assert!(bytes.len() == 4 * N);",
+            "    let mut out = [0.0; N];",
+            "    let mut i = 0;",
+            "    while i < N {",
+            "        let b = [bytes[4 * i], bytes[4 * i + 1], bytes[4 * i + 2], bytes[4 * i + 3]];",
+            "        out[i] = f32::from_le_bytes(b);",
+            "        i += 1;",
+            "    }",
+            "    out",
+            "}",
+            "",
+            "// max/min as the maku kernels compute them (NaN handling included)",
+            "#[allow(dead_code)]",
+            "#[inline(always)]",
+            "fn max(a: f32, b: f32) -> f32 {",
+            "    if a < b { b } else { a }",
+            "}",
+            "",
+            "#[allow(dead_code)]",
+            "#[inline(always)]",
+            "fn min(a: f32, b: f32) -> f32 {",
+            "    if b < a { b } else { a }",
+            "}",
+        ] {
+            self.line(text);
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::For(l) => {
+                    let v = self.program.var_name(l.var);
+                    self.open(&format!("for {} in 0..{}_isize {{", v, l.extent));
+                    self.stmts(&l.body);
+                    self.close("}");
+                }
+                Stmt::If { bounds, body } => {
+                    let conds: Vec<String> = bounds.iter().map(|b| self.bound(b)).collect();
+                    self.open(&format!("if {} {{", conds.join(" && ")));
+                    self.stmts(body);
+                    self.close("}");
+                }
+                Stmt::Assign { local, value } => {
+                    let text = format!("a{} = {};", local.0, self.expr(value));
+                    self.line(&text);
+                }
+                Stmt::Store {
+                    buffer,
+                    index,
+                    value,
+                } => {
+                    let text = format!(
+                        "{}[{}] = {};",
+                        self.name(*buffer),
+                        self.index(index),
+                        self.expr(value)
+                    );
+                    self.line(&text);
+                }
+            }
+        }
+    }
+
+    fn bound(&self, b: &Bound) -> String {
+        format!("(0..{}).contains(&({}))", b.extent, self.affine(&b.index))
+    }
+
+    fn index(&self, a: &Affine) -> String {
+        match a.terms.as_slice() {
+            [] => a.constant.to_string(),
+            [(_, 1)] if a.constant == 0 => format!("{} as usize", self.affine(a)),
+            _ => format!("({}) as usize", self.affine(a)),
+        }
+    }
+
+    fn affine(&self, a: &Affine) -> String {
+        let mut sorted = a.terms.clone();
+        sorted.sort_by_key(|&(_, k)| std::cmp::Reverse(k.abs()));
+        let mut terms: Vec<String> = sorted
+            .iter()
+            .map(|&(v, k)| match k {
+                1 => self.program.var_name(v).to_string(),
+                k => format!("{} * {}", k, self.program.var_name(v)),
+            })
+            .collect();
+        if a.constant != 0 || terms.is_empty() {
+            terms.push(a.constant.to_string());
+        }
+        terms.join(" + ").replace("+ -", "- ")
+    }
+
+    fn expr(&self, e: &Expr) -> String {
+        match e {
+            Expr::Const(c) => float(*c),
+            Expr::Local(l) => format!("a{}", l.0),
+            Expr::Load(b, index) => format!("{}[{}]", self.name(*b), self.index(index)),
+            Expr::Unary(UnaryOp::Sqrt, x) => format!("{}.sqrt()", self.operand(x)),
+            Expr::Binary(BinaryOp::Max, a, b) => {
+                format!("max({}, {})", self.expr(a), self.expr(b))
+            }
+            Expr::Binary(BinaryOp::Min, a, b) => {
+                format!("min({}, {})", self.expr(a), self.expr(b))
+            }
+            Expr::Binary(op, a, b) => {
+                let op = op.symbol().expect("max and min are handled above");
+                format!("{} {} {}", self.operand(a), op, self.operand(b))
+            }
+        }
+    }
+
+    /// `e` as an operand of an infix operator or method call
+    fn operand(&self, e: &Expr) -> String {
+        match e {
+            Expr::Binary(BinaryOp::Max | BinaryOp::Min, ..) => self.expr(e),
+            Expr::Binary(..) => format!("({})", self.expr(e)),
+            Expr::Const(c) if c.is_sign_negative() => format!("({})", float(*c)),
+            _ => self.expr(e),
+        }
+    }
+}
+
+/// A Rust f32 literal
+fn float(c: f32) -> String {
+    if c.is_nan() {
+        "f32::NAN".to_string()
+    } else if c.is_infinite() {
+        let sign = if c > 0.0 { "" } else { "NEG_" };
+        format!("f32::{}INFINITY", sign)
+    } else {
+        format!("{:?}_f32", c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::process::Command;
+
+    use super::*;
+    use crate::loop_ir::lower;
+    use crate::test_util::{constant, desc, node};
+    use crate::{
+        Activation, BatchNormAttrs, CpuBackend, DepthwiseConv2DAttrs, Graph, MatMulAttrs, OpKind,
+        ReshapeAttrs, Tensor, ValueId,
+    };
+
+    #[test]
+    fn add_relu_snapshot() {
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![2, 3], 1), &[], 1),
+                node(OpKind::Add, &[0, 1], 2),
+                node(OpKind::Relu, &[2], 3),
+            ],
+            outputs: vec![ValueId(3)],
+            value_types: HashMap::from([(ValueId(0), desc(vec![2, 3]))]),
+        };
+        let program = lower(&graph, &HashMap::new()).unwrap();
+        insta::assert_snapshot!(generate(&program));
+    }
+
+    /// Compile the generated module with a driver printing the outputs of
+    /// one run, and compare them with the CPU backend.
+    #[test]
+    fn compiled_module_matches_cpu_backend() {
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![3, 3, 2, 2], 4), &[], 1),
+                node(
+                    OpKind::DepthwiseConv2D(DepthwiseConv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [2, 1],
+                        pads: [1, 1, 1, 1],
+                        dilations: [1, 1],
+                        depth_multiplier: 2,
+                        activation: Some(Activation::HardSwish),
+                    }),
+                    &[0, 1],
+                    2,
+                ),
+                node(constant(vec![4], 3), &[], 3),
+                node(constant(vec![4], 4), &[], 4),
+                node(constant(vec![4], 5), &[], 5),
+                node(OpKind::Constant(Tensor::full(vec![4], 0.5).into()), &[], 6),
+                node(
+                    OpKind::BatchNorm(Some(BatchNormAttrs {
+                        epsilon: 1e-3,
+                        momentum: 0.9,
+                    })),
+                    &[2, 3, 4, 5, 6],
+                    7,
+                ),
+                node(
+                    OpKind::Reshape(ReshapeAttrs {
+                        shape: vec![-1, 4],
+                        allowzero: false,
+                    }),
+                    &[7],
+                    8,
+                ),
+                node(constant(vec![4, 3], 9), &[], 9),
+                node(
+                    OpKind::MatMul(Some(MatMulAttrs {
+                        trans_a: false,
+                        trans_b: false,
+                        activation: Some(Activation::Relu6),
+                    })),
+                    &[8, 9],
+                    10,
+                ),
+            ],
+            outputs: vec![ValueId(7), ValueId(10)],
+            value_types: HashMap::new(),
+        };
+        let x = Tensor::from_fn(vec![1, 5, 4, 2], |i| {
+            (i[1] * 8 + i[2] * 2 + i[3]) as f32 / 8.0 - 2.0
+        });
+        let inputs = HashMap::from([(ValueId(0), x.clone())]);
+        let program = lower(&graph, &HashMap::from([(ValueId(0), x.desc.clone())])).unwrap();
+        let expected = CpuBackend::new().run(&graph, &inputs).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("maku-aot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("model.rs"), generate(&program)).unwrap();
+        let outputs: Vec<String> = graph
+            .outputs
+            .iter()
+            .map(|v| format!("&mut [0.0; {}]", expected[v].data.len()))
+            .collect();
+        let driver = format!(
+            "mod model {{\n    include!(\"model.rs\");\n}}\n\n\
+             fn main() {{\n\
+             \x20   let x: [f32; {}] = {:?};\n\
+             \x20   let (a, b) = ({});\n\
+             \x20   model::Model::new().run(&x, a, b);\n\
+             \x20   for v in a.iter().chain(b.iter()) {{\n\
+             \x20       println!(\"{{}}\", v.to_bits());\n\
+             \x20   }}\n\
+             }}\n",
+            x.data.len(),
+            x.data,
+            outputs.join(", ")
+        );
+        std::fs::write(dir.join("main.rs"), driver).unwrap();
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let status = Command::new(rustc)
+            .args(["--edition", "2024", "-D", "warnings", "-o"])
+            .arg(dir.join("model"))
+            .arg(dir.join("main.rs"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "generated code failed to compile");
+        let run = Command::new(dir.join("model")).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let actual: Vec<f32> = String::from_utf8(run.stdout)
+            .unwrap()
+            .lines()
+            .map(|l| f32::from_bits(l.parse().unwrap()))
+            .collect();
+        let (a, b) = actual.split_at(expected[&ValueId(7)].data.len());
+        for (v, data) in graph.outputs.iter().zip([a, b]) {
+            let t = Tensor::new(expected[v].desc.clone(), data.to_vec());
+            assert!(t.approx_eq(&expected[v], 1e-5), "{:?}", v);
+        }
+    }
+}
@@ -0,0 +1,603 @@
+//! WebAssembly modules for Loop IR programs.
+//!
+//! A module exports its linear memory as `memory` and a `run` function with
+//! no parameters. Every buffer has a fixed region of the memory and
+//! constants are data segments, so a caller writes the inputs into their
+//! regions, calls `run` and reads the outputs back. Shapes, strides and loop
+//! bounds are constants in the code.
+//!
+//! Innermost loops that carry no locals and access consecutive elements
+//! run four iterations at a time with simd128 instructions, followed by a
+//! scalar loop over the remainder.
+
+use std::collections::HashMap;
+
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, DataSection, ExportKind, ExportSection, Function,
+    FunctionSection, Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+
+use crate::ValueId;
+use crate::error::{Result, ensure};
+use crate::loop_ir::{
+    Affine, BinaryOp, Bound, BufferId, BufferKind, Expr, Local, Loop, Program, Stmt, UnaryOp, Var,
+};
+
+/// f32 lanes of a v128
+const LANES: usize = 4;
+
+const PAGE_SIZE: u64 = 65536;
+
+#[derive(Debug, Clone)]
+pub struct WasmModule {
+    pub bytes: Vec<u8>,
+    /// Region of every input, in `Program::inputs` order
+    pub inputs: Vec<(ValueId, Region)>,
+    /// Region of every output, in `Program::outputs` order
+    pub outputs: Vec<(ValueId, Region)>,
+}
+
+/// `len` little-endian f32s at byte `offset` of the exported memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// Compile `program` into a module running all its kernels in `run`.
+pub fn generate(program: &Program) -> Result<WasmModule> {
+    // 16-byte aligned regions, one after another
+    let mut offsets = Vec::with_capacity(program.buffers.len());
+    let mut end = 0u64;
+    for b in &program.buffers {
+        offsets.push(end);
+        end += (b.len() as u64 * 4).next_multiple_of(16);
+    }
+    ensure!(
+        end <= u32::MAX as u64,
+        UnsupportedOp,
+        "buffers take {} bytes, more than a 32-bit memory holds",
+        end
+    );
+    let offsets: Vec<u32> = offsets.into_iter().map(|o| o as u32).collect();
+    let region = |&(value, id): &(ValueId, BufferId)| {
+        let len = program.buffer(id).len() as u32;
+        (
+            value,
+            Region {
+                offset: offsets[id.0 as usize],
+                len,
+            },
+        )
+    };
+
+    let mut e = Emitter {
+        offsets: &offsets,
+        locals: Vec::new(),
+        code: Vec::new(),
+        vars: Vec::new(),
+        scalars: Vec::new(),
+        lanes: HashMap::new(),
+    };
+    e.vars = (0..program.var_names.len())
+        .map(|_| e.local(ValType::I32))
+        .collect();
+    e.scalars = (0..program.locals).map(|_| e.local(ValType::F32)).collect();
+    for kernel in &program.kernels {
+        e.stmts(&kernel.body);
+    }
+
+    let mut types = TypeSection::new();
+    types.ty().function([], []);
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: end.div_ceil(PAGE_SIZE).max(1),
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    let mut exports = ExportSection::new();
+    exports.export("memory", ExportKind::Memory, 0);
+    exports.export("run", ExportKind::Func, 0);
+
+    let mut run = Function::new(e.locals.iter().map(|&ty| (1, ty)));
+    for instruction in &e.code {
+        run.instruction(instruction);
+    }
+    run.instruction(&Instruction::End);
+    let mut code = CodeSection::new();
+    code.function(&run);
+
+    let mut data = DataSection::new();
+    for (b, &offset) in program.buffers.iter().zip(&offsets) {
+        if let BufferKind::Constant(t) = &b.kind {
+            let bytes: Vec<u8> = t.data.iter().flat_map(|x| x.to_le_bytes()).collect();
+            data.active(0, &ConstExpr::i32_const(offset as i32), bytes);
+        }
+    }
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&functions)
+        .section(&memories)
+        .section(&exports)
+        .section(&code)
+        .section(&data);
+    Ok(WasmModule {
+        bytes: module.finish(),
+        inputs: program.inputs.iter().map(region).collect(),
+        outputs: program.outputs.iter().map(region).collect(),
+    })
+}
+
+struct Emitter<'a> {
+    /// Byte offset of each buffer
+    offsets: &'a [u32],
+    /// Types of the locals of `run`
+    locals: Vec<ValType>,
+    code: Vec<Instruction<'static>>,
+    /// i32 local of each loop variable
+    vars: Vec<u32>,
+    /// f32 local of each Local
+    scalars: Vec<u32>,
+    /// v128 locals of the Locals assigned in the loop being vectorized
+    lanes: HashMap<Local, u32>,
+}
+
+impl Emitter<'_> {
+    fn local(&mut self, ty: ValType) -> u32 {
+        self.locals.push(ty);
+        self.locals.len() as u32 - 1
+    }
+
+    fn push(&mut self, instruction: Instruction<'static>) {
+        self.code.push(instruction);
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::For(l) if vectorizable(l) => self.vector_loop(l),
+                Stmt::For(l) => self.scalar_loop(l.var, 0, l.extent, &l.body),
+                Stmt::If { bounds, body } => {
+                    self.condition(bounds);
+                    self.push(Instruction::If(BlockType::Empty));
+                    self.stmts(body);
+                    self.push(Instruction::End);
+                }
+                Stmt::Assign { local, value } => {
+                    self.expr(value);
+                    self.push(Instruction::LocalSet(self.scalars[local.0 as usize]));
+                }
+                Stmt::Store {
+                    buffer,
+                    index,
+                    value,
+                } => {
+                    let memarg = self.address(*buffer, index);
+                    self.expr(value);
+                    self.push(Instruction::F32Store(memarg));
+                }
+            }
+        }
+    }
+
+    /// `var` from `start` to `end` by `step` around `body`
+    fn counted_loop(
+        &mut self,
+        var: Var,
+        start: usize,
+        end: usize,
+        step: usize,
+        body: impl FnOnce(&mut Self),
+    ) {
+        if start >= end {
+            return;
+        }
+        let v = self.vars[var.0 as usize];
+        self.push(Instruction::I32Const(start as i32));
+        self.push(Instruction::LocalSet(v));
+        self.push(Instruction::Loop(BlockType::Empty));
+        body(self);
+        self.push(Instruction::LocalGet(v));
+        self.push(Instruction::I32Const(step as i32));
+        self.push(Instruction::I32Add);
+        self.push(Instruction::LocalTee(v));
+        self.push(Instruction::I32Const(end as i32));
+        self.push(Instruction::I32LtS);
+        self.push(Instruction::BrIf(0));
+        self.push(Instruction::End);
+    }
+
+    fn scalar_loop(&mut self, var: Var, start: usize, end: usize, body: &[Stmt]) {
+        self.counted_loop(var, start, end, 1, |e| e.stmts(body));
+    }
+
+    /// Whole vectors first, then the remaining iterations one at a time.
+    /// Locals assigned in the loop live in v128 locals meanwhile; lowering
+    /// never reads them after the loop.
+    fn vector_loop(&mut self, l: &Loop) {
+        let whole = l.extent - l.extent % LANES;
+        let mut assigned = Vec::new();
+        assigned_locals(&l.body, &mut assigned);
+        for local in assigned {
+            let v128 = self.local(ValType::V128);
+            self.lanes.insert(local, v128);
+        }
+        self.counted_loop(l.var, 0, whole, LANES, |e| {
+            for stmt in &l.body {
+                e.vector_stmt(l.var, stmt);
+            }
+        });
+        self.lanes.clear();
+        self.scalar_loop(l.var, whole, l.extent, &l.body);
+    }
+
+    fn vector_stmt(&mut self, var: Var, stmt: &Stmt) {
+        match stmt {
+            Stmt::Assign { local, value } => {
+                self.vector_expr(var, value);
+                self.push(Instruction::LocalSet(self.lanes[local]));
+            }
+            Stmt::Store {
+                buffer,
+                index,
+                value,
+            } => {
+                let memarg = self.address(*buffer, index);
+                self.vector_expr(var, value);
+                self.push(Instruction::V128Store(memarg));
+            }
+            Stmt::For(_) | Stmt::If { .. } => unreachable!("vectorized loops are innermost"),
+        }
+    }
+
+    /// i32 `Σ scale * k * var + constant`
+    fn linear(&mut self, terms: &[(Var, i64)], scale: i64, constant: i64) {
+        let mut first = true;
+        for &(var, k) in terms {
+            self.push(Instruction::LocalGet(self.vars[var.0 as usize]));
+            if scale * k != 1 {
+                self.push(Instruction::I32Const((scale * k) as i32));
+                self.push(Instruction::I32Mul);
+            }
+            if !first {
+                self.push(Instruction::I32Add);
+            }
+            first = false;
+        }
+        match (first, constant) {
+            (true, c) => self.push(Instruction::I32Const(c as i32)),
+            (false, 0) => {}
+            (false, c) => {
+                self.push(Instruction::I32Const(c as i32));
+                self.push(Instruction::I32Add);
+            }
+        }
+    }
+
+    /// Push the dynamic part of the address of `buffer[index]` and return
+    /// the memarg holding the static part
+    fn address(&mut self, buffer: BufferId, index: &Affine) -> MemArg {
+        let base = self.offsets[buffer.0 as usize] as i64 + 4 * index.constant;
+        // The static offset is unsigned and added without wrapping, so it
+        // needs a dynamic part that is never negative
+        let fold = base >= 0 && index.terms.iter().all(|&(_, k)| k >= 0);
+        let (constant, offset) = if fold { (0, base as u64) } else { (base, 0) };
+        self.linear(&index.terms, 4, constant);
+        MemArg {
+            offset,
+            align: 2,
+            memory_index: 0,
+        }
+    }
+
+    /// i32 truth of all of `bounds`
+    fn condition(&mut self, bounds: &[Bound]) {
+        for (i, b) in bounds.iter().enumerate() {
+            // 0 <= index < extent as one unsigned comparison
+            self.linear(&b.index.terms, 1, b.index.constant);
+            self.push(Instruction::I32Const(b.extent as i32));
+            self.push(Instruction::I32LtU);
+            if i > 0 {
+                self.push(Instruction::I32And);
+            }
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Const(c) => self.push(Instruction::F32Const((*c).into())),
+            Expr::Local(l) => self.push(Instruction::LocalGet(self.scalars[l.0 as usize])),
+            Expr::Load(b, index) => {
+                let memarg = self.address(*b, index);
+                self.push(Instruction::F32Load(memarg));
+            }
+            Expr::Unary(UnaryOp::Sqrt, x) => {
+                self.expr(x);
+                self.push(Instruction::F32Sqrt);
+            }
+            Expr::Binary(op @ (BinaryOp::Max | BinaryOp::Min), a, b) => {
+                // select(b, a, a < b) for max and select(b, a, b < a) for
+                // min, as `BinaryOp::eval` (f32.max/min differ on NaN and -0)
+                let (x, y) = (self.local(ValType::F32), self.local(ValType::F32));
+                self.expr(a);
+                self.push(Instruction::LocalSet(x));
+                self.expr(b);
+                self.push(Instruction::LocalSet(y));
+                self.push(Instruction::LocalGet(y));
+                self.push(Instruction::LocalGet(x));
+                let (lhs, rhs) = match op {
+                    BinaryOp::Max => (x, y),
+                    _ => (y, x),
+                };
+                self.push(Instruction::LocalGet(lhs));
+                self.push(Instruction::LocalGet(rhs));
+                self.push(Instruction::F32Lt);
+                self.push(Instruction::Select);
+            }
+            Expr::Binary(op, a, b) => {
+                self.expr(a);
+                self.expr(b);
+                self.push(match op {
+                    BinaryOp::Add => Instruction::F32Add,
+                    BinaryOp::Sub => Instruction::F32Sub,
+                    BinaryOp::Mul => Instruction::F32Mul,
+                    BinaryOp::Div => Instruction::F32Div,
+                    BinaryOp::Max | BinaryOp::Min => unreachable!("handled above"),
+                });
+            }
+        }
+    }
+
+    /// `e` for the lanes `var .. var + 4`
+    fn vector_expr(&mut self, var: Var, e: &Expr) {
+        match e {
+            Expr::Const(c) => {
+                self.push(Instruction::F32Const((*c).into()));
+                self.push(Instruction::F32x4Splat);
+            }
+            Expr::Local(l) => match self.lanes.get(l) {
+                Some(&v128) => self.push(Instruction::LocalGet(v128)),
+                None => {
+                    self.push(Instruction::LocalGet(self.scalars[l.0 as usize]));
+                    self.push(Instruction::F32x4Splat);
+                }
+            },
+            Expr::Load(b, index) => {
+                let memarg = self.address(*b, index);
+                match coefficient(index, var) {
+                    0 => {
+                        self.push(Instruction::F32Load(memarg));
+                        self.push(Instruction::F32x4Splat);
+                    }
+                    _ => self.push(Instruction::V128Load(memarg)),
+                }
+            }
+            Expr::Unary(UnaryOp::Sqrt, x) => {
+                self.vector_expr(var, x);
+                self.push(Instruction::F32x4Sqrt);
+            }
+            Expr::Binary(op, a, b) => {
+                self.vector_expr(var, a);
+                self.vector_expr(var, b);
+                // pmax/pmin are exactly `BinaryOp::eval`
+                self.push(match op {
+                    BinaryOp::Add => Instruction::F32x4Add,
+                    BinaryOp::Sub => Instruction::F32x4Sub,
+                    BinaryOp::Mul => Instruction::F32x4Mul,
+                    BinaryOp::Div => Instruction::F32x4Div,
+                    BinaryOp::Max => Instruction::F32x4PMax,
+                    BinaryOp::Min => Instruction::F32x4PMin,
+                });
+            }
+        }
+    }
+}
+
+fn coefficient(index: &Affine, var: Var) -> i64 {
+    index
+        .terms
+        .iter()
+        .find(|&&(v, _)| v == var)
+        .map_or(0, |&(_, k)| k)
+}
+
+/// An innermost loop without carried locals whose stores hit consecutive
+/// elements and whose loads are consecutive or loop-invariant
+fn vectorizable(l: &Loop) -> bool {
+    fn loads(e: &Expr, var: Var) -> bool {
+        match e {
+            Expr::Const(_) | Expr::Local(_) => true,
+            Expr::Load(_, index) => matches!(coefficient(index, var), 0 | 1),
+            Expr::Unary(_, x) => loads(x, var),
+            Expr::Binary(_, a, b) => loads(a, var) && loads(b, var),
+        }
+    }
+    l.extent >= LANES
+        && l.carried_local().is_none()
+        && l.body.iter().all(|stmt| match stmt {
+            Stmt::Assign { value, .. } => loads(value, l.var),
+            Stmt::Store { index, value, .. } => {
+                coefficient(index, l.var) == 1 && loads(value, l.var)
+            }
+            Stmt::For(_) | Stmt::If { .. } => false,
+        })
+}
+
+fn assigned_locals(stmts: &[Stmt], out: &mut Vec<Local>) {
+    for stmt in stmts {
+        if let Stmt::Assign { local, .. } = stmt
+            && !out.contains(local)
+        {
+            out.push(*local);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loop_ir::lower;
+    use crate::test_util::{constant, node};
+    use crate::{
+        Activation, Conv2DAttrs, CpuBackend, DType, ElementwiseOp, FusedArg, FusedElementwiseAttrs,
+        FusedStep, Graph, MatMulAttrs, OpKind, ReshapeAttrs, Tensor, TensorDesc, TransposeAttrs,
+    };
+
+    /// Instantiate `module` in wasmtime, run it on `inputs` and return the
+    /// outputs with the shapes of `expected`
+    fn execute(
+        module: &WasmModule,
+        inputs: &HashMap<ValueId, Tensor>,
+        expected: &HashMap<ValueId, Tensor>,
+    ) -> HashMap<ValueId, Tensor> {
+        let engine = wasmtime::Engine::default();
+        let compiled = wasmtime::Module::new(&engine, &module.bytes).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &compiled, &[]).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        for (value, region) in &module.inputs {
+            let bytes: Vec<u8> = inputs[value]
+                .data
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect();
+            memory
+                .write(&mut store, region.offset as usize, &bytes)
+                .unwrap();
+        }
+        let run = instance
+            .get_typed_func::<(), ()>(&mut store, "run")
+            .unwrap();
+        run.call(&mut store, ()).unwrap();
+        module
+            .outputs
+            .iter()
+            .map(|(value, region)| {
+                let mut bytes = vec![0u8; region.len as usize * 4];
+                memory
+                    .read(&store, region.offset as usize, &mut bytes)
+                    .unwrap();
+                let data = bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                (*value, Tensor::new(expected[value].desc.clone(), data))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_cpu_backend() {
+        let fused = FusedElementwiseAttrs {
+            steps: vec![
+                FusedStep {
+                    op: ElementwiseOp::Mul,
+                    args: vec![FusedArg::Input(0), FusedArg::Input(0)],
+                },
+                FusedStep {
+                    op: ElementwiseOp::HardSwish,
+                    args: vec![FusedArg::Step(0)],
+                },
+            ],
+        };
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![3, 3, 1, 6], 1), &[], 1),
+                node(constant(vec![6], 2), &[], 2),
+                node(
+                    OpKind::Conv2D(Conv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [1, 2],
+                        pads: [1, 1, 1, 1],
+                        dilations: [1, 1],
+                        group: 2,
+                        activation: Some(Activation::Relu6),
+                    }),
+                    &[0, 1, 2],
+                    3,
+                ),
+                node(OpKind::FusedElementwise(fused), &[3], 4),
+                node(
+                    OpKind::Transpose(TransposeAttrs {
+                        perm: vec![0, 3, 1, 2],
+                    }),
+                    &[4],
+                    5,
+                ),
+                node(
+                    OpKind::Reshape(ReshapeAttrs {
+                        shape: vec![-1, 3],
+                        allowzero: false,
+                    }),
+                    &[5],
+                    6,
+                ),
+                node(constant(vec![3, 5], 7), &[], 7),
+                node(
+                    OpKind::MatMul(Some(MatMulAttrs {
+                        trans_a: false,
+                        trans_b: false,
+                        activation: Some(Activation::Relu),
+                    })),
+                    &[6, 7],
+                    8,
+                ),
+            ],
+            outputs: vec![ValueId(4), ValueId(8)],
+            value_types: HashMap::new(),
+        };
+        let x = Tensor::from_fn(vec![1, 5, 6, 2], |i| {
+            (i[1] * 12 + i[2] * 2 + i[3]) as f32 / 12.0 - 2.0
+        });
+        let types = HashMap::from([(ValueId(0), x.desc.clone())]);
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let program = lower(&graph, &types).unwrap();
+
+        let module = generate(&program).unwrap();
+        let expected = CpuBackend::new().run(&graph, &inputs).unwrap();
+        let actual = execute(&module, &inputs, &expected);
+        for v in &graph.outputs {
+            assert!(actual[v].approx_eq(&expected[v], 1e-5), "{:?}", v);
+        }
+    }
+
+    #[test]
+    fn vectorizes_elementwise_loops() {
+        // 14 elements: three vectors and a scalar tail of two
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![2, 7], 1), &[], 1),
+                node(OpKind::Add, &[0, 1], 2),
+                node(OpKind::Relu, &[2], 3),
+            ],
+            outputs: vec![ValueId(3)],
+            value_types: HashMap::new(),
+        };
+        let x = Tensor::from_fn(vec![2, 7], |i| i[1] as f32 - 3.0 * i[0] as f32);
+        let desc = TensorDesc {
+            dtype: DType::F32,
+            shape: vec![2, 7],
+        };
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let program = lower(&graph, &HashMap::from([(ValueId(0), desc)])).unwrap();
+        let module = generate(&program).unwrap();
+
+        let expected = CpuBackend::new().run(&graph, &inputs).unwrap();
+        let actual = execute(&module, &inputs, &expected);
+        assert!(actual[&ValueId(3)].approx_eq(&expected[&ValueId(3)], 0.0));
+
+        // The module only validates with simd128 enabled
+        let mut config = wasmtime::Config::new();
+        config.wasm_relaxed_simd(false).wasm_simd(false);
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        assert!(wasmtime::Module::new(&engine, &module.bytes).is_err());
+    }
+}
@@ -0,0 +1,514 @@
+//! WGSL compute shaders for the kernels of a Loop IR program.
+//!
+//! Each kernel becomes one shader with one invocation per iteration of its
+//! outer independent loops (the output elements, for a lowered kernel);
+//! reductions and other loops run inside the invocation. Every buffer the
+//! kernel touches is a storage binding in group 0, so a runtime uploads
+//! inputs and constants, dispatches the shaders in order and reads back
+//! the outputs.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::NodeId;
+use crate::error::{Result, bail, ensure};
+use crate::loop_ir::{
+    Affine, BinaryOp, Bound, BufferId, Expr, Kernel, Local, Loop, Program, Stmt, UnaryOp,
+};
+
+/// Invocations per workgroup
+pub const WORKGROUP_SIZE: u32 = 64;
+
+/// Most workgroups in one dispatch dimension
+const MAX_WORKGROUPS: u32 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+/// `@group(0) @binding(binding)` holds `buffer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub binding: u32,
+    pub buffer: BufferId,
+    pub access: Access,
+}
+
+#[derive(Debug, Clone)]
+pub struct ComputeShader {
+    /// Name of the kernel in the program
+    pub kernel: String,
+    pub node: NodeId,
+    pub source: String,
+    pub entry_point: &'static str,
+    pub bindings: Vec<Binding>,
+    /// Invocations that do work; the rest return at once
+    pub invocations: u32,
+    /// Workgroup counts for `dispatch_workgroups`
+    pub workgroups: [u32; 3],
+}
+
+/// A shader for every kernel of `program`, in execution order.
+pub fn generate(program: &Program) -> Result<Vec<ComputeShader>> {
+    for b in &program.buffers {
+        ensure!(
+            b.len() <= i32::MAX as usize,
+            UnsupportedOp,
+            "buffer {} has {} elements, more than WGSL indices reach",
+            b.name,
+            b.len()
+        );
+    }
+    program.kernels.iter().map(|k| kernel(program, k)).collect()
+}
+
+fn kernel(program: &Program, kernel: &Kernel) -> Result<ComputeShader> {
+    let mut bindings = BTreeMap::new();
+    buffers(&kernel.body, &mut bindings);
+    let bindings: Vec<Binding> = bindings
+        .into_iter()
+        .enumerate()
+        .map(|(i, (buffer, access))| Binding {
+            binding: i as u32,
+            buffer,
+            access,
+        })
+        .collect();
+    let mut locals = Vec::new();
+    collect_locals(&kernel.body, &mut locals);
+    locals.sort_by_key(|l| l.0);
+    locals.dedup();
+
+    let mut w = Writer {
+        program,
+        out: String::new(),
+        depth: 1,
+    };
+    let mut invocations = 1u32;
+    for stmt in &kernel.body {
+        let threads = w.top_level(stmt)?;
+        invocations = invocations.max(threads);
+    }
+    let body = w.out;
+
+    let mut source = String::new();
+    for b in &bindings {
+        let access = match b.access {
+            Access::Read => "read",
+            Access::ReadWrite => "read_write",
+        };
+        let name = &program.buffer(b.buffer).name;
+        writeln!(
+            source,
+            "@group(0) @binding({}) var<storage, {}> {}: array<f32>;",
+            b.binding, access, name
+        )
+        .unwrap();
+    }
+    writeln!(source).unwrap();
+    writeln!(source, "// {}", kernel.name).unwrap();
+    writeln!(source, "@compute @workgroup_size({})", WORKGROUP_SIZE).unwrap();
+    writeln!(
+        source,
+        "fn main(@builtin(global_invocation_id) gid: vec3<u32>, \
+         @builtin(num_workgroups) groups: vec3<u32>) {{"
+    )
+    .unwrap();
+    writeln!(
+        source,
+        "    let t = gid.x + gid.y * groups.x * {}u;",
+        WORKGROUP_SIZE
+    )
+    .unwrap();
+    for l in &locals {
+        writeln!(source, "    var a{}: f32;", l.0).unwrap();
+    }
+    source.push_str(&body);
+    source.push_str("}\n");
+
+    let groups = invocations.div_ceil(WORKGROUP_SIZE).max(1);
+    let x = groups.min(MAX_WORKGROUPS);
+    Ok(ComputeShader {
+        kernel: kernel.name.clone(),
+        node: kernel.node,
+        source,
+        entry_point: "main",
+        bindings,
+        invocations,
+        workgroups: [x, groups.div_ceil(x), 1],
+    })
+}
+
+struct Writer<'a> {
+    program: &'a Program,
+    out: String,
+    depth: usize,
+}
+
+impl Writer<'_> {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    /// Emit a top-level statement with its outer independent loops spread
+    /// over invocations `0..n`; returns `n`.
+    fn top_level(&mut self, stmt: &Stmt) -> Result<u32> {
+        let mut loops: Vec<&Loop> = Vec::new();
+        let mut guards: Vec<&Bound> = Vec::new();
+        let mut body = std::slice::from_ref(stmt);
+        loop {
+            match body {
+                [Stmt::For(l)] if l.carried_local().is_none() => {
+                    loops.push(l);
+                    body = &l.body;
+                }
+                [
+                    Stmt::If {
+                        bounds,
+                        body: inner,
+                    },
+                ] => {
+                    guards.extend(bounds);
+                    body = inner;
+                }
+                _ => break,
+            }
+        }
+        let threads = loops
+            .iter()
+            .try_fold(1u32, |n, l| u32::try_from(l.extent).ok()?.checked_mul(n));
+        let Some(threads) = threads.filter(|&n| n <= i32::MAX as u32) else {
+            let extents: Vec<usize> = loops.iter().map(|l| l.extent).collect();
+            bail!(
+                UnsupportedOp,
+                "invocations over loop extents {:?} do not fit a dispatch",
+                extents
+            );
+        };
+
+        self.line(&format!("if (t < {}u) {{", threads));
+        self.depth += 1;
+        let mut stride = 1u32;
+        let mut lets = Vec::new();
+        for (i, l) in loops.iter().enumerate().rev() {
+            let name = self.program.var_name(l.var);
+            let index = match (i, stride) {
+                (0, 1) => "i32(t)".to_string(),
+                (0, s) => format!("i32(t / {}u)", s),
+                (_, 1) => format!("i32(t % {}u)", l.extent),
+                (_, s) => format!("i32((t / {}u) % {}u)", s, l.extent),
+            };
+            lets.push(format!("let {} = {};", name, index));
+            stride *= l.extent as u32;
+        }
+        for text in lets.iter().rev() {
+            self.line(text);
+        }
+        self.guarded(&guards, body);
+        self.depth -= 1;
+        self.line("}");
+        Ok(threads)
+    }
+
+    fn guarded(&mut self, bounds: &[&Bound], body: &[Stmt]) {
+        if bounds.is_empty() {
+            return self.stmts(body);
+        }
+        let conds: Vec<String> = bounds.iter().map(|b| self.bound(b)).collect();
+        self.line(&format!("if ({}) {{", conds.join(" && ")));
+        self.depth += 1;
+        self.stmts(body);
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::For(l) => {
+                    let v = self.program.var_name(l.var);
+                    self.line(&format!(
+                        "for (var {v}: i32 = 0; {v} < {}; {v}++) {{",
+                        l.extent
+                    ));
+                    self.depth += 1;
+                    self.stmts(&l.body);
+                    self.depth -= 1;
+                    self.line("}");
+                }
+                Stmt::If { bounds, body } => {
+                    let bounds: Vec<&Bound> = bounds.iter().collect();
+                    self.guarded(&bounds, body);
+                }
+                Stmt::Assign { local, value } => {
+                    let text = format!("a{} = {};", local.0, self.expr(value));
+                    self.line(&text);
+                }
+                Stmt::Store {
+                    buffer,
+                    index,
+                    value,
+                } => {
+                    let text = format!(
+                        "{}[{}] = {};",
+                        self.program.buffer(*buffer).name,
+                        self.affine(index),
+                        self.expr(value)
+                    );
+                    self.line(&text);
+                }
+            }
+        }
+    }
+
+    fn bound(&self, b: &Bound) -> String {
+        let index = self.affine(&b.index);
+        format!("0 <= {} && {} < {}", index, index, b.extent)
+    }
+
+    fn affine(&self, a: &Affine) -> String {
+        let mut sorted = a.terms.clone();
+        sorted.sort_by_key(|&(_, k)| std::cmp::Reverse(k.abs()));
+        let mut terms: Vec<String> = sorted
+            .iter()
+            .map(|&(v, k)| match k {
+                1 => self.program.var_name(v).to_string(),
+                k => format!("{} * {}", k, self.program.var_name(v)),
+            })
+            .collect();
+        if a.constant != 0 || terms.is_empty() {
+            terms.push(a.constant.to_string());
+        }
+        terms.join(" + ").replace("+ -", "- ")
+    }
+
+    fn expr(&self, e: &Expr) -> String {
+        match e {
+            Expr::Const(c) => float(*c),
+            Expr::Local(l) => format!("a{}", l.0),
+            Expr::Load(b, index) => {
+                format!("{}[{}]", self.program.buffer(*b).name, self.affine(index))
+            }
+            Expr::Unary(UnaryOp::Sqrt, x) => format!("sqrt({})", self.expr(x)),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.expr(a), self.expr(b));
+                match op {
+                    BinaryOp::Add => format!("({} + {})", a, b),
+                    BinaryOp::Sub => format!("({} - {})", a, b),
+                    BinaryOp::Mul => format!("({} * {})", a, b),
+                    BinaryOp::Div => format!("({} / {})", a, b),
+                    BinaryOp::Max => format!("max({}, {})", a, b),
+                    BinaryOp::Min => format!("min({}, {})", a, b),
+                }
+            }
+        }
+    }
+}
+
+/// A WGSL f32 literal
+fn float(c: f32) -> String {
+    if c.is_finite() {
+        format!("f32({:?})", c)
+    } else if c.is_nan() {
+        "bitcast<f32>(0x7fc00000u)".to_string()
+    } else {
+        let bits = if c > 0.0 {
+            "0x7f800000u"
+        } else {
+            "0xff800000u"
+        };
+        format!("bitcast<f32>({})", bits)
+    }
+}
+
+/// Buffers read or written below `stmts`
+fn buffers(stmts: &[Stmt], out: &mut BTreeMap<BufferId, Access>) {
+    fn reads(e: &Expr, out: &mut BTreeMap<BufferId, Access>) {
+        match e {
+            Expr::Const(_) | Expr::Local(_) => {}
+            Expr::Load(b, _) => {
+                out.entry(*b).or_insert(Access::Read);
+            }
+            Expr::Unary(_, x) => reads(x, out),
+            Expr::Binary(_, a, b) => {
+                reads(a, out);
+                reads(b, out);
+            }
+        }
+    }
+    for stmt in stmts {
+        match stmt {
+            Stmt::For(Loop { body, .. }) | Stmt::If { body, .. } => buffers(body, out),
+            Stmt::Assign { value, .. } => reads(value, out),
+            Stmt::Store { buffer, value, .. } => {
+                reads(value, out);
+                out.insert(*buffer, Access::ReadWrite);
+            }
+        }
+    }
+}
+
+fn collect_locals(stmts: &[Stmt], out: &mut Vec<Local>) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::For(Loop { body, .. }) | Stmt::If { body, .. } => collect_locals(body, out),
+            Stmt::Assign { local, .. } => out.push(*local),
+            Stmt::Store { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::loop_ir::lower;
+    use crate::test_util::{desc, node};
+    use crate::{
+        Activation, AveragePoolAttrs, ConcatAttrs, Conv2DAttrs, Graph, MatMulAttrs, OpKind, Tensor,
+        TransposeAttrs, ValueId,
+    };
+
+    fn validate(shader: &ComputeShader) {
+        let module = naga::front::wgsl::parse_str(&shader.source)
+            .unwrap_or_else(|e| panic!("{}\n{}", e.emit_to_string(&shader.source), shader.source));
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap_or_else(|e| panic!("{:?}\n{}", e, shader.source));
+    }
+
+    #[test]
+    fn matmul_snapshot() {
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(
+                    OpKind::Constant(Tensor::full(vec![3, 2], 0.5).into()),
+                    &[],
+                    1,
+                ),
+                node(
+                    OpKind::MatMul(Some(MatMulAttrs {
+                        trans_a: false,
+                        trans_b: false,
+                        activation: Some(Activation::Relu),
+                    })),
+                    &[0, 1],
+                    2,
+                ),
+            ],
+            outputs: vec![ValueId(2)],
+            value_types: HashMap::from([(ValueId(0), desc(vec![4, 3]))]),
+        };
+        let program = lower(&graph, &HashMap::new()).unwrap();
+        let shaders = generate(&program).unwrap();
+        assert_eq!(shaders.len(), 1);
+        let s = &shaders[0];
+        validate(s);
+        assert_eq!(s.invocations, 8);
+        assert_eq!(s.workgroups, [1, 1, 1]);
+        assert_eq!(
+            s.bindings
+                .iter()
+                .map(|b| (b.buffer.0, b.access))
+                .collect::<Vec<_>>(),
+            vec![(0, Access::Read), (1, Access::Read), (2, Access::ReadWrite)]
+        );
+        insta::assert_snapshot!(s.source);
+    }
+
+    #[test]
+    fn shaders_validate() {
+        let kernel = Tensor::from_fn(vec![3, 3, 2, 4], |i| (i[0] + i[3]) as f32 - 2.0);
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Constant(kernel.into()), &[], 1),
+                node(
+                    OpKind::Conv2D(Conv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [2, 2],
+                        pads: [1, 1, 1, 1],
+                        dilations: [1, 1],
+                        group: 1,
+                        activation: Some(Activation::HardSwish),
+                    }),
+                    &[0, 1],
+                    2,
+                ),
+                node(
+                    OpKind::AveragePool(AveragePoolAttrs {
+                        kernel_shape: [2, 2],
+                        strides: [1, 1],
+                        pads: [0, 0, 1, 1],
+                        count_include_pad: false,
+                    }),
+                    &[2],
+                    3,
+                ),
+                node(OpKind::GlobalAveragePool, &[3], 4),
+                node(OpKind::Concat(ConcatAttrs { axis: 3 }), &[4, 4], 5),
+                node(OpKind::Relu6, &[5], 6),
+            ],
+            outputs: vec![ValueId(6)],
+            value_types: HashMap::from([(ValueId(0), desc(vec![1, 7, 7, 2]))]),
+        };
+        let mut program = lower(&graph, &HashMap::new()).unwrap();
+        program
+            .schedule("Conv2D_2")
+            .unwrap()
+            .tile("oh", "ow", 3, 2)
+            .unwrap()
+            .unroll("kw", 3)
+            .unwrap();
+
+        let shaders = generate(&program).unwrap();
+        assert_eq!(shaders.len(), program.kernels.len());
+        for s in &shaders {
+            validate(s);
+        }
+        // Output elements of the conv, guarded where the tiles overhang
+        assert_eq!(shaders[0].invocations, 2 * 2 * 3 * 2 * 4);
+    }
+
+    #[test]
+    fn rejects_oversized_dispatch() {
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(
+                    OpKind::Transpose(TransposeAttrs { perm: vec![1, 0] }),
+                    &[0],
+                    1,
+                ),
+            ],
+            outputs: vec![ValueId(1)],
+            value_types: HashMap::from([(ValueId(0), desc(vec![2, 3]))]),
+        };
+        let mut program = lower(&graph, &HashMap::new()).unwrap();
+        // Extents whose product overflows usize
+        let Stmt::For(outer) = &mut program.kernels[0].body[0] else {
+            panic!("Transpose lowers to a loop nest");
+        };
+        outer.extent = 1 << 40;
+        let Stmt::For(inner) = &mut outer.body[0] else {
+            panic!("Transpose lowers to a 2D loop nest");
+        };
+        inner.extent = 1 << 40;
+
+        let e = generate(&program).unwrap_err();
+        assert_eq!(e.kind(), "UnsupportedOp");
+        let extents = format!("{:?}", [1usize << 40, 1 << 40]);
+        assert!(e.to_string().contains(&extents), "{}", e);
+    }
+}
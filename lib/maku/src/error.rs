@@ -0,0 +1,226 @@
+//! Typed errors for graph validation and execution.
+//!
+//! Kernels and shape helpers raise errors without node context; the
+//! executor attaches the failing node with [`Error::at`] so callers (e.g.
+//! the editor) can point at it.
+
+use std::fmt;
+
+use crate::{Node, NodeId, ValueId};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The node an error was raised at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub op: &'static str,
+    pub inputs: Vec<ValueId>,
+    pub output: ValueId,
+}
+
+impl From<&Node> for NodeInfo {
+    fn from(node: &Node) -> Self {
+        NodeInfo {
+            id: node.id,
+            op: node.op.name(),
+            inputs: node.inputs.clone(),
+            output: node.output,
+        }
+    }
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inputs: Vec<String> = self.inputs.iter().map(|v| v.0.to_string()).collect();
+        write!(
+            f,
+            "{} node {} ([{}] -> {})",
+            self.op,
+            self.id.0,
+            inputs.join(", "),
+            self.output.0
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Input shapes the op cannot combine
+    ShapeMismatch {
+        node: Option<NodeInfo>,
+        message: String,
+    },
+    /// A value read by a node was neither provided nor computed
+    MissingInput {
+        node: Option<NodeInfo>,
+        value: ValueId,
+    },
+    /// An Input node's type was given neither by the caller nor in
+    /// `graph.value_types`
+    MissingType {
+        node: Option<NodeInfo>,
+        value: ValueId,
+    },
+    /// Malformed graph structure, e.g. a node with too few inputs
+    InvalidGraph {
+        node: Option<NodeInfo>,
+        message: String,
+    },
+    /// The op cannot be executed on this path
+    UnsupportedOp {
+        node: Option<NodeInfo>,
+        message: String,
+    },
+    /// Attribute values the op rejects
+    InvalidAttr {
+        node: Option<NodeInfo>,
+        message: String,
+    },
+    /// A graph output was never produced
+    MissingOutput { value: ValueId },
+    /// Broken invariant of the executor or planner
+    Internal { message: String },
+    /// The GPU adapter or device failed
+    Device { message: String },
+}
+
+impl Error {
+    /// Attach `node` unless the error already names one.
+    pub fn at(mut self, node: &Node) -> Self {
+        if let Some(slot @ None) = self.node_slot() {
+            *slot = Some(node.into());
+        }
+        self
+    }
+
+    /// The node the error was raised at, if known.
+    pub fn node(&self) -> Option<&NodeInfo> {
+        match self {
+            Error::ShapeMismatch { node, .. }
+            | Error::MissingInput { node, .. }
+            | Error::MissingType { node, .. }
+            | Error::InvalidGraph { node, .. }
+            | Error::UnsupportedOp { node, .. }
+            | Error::InvalidAttr { node, .. } => node.as_ref(),
+            Error::MissingOutput { .. } | Error::Internal { .. } | Error::Device { .. } => None,
+        }
+    }
+
+    /// Variant name, e.g. `"ShapeMismatch"`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::ShapeMismatch { .. } => "ShapeMismatch",
+            Error::MissingInput { .. } => "MissingInput",
+            Error::MissingType { .. } => "MissingType",
+            Error::InvalidGraph { .. } => "InvalidGraph",
+            Error::UnsupportedOp { .. } => "UnsupportedOp",
+            Error::InvalidAttr { .. } => "InvalidAttr",
+            Error::MissingOutput { .. } => "MissingOutput",
+            Error::Internal { .. } => "Internal",
+            Error::Device { .. } => "Device",
+        }
+    }
+
+    /// Description without the node context.
+    pub fn message(&self) -> String {
+        match self {
+            Error::ShapeMismatch { message, .. }
+            | Error::InvalidGraph { message, .. }
+            | Error::UnsupportedOp { message, .. }
+            | Error::InvalidAttr { message, .. }
+            | Error::Internal { message }
+            | Error::Device { message } => message.clone(),
+            Error::MissingInput { node, value } => match node {
+                Some(node) => format!("{} missing input {:?}", node.op, value),
+                None => format!("missing input tensor for {:?}", value),
+            },
+            Error::MissingType { value, .. } => format!("no type for Input {:?}", value),
+            Error::MissingOutput { value } => format!("missing output tensor for {:?}", value),
+        }
+    }
+
+    fn node_slot(&mut self) -> Option<&mut Option<NodeInfo>> {
+        match self {
+            Error::ShapeMismatch { node, .. }
+            | Error::MissingInput { node, .. }
+            | Error::MissingType { node, .. }
+            | Error::InvalidGraph { node, .. }
+            | Error::UnsupportedOp { node, .. }
+            | Error::InvalidAttr { node, .. } => Some(node),
+            Error::MissingOutput { .. } | Error::Internal { .. } | Error::Device { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.node() {
+            Some(node) => write!(f, "{} at {}", self.message(), node),
+            None => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// `Error::$kind { node: None, message: format!(...) }`
+macro_rules! err {
+    (Internal, $($arg:tt)+) => {
+        $crate::Error::Internal { message: format!($($arg)+) }
+    };
+    (Device, $($arg:tt)+) => {
+        $crate::Error::Device { message: format!($($arg)+) }
+    };
+    ($kind:ident, $($arg:tt)+) => {
+        $crate::Error::$kind { node: None, message: format!($($arg)+) }
+    };
+}
+
+/// Return `err!($kind, ...)` from the enclosing function.
+macro_rules! bail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err($crate::error::err!($kind, $($arg)+))
+    };
+}
+
+/// `bail!` unless `$cond` holds.
+macro_rules! ensure {
+    ($cond:expr, $kind:ident, $($arg:tt)+) => {
+        if !$cond {
+            $crate::error::bail!($kind, $($arg)+);
+        }
+    };
+}
+
+pub(crate) use {bail, ensure, err};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpKind;
+
+    #[test]
+    fn at_attaches_node_once() {
+        let node = |id: u32| Node {
+            id: NodeId(id),
+            op: OpKind::Add,
+            inputs: vec![ValueId(0), ValueId(1)],
+            output: ValueId(id),
+        };
+        let e = err!(ShapeMismatch, "Add shape mismatch: {:?} vs {:?}", [2], [3]);
+        assert_eq!(e.node(), None);
+        assert_eq!(e.to_string(), "Add shape mismatch: [2] vs [3]");
+
+        let e = e.at(&node(2)).at(&node(5));
+        assert_eq!(e.node().unwrap().id, NodeId(2));
+        assert_eq!(e.kind(), "ShapeMismatch");
+        assert_eq!(
+            e.to_string(),
+            "Add shape mismatch: [2] vs [3] at Add node 2 ([0, 1] -> 2)"
+        );
+
+        let missing = Error::MissingOutput { value: ValueId(7) }.at(&node(1));
+        assert_eq!(missing.node(), None);
+    }
+}
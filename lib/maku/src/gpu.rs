@@ -0,0 +1,539 @@
+//! WebGPU backend (feature `gpu`).
+//!
+//! Each node is lowered to Loop IR on its own and runs as the WGSL shaders
+//! [`codegen::wgsl`] generates for it. Values stay in GPU buffers between
+//! nodes and are read back only for graph outputs and for nodes that fall
+//! back to the [`CpuBackend`]: ops the lowering rejects, and kernels beyond
+//! the device limits.
+//!
+//! The same code runs natively (Vulkan, Metal, DX12, GL) and in the browser
+//! (WebGPU). Native callers without an executor can use the `*_blocking`
+//! methods.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use wgpu::util::DeviceExt;
+
+use crate::codegen::wgsl::{self, ComputeShader};
+use crate::error::{Result, err};
+use crate::loop_ir::{BufferKind, Program, lower};
+use crate::memory::last_uses;
+use crate::shape::infer_shapes;
+use crate::{
+    CpuBackend, Error, Graph, Node, NodeId, OpKind, Tensor, TensorDesc, TensorView, ValueId,
+};
+
+pub struct GpuBackend {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    /// Runs the nodes the GPU cannot
+    cpu: CpuBackend,
+    /// Compiled pipelines by WGSL source
+    pipelines: Mutex<HashMap<String, wgpu::ComputePipeline>>,
+}
+
+/// Current copies of a value; at least one is set
+struct Value<'a> {
+    host: Option<TensorView<'a>>,
+    device: Option<wgpu::Buffer>,
+}
+
+impl<'a> Value<'a> {
+    fn host(t: TensorView<'a>) -> Self {
+        Value {
+            host: Some(t),
+            device: None,
+        }
+    }
+}
+
+impl GpuBackend {
+    /// Backend on the default adapter, honouring the `WGPU_BACKEND`,
+    /// `WGPU_ADAPTER_NAME` and `WGPU_POWER_PREF` environment variables (so
+    /// CI can pick a software adapter).
+    pub async fn new() -> Result<Self> {
+        let instance =
+            wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
+        let adapter = wgpu::util::initialize_adapter_from_env_or_default(&instance, None)
+            .await
+            .map_err(|e| err!(Device, "no GPU adapter: {}", e))?;
+        if !adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+        {
+            let name = adapter.get_info().name;
+            return Err(err!(Device, "adapter {} has no compute shaders", name));
+        }
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                label: Some("maku"),
+                required_limits: adapter.limits(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| err!(Device, "{}", e))?;
+        Ok(Self::from_device(device, queue))
+    }
+
+    /// Backend on a device the caller already owns, e.g. one shared with a
+    /// renderer.
+    pub fn from_device(device: wgpu::Device, queue: wgpu::Queue) -> Self {
+        GpuBackend {
+            device,
+            queue,
+            cpu: CpuBackend::new(),
+            pipelines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    /// Execute `graph` like [`CpuBackend::run`].
+    pub async fn run(
+        &self,
+        graph: &Graph,
+        input_tensors: &HashMap<ValueId, Tensor>,
+    ) -> Result<HashMap<ValueId, Tensor>> {
+        // A missing tensor, not a missing type, as in `CpuBackend::run`
+        let missing = graph
+            .nodes
+            .iter()
+            .find(|n| matches!(n.op, OpKind::Input) && !input_tensors.contains_key(&n.output));
+        if let Some(node) = missing {
+            return Err(Error::MissingInput {
+                node: Some(node.into()),
+                value: node.output,
+            });
+        }
+        let descs = input_tensors
+            .iter()
+            .map(|(&id, t)| (id, t.desc.clone()))
+            .collect();
+        let types = infer_shapes(graph, &descs)?;
+        let last_uses = last_uses(graph);
+
+        let mut values: HashMap<ValueId, Value> = HashMap::new();
+        for (i, node) in graph.nodes.iter().enumerate() {
+            let value = match &node.op {
+                OpKind::Input => Value::host(input_tensors[&node.output].view()),
+                OpKind::Constant(t) => Value::host(t.view()),
+                _ => self
+                    .eval_node(node, &types, &mut values)
+                    .await
+                    .map_err(|e| e.at(node))?,
+            };
+
+            values.insert(node.output, value);
+            for id in node.inputs.iter().chain([&node.output]) {
+                if last_uses.get(id).is_none_or(|&last| last <= i) {
+                    values.remove(id);
+                }
+            }
+        }
+
+        let mut outputs = HashMap::new();
+        for &vid in &graph.outputs {
+            if let Some(mut value) = values.remove(&vid) {
+                self.to_host(&mut value, &types[&vid]).await?;
+                let t = value.host.expect("to_host sets the host copy");
+                outputs.insert(vid, t.into_tensor());
+            } else if !outputs.contains_key(&vid) {
+                return Err(Error::MissingOutput { value: vid });
+            }
+        }
+        Ok(outputs)
+    }
+
+    async fn eval_node<'a>(
+        &self,
+        node: &'a Node,
+        types: &HashMap<ValueId, TensorDesc>,
+        values: &mut HashMap<ValueId, Value<'a>>,
+    ) -> Result<Value<'a>> {
+        if let Some(&value) = node.inputs.iter().find(|id| !values.contains_key(id)) {
+            return Err(Error::MissingInput { node: None, value });
+        }
+        if let Some((program, shaders)) = self.kernels(node, types)? {
+            let out = self.dispatch(&program, &shaders, values).await?;
+            return Ok(Value {
+                host: None,
+                device: Some(out),
+            });
+        }
+
+        for id in &node.inputs {
+            let value = values.get_mut(id).expect("checked above");
+            self.to_host(value, &types[id]).await?;
+        }
+        let args: Vec<&TensorView> = node
+            .inputs
+            .iter()
+            .map(|id| {
+                values[id]
+                    .host
+                    .as_ref()
+                    .expect("to_host sets the host copy")
+            })
+            .collect();
+        Ok(Value::host(self.cpu.eval_node(node, &args)?))
+    }
+
+    /// Shaders for `node` alone, or None when it falls back to the CPU.
+    fn kernels(
+        &self,
+        node: &Node,
+        types: &HashMap<ValueId, TensorDesc>,
+    ) -> Result<Option<(Program, Vec<ComputeShader>)>> {
+        let program = match lower(&single_node_graph(node, types), &HashMap::new()) {
+            Ok(program) => program,
+            Err(e) if e.kind() == "UnsupportedOp" => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let shaders = match wgsl::generate(&program) {
+            Ok(shaders) => shaders,
+            Err(e) if e.kind() == "UnsupportedOp" => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let limits = self.device.limits();
+        let fits = program.buffers.iter().all(|b| {
+            !b.is_empty() && (b.len() * 4) as u64 <= limits.max_storage_buffer_binding_size
+        }) && shaders.iter().all(|s| {
+            s.bindings.len() as u32 <= limits.max_storage_buffers_per_shader_stage
+                && s.workgroups[1] <= limits.max_compute_workgroups_per_dimension
+        });
+        Ok(fits.then_some((program, shaders)))
+    }
+
+    /// Run the shaders of a single-node program and return its output buffer.
+    async fn dispatch(
+        &self,
+        program: &Program,
+        shaders: &[ComputeShader],
+        values: &mut HashMap<ValueId, Value<'_>>,
+    ) -> Result<wgpu::Buffer> {
+        let mut buffers = Vec::with_capacity(program.buffers.len());
+        for (i, b) in program.buffers.iter().enumerate() {
+            let buffer = match &b.kind {
+                BufferKind::Input => {
+                    let &(value, _) = program
+                        .inputs
+                        .iter()
+                        .find(|(_, id)| id.0 as usize == i)
+                        .ok_or_else(|| err!(Internal, "no value for input buffer {}", b.name))?;
+                    let value = values.get_mut(&value).expect("inputs are present");
+                    self.to_device(value).clone()
+                }
+                BufferKind::Constant(t) => self.upload(&t.data),
+                BufferKind::Temp => self.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(&b.name),
+                    size: (b.len() * 4) as u64,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+            };
+            buffers.push(buffer);
+        }
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        for shader in shaders {
+            let pipeline = self.pipeline(shader).await?;
+            let entries: Vec<wgpu::BindGroupEntry> = shader
+                .bindings
+                .iter()
+                .map(|b| wgpu::BindGroupEntry {
+                    binding: b.binding,
+                    resource: buffers[b.buffer.0 as usize].as_entire_binding(),
+                })
+                .collect();
+            let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(&shader.kernel),
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &entries,
+            });
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&shader.kernel),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            let [x, y, z] = shader.workgroups;
+            pass.dispatch_workgroups(x, y, z);
+        }
+        self.queue.submit([encoder.finish()]);
+
+        let &(_, out) = program
+            .outputs
+            .first()
+            .ok_or_else(|| err!(Internal, "single-node program without output"))?;
+        Ok(buffers.swap_remove(out.0 as usize))
+    }
+
+    async fn pipeline(&self, shader: &ComputeShader) -> Result<wgpu::ComputePipeline> {
+        if let Some(p) = self.pipelines.lock().unwrap().get(&shader.source) {
+            return Ok(p.clone());
+        }
+        let scope = self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let module = self
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(&shader.kernel),
+                source: wgpu::ShaderSource::Wgsl(shader.source.as_str().into()),
+            });
+        let pipeline = self
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&shader.kernel),
+                layout: None,
+                module: &module,
+                entry_point: Some(shader.entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+        if let Some(e) = scope.pop().await {
+            return Err(err!(Device, "compiling {}: {}", shader.kernel, e));
+        }
+        self.pipelines
+            .lock()
+            .unwrap()
+            .insert(shader.source.clone(), pipeline.clone());
+        Ok(pipeline)
+    }
+
+    fn upload(&self, data: &[f32]) -> wgpu::Buffer {
+        let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: &bytes,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            })
+    }
+
+    fn to_device<'v>(&self, value: &'v mut Value) -> &'v wgpu::Buffer {
+        if value.device.is_none() {
+            let t = value
+                .host
+                .as_ref()
+                .expect("a value has a host or device copy");
+            value.device = Some(self.upload(&t.contiguous()));
+        }
+        value.device.as_ref().unwrap()
+    }
+
+    async fn to_host(&self, value: &mut Value<'_>, desc: &TensorDesc) -> Result<()> {
+        if value.host.is_some() {
+            return Ok(());
+        }
+        let buffer = value
+            .device
+            .as_ref()
+            .expect("a value has a host or device copy");
+        let size = buffer.size();
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+        self.queue.submit([encoder.finish()]);
+
+        let state = Arc::new(Mutex::new(MapState::default()));
+        let callback = state.clone();
+        staging.map_async(wgpu::MapMode::Read, .., move |r| {
+            let mut s = callback.lock().unwrap();
+            s.result = Some(r);
+            if let Some(w) = s.waker.take() {
+                w.wake();
+            }
+        });
+        // Native backends run the callback from here; the browser from its
+        // event loop
+        self.device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .map_err(|e| err!(Device, "{}", e))?;
+        Mapped(state)
+            .await
+            .map_err(|e| err!(Device, "reading back a buffer: {}", e))?;
+
+        let data = {
+            let view = staging
+                .get_mapped_range(..)
+                .map_err(|e| err!(Device, "reading back a buffer: {}", e))?;
+            view.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        value.host = Some(Tensor::new(desc.clone(), data).into());
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl GpuBackend {
+    /// [`GpuBackend::new`], blocking the current thread.
+    pub fn new_blocking() -> Result<Self> {
+        pollster::block_on(Self::new())
+    }
+
+    /// [`GpuBackend::run`], blocking the current thread.
+    pub fn run_blocking(
+        &self,
+        graph: &Graph,
+        input_tensors: &HashMap<ValueId, Tensor>,
+    ) -> Result<HashMap<ValueId, Tensor>> {
+        pollster::block_on(self.run(graph, input_tensors))
+    }
+}
+
+/// `node` reading Inputs of the types in `types`
+fn single_node_graph(node: &Node, types: &HashMap<ValueId, TensorDesc>) -> Graph {
+    let mut inputs = node.inputs.clone();
+    inputs.sort_by_key(|v| v.0);
+    inputs.dedup();
+    let mut nodes: Vec<Node> = inputs
+        .iter()
+        .map(|&v| Node {
+            id: NodeId(v.0),
+            op: OpKind::Input,
+            inputs: Vec::new(),
+            output: v,
+        })
+        .collect();
+    nodes.push(node.clone());
+    Graph {
+        nodes,
+        outputs: vec![node.output],
+        value_types: inputs.iter().map(|v| (*v, types[v].clone())).collect(),
+    }
+}
+
+#[derive(Default)]
+struct MapState {
+    result: Option<Result<(), wgpu::BufferAsyncError>>,
+    waker: Option<Waker>,
+}
+
+/// Resolves when the `map_async` callback has run
+struct Mapped(Arc<Mutex<MapState>>);
+
+impl Future for Mapped {
+    type Output = Result<(), wgpu::BufferAsyncError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut s = self.0.lock().unwrap();
+        match s.result.take() {
+            Some(r) => Poll::Ready(r),
+            None => {
+                s.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::node;
+    use crate::{
+        Activation, AveragePoolAttrs, ConcatAttrs, Conv2DAttrs, MatMulAttrs, ReshapeAttrs,
+    };
+
+    /// Backend on the default adapter; None (skipping the test) on
+    /// machines without one. CI can pick a software adapter (lavapipe,
+    /// llvmpipe) through `WGPU_BACKEND`.
+    fn backend() -> Option<GpuBackend> {
+        match GpuBackend::new_blocking() {
+            Ok(b) => Some(b),
+            Err(e) => {
+                eprintln!("skipping GPU test: {}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn matches_cpu_backend() {
+        let Some(gpu) = backend() else { return };
+        let kernel = Tensor::from_fn(vec![3, 3, 2, 4], |i| {
+            ((i[0] * 5 + i[1] * 3 + i[2] * 2 + i[3]) % 7) as f32 / 4.0 - 0.75
+        });
+        let weight = Tensor::from_fn(vec![4, 4], |i| (i[0] * 4 + i[1]) as f32 * 0.1 - 0.5);
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Constant(kernel.into()), &[], 1),
+                node(
+                    OpKind::Conv2D(Conv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [1, 1],
+                        pads: [1, 1, 1, 1],
+                        dilations: [1, 1],
+                        group: 1,
+                        activation: Some(Activation::Relu6),
+                    }),
+                    &[0, 1],
+                    2,
+                ),
+                node(
+                    OpKind::Reshape(ReshapeAttrs {
+                        shape: vec![20, 4],
+                        allowzero: false,
+                    }),
+                    &[2],
+                    3,
+                ),
+                node(OpKind::Constant(weight.into()), &[], 4),
+                // Lowered with the weight read in swapped index order
+                node(
+                    OpKind::MatMul(Some(MatMulAttrs {
+                        trans_a: false,
+                        trans_b: true,
+                        activation: None,
+                    })),
+                    &[3, 4],
+                    5,
+                ),
+                node(OpKind::HardSwish, &[5], 6),
+                node(
+                    OpKind::AveragePool(AveragePoolAttrs {
+                        kernel_shape: [2, 2],
+                        strides: [2, 2],
+                        pads: [0, 0, 1, 1],
+                        count_include_pad: false,
+                    }),
+                    &[2],
+                    7,
+                ),
+                node(OpKind::GlobalAveragePool, &[2], 8),
+                node(OpKind::Concat(ConcatAttrs { axis: 3 }), &[8, 8], 9),
+            ],
+            outputs: vec![ValueId(2), ValueId(6), ValueId(7), ValueId(9)],
+            value_types: HashMap::new(),
+        };
+        let x = Tensor::from_fn(vec![1, 4, 5, 2], |i| {
+            (i[1] * 10 + i[2] * 2 + i[3]) as f32 / 10.0 - 2.0
+        });
+        let inputs = HashMap::from([(ValueId(0), x)]);
+
+        let expected = CpuBackend::new().run(&graph, &inputs).unwrap();
+        let actual = gpu.run_blocking(&graph, &inputs).unwrap();
+        for v in &graph.outputs {
+            assert_eq!(actual[v].desc.shape, expected[v].desc.shape);
+            assert!(actual[v].approx_eq(&expected[v], 1e-4), "{:?}", v);
+        }
+
+        let e = gpu.run_blocking(&graph, &HashMap::new()).unwrap_err();
+        assert_eq!(e.kind(), "MissingInput");
+    }
+}
@@ -1,6 +1,35 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
-/// ---------- Basic types: Tensor / Op / Graph ----------
+use error::{bail, ensure, err};
+
+pub mod codegen;
+mod error;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod loop_ir;
+pub mod memory;
+pub mod parallel;
+pub mod passes;
+mod scheduler;
+pub mod session;
+pub mod shape;
+pub mod simd;
+mod tensor;
+#[cfg(test)]
+mod test_util;
+pub mod view;
+
+pub use error::{Error, NodeInfo, Result};
+#[cfg(feature = "gpu")]
+pub use gpu::GpuBackend;
+pub use parallel::ThreadPool;
+pub use session::Session;
+pub use simd::SimdLevel;
+pub use tensor::TensorError;
+pub use view::TensorView;
+
+// ---------- Basic types: Tensor / Op / Graph ----------
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ValueId(pub u32);
@@ -28,17 +57,28 @@ pub struct Tensor {
 }
 
 impl Tensor {
+    /// Panics if `data` does not fit the shape; use [`Tensor::try_new`] for
+    /// data from outside the program.
     pub fn new(desc: TensorDesc, data: Vec<f32>) -> Self {
-        let expected: usize = desc.shape.iter().product();
-        assert_eq!(
-            expected,
-            data.len(),
-            "tensor data length {} does not match shape {:?} ({} elements expected)",
-            data.len(),
-            desc.shape,
-            expected
-        );
-        Tensor { desc, data }
+        Tensor::try_new(desc, data).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(desc: TensorDesc, data: Vec<f32>) -> Result<Self, TensorError> {
+        let expected = desc
+            .shape
+            .iter()
+            .try_fold(1usize, |n, &d| n.checked_mul(d))
+            .ok_or_else(|| TensorError::ShapeOverflow {
+                shape: desc.shape.clone(),
+            })?;
+        if expected != data.len() {
+            return Err(TensorError::DataLength {
+                shape: desc.shape,
+                expected,
+                actual: data.len(),
+            });
+        }
+        Ok(Tensor { desc, data })
     }
 
     pub fn zeros(shape: Vec<usize>) -> Self {
@@ -55,34 +95,47 @@ impl Tensor {
     pub fn len(&self) -> usize {
         self.data.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Borrow as a contiguous view.
+    pub fn view(&self) -> TensorView<'_> {
+        TensorView::from(self)
+    }
 }
 
 /// Attribute structures for each operation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Conv2DAttrs {
     pub kernel_shape: [usize; 2],
     pub strides: [usize; 2],
     pub pads: [usize; 4],
     pub dilations: [usize; 2],
     pub group: usize,
+    /// Applied to each output as it is written
+    pub activation: Option<Activation>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DepthwiseConv2DAttrs {
     pub kernel_shape: [usize; 2],
     pub strides: [usize; 2],
     pub pads: [usize; 4],
     pub dilations: [usize; 2],
     pub depth_multiplier: usize,
+    /// Applied to each output as it is written
+    pub activation: Option<Activation>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BatchNormAttrs {
     pub epsilon: f32,
     pub momentum: f32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AveragePoolAttrs {
     pub kernel_shape: [usize; 2],
     pub strides: [usize; 2],
@@ -90,33 +143,151 @@ pub struct AveragePoolAttrs {
     pub count_include_pad: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MatMulAttrs {
     pub trans_a: bool,
     pub trans_b: bool,
+    /// Applied to each output as it is written
+    pub activation: Option<Activation>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ReshapeAttrs {
     pub shape: Vec<isize>,
     pub allowzero: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TransposeAttrs {
     pub perm: Vec<usize>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ConcatAttrs {
     pub axis: usize,
 }
 
+/// Activation epilogue fused into Conv2D / DepthwiseConv2D / MatMul (see
+/// [`passes::ActivationFusion`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    Relu,
+    Relu6,
+    HardSwish,
+}
+
+impl Activation {
+    /// The activation computed by a standalone `op`, if any.
+    pub fn from_op(op: &OpKind) -> Option<Self> {
+        match op {
+            OpKind::Relu => Some(Activation::Relu),
+            OpKind::Relu6 => Some(Activation::Relu6),
+            OpKind::HardSwish => Some(Activation::HardSwish),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Activation::Relu => "Relu",
+            Activation::Relu6 => "Relu6",
+            Activation::HardSwish => "HardSwish",
+        }
+    }
+
+    /// Overwrite `x` with the activation of each element.
+    pub(crate) fn apply(self, level: SimdLevel, x: &mut [f32]) {
+        match self {
+            Activation::Relu => simd::relu_inplace(level, x),
+            Activation::Relu6 => simd::relu6_inplace(level, x),
+            Activation::HardSwish => simd::hard_swish_inplace(level, x),
+        }
+    }
+}
+
+/// Program of a fused elementwise node (see [`passes::ElementwiseFusion`]).
+/// Every input has the output's shape; the result is the last step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedElementwiseAttrs {
+    pub steps: Vec<FusedStep>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedStep {
+    pub op: ElementwiseOp,
+    pub args: Vec<FusedArg>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementwiseOp {
+    Add,
+    Mul,
+    Relu,
+    Relu6,
+    HardSwish,
+}
+
+/// Operand of a [`FusedStep`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FusedArg {
+    /// `i`-th input of the node
+    Input(usize),
+    /// Result of an earlier step
+    Step(usize),
+}
+
+impl ElementwiseOp {
+    /// The fusable op computed by a standalone `op`, if any.
+    pub fn from_op(op: &OpKind) -> Option<Self> {
+        match op {
+            OpKind::Add => Some(ElementwiseOp::Add),
+            OpKind::Mul => Some(ElementwiseOp::Mul),
+            OpKind::Relu => Some(ElementwiseOp::Relu),
+            OpKind::Relu6 => Some(ElementwiseOp::Relu6),
+            OpKind::HardSwish => Some(ElementwiseOp::HardSwish),
+            _ => None,
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            ElementwiseOp::Add | ElementwiseOp::Mul => 2,
+            ElementwiseOp::Relu | ElementwiseOp::Relu6 | ElementwiseOp::HardSwish => 1,
+        }
+    }
+}
+
+impl FusedElementwiseAttrs {
+    /// Check that every step has its arity and only reads node inputs below
+    /// `inputs` and earlier steps.
+    pub fn validate(&self, inputs: usize) -> Result<()> {
+        ensure!(!self.steps.is_empty(), InvalidAttr, "FusedElementwise has no steps");
+        for (s, step) in self.steps.iter().enumerate() {
+            ensure!(
+                step.args.len() == step.op.arity(),
+                InvalidAttr,
+                "FusedElementwise step {} ({:?}) has {} args",
+                s,
+                step.op,
+                step.args.len()
+            );
+            for arg in &step.args {
+                let ok = match *arg {
+                    FusedArg::Input(i) => i < inputs,
+                    FusedArg::Step(t) => t < s,
+                };
+                ensure!(ok, InvalidAttr, "FusedElementwise step {} reads {:?}", s, arg);
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Types of supported operations
 #[derive(Debug, Clone)]
 pub enum OpKind {
     Input,                                      // Value provided from outside
-    Constant(Tensor),                           // Constant embedded in the graph
+    Constant(Arc<Tensor>),                      // Constant embedded in the graph (shared, never copied)
     Add,                                        // Element-wise addition
     Mul,                                        // Element-wise multiplication
     MatMul(Option<MatMulAttrs>),                // Matrix multiplication (2D x 2D)
@@ -131,6 +302,41 @@ pub enum OpKind {
     Reshape(ReshapeAttrs),                      // Reshape tensor
     Transpose(TransposeAttrs),                  // Transpose tensor
     Concat(ConcatAttrs),                        // Concatenate tensors
+    FusedElementwise(FusedElementwiseAttrs),    // Chain of elementwise ops in one loop
+}
+
+impl OpKind {
+    /// Operation name as used in the JSON graph format
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpKind::Input => "Input",
+            OpKind::Constant(_) => "Constant",
+            OpKind::Add => "Add",
+            OpKind::Mul => "Mul",
+            OpKind::MatMul(_) => "MatMul",
+            OpKind::Relu => "Relu",
+            OpKind::Relu6 => "Relu6",
+            OpKind::HardSwish => "HardSwish",
+            OpKind::Conv2D(_) => "Conv2D",
+            OpKind::DepthwiseConv2D(_) => "DepthwiseConv2D",
+            OpKind::BatchNorm(_) => "BatchNorm",
+            OpKind::AveragePool(_) => "AveragePool",
+            OpKind::GlobalAveragePool => "GlobalAveragePool",
+            OpKind::Reshape(_) => "Reshape",
+            OpKind::Transpose(_) => "Transpose",
+            OpKind::Concat(_) => "Concat",
+            OpKind::FusedElementwise(_) => "FusedElementwise",
+        }
+    }
+
+    /// Elementwise ops: the output has the shape of every input, so it can
+    /// be written over an input buffer
+    pub fn is_elementwise(&self) -> bool {
+        matches!(
+            self,
+            OpKind::Add | OpKind::Mul | OpKind::Relu | OpKind::Relu6 | OpKind::HardSwish
+        )
+    }
 }
 
 /// Node in graph
@@ -154,13 +360,76 @@ pub struct Graph {
     pub value_types: HashMap<ValueId, TensorDesc>,
 }
 
-/// ---------- CPU Backend main body ----------
+// ---------- CPU Backend main body ----------
+
+pub struct CpuBackend {
+    /// Instruction set used by the kernels
+    simd: SimdLevel,
+    /// Intra-op worker threads; None runs every kernel on the calling thread
+    pool: Option<ThreadPool>,
+    /// Run independent nodes concurrently on `pool`
+    inter_op: bool,
+}
 
-pub struct CpuBackend;
+impl Default for CpuBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl CpuBackend {
+    /// Backend using the best SIMD level supported by the running CPU.
     pub fn new() -> Self {
-        CpuBackend
+        CpuBackend {
+            simd: SimdLevel::detect(),
+            pool: None,
+            inter_op: false,
+        }
+    }
+
+    /// Pin the kernels to a specific SIMD level (e.g. `SimdLevel::Scalar` as a reference).
+    ///
+    /// Panics if the running CPU does not support `level`.
+    pub fn with_simd_level(mut self, level: SimdLevel) -> Self {
+        assert!(
+            level.is_supported(),
+            "{:?} is not supported on this CPU",
+            level
+        );
+        self.simd = level;
+        self
+    }
+
+    /// Run large kernels (MatMul, convolutions, big elementwise ops) on
+    /// `threads` worker threads. `threads <= 1` disables the pool.
+    ///
+    /// Outputs are bit-identical for every thread count.
+    pub fn with_threads(mut self, threads: usize) -> Result<Self> {
+        self.pool = if threads > 1 {
+            Some(ThreadPool::new(threads)?)
+        } else {
+            None
+        };
+        Ok(self)
+    }
+
+    /// Schedule nodes by their dependencies instead of in `graph.nodes`
+    /// order, running independent branches concurrently on the thread pool.
+    /// Has no effect unless `with_threads` enabled the pool.
+    ///
+    /// Outputs are identical to the sequential executor.
+    pub fn with_inter_op(mut self, enabled: bool) -> Self {
+        self.inter_op = enabled;
+        self
+    }
+
+    pub fn simd_level(&self) -> SimdLevel {
+        self.simd
+    }
+
+    /// Number of threads kernels may use (1 when the pool is disabled).
+    pub fn threads(&self) -> usize {
+        self.pool.as_ref().map_or(1, ThreadPool::threads)
     }
 
     /// Execute the graph.
@@ -173,252 +442,929 @@ impl CpuBackend {
         &self,
         graph: &Graph,
         input_tensors: &HashMap<ValueId, Tensor>,
-    ) -> anyhow::Result<HashMap<ValueId, Tensor>> {
-        // Entity of ValueId -> Tensor
-        let mut values: HashMap<ValueId, Tensor> = HashMap::new();
+    ) -> Result<HashMap<ValueId, Tensor>> {
+        let outputs = self.run_borrowed(graph, input_tensors)?;
+        Ok(outputs
+            .into_iter()
+            .map(|(id, t)| (id, t.into_tensor()))
+            .collect())
+    }
+
+    /// Like [`CpuBackend::run`], but outputs are views: caller inputs and
+    /// constants are borrowed rather than copied, and layout ops (Reshape,
+    /// Transpose) share their input's storage.
+    pub fn run_borrowed<'a>(
+        &self,
+        graph: &'a Graph,
+        input_tensors: &'a HashMap<ValueId, Tensor>,
+    ) -> Result<HashMap<ValueId, TensorView<'a>>> {
+        if let (true, Some(pool)) = (self.inter_op, &self.pool) {
+            return scheduler::run(self, pool, graph, input_tensors);
+        }
+
+        // Entity of ValueId -> Tensor; caller inputs and constants are
+        // borrowed, not copied
+        let mut values: HashMap<ValueId, TensorView> = HashMap::new();
 
         // Register Input first
         for (id, t) in input_tensors {
-            values.insert(*id, t.clone());
+            values.insert(*id, t.view());
         }
 
+        // Intermediates are dropped after their last reader, and elementwise
+        // nodes overwrite an input nobody reads afterwards
+        let last_uses = memory::last_uses(graph);
+        let in_place = memory::in_place_inputs(graph);
+
         // Execute nodes in order (MVP assumes topologically sorted)
-        for node in &graph.nodes {
+        for (i, node) in graph.nodes.iter().enumerate() {
             let out = match &node.op {
-                OpKind::Input => {
-                    // Assumes Input is already in values
-                    continue;
-                }
-                OpKind::Constant(t) => t.clone(),
-                OpKind::Add => {
-                    let a = values.get(&node.inputs[0]).expect("Add missing input 0");
-                    let b = values.get(&node.inputs[1]).expect("Add missing input 1");
-                    add(a, b)?
-                }
-                OpKind::Mul => {
-                    let a = values.get(&node.inputs[0]).expect("Mul missing input 0");
-                    let b = values.get(&node.inputs[1]).expect("Mul missing input 1");
-                    mul(a, b)?
-                }
-                OpKind::MatMul(attrs) => {
-                    let a = values.get(&node.inputs[0]).expect("MatMul missing input 0");
-                    let b = values.get(&node.inputs[1]).expect("MatMul missing input 1");
-                    matmul(a, b, attrs.as_ref())?
-                }
-                OpKind::Relu => {
-                    let x = values.get(&node.inputs[0]).expect("Relu missing input 0");
-                    relu(x)
-                }
-                OpKind::Relu6 => {
-                    let x = values.get(&node.inputs[0]).expect("Relu6 missing input 0");
-                    relu6(x)
-                }
-                OpKind::HardSwish => {
-                    let x = values.get(&node.inputs[0]).expect("HardSwish missing input 0");
-                    hard_swish(x)
-                }
-                OpKind::Conv2D(attrs) => {
-                    let input = values.get(&node.inputs[0]).expect("Conv2D missing input 0");
-                    let kernel = values.get(&node.inputs[1]).expect("Conv2D missing kernel");
-                    let bias = if node.inputs.len() > 2 {
-                        Some(values.get(&node.inputs[2]).expect("Conv2D missing bias"))
-                    } else {
-                        None
-                    };
-                    conv2d(input, kernel, bias, attrs)?
-                }
-                OpKind::DepthwiseConv2D(attrs) => {
-                    let input = values.get(&node.inputs[0]).expect("DepthwiseConv2D missing input 0");
-                    let kernel = values.get(&node.inputs[1]).expect("DepthwiseConv2D missing kernel");
-                    let bias = if node.inputs.len() > 2 {
-                        Some(values.get(&node.inputs[2]).expect("DepthwiseConv2D missing bias"))
-                    } else {
-                        None
-                    };
-                    depthwise_conv2d(input, kernel, bias, attrs)?
-                }
-                OpKind::BatchNorm(attrs) => {
-                    let input = values.get(&node.inputs[0]).expect("BatchNorm missing input");
-                    let scale = values.get(&node.inputs[1]).expect("BatchNorm missing scale");
-                    let bias = values.get(&node.inputs[2]).expect("BatchNorm missing bias");
-                    let mean = values.get(&node.inputs[3]).expect("BatchNorm missing mean");
-                    let var = values.get(&node.inputs[4]).expect("BatchNorm missing var");
-                    batch_norm(input, scale, bias, mean, var, attrs.as_ref())?
-                }
-                OpKind::AveragePool(attrs) => {
-                    let input = values.get(&node.inputs[0]).expect("AveragePool missing input");
-                    average_pool(input, attrs)?
-                }
-                OpKind::GlobalAveragePool => {
-                    let input = values.get(&node.inputs[0]).expect("GlobalAveragePool missing input");
-                    global_average_pool(input)?
-                }
-                OpKind::Reshape(attrs) => {
-                    let input = values.get(&node.inputs[0]).expect("Reshape missing input");
-                    reshape(input, attrs)?
-                }
-                OpKind::Transpose(attrs) => {
-                    let input = values.get(&node.inputs[0]).expect("Transpose missing input");
-                    transpose(input, attrs)?
-                }
-                OpKind::Concat(attrs) => {
-                    let inputs: Vec<&Tensor> = node.inputs.iter()
-                        .map(|id| values.get(id).expect("Concat missing input"))
-                        .collect();
-                    concat(&inputs, attrs)?
-                }
+                // Assumes Input is already in values
+                OpKind::Input => continue,
+                OpKind::Constant(t) => t.view(),
+                _ => self.eval_computed(node, i, &in_place, &mut values)?,
             };
 
             values.insert(node.output, out);
+            for id in node.inputs.iter().chain([&node.output]) {
+                if last_uses.get(id).is_none_or(|&last| last <= i) {
+                    values.remove(id);
+                }
+            }
         }
 
-        // Extract only outputs
+        // Extract only outputs, moving computed tensors out
         let mut outputs = HashMap::new();
         for &vid in &graph.outputs {
-            if let Some(t) = values.get(&vid) {
-                outputs.insert(vid, t.clone());
-            } else {
-                anyhow::bail!("missing output tensor for {:?}", vid);
+            if let Some(t) = values.remove(&vid) {
+                outputs.insert(vid, t);
+            } else if !outputs.contains_key(&vid) {
+                return Err(Error::MissingOutput { value: vid });
             }
         }
         Ok(outputs)
     }
+
+    /// Evaluate `graph.nodes[i]`, over a dead input's buffer when `in_place` allows.
+    fn eval_computed<'a>(
+        &self,
+        node: &'a Node,
+        i: usize,
+        in_place: &HashMap<usize, usize>,
+        values: &mut HashMap<ValueId, TensorView<'a>>,
+    ) -> Result<TensorView<'a>> {
+        let reuse = in_place
+            .get(&i)
+            .and_then(|&slot| Some((slot, take_unique(values, node.inputs[slot])?)));
+        let args = node
+            .inputs
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| reuse.as_ref().is_none_or(|(slot, _)| slot != j))
+            .map(|(_, id)| {
+                values.get(id).ok_or(Error::MissingInput {
+                    node: None,
+                    value: *id,
+                })
+            })
+            .collect::<Result<Vec<&TensorView>>>()
+            .map_err(|e| e.at(node))?;
+        match reuse {
+            Some((_, x)) => self.eval_node_in_place(node, x, &args),
+            None => self.eval_node(node, &args),
+        }
+        .map_err(|e| e.at(node))
+    }
+
+    /// Validate `graph`, infer its shapes and plan its memory once, for
+    /// repeated execution with [`Session::run`]. Input shapes come from
+    /// `inputs`, falling back to `graph.value_types`.
+    ///
+    /// The graph is compiled as given; run a
+    /// [`PassManager`](passes::PassManager) over it first to optimize it.
+    pub fn compile(
+        self,
+        graph: &Graph,
+        inputs: &HashMap<ValueId, TensorDesc>,
+    ) -> Result<Session> {
+        Session::compile(self, graph, inputs)
+    }
+
+    /// Evaluate a single node given its resolved input views (in `node.inputs` order).
+    ///
+    /// Constants and layout ops return views borrowing the node or sharing
+    /// their input's storage; every other op returns a new contiguous buffer.
+    pub(crate) fn eval_node<'a>(
+        &self,
+        node: &'a Node,
+        args: &[&TensorView<'a>],
+    ) -> Result<TensorView<'a>> {
+        Ok(match &node.op {
+            OpKind::Input => {
+                bail!(
+                    UnsupportedOp,
+                    "Input {:?} is provided by the caller, not evaluated",
+                    node.output
+                )
+            }
+            OpKind::Constant(t) => t.view(),
+            OpKind::Add => {
+                let a = arg(args, 0, "Add missing input 0")?;
+                let b = arg(args, 1, "Add missing input 1")?;
+                add(self, a, b)?.into()
+            }
+            OpKind::Mul => {
+                let a = arg(args, 0, "Mul missing input 0")?;
+                let b = arg(args, 1, "Mul missing input 1")?;
+                mul(self, a, b)?.into()
+            }
+            OpKind::MatMul(attrs) => {
+                let a = arg(args, 0, "MatMul missing input 0")?;
+                let b = arg(args, 1, "MatMul missing input 1")?;
+                matmul(self, a, b, attrs.as_ref())?.into()
+            }
+            OpKind::Relu => {
+                let x = arg(args, 0, "Relu missing input 0")?;
+                relu(self, x).into()
+            }
+            OpKind::Relu6 => {
+                let x = arg(args, 0, "Relu6 missing input 0")?;
+                relu6(self, x).into()
+            }
+            OpKind::HardSwish => {
+                let x = arg(args, 0, "HardSwish missing input 0")?;
+                hard_swish(self, x).into()
+            }
+            OpKind::Conv2D(attrs) => {
+                let input = arg(args, 0, "Conv2D missing input 0")?;
+                let kernel = arg(args, 1, "Conv2D missing kernel")?;
+                let bias = args.get(2).copied();
+                conv2d(self, input, kernel, bias, attrs)?.into()
+            }
+            OpKind::DepthwiseConv2D(attrs) => {
+                let input = arg(args, 0, "DepthwiseConv2D missing input 0")?;
+                let kernel = arg(args, 1, "DepthwiseConv2D missing kernel")?;
+                let bias = args.get(2).copied();
+                depthwise_conv2d(self, input, kernel, bias, attrs)?.into()
+            }
+            OpKind::BatchNorm(attrs) => {
+                let input = arg(args, 0, "BatchNorm missing input")?;
+                let scale = arg(args, 1, "BatchNorm missing scale")?;
+                let bias = arg(args, 2, "BatchNorm missing bias")?;
+                let mean = arg(args, 3, "BatchNorm missing mean")?;
+                let var = arg(args, 4, "BatchNorm missing var")?;
+                batch_norm(input, scale, bias, mean, var, attrs.as_ref())?.into()
+            }
+            OpKind::AveragePool(attrs) => {
+                let input = arg(args, 0, "AveragePool missing input")?;
+                average_pool(input, attrs)?.into()
+            }
+            OpKind::GlobalAveragePool => {
+                let input = arg(args, 0, "GlobalAveragePool missing input")?;
+                global_average_pool(input)?.into()
+            }
+            OpKind::Reshape(attrs) => {
+                let input = arg(args, 0, "Reshape missing input")?;
+                reshape(input, attrs)?
+            }
+            OpKind::Transpose(attrs) => {
+                let input = arg(args, 0, "Transpose missing input")?;
+                transpose(input, attrs)?
+            }
+            OpKind::Concat(attrs) => concat(args, attrs)?.into(),
+            OpKind::FusedElementwise(attrs) => fused_elementwise(self, args, attrs)?.into(),
+        })
+    }
+
+    /// Evaluate an elementwise node by overwriting `x`, an input that is dead
+    /// afterwards. `rest` holds the node's other inputs.
+    pub(crate) fn eval_node_in_place<'a>(
+        &self,
+        node: &Node,
+        mut x: TensorView<'a>,
+        rest: &[&TensorView],
+    ) -> Result<TensorView<'a>> {
+        let shape = x.shape().to_vec();
+        let data = x
+            .make_mut()
+            .ok_or_else(|| err!(Internal, "{} input is not writable", node.op.name()))?;
+        match &node.op {
+            OpKind::Add => {
+                let b = arg(rest, 0, "Add missing input")?;
+                add_inplace(self, data, &shape, b)?
+            }
+            OpKind::Mul => {
+                let b = arg(rest, 0, "Mul missing input")?;
+                mul_inplace(self, data, &shape, b)?
+            }
+            OpKind::Relu => unary_inplace(self, simd::relu_inplace, data),
+            OpKind::Relu6 => unary_inplace(self, simd::relu6_inplace, data),
+            OpKind::HardSwish => unary_inplace(self, simd::hard_swish_inplace, data),
+            op => bail!(UnsupportedOp, "{} cannot run in place", op.name()),
+        }
+        Ok(x)
+    }
+}
+
+/// Remove `id` from `values` if it is the sole owner of its buffer;
+/// borrowed inputs and views sharing storage stay.
+fn take_unique<'a>(
+    values: &mut HashMap<ValueId, TensorView<'a>>,
+    id: ValueId,
+) -> Option<TensorView<'a>> {
+    if values.get(&id)?.is_unique() {
+        values.remove(&id)
+    } else {
+        None
+    }
+}
+
+// ---------- Implementation of individual operations ----------
+
+/// The `i`-th resolved input of a node, or `what` as the error.
+fn arg<'a, 'v>(
+    args: &[&'a TensorView<'v>],
+    i: usize,
+    what: &str,
+) -> Result<&'a TensorView<'v>> {
+    args.get(i).copied().ok_or_else(|| err!(InvalidGraph, "{}", what))
+}
+
+/// Run a binary elementwise kernel, tiled over the backend's thread pool.
+fn binary(
+    cpu: &CpuBackend,
+    kernel: fn(SimdLevel, &[f32], &[f32], &mut [f32]),
+    a: &[f32],
+    b: &[f32],
+    out: &mut [f32],
+) {
+    let row = parallel::ELEMENTWISE_ROW;
+    parallel::for_each_rows(cpu.pool.as_ref(), out, row, row, |rows, out| {
+        let range = rows.start * row..rows.start * row + out.len();
+        kernel(cpu.simd, &a[range.clone()], &b[range], out)
+    });
+}
+
+/// Run a unary elementwise kernel, tiled over the backend's thread pool.
+fn unary(cpu: &CpuBackend, kernel: fn(SimdLevel, &[f32], &mut [f32]), x: &[f32], out: &mut [f32]) {
+    let row = parallel::ELEMENTWISE_ROW;
+    parallel::for_each_rows(cpu.pool.as_ref(), out, row, row, |rows, out| {
+        let start = rows.start * row;
+        kernel(cpu.simd, &x[start..start + out.len()], out)
+    });
 }
 
-/// ---------- Implementation of individual operations ----------
+/// `binary` writing into the first operand.
+fn binary_inplace(
+    cpu: &CpuBackend,
+    kernel: fn(SimdLevel, &mut [f32], &[f32]),
+    acc: &mut [f32],
+    b: &[f32],
+) {
+    let row = parallel::ELEMENTWISE_ROW;
+    parallel::for_each_rows(cpu.pool.as_ref(), acc, row, row, |rows, acc| {
+        let start = rows.start * row;
+        kernel(cpu.simd, acc, &b[start..start + acc.len()])
+    });
+}
 
-fn add(a: &Tensor, b: &Tensor) -> anyhow::Result<Tensor> {
-    anyhow::ensure!(
-        a.desc.shape == b.desc.shape,
+/// `unary` writing into its input.
+fn unary_inplace(cpu: &CpuBackend, kernel: fn(SimdLevel, &mut [f32]), x: &mut [f32]) {
+    let row = parallel::ELEMENTWISE_ROW;
+    parallel::for_each_rows(cpu.pool.as_ref(), x, row, row, |_, x| kernel(cpu.simd, x));
+}
+
+fn add(cpu: &CpuBackend, a: &TensorView, b: &TensorView) -> Result<Tensor> {
+    ensure!(
+        a.shape() == b.shape(),
+        ShapeMismatch,
         "Add shape mismatch: {:?} vs {:?}",
-        a.desc.shape,
-        b.desc.shape
+        a.shape(),
+        b.shape()
     );
 
-    let mut out = Tensor::zeros(a.desc.shape.clone());
-    for i in 0..a.len() {
-        out.data[i] = a.data[i] + b.data[i];
-    }
+    let mut out = Tensor::zeros(a.shape().to_vec());
+    binary(cpu, simd::add, &a.contiguous(), &b.contiguous(), &mut out.data);
     Ok(out)
 }
 
-fn mul(a: &Tensor, b: &Tensor) -> anyhow::Result<Tensor> {
-    anyhow::ensure!(
-        a.desc.shape == b.desc.shape,
+fn mul(cpu: &CpuBackend, a: &TensorView, b: &TensorView) -> Result<Tensor> {
+    ensure!(
+        a.shape() == b.shape(),
+        ShapeMismatch,
         "Mul shape mismatch: {:?} vs {:?}",
-        a.desc.shape,
-        b.desc.shape
+        a.shape(),
+        b.shape()
     );
 
-    let mut out = Tensor::zeros(a.desc.shape.clone());
-    for i in 0..a.len() {
-        out.data[i] = a.data[i] * b.data[i];
-    }
+    let mut out = Tensor::zeros(a.shape().to_vec());
+    binary(cpu, simd::mul, &a.contiguous(), &b.contiguous(), &mut out.data);
     Ok(out)
 }
 
-fn matmul(a: &Tensor, b: &Tensor, _attrs: Option<&MatMulAttrs>) -> anyhow::Result<Tensor> {
-    // TODO: Implement transpose support
-    anyhow::ensure!(
-        a.desc.shape.len() == 2 && b.desc.shape.len() == 2,
+/// Add / Mul are commutative, so either operand may be the accumulator.
+fn add_inplace(
+    cpu: &CpuBackend,
+    acc: &mut [f32],
+    shape: &[usize],
+    b: &TensorView,
+) -> Result<()> {
+    ensure!(
+        shape == b.shape(),
+        ShapeMismatch,
+        "Add shape mismatch: {:?} vs {:?}",
+        shape,
+        b.shape()
+    );
+    binary_inplace(cpu, simd::add_inplace, acc, &b.contiguous());
+    Ok(())
+}
+
+fn mul_inplace(
+    cpu: &CpuBackend,
+    acc: &mut [f32],
+    shape: &[usize],
+    b: &TensorView,
+) -> Result<()> {
+    ensure!(
+        shape == b.shape(),
+        ShapeMismatch,
+        "Mul shape mismatch: {:?} vs {:?}",
+        shape,
+        b.shape()
+    );
+    binary_inplace(cpu, simd::mul_inplace, acc, &b.contiguous());
+    Ok(())
+}
+
+fn matmul(
+    cpu: &CpuBackend,
+    a: &TensorView,
+    b: &TensorView,
+    attrs: Option<&MatMulAttrs>,
+) -> Result<Tensor> {
+    ensure!(
+        a.shape().len() == 2 && b.shape().len() == 2,
+        ShapeMismatch,
         "MatMul expects 2D tensors, got {:?} and {:?}",
-        a.desc.shape,
-        b.desc.shape
+        a.shape(),
+        b.shape()
     );
+    // Transposed operands are strided views, gathered below
+    let (trans_a, trans_b) = attrs.map_or((false, false), |a| (a.trans_a, a.trans_b));
+    let a = if trans_a { a.permute(&[1, 0])? } else { a.clone() };
+    let b = if trans_b { b.permute(&[1, 0])? } else { b.clone() };
 
-    let (m, k1) = (a.desc.shape[0], a.desc.shape[1]);
-    let (k2, n) = (b.desc.shape[0], b.desc.shape[1]);
-    anyhow::ensure!(k1 == k2, "MatMul inner dim mismatch: {} vs {}", k1, k2);
+    let (m, k1) = (a.shape()[0], a.shape()[1]);
+    let (k2, n) = (b.shape()[0], b.shape()[1]);
+    ensure!(k1 == k2, ShapeMismatch, "MatMul inner dim mismatch: {} vs {}", k1, k2);
 
     let mut out = Tensor::zeros(vec![m, n]);
+    let activation = attrs.and_then(|a| a.activation);
+    let (a, b) = (a.contiguous(), b.contiguous());
+    matmul_into(cpu, &a, &b, &mut out.data, m, k1, n, activation);
+    Ok(out)
+}
 
-    for i in 0..m {
-        for j in 0..n {
-            let mut sum = 0.0;
-            for k in 0..k1 {
-                let a_idx = i * k1 + k;
-                let b_idx = k * n + j;
-                sum += a.data[a_idx] * b.data[b_idx];
-            }
-            out.data[i * n + j] = sum;
+/// MatMul kernel writing into a preallocated output, parallel over rows of
+/// the output. `activation` is applied to each tile while it is in cache.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn matmul_into(
+    cpu: &CpuBackend,
+    a: &[f32],
+    b: &[f32],
+    out: &mut [f32],
+    m: usize,
+    k: usize,
+    n: usize,
+    activation: Option<Activation>,
+) {
+    debug_assert_eq!(out.len(), m * n);
+    parallel::for_each_rows(cpu.pool.as_ref(), out, n, k * n, |rows, out| {
+        let a_rows = &a[rows.start * k..rows.end * k];
+        simd::matmul(cpu.simd, a_rows, b, out, rows.len(), k, n);
+        if let Some(activation) = activation {
+            activation.apply(cpu.simd, out);
         }
+    });
+}
+
+fn relu(cpu: &CpuBackend, x: &TensorView) -> Tensor {
+    let mut out = Tensor::zeros(x.shape().to_vec());
+    unary(cpu, simd::relu, &x.contiguous(), &mut out.data);
+    out
+}
+
+fn relu6(cpu: &CpuBackend, x: &TensorView) -> Tensor {
+    let mut out = Tensor::zeros(x.shape().to_vec());
+    unary(cpu, simd::relu6, &x.contiguous(), &mut out.data);
+    out
+}
+
+fn hard_swish(cpu: &CpuBackend, x: &TensorView) -> Tensor {
+    // HardSwish: x * relu6(x + 3) / 6
+    let mut out = Tensor::zeros(x.shape().to_vec());
+    unary(cpu, simd::hard_swish, &x.contiguous(), &mut out.data);
+    out
+}
+
+/// Elements per block of a fused elementwise loop, small enough that the
+/// block of every step stays in L1
+const FUSED_BLOCK: usize = 1024;
+
+fn fused_elementwise(
+    cpu: &CpuBackend,
+    args: &[&TensorView],
+    attrs: &FusedElementwiseAttrs,
+) -> Result<Tensor> {
+    attrs.validate(args.len())?;
+    let shape = arg(args, 0, "FusedElementwise missing input 0")?.shape();
+    for x in args {
+        ensure!(
+            x.shape() == shape,
+            ShapeMismatch,
+            "FusedElementwise shape mismatch: {:?} vs {:?}",
+            shape,
+            x.shape()
+        );
     }
 
+    let data: Vec<_> = args.iter().map(|x| x.contiguous()).collect();
+    let inputs: Vec<&[f32]> = data.iter().map(|d| d.as_ref()).collect();
+    let mut out = Tensor::zeros(shape.to_vec());
+    fused_elementwise_into(cpu, &attrs.steps, &inputs, &mut out.data);
     Ok(out)
 }
 
-fn relu(x: &Tensor) -> Tensor {
-    let mut out = x.clone();
-    for v in &mut out.data {
-        if *v < 0.0 {
-            *v = 0.0;
+/// Run a fused program over `out` one block at a time, tiled over the
+/// thread pool. Each step calls the SIMD kernel of the unfused op, so
+/// results are identical to running the ops one by one.
+pub(crate) fn fused_elementwise_into(
+    cpu: &CpuBackend,
+    steps: &[FusedStep],
+    inputs: &[&[f32]],
+    out: &mut [f32],
+) {
+    let row = parallel::ELEMENTWISE_ROW;
+    parallel::for_each_rows(cpu.pool.as_ref(), out, row, row * steps.len(), |rows, out| {
+        let mut scratch = vec![vec![0.0; FUSED_BLOCK]; steps.len() - 1];
+        for (b, out) in out.chunks_mut(FUSED_BLOCK).enumerate() {
+            let (start, len) = (rows.start * row + b * FUSED_BLOCK, out.len());
+            let block: Vec<&[f32]> = inputs.iter().map(|x| &x[start..start + len]).collect();
+            for (s, step) in steps.iter().enumerate() {
+                // Every step but the last writes its scratch block
+                let (done, rest) = scratch.split_at_mut(s.min(steps.len() - 1));
+                let dst = match rest.first_mut() {
+                    Some(dst) => &mut dst[..len],
+                    None => &mut *out,
+                };
+                let arg = |i: usize| match step.args[i] {
+                    FusedArg::Input(j) => block[j],
+                    FusedArg::Step(t) => &done[t][..len],
+                };
+                match step.op {
+                    ElementwiseOp::Add => simd::add(cpu.simd, arg(0), arg(1), dst),
+                    ElementwiseOp::Mul => simd::mul(cpu.simd, arg(0), arg(1), dst),
+                    ElementwiseOp::Relu => simd::relu(cpu.simd, arg(0), dst),
+                    ElementwiseOp::Relu6 => simd::relu6(cpu.simd, arg(0), dst),
+                    ElementwiseOp::HardSwish => simd::hard_swish(cpu.simd, arg(0), dst),
+                }
+            }
         }
-    }
-    out
+    });
 }
 
-fn relu6(x: &Tensor) -> Tensor {
-    let mut out = x.clone();
-    for v in &mut out.data {
-        *v = v.clamp(0.0, 6.0);
-    }
-    out
+/// Output spatial size: floor((in + pads - dilation * (k - 1) - 1) / stride + 1)
+pub(crate) fn conv_out_dim(
+    op: &str,
+    input: usize,
+    pads: usize,
+    kernel: usize,
+    stride: usize,
+    dilation: usize,
+) -> Result<usize> {
+    ensure!(
+        kernel > 0 && stride > 0 && dilation > 0,
+        InvalidAttr,
+        "{} kernel, stride and dilation must be positive",
+        op
+    );
+    let span = dilation * (kernel - 1) + 1;
+    ensure!(
+        input + pads >= span,
+        ShapeMismatch,
+        "{} kernel extent {} exceeds padded input size {}",
+        op,
+        span,
+        input + pads
+    );
+    Ok((input + pads - span) / stride + 1)
 }
 
-fn hard_swish(x: &Tensor) -> Tensor {
-    let mut out = x.clone();
-    for v in &mut out.data {
-        // HardSwish: x * relu6(x + 3) / 6
-        *v = *v * (*v + 3.0).clamp(0.0, 6.0) / 6.0;
+/// Shared geometry for NHWC convolutions producing `out_c` channels.
+#[allow(clippy::too_many_arguments)]
+fn conv_params(
+    op: &str,
+    input: &[usize],
+    kernel: &[usize],
+    bias: Option<&[usize]>,
+    kernel_shape: [usize; 2],
+    strides: [usize; 2],
+    pads: [usize; 4],
+    dilations: [usize; 2],
+    group: usize,
+    out_c: usize,
+) -> Result<simd::ConvParams> {
+    ensure!(
+        input.len() == 4,
+        ShapeMismatch,
+        "{} expects NHWC input, got {:?}",
+        op,
+        input
+    );
+    ensure!(
+        kernel.len() == 4,
+        ShapeMismatch,
+        "{} expects 4D kernel, got {:?}",
+        op,
+        kernel
+    );
+    let (batch, in_h, in_w, in_c) = (input[0], input[1], input[2], input[3]);
+    let (k_h, k_w) = (kernel[0], kernel[1]);
+    ensure!(
+        [k_h, k_w] == kernel_shape,
+        InvalidAttr,
+        "{} kernel shape {:?} does not match attribute kernel_shape {:?}",
+        op,
+        kernel,
+        kernel_shape
+    );
+    ensure!(
+        group > 0 && in_c.is_multiple_of(group) && out_c.is_multiple_of(group),
+        InvalidAttr,
+        "{} channels ({} in, {} out) are not divisible by group {}",
+        op,
+        in_c,
+        out_c,
+        group
+    );
+    if let Some(bias) = bias {
+        ensure!(
+            bias == [out_c],
+            ShapeMismatch,
+            "{} bias shape {:?} does not match {} output channels",
+            op,
+            bias,
+            out_c
+        );
     }
-    out
+
+    Ok(simd::ConvParams {
+        batch,
+        in_h,
+        in_w,
+        in_c,
+        out_h: conv_out_dim(op, in_h, pads[0] + pads[2], k_h, strides[0], dilations[0])?,
+        out_w: conv_out_dim(op, in_w, pads[1] + pads[3], k_w, strides[1], dilations[1])?,
+        out_c,
+        k_h,
+        k_w,
+        stride_h: strides[0],
+        stride_w: strides[1],
+        dilation_h: dilations[0],
+        dilation_w: dilations[1],
+        pad_top: pads[0],
+        pad_left: pads[1],
+        group,
+        activation: None,
+    })
 }
 
-fn conv2d(_input: &Tensor, _kernel: &Tensor, _bias: Option<&Tensor>, _attrs: &Conv2DAttrs) -> anyhow::Result<Tensor> {
-    // TODO: Implement Conv2D kernel
-    anyhow::bail!("Conv2D not yet implemented")
+/// Geometry of a Conv2D node from its input, kernel and bias shapes.
+pub(crate) fn conv2d_params(
+    input: &[usize],
+    kernel: &[usize],
+    bias: Option<&[usize]>,
+    attrs: &Conv2DAttrs,
+) -> Result<simd::ConvParams> {
+    // input [N, H, W, C_in], kernel [Kh, Kw, C_in / group, C_out]
+    ensure!(attrs.group > 0, InvalidAttr, "Conv2D group must be positive");
+    let in_c = input.get(3).copied().unwrap_or(0);
+    ensure!(
+        kernel.len() == 4 && kernel[2] * attrs.group == in_c,
+        ShapeMismatch,
+        "Conv2D kernel {:?} does not match input channels {} with group {}",
+        kernel,
+        in_c,
+        attrs.group
+    );
+    let p = conv_params(
+        "Conv2D",
+        input,
+        kernel,
+        bias,
+        attrs.kernel_shape,
+        attrs.strides,
+        attrs.pads,
+        attrs.dilations,
+        attrs.group,
+        kernel[3],
+    )?;
+    Ok(simd::ConvParams {
+        activation: attrs.activation,
+        ..p
+    })
 }
 
-fn depthwise_conv2d(_input: &Tensor, _kernel: &Tensor, _bias: Option<&Tensor>, _attrs: &DepthwiseConv2DAttrs) -> anyhow::Result<Tensor> {
-    // TODO: Implement DepthwiseConv2D kernel
-    anyhow::bail!("DepthwiseConv2D not yet implemented")
+fn conv2d(
+    cpu: &CpuBackend,
+    input: &TensorView,
+    kernel: &TensorView,
+    bias: Option<&TensorView>,
+    attrs: &Conv2DAttrs,
+) -> Result<Tensor> {
+    let p = conv2d_params(input.shape(), kernel.shape(), bias.map(|b| b.shape()), attrs)?;
+
+    let mut out = Tensor::zeros(vec![p.batch, p.out_h, p.out_w, p.out_c]);
+    let bias = bias.map(|b| b.contiguous());
+    conv2d_into(
+        cpu,
+        &input.contiguous(),
+        &kernel.contiguous(),
+        bias.as_deref(),
+        &mut out.data,
+        &p,
+    );
+    Ok(out)
 }
 
-fn batch_norm(_input: &Tensor, _scale: &Tensor, _bias: &Tensor, _mean: &Tensor, _var: &Tensor, _attrs: Option<&BatchNormAttrs>) -> anyhow::Result<Tensor> {
-    // TODO: Implement BatchNorm kernel
-    anyhow::bail!("BatchNorm not yet implemented")
+/// Conv2D kernel writing into a preallocated output, parallel over output
+/// rows (batch * out_h).
+pub(crate) fn conv2d_into(
+    cpu: &CpuBackend,
+    input: &[f32],
+    kernel: &[f32],
+    bias: Option<&[f32]>,
+    out: &mut [f32],
+    p: &simd::ConvParams,
+) {
+    let row_len = p.out_w * p.out_c;
+    let work_per_row = row_len * p.k_h * p.k_w * p.in_c / p.group;
+    parallel::for_each_rows(cpu.pool.as_ref(), out, row_len, work_per_row, |rows, out| {
+        simd::conv2d_nhwc(cpu.simd, input, kernel, bias, out, p, rows);
+        if let Some(activation) = p.activation {
+            activation.apply(cpu.simd, out);
+        }
+    });
 }
 
-fn average_pool(_input: &Tensor, _attrs: &AveragePoolAttrs) -> anyhow::Result<Tensor> {
-    // TODO: Implement AveragePool kernel
-    anyhow::bail!("AveragePool not yet implemented")
+/// Geometry of a DepthwiseConv2D node from its input, kernel and bias shapes.
+pub(crate) fn depthwise_conv2d_params(
+    input: &[usize],
+    kernel: &[usize],
+    bias: Option<&[usize]>,
+    attrs: &DepthwiseConv2DAttrs,
+) -> Result<simd::ConvParams> {
+    // input [N, H, W, C], kernel [Kh, Kw, C, depth_multiplier]
+    let in_c = input.get(3).copied().unwrap_or(0);
+    ensure!(
+        kernel.len() == 4 && kernel[2] == in_c && kernel[3] == attrs.depth_multiplier,
+        ShapeMismatch,
+        "DepthwiseConv2D kernel {:?} does not match input channels {} with depth_multiplier {}",
+        kernel,
+        in_c,
+        attrs.depth_multiplier
+    );
+    let p = conv_params(
+        "DepthwiseConv2D",
+        input,
+        kernel,
+        bias,
+        attrs.kernel_shape,
+        attrs.strides,
+        attrs.pads,
+        attrs.dilations,
+        in_c,
+        in_c * attrs.depth_multiplier,
+    )?;
+    Ok(simd::ConvParams {
+        activation: attrs.activation,
+        ..p
+    })
 }
 
-fn global_average_pool(_input: &Tensor) -> anyhow::Result<Tensor> {
-    // TODO: Implement GlobalAveragePool kernel
-    anyhow::bail!("GlobalAveragePool not yet implemented")
+fn depthwise_conv2d(
+    cpu: &CpuBackend,
+    input: &TensorView,
+    kernel: &TensorView,
+    bias: Option<&TensorView>,
+    attrs: &DepthwiseConv2DAttrs,
+) -> Result<Tensor> {
+    let p = depthwise_conv2d_params(input.shape(), kernel.shape(), bias.map(|b| b.shape()), attrs)?;
+
+    let mut out = Tensor::zeros(vec![p.batch, p.out_h, p.out_w, p.out_c]);
+    let bias = bias.map(|b| b.contiguous());
+    depthwise_conv2d_into(
+        cpu,
+        &input.contiguous(),
+        &kernel.contiguous(),
+        bias.as_deref(),
+        &mut out.data,
+        &p,
+    );
+    Ok(out)
 }
 
-fn reshape(_input: &Tensor, _attrs: &ReshapeAttrs) -> anyhow::Result<Tensor> {
-    // TODO: Implement Reshape kernel
-    anyhow::bail!("Reshape not yet implemented")
+/// DepthwiseConv2D kernel writing into a preallocated output, parallel over
+/// output rows (batch * out_h).
+pub(crate) fn depthwise_conv2d_into(
+    cpu: &CpuBackend,
+    input: &[f32],
+    kernel: &[f32],
+    bias: Option<&[f32]>,
+    out: &mut [f32],
+    p: &simd::ConvParams,
+) {
+    let row_len = p.out_w * p.out_c;
+    let work_per_row = row_len * p.k_h * p.k_w;
+    parallel::for_each_rows(cpu.pool.as_ref(), out, row_len, work_per_row, |rows, out| {
+        simd::depthwise_conv2d_nhwc(cpu.simd, input, kernel, bias, out, p, rows);
+        if let Some(activation) = p.activation {
+            activation.apply(cpu.simd, out);
+        }
+    });
 }
 
-fn transpose(_input: &Tensor, _attrs: &TransposeAttrs) -> anyhow::Result<Tensor> {
-    // TODO: Implement Transpose kernel
-    anyhow::bail!("Transpose not yet implemented")
+/// Inference BatchNorm over the last (channel) axis:
+/// y = scale * (x - mean) / sqrt(var + epsilon) + bias
+fn batch_norm(
+    input: &TensorView,
+    scale: &TensorView,
+    bias: &TensorView,
+    mean: &TensorView,
+    var: &TensorView,
+    attrs: Option<&BatchNormAttrs>,
+) -> Result<Tensor> {
+    let c = input.shape().last().copied().unwrap_or(0);
+    for (name, param) in [("scale", scale), ("bias", bias), ("mean", mean), ("var", var)] {
+        ensure!(
+            param.shape() == [c],
+            ShapeMismatch,
+            "BatchNorm {} shape {:?} does not match {} channels",
+            name,
+            param.shape(),
+            c
+        );
+    }
+
+    let (mul, add) = batch_norm_affine(
+        &scale.contiguous(),
+        &bias.contiguous(),
+        &mean.contiguous(),
+        &var.contiguous(),
+        attrs,
+    );
+    let mut out = Tensor::zeros(input.shape().to_vec());
+    if c > 0 {
+        let x = input.contiguous();
+        for (out, x) in out.data.chunks_exact_mut(c).zip(x.chunks_exact(c)) {
+            for i in 0..c {
+                out[i] = x[i] * mul[i] + add[i];
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// BatchNorm as a per-channel `y = x * mul + add`.
+pub(crate) fn batch_norm_affine(
+    scale: &[f32],
+    bias: &[f32],
+    mean: &[f32],
+    var: &[f32],
+    attrs: Option<&BatchNormAttrs>,
+) -> (Vec<f32>, Vec<f32>) {
+    let epsilon = attrs.map_or(1e-5, |a| a.epsilon);
+    let mul: Vec<f32> = scale
+        .iter()
+        .zip(var)
+        .map(|(s, v)| s / (v + epsilon).sqrt())
+        .collect();
+    let add = bias
+        .iter()
+        .zip(mean)
+        .zip(&mul)
+        .map(|((b, m), k)| b - m * k)
+        .collect();
+    (mul, add)
+}
+
+/// NHWC average pooling. Windows are summed row by row in the same order
+/// as the Loop IR lowering, so both give identical results.
+fn average_pool(input: &TensorView, attrs: &AveragePoolAttrs) -> Result<Tensor> {
+    let out_shape = shape::average_pool_shape(input.shape(), attrs)?;
+    let (in_h, in_w, c) = (input.shape()[1], input.shape()[2], input.shape()[3]);
+    let [k_h, k_w] = attrs.kernel_shape;
+    let x = input.contiguous();
+    let (out_h, out_w) = (out_shape[1], out_shape[2]);
+    let mut out = Tensor::zeros(out_shape.clone());
+    for n in 0..out_shape[0] {
+        for oh in 0..out_h {
+            for ow in 0..out_w {
+                for ch in 0..c {
+                    let (mut sum, mut count) = (0.0, 0.0);
+                    for kh in 0..k_h {
+                        let ih = (oh * attrs.strides[0] + kh).checked_sub(attrs.pads[0]);
+                        let Some(ih) = ih.filter(|&ih| ih < in_h) else {
+                            continue;
+                        };
+                        for kw in 0..k_w {
+                            let iw = (ow * attrs.strides[1] + kw).checked_sub(attrs.pads[1]);
+                            let Some(iw) = iw.filter(|&iw| iw < in_w) else {
+                                continue;
+                            };
+                            sum += x[((n * in_h + ih) * in_w + iw) * c + ch];
+                            count += 1.0;
+                        }
+                    }
+                    let divisor = if attrs.count_include_pad {
+                        (k_h * k_w) as f32
+                    } else {
+                        count
+                    };
+                    out.data[((n * out_h + oh) * out_w + ow) * c + ch] = sum / divisor;
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// NHWC mean over H and W, giving `[N, 1, 1, C]`.
+fn global_average_pool(input: &TensorView) -> Result<Tensor> {
+    let shape = input.shape();
+    ensure!(
+        shape.len() == 4,
+        ShapeMismatch,
+        "GlobalAveragePool expects NHWC input, got {:?}",
+        shape
+    );
+    let (batch, hw, c) = (shape[0], shape[1] * shape[2], shape[3]);
+    let x = input.contiguous();
+    let mut out = Tensor::zeros(vec![batch, 1, 1, c]);
+    for n in 0..batch {
+        for ch in 0..c {
+            let mut sum = 0.0;
+            for p in 0..hw {
+                sum += x[(n * hw + p) * c + ch];
+            }
+            out.data[n * c + ch] = sum / hw as f32;
+        }
+    }
+    Ok(out)
+}
+
+/// Metadata-only: the result shares the input's storage.
+fn reshape<'a>(input: &TensorView<'a>, attrs: &ReshapeAttrs) -> Result<TensorView<'a>> {
+    input.reshape(shape::reshape_shape(input.shape(), attrs)?)
+}
+
+/// Metadata-only: the result is a strided view of the input's storage.
+fn transpose<'a>(input: &TensorView<'a>, attrs: &TransposeAttrs) -> Result<TensorView<'a>> {
+    input.permute(&attrs.perm)
 }
 
-fn concat(_inputs: &[&Tensor], _attrs: &ConcatAttrs) -> anyhow::Result<Tensor> {
-    // TODO: Implement Concat kernel
-    anyhow::bail!("Concat not yet implemented")
+fn concat(inputs: &[&TensorView], attrs: &ConcatAttrs) -> Result<Tensor> {
+    let shapes: Vec<&[usize]> = inputs.iter().map(|x| x.shape()).collect();
+    let shape = shape::concat_shape(&shapes, attrs)?;
+
+    // Every input is `outer` blocks of `shape[axis] * inner` elements, and
+    // the output interleaves them block by block
+    let outer: usize = shape[..attrs.axis].iter().product();
+    let inner: usize = shape[attrs.axis + 1..].iter().product();
+    let data: Vec<_> = inputs.iter().map(|x| x.contiguous()).collect();
+    let mut out = Vec::with_capacity(outer * shape[attrs.axis] * inner);
+    for o in 0..outer {
+        for (x, data) in inputs.iter().zip(&data) {
+            let block = x.shape()[attrs.axis] * inner;
+            out.extend_from_slice(&data[o * block..(o + 1) * block]);
+        }
+    }
+    Ok(Tensor::new(
+        TensorDesc {
+            dtype: DType::F32,
+            shape,
+        },
+        out,
+    ))
 }
 
-/// ---------- Mini sample usage ----------
+// ---------- Mini sample usage ----------
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::node;
 
     #[test]
     fn simple_graph() {
@@ -454,7 +1400,7 @@ mod tests {
         // Define node: y = MatMul(x, w)
         let node = Node {
             id: NodeId(0),
-            op: OpKind::MatMul,
+            op: OpKind::MatMul(None),
             inputs: vec![x_id, w_id],
             output: y_id,
         };
@@ -501,4 +1447,421 @@ mod tests {
         assert_eq!(y.desc.shape, vec![2, 1]);
         // If you want to roughly verify the calculation result, you can assert here
     }
+
+    #[test]
+    fn conv_graph_simd_matches_scalar() {
+        let desc = |shape: Vec<usize>| TensorDesc {
+            dtype: DType::F32,
+            shape,
+        };
+        let ramp = |len: usize, scale: f32| {
+            (0..len)
+                .map(|i| ((i * 7) % 11) as f32 * scale - 1.0)
+                .collect::<Vec<f32>>()
+        };
+
+        // y = Relu6(DepthwiseConv2D(Conv2D(x, w1, b1), w2))
+        let (x_id, w1_id, b1_id, c1_id, w2_id, c2_id, y_id) = (
+            ValueId(0),
+            ValueId(1),
+            ValueId(2),
+            ValueId(3),
+            ValueId(4),
+            ValueId(5),
+            ValueId(6),
+        );
+        let conv_attrs = Conv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [2, 2],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        };
+        let dw_attrs = DepthwiseConv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [1, 1],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            depth_multiplier: 1,
+            activation: None,
+        };
+        let graph = Graph {
+            nodes: vec![
+                Node {
+                    id: NodeId(0),
+                    op: OpKind::Input,
+                    inputs: vec![],
+                    output: x_id,
+                },
+                Node {
+                    id: NodeId(1),
+                    op: OpKind::Constant(Tensor::new(desc(vec![3, 3, 3, 12]), ramp(324, 0.1)).into()),
+                    inputs: vec![],
+                    output: w1_id,
+                },
+                Node {
+                    id: NodeId(2),
+                    op: OpKind::Constant(Tensor::new(desc(vec![12]), ramp(12, 0.05)).into()),
+                    inputs: vec![],
+                    output: b1_id,
+                },
+                Node {
+                    id: NodeId(3),
+                    op: OpKind::Conv2D(conv_attrs),
+                    inputs: vec![x_id, w1_id, b1_id],
+                    output: c1_id,
+                },
+                Node {
+                    id: NodeId(4),
+                    op: OpKind::Constant(Tensor::new(desc(vec![3, 3, 12, 1]), ramp(108, 0.2)).into()),
+                    inputs: vec![],
+                    output: w2_id,
+                },
+                Node {
+                    id: NodeId(5),
+                    op: OpKind::DepthwiseConv2D(dw_attrs),
+                    inputs: vec![c1_id, w2_id],
+                    output: c2_id,
+                },
+                Node {
+                    id: NodeId(6),
+                    op: OpKind::Relu6,
+                    inputs: vec![c2_id],
+                    output: y_id,
+                },
+            ],
+            outputs: vec![y_id],
+            value_types: HashMap::new(),
+        };
+
+        let mut inputs = HashMap::new();
+        inputs.insert(x_id, Tensor::new(desc(vec![1, 9, 9, 3]), ramp(243, 0.3)));
+
+        let expected = CpuBackend::new()
+            .with_simd_level(SimdLevel::Scalar)
+            .run(&graph, &inputs)
+            .unwrap();
+        let actual = CpuBackend::new().run(&graph, &inputs).unwrap();
+
+        let (e, a) = (&expected[&y_id], &actual[&y_id]);
+        assert_eq!(a.desc.shape, vec![1, 5, 5, 12]);
+        assert!(a.approx_eq(e, 1e-4), "max diff {:?}", a.max_abs_diff(e));
+    }
+
+    #[test]
+    fn in_place_run_matches_reference() {
+        let desc = TensorDesc {
+            dtype: DType::F32,
+            shape: vec![3, 37],
+        };
+        let x: Vec<f32> = (0..111).map(|i| (i % 13) as f32 * 0.5 - 3.0).collect();
+        let y: Vec<f32> = (0..111).map(|i| (i % 7) as f32 * 0.25 - 0.75).collect();
+
+        // d = HardSwish(Add(x, Relu(Mul(x, y)))); Relu, Add and HardSwish
+        // overwrite the value they consume
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Input, &[], 1),
+                node(OpKind::Mul, &[0, 1], 2),
+                node(OpKind::Relu, &[2], 3),
+                node(OpKind::Add, &[0, 3], 4),
+                node(OpKind::HardSwish, &[4], 5),
+            ],
+            outputs: vec![ValueId(5)],
+            value_types: HashMap::new(),
+        };
+        assert_eq!(
+            memory::in_place_inputs(&graph),
+            HashMap::from([(3, 0), (4, 1), (5, 0)])
+        );
+
+        let inputs = HashMap::from([
+            (ValueId(0), Tensor::new(desc.clone(), x.clone())),
+            (ValueId(1), Tensor::new(desc, y.clone())),
+        ]);
+        let outputs = CpuBackend::new().run(&graph, &inputs).unwrap();
+
+        let expected: Vec<f32> = x
+            .iter()
+            .zip(&y)
+            .map(|(&x, &y)| {
+                let c = x + (x * y).max(0.0);
+                c * (c + 3.0).clamp(0.0, 6.0) / 6.0
+            })
+            .collect();
+        assert_eq!(outputs[&ValueId(5)].data, expected);
+        // Caller inputs are never written
+        assert_eq!(inputs[&ValueId(0)].data, x);
+    }
+
+    #[test]
+    fn outputs_borrow_inputs_constants_and_layout_ops() {
+        let desc = TensorDesc {
+            dtype: DType::F32,
+            shape: vec![2, 2],
+        };
+        let weight = Arc::new(Tensor::new(desc.clone(), vec![1.0, -2.0, 3.0, -4.0]));
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Constant(weight.clone()), &[], 1),
+                node(OpKind::Mul, &[0, 1], 2),
+                node(OpKind::Transpose(TransposeAttrs { perm: vec![1, 0] }), &[2], 3),
+                node(
+                    OpKind::Reshape(ReshapeAttrs {
+                        shape: vec![4],
+                        allowzero: false,
+                    }),
+                    &[2],
+                    4,
+                ),
+            ],
+            outputs: vec![ValueId(0), ValueId(1), ValueId(2), ValueId(2), ValueId(3), ValueId(4)],
+            value_types: HashMap::new(),
+        };
+        let inputs = HashMap::from([(ValueId(0), Tensor::new(desc, vec![2.0; 4]))]);
+
+        let sequential = CpuBackend::new();
+        let inter_op = CpuBackend::new()
+            .with_threads(2)
+            .unwrap()
+            .with_inter_op(true);
+        for backend in [sequential, inter_op] {
+            let outputs = backend.run_borrowed(&graph, &inputs).unwrap();
+            assert_eq!(outputs.len(), 5);
+            let borrows = |id: u32, data: &[f32]| {
+                outputs[&ValueId(id)]
+                    .as_slice()
+                    .is_some_and(|o| std::ptr::eq(o, data))
+            };
+            assert!(borrows(0, &inputs[&ValueId(0)].data));
+            assert!(borrows(1, &weight.data));
+
+            // Transpose and Reshape are views of the Mul result
+            let y = &outputs[&ValueId(2)];
+            assert_eq!(y.as_slice().unwrap(), [2.0, -4.0, 6.0, -8.0]);
+            assert!(outputs[&ValueId(3)].shares_storage(y));
+            assert!(outputs[&ValueId(4)].shares_storage(y));
+            assert_eq!(outputs[&ValueId(3)].contiguous().as_ref(), [2.0, 6.0, -4.0, -8.0]);
+        }
+
+        // So does evaluating the Constant node on its own
+        let constant = CpuBackend::new().eval_node(&graph.nodes[1], &[]).unwrap();
+        assert!(std::ptr::eq(constant.as_slice().unwrap(), weight.data.as_slice()));
+
+        // Sessions share the constant instead of copying it
+        let descs = HashMap::from([(ValueId(0), inputs[&ValueId(0)].desc.clone())]);
+        let mut session = CpuBackend::new().compile(&graph, &descs).unwrap();
+        let x = &inputs[&ValueId(0)];
+        let outputs = session.run_borrowed(&[x]).unwrap();
+        assert!(std::ptr::eq(outputs[1].data, weight.data.as_slice()));
+        assert_eq!(outputs[2].data, [2.0, -4.0, 6.0, -8.0]);
+        assert_eq!(outputs[4].data, [2.0, 6.0, -4.0, -8.0]);
+    }
+
+    #[test]
+    fn threaded_run_is_deterministic() {
+        let desc = |shape: Vec<usize>| TensorDesc {
+            dtype: DType::F32,
+            shape,
+        };
+        let ramp = |len: usize, scale: f32| {
+            (0..len)
+                .map(|i| ((i * 13) % 17) as f32 * scale - 0.5)
+                .collect::<Vec<f32>>()
+        };
+
+        // h = HardSwish(Conv2D(x, w)), y = MatMul(a, m)
+        // Large enough that every kernel crosses the parallel threshold.
+        let (x_id, w_id, c_id, h_id, a_id, m_id, y_id) = (
+            ValueId(0),
+            ValueId(1),
+            ValueId(2),
+            ValueId(3),
+            ValueId(4),
+            ValueId(5),
+            ValueId(6),
+        );
+        let conv = Conv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [1, 1],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        };
+        let graph = Graph {
+            nodes: vec![
+                Node {
+                    id: NodeId(0),
+                    op: OpKind::Input,
+                    inputs: vec![],
+                    output: x_id,
+                },
+                Node {
+                    id: NodeId(1),
+                    op: OpKind::Constant(Tensor::new(desc(vec![3, 3, 8, 16]), ramp(1152, 0.01)).into()),
+                    inputs: vec![],
+                    output: w_id,
+                },
+                Node {
+                    id: NodeId(2),
+                    op: OpKind::Conv2D(conv),
+                    inputs: vec![x_id, w_id],
+                    output: c_id,
+                },
+                Node {
+                    id: NodeId(3),
+                    op: OpKind::HardSwish,
+                    inputs: vec![c_id],
+                    output: h_id,
+                },
+                Node {
+                    id: NodeId(4),
+                    op: OpKind::Input,
+                    inputs: vec![],
+                    output: a_id,
+                },
+                Node {
+                    id: NodeId(5),
+                    op: OpKind::Constant(Tensor::new(desc(vec![256, 200]), ramp(51200, 0.001)).into()),
+                    inputs: vec![],
+                    output: m_id,
+                },
+                Node {
+                    id: NodeId(6),
+                    op: OpKind::MatMul(None),
+                    inputs: vec![a_id, m_id],
+                    output: y_id,
+                },
+            ],
+            outputs: vec![h_id, y_id],
+            value_types: HashMap::new(),
+        };
+
+        let mut inputs = HashMap::new();
+        inputs.insert(x_id, Tensor::new(desc(vec![2, 24, 24, 8]), ramp(9216, 0.1)));
+        inputs.insert(a_id, Tensor::new(desc(vec![67, 256]), ramp(17152, 0.02)));
+
+        let reference = CpuBackend::new().run(&graph, &inputs).unwrap();
+        for threads in [2, 3, 4] {
+            let backend = CpuBackend::new().with_threads(threads).unwrap();
+            assert_eq!(backend.threads(), threads);
+            let outputs = backend.run(&graph, &inputs).unwrap();
+            for id in [h_id, y_id] {
+                assert_eq!(outputs[&id].data, reference[&id].data, "threads={}", threads);
+            }
+        }
+
+        let err = ThreadPool::new(0).err().unwrap();
+        assert_eq!(err.kind(), "InvalidAttr");
+    }
+
+    #[test]
+    fn concat_interleaves_blocks() {
+        let desc = |shape| TensorDesc {
+            dtype: DType::F32,
+            shape,
+        };
+        let a = Tensor::new(desc(vec![2, 2]), vec![0.0, 1.0, 2.0, 3.0]);
+        let b = Tensor::new(desc(vec![2, 1]), vec![4.0, 5.0]);
+        // A transposed view is gathered before copying
+        let t = a.view().permute(&[1, 0]).unwrap();
+        let y = concat(&[&a.view(), &b.view(), &t], &ConcatAttrs { axis: 1 }).unwrap();
+        assert_eq!(y.desc.shape, vec![2, 5]);
+        assert_eq!(y.data, vec![0.0, 1.0, 4.0, 0.0, 2.0, 2.0, 3.0, 5.0, 1.0, 3.0]);
+
+        let y = concat(&[&a.view(), &t], &ConcatAttrs { axis: 0 }).unwrap();
+        assert_eq!(y.data, vec![0.0, 1.0, 2.0, 3.0, 0.0, 2.0, 1.0, 3.0]);
+
+        assert!(concat(&[&a.view(), &b.view()], &ConcatAttrs { axis: 0 }).is_err());
+    }
+
+    #[test]
+    fn errors_name_the_failing_node() {
+        // y = Relu(Add(x, z)) with mismatched x and z
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Input, &[], 1),
+                node(OpKind::Add, &[0, 1], 2),
+                node(OpKind::Relu, &[2], 3),
+            ],
+            outputs: vec![ValueId(3)],
+            value_types: HashMap::new(),
+        };
+        let inputs = HashMap::from([
+            (ValueId(0), Tensor::zeros(vec![2, 3])),
+            (ValueId(1), Tensor::zeros(vec![3, 2])),
+        ]);
+
+        let sequential = CpuBackend::new();
+        let scheduled = CpuBackend::new().with_threads(2).unwrap().with_inter_op(true);
+        for backend in [sequential, scheduled] {
+            let err = backend.run(&graph, &inputs).unwrap_err();
+            assert!(matches!(err, Error::ShapeMismatch { .. }), "{:?}", err);
+            let info = err.node().unwrap();
+            assert_eq!((info.id, info.op), (NodeId(2), "Add"));
+            assert_eq!(info.inputs, vec![ValueId(0), ValueId(1)]);
+
+            let missing = backend.run(&graph, &HashMap::new()).unwrap_err();
+            assert_eq!(missing.kind(), "MissingInput");
+            assert_eq!(missing.node().unwrap().id, NodeId(2));
+        }
+    }
+
+    #[test]
+    fn batch_norm_matches_formula() {
+        let x = Tensor::arange(0.0, 6.0, 1.0).reshape(vec![1, 1, 3, 2]).unwrap();
+        let param = |a: f32, b: f32| Tensor::from_fn(vec![2], |i| [a, b][i[0]]);
+        let (scale, bias) = (param(2.0, 0.5), param(1.0, -1.0));
+        let (mean, var) = (param(1.0, 3.0), param(4.0, 0.25));
+        let attrs = BatchNormAttrs {
+            epsilon: 0.0,
+            momentum: 0.9,
+        };
+        let args = [&x, &scale, &bias, &mean, &var].map(|t| t.view());
+        let y = batch_norm(&args[0], &args[1], &args[2], &args[3], &args[4], Some(&attrs)).unwrap();
+        // channel 0: 2 * (x - 1) / 2 + 1, channel 1: 0.5 * (x - 3) / 0.5 - 1
+        assert_eq!(y.data, vec![0.0, -3.0, 2.0, -1.0, 4.0, 1.0]);
+    }
+
+    #[test]
+    fn transposed_matmul_matches_explicit_transpose() {
+        let matmul = |trans_a, trans_b| {
+            OpKind::MatMul(Some(MatMulAttrs {
+                trans_a,
+                trans_b,
+                activation: None,
+            }))
+        };
+        let perm = || OpKind::Transpose(TransposeAttrs { perm: vec![1, 0] });
+        // y = A^T B^T, both as one MatMul and with explicit Transposes
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Input, &[], 1),
+                node(matmul(true, true), &[0, 1], 2),
+                node(perm(), &[0], 3),
+                node(perm(), &[1], 4),
+                node(matmul(false, false), &[3, 4], 5),
+            ],
+            outputs: vec![ValueId(2), ValueId(5)],
+            value_types: HashMap::new(),
+        };
+        let a = Tensor::from_fn(vec![3, 2], |i| (i[0] * 2 + i[1]) as f32 - 2.0);
+        let b = Tensor::from_fn(vec![4, 3], |i| (i[0] + i[1] * 4) as f32 * 0.5);
+        let inputs = HashMap::from([(ValueId(0), a), (ValueId(1), b)]);
+
+        let outputs = CpuBackend::new().run(&graph, &inputs).unwrap();
+        assert_eq!(outputs[&ValueId(2)].shape(), &[2, 4]);
+        assert_eq!(outputs[&ValueId(2)].data, outputs[&ValueId(5)].data);
+
+        let descs = inputs.iter().map(|(&id, t)| (id, t.desc.clone())).collect();
+        let mut session = CpuBackend::new().compile(&graph, &descs).unwrap();
+        let actual = session.run(&inputs).unwrap();
+        assert_eq!(actual[&ValueId(2)].data, outputs[&ValueId(2)].data);
+    }
 }
@@ -0,0 +1,133 @@
+//! Reference interpreter for Loop IR programs.
+
+use std::collections::HashMap;
+
+use super::{Affine, BufferId, BufferKind, Expr, Program, Stmt, UnaryOp};
+use crate::error::{Result, ensure, err};
+use crate::{DType, Error, Tensor, TensorDesc, ValueId};
+
+struct State<'a> {
+    program: &'a Program,
+    memory: Vec<Vec<f32>>,
+    vars: Vec<i64>,
+    locals: Vec<f32>,
+}
+
+impl Program {
+    /// Run every kernel in order, one scalar at a time, and return the
+    /// output tensors.
+    pub fn run(&self, inputs: &HashMap<ValueId, Tensor>) -> Result<HashMap<ValueId, Tensor>> {
+        let mut memory: Vec<Vec<f32>> = self
+            .buffers
+            .iter()
+            .map(|b| match &b.kind {
+                BufferKind::Constant(t) => t.data.clone(),
+                BufferKind::Input | BufferKind::Temp => vec![0.0; b.len()],
+            })
+            .collect();
+        for &(value, id) in &self.inputs {
+            let t = inputs
+                .get(&value)
+                .ok_or(Error::MissingInput { node: None, value })?;
+            let buffer = self.buffer(id);
+            ensure!(
+                t.desc.shape == buffer.shape,
+                ShapeMismatch,
+                "input {:?} has shape {:?}, program expects {:?}",
+                value,
+                t.desc.shape,
+                buffer.shape
+            );
+            memory[id.0 as usize].clone_from(&t.data);
+        }
+
+        let mut state = State {
+            program: self,
+            memory,
+            vars: vec![0; self.var_names.len()],
+            locals: vec![0.0; self.locals as usize],
+        };
+        for kernel in &self.kernels {
+            state.exec(&kernel.body)?;
+        }
+
+        Ok(self
+            .outputs
+            .iter()
+            .map(|&(value, id)| {
+                let desc = TensorDesc {
+                    dtype: DType::F32,
+                    shape: self.buffer(id).shape.clone(),
+                };
+                (
+                    value,
+                    Tensor::new(desc, state.memory[id.0 as usize].clone()),
+                )
+            })
+            .collect())
+    }
+}
+
+impl State<'_> {
+    fn exec(&mut self, stmts: &[Stmt]) -> Result<()> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::For(l) => {
+                    for i in 0..l.extent {
+                        self.vars[l.var.0 as usize] = i as i64;
+                        self.exec(&l.body)?;
+                    }
+                }
+                Stmt::If { bounds, body } => {
+                    let inside = bounds.iter().all(|b| {
+                        let i = b.index.eval(&self.vars);
+                        i >= 0 && (i as usize) < b.extent
+                    });
+                    if inside {
+                        self.exec(body)?;
+                    }
+                }
+                Stmt::Assign { local, value } => {
+                    self.locals[local.0 as usize] = self.eval(value)?;
+                }
+                Stmt::Store {
+                    buffer,
+                    index,
+                    value,
+                } => {
+                    let v = self.eval(value)?;
+                    let i = self.offset(*buffer, index)?;
+                    self.memory[buffer.0 as usize][i] = v;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<f32> {
+        Ok(match expr {
+            Expr::Const(c) => *c,
+            Expr::Local(l) => self.locals[l.0 as usize],
+            Expr::Load(b, index) => self.memory[b.0 as usize][self.offset(*b, index)?],
+            Expr::Unary(UnaryOp::Sqrt, x) => self.eval(x)?.sqrt(),
+            Expr::Binary(op, a, b) => op.eval(self.eval(a)?, self.eval(b)?),
+        })
+    }
+
+    fn offset(&self, buffer: BufferId, index: &Affine) -> Result<usize> {
+        let i = index.eval(&self.vars);
+        let b = self.program.buffer(buffer);
+        usize::try_from(i)
+            .ok()
+            .filter(|&i| i < b.len())
+            .ok_or_else(|| {
+                err!(
+                    Internal,
+                    "index {} ({}) out of bounds for {}",
+                    self.program.affine(index),
+                    i,
+                    b.name
+                )
+            })
+    }
+}
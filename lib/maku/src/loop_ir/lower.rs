@@ -0,0 +1,659 @@
+//! Lowering rules from each [`OpKind`] to loop nests.
+//!
+//! Every kernel computes its output in one pass over the output elements,
+//! keeping reductions in locals, and mirrors the arithmetic of the
+//! [`CpuBackend`](crate::CpuBackend) kernels.
+
+use std::collections::HashMap;
+
+use super::{
+    Affine, Bound, Buffer, BufferId, BufferKind, Expr, Kernel, Local, Loop, LoopKind, Program,
+    Stmt, Var,
+};
+use crate::error::Result;
+use crate::shape::infer_shapes;
+use crate::{
+    Activation, AveragePoolAttrs, BatchNormAttrs, ConcatAttrs, ElementwiseOp, FusedArg,
+    FusedElementwiseAttrs, Graph, MatMulAttrs, Node, OpKind, TensorDesc, TransposeAttrs, ValueId,
+};
+
+/// Lower `graph` with Input types from `inputs` (falling back to
+/// `graph.value_types`).
+pub fn lower(graph: &Graph, inputs: &HashMap<ValueId, TensorDesc>) -> Result<Program> {
+    let types = infer_shapes(graph, inputs)?;
+    let mut cx = Lowering {
+        program: Program {
+            buffers: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            kernels: Vec::new(),
+            var_names: Vec::new(),
+            locals: 0,
+        },
+        buffers: HashMap::new(),
+    };
+
+    for node in &graph.nodes {
+        let kind = match &node.op {
+            OpKind::Input => BufferKind::Input,
+            OpKind::Constant(t) => BufferKind::Constant(t.clone()),
+            _ => BufferKind::Temp,
+        };
+        let id = BufferId(cx.program.buffers.len() as u32);
+        cx.program.buffers.push(Buffer {
+            name: format!("v{}", node.output.0),
+            shape: types[&node.output].shape.clone(),
+            kind,
+        });
+        cx.buffers.insert(node.output, id);
+
+        match node.op {
+            OpKind::Input => cx.program.inputs.push((node.output, id)),
+            OpKind::Constant(_) => {}
+            _ => {
+                let body = cx.lower_node(node, id).map_err(|e| e.at(node))?;
+                cx.program.kernels.push(Kernel {
+                    name: format!("{}_{}", node.op.name(), node.id.0),
+                    node: node.id,
+                    body,
+                });
+            }
+        }
+    }
+
+    for &value in &graph.outputs {
+        let id = *cx
+            .buffers
+            .get(&value)
+            .ok_or(crate::Error::MissingOutput { value })?;
+        cx.program.outputs.push((value, id));
+    }
+    Ok(cx.program)
+}
+
+struct Lowering {
+    program: Program,
+    buffers: HashMap<ValueId, BufferId>,
+}
+
+impl Lowering {
+    fn var(&mut self, name: &str) -> Var {
+        self.program.var_names.push(name.to_string());
+        Var(self.program.var_names.len() as u32 - 1)
+    }
+
+    fn local(&mut self) -> Local {
+        self.program.locals += 1;
+        Local(self.program.locals - 1)
+    }
+
+    fn shape(&self, id: BufferId) -> &[usize] {
+        &self.program.buffer(id).shape
+    }
+
+    /// Loops over `(name, extent)` pairs, outermost first, around the
+    /// statements `body` builds from the loop variables. Names are unique
+    /// within a kernel so schedules can refer to them.
+    fn nest<S: AsRef<str>>(
+        &mut self,
+        loops: &[(S, usize)],
+        body: impl FnOnce(&mut Self, &[Affine]) -> Vec<Stmt>,
+    ) -> Vec<Stmt> {
+        let vars: Vec<Var> = loops
+            .iter()
+            .map(|(name, _)| self.var(name.as_ref()))
+            .collect();
+        let index: Vec<Affine> = vars.iter().map(|&v| v.into()).collect();
+        let mut stmts = body(self, &index);
+        for (&var, &(_, extent)) in vars.iter().zip(loops).rev() {
+            stmts = vec![Stmt::For(Loop {
+                var,
+                extent,
+                kind: LoopKind::Serial,
+                body: stmts,
+            })];
+        }
+        stmts
+    }
+
+    fn lower_node(&mut self, node: &Node, out: BufferId) -> Result<Vec<Stmt>> {
+        let args: Vec<BufferId> = node.inputs.iter().map(|v| self.buffers[v]).collect();
+        let arg = |i: usize| args[i];
+        let len = self.program.buffer(out).len();
+
+        let body = match &node.op {
+            OpKind::Input | OpKind::Constant(_) => Vec::new(),
+            OpKind::Add | OpKind::Mul | OpKind::Relu | OpKind::Relu6 | OpKind::HardSwish => {
+                let op = ElementwiseOp::from_op(&node.op).expect("elementwise op");
+                self.nest(&[("i", len)], |_, i| {
+                    let value = elementwise(op, |j| Expr::load(arg(j), i[0].clone()));
+                    vec![store(out, i[0].clone(), value)]
+                })
+            }
+            OpKind::FusedElementwise(attrs) => self.fused_elementwise(attrs, &args, out, len),
+            OpKind::MatMul(attrs) => self.matmul(arg(0), arg(1), out, attrs.as_ref()),
+            OpKind::Conv2D(attrs) => {
+                let p = crate::conv2d_params(
+                    self.shape(arg(0)),
+                    self.shape(arg(1)),
+                    args.get(2).map(|&b| self.shape(b)),
+                    attrs,
+                )?;
+                self.conv(&p, &args, out, false)
+            }
+            OpKind::DepthwiseConv2D(attrs) => {
+                let p = crate::depthwise_conv2d_params(
+                    self.shape(arg(0)),
+                    self.shape(arg(1)),
+                    args.get(2).map(|&b| self.shape(b)),
+                    attrs,
+                )?;
+                self.conv(&p, &args, out, true)
+            }
+            OpKind::BatchNorm(attrs) => self.batch_norm(&args, attrs.as_ref(), out),
+            OpKind::AveragePool(attrs) => self.average_pool(arg(0), attrs, out),
+            OpKind::GlobalAveragePool => self.global_average_pool(arg(0), out),
+            OpKind::Reshape(_) => self.nest(&[("i", len)], |_, i| {
+                vec![store(out, i[0].clone(), Expr::load(arg(0), i[0].clone()))]
+            }),
+            OpKind::Transpose(attrs) => self.transpose(arg(0), attrs, out),
+            OpKind::Concat(attrs) => self.concat(&args, attrs, out),
+        };
+        Ok(body)
+    }
+
+    fn fused_elementwise(
+        &mut self,
+        attrs: &FusedElementwiseAttrs,
+        args: &[BufferId],
+        out: BufferId,
+        len: usize,
+    ) -> Vec<Stmt> {
+        let steps: Vec<Local> = attrs.steps.iter().map(|_| self.local()).collect();
+        self.nest(&[("i", len)], |_, i| {
+            let operand = |a: &FusedArg| match *a {
+                FusedArg::Input(j) => Expr::load(args[j], i[0].clone()),
+                FusedArg::Step(s) => steps[s].into(),
+            };
+            let mut body: Vec<Stmt> = attrs
+                .steps
+                .iter()
+                .zip(&steps)
+                .map(|(step, &local)| {
+                    assign(local, elementwise(step.op, |j| operand(&step.args[j])))
+                })
+                .collect();
+            let last = *steps.last().expect("validated FusedElementwise has steps");
+            body.push(store(out, i[0].clone(), last.into()));
+            body
+        })
+    }
+
+    /// A transposed operand is read with its two indices swapped
+    fn matmul(
+        &mut self,
+        a: BufferId,
+        b: BufferId,
+        out: BufferId,
+        attrs: Option<&MatMulAttrs>,
+    ) -> Vec<Stmt> {
+        let (trans_a, trans_b) = attrs.map_or((false, false), |a| (a.trans_a, a.trans_b));
+        let activation = attrs.and_then(|a| a.activation);
+        let (a_shape, b_shape) = (self.shape(a).to_vec(), self.shape(b).to_vec());
+        let (m, k) = if trans_a {
+            (a_shape[1], a_shape[0])
+        } else {
+            (a_shape[0], a_shape[1])
+        };
+        let n = if trans_b { b_shape[0] } else { b_shape[1] };
+        let acc = self.local();
+        self.nest(&[("i", m), ("j", n)], |cx, ij| {
+            let (i, j) = (&ij[0], &ij[1]);
+            let mut body = vec![assign(acc, Expr::Const(0.0))];
+            body.extend(cx.nest(&[("k", k)], |_, kk| {
+                let k = &kk[0];
+                let a_index = if trans_a { [k, i] } else { [i, k] };
+                let b_index = if trans_b { [j, k] } else { [k, j] };
+                let a = Expr::load(a, Affine::flat(&a_shape, &a_index.map(Affine::clone)));
+                let b = Expr::load(b, Affine::flat(&b_shape, &b_index.map(Affine::clone)));
+                vec![assign(acc, Expr::from(acc) + a * b)]
+            }));
+            body.push(store(
+                out,
+                Affine::flat(&[m, n], ij),
+                activate(activation, acc),
+            ));
+            body
+        })
+    }
+
+    /// Conv2D, or DepthwiseConv2D when `depthwise` (output channel
+    /// `c * depth_multiplier + m` reads input channel `c`).
+    fn conv(
+        &mut self,
+        p: &crate::simd::ConvParams,
+        args: &[BufferId],
+        out: BufferId,
+        depthwise: bool,
+    ) -> Vec<Stmt> {
+        let (input, kernel, bias) = (args[0], args[1], args.get(2).copied());
+        let in_shape = [p.batch, p.in_h, p.in_w, p.in_c];
+        let out_shape = [p.batch, p.out_h, p.out_w, p.out_c];
+        let k_shape = self.shape(kernel).to_vec();
+        // Channels are split as (group, channel in group); a depthwise
+        // conv has one input channel per group
+        let in_c_g = if depthwise { 1 } else { p.in_c / p.group };
+        let out_c_g = p.out_c / p.group;
+        let acc = self.local();
+
+        let loops = [
+            ("n", p.batch),
+            ("oh", p.out_h),
+            ("ow", p.out_w),
+            ("g", p.group),
+            ("oc", out_c_g),
+        ];
+        self.nest(&loops, |cx, o| {
+            let (n, oh, ow, g) = (&o[0], &o[1], &o[2], &o[3]);
+            let oc = g.clone() * out_c_g as i64 + o[4].clone();
+            let mut body = vec![assign(
+                acc,
+                bias.map_or(Expr::Const(0.0), |b| Expr::load(b, oc.clone())),
+            )];
+            body.extend(cx.nest(&[("kh", p.k_h)], |cx, kh| {
+                let ih = oh.clone() * p.stride_h as i64
+                    + kh[0].clone() * p.dilation_h as i64
+                    + -(p.pad_top as i64);
+                let taps = cx.nest(&[("kw", p.k_w)], |cx, kw| {
+                    let iw = ow.clone() * p.stride_w as i64
+                        + kw[0].clone() * p.dilation_w as i64
+                        + -(p.pad_left as i64);
+                    let macs = cx.nest(&[("ic", in_c_g)], |_, ic| {
+                        let c = g.clone() * in_c_g as i64 + ic[0].clone();
+                        let x_index = [n.clone(), ih.clone(), iw.clone(), c];
+                        let x = Expr::load(input, Affine::flat(&in_shape, &x_index));
+                        let k_index = if depthwise {
+                            [kh[0].clone(), kw[0].clone(), g.clone(), o[4].clone()]
+                        } else {
+                            [kh[0].clone(), kw[0].clone(), ic[0].clone(), oc.clone()]
+                        };
+                        let k = Expr::load(kernel, Affine::flat(&k_shape, &k_index));
+                        vec![assign(acc, Expr::from(acc) + x * k)]
+                    });
+                    vec![guard(iw, p.in_w, macs)]
+                });
+                vec![guard(ih, p.in_h, taps)]
+            }));
+            let index = Affine::flat(&out_shape, &[n.clone(), oh.clone(), ow.clone(), oc]);
+            body.push(store(out, index, activate(p.activation, acc)));
+            body
+        })
+    }
+
+    fn batch_norm(
+        &mut self,
+        args: &[BufferId],
+        attrs: Option<&BatchNormAttrs>,
+        out: BufferId,
+    ) -> Vec<Stmt> {
+        let epsilon = attrs.map_or(1e-5, |a| a.epsilon);
+        let c = self.shape(args[0]).last().copied().unwrap_or(0);
+        let rows = self.program.buffer(out).len().checked_div(c).unwrap_or(0);
+        let mul = self.local();
+        self.nest(&[("r", rows), ("c", c)], |_, rc| {
+            let param = |i: usize| Expr::load(args[i], rc[1].clone());
+            let index = Affine::flat(&[rows, c], rc);
+            // y = x * mul + (bias - mean * mul), mul = scale / sqrt(var + epsilon)
+            vec![
+                assign(mul, param(1) / (param(4) + Expr::Const(epsilon)).sqrt()),
+                store(
+                    out,
+                    index.clone(),
+                    Expr::load(args[0], index) * mul.into() + (param(2) - param(3) * mul.into()),
+                ),
+            ]
+        })
+    }
+
+    fn average_pool(
+        &mut self,
+        input: BufferId,
+        attrs: &AveragePoolAttrs,
+        out: BufferId,
+    ) -> Vec<Stmt> {
+        let in_shape = self.shape(input).to_vec();
+        let out_shape = self.shape(out).to_vec();
+        let [k_h, k_w] = attrs.kernel_shape;
+        let (sum, count) = (self.local(), self.local());
+        let loops = ["n", "oh", "ow", "c"]
+            .into_iter()
+            .zip(out_shape.iter().copied());
+        self.nest(&loops.collect::<Vec<_>>(), |cx, o| {
+            let mut body = vec![
+                assign(sum, Expr::Const(0.0)),
+                assign(count, Expr::Const(0.0)),
+            ];
+            body.extend(cx.nest(&[("kh", k_h), ("kw", k_w)], |_, k| {
+                let ih =
+                    o[1].clone() * attrs.strides[0] as i64 + k[0].clone() + -(attrs.pads[0] as i64);
+                let iw =
+                    o[2].clone() * attrs.strides[1] as i64 + k[1].clone() + -(attrs.pads[1] as i64);
+                let x_index = [o[0].clone(), ih.clone(), iw.clone(), o[3].clone()];
+                let x = Expr::load(input, Affine::flat(&in_shape, &x_index));
+                vec![Stmt::If {
+                    bounds: vec![
+                        Bound {
+                            index: ih,
+                            extent: in_shape[1],
+                        },
+                        Bound {
+                            index: iw,
+                            extent: in_shape[2],
+                        },
+                    ],
+                    body: vec![
+                        assign(sum, Expr::from(sum) + x),
+                        assign(count, Expr::from(count) + Expr::Const(1.0)),
+                    ],
+                }]
+            }));
+            let divisor = if attrs.count_include_pad {
+                Expr::Const((k_h * k_w) as f32)
+            } else {
+                count.into()
+            };
+            body.push(store(
+                out,
+                Affine::flat(&out_shape, o),
+                Expr::from(sum) / divisor,
+            ));
+            body
+        })
+    }
+
+    fn global_average_pool(&mut self, input: BufferId, out: BufferId) -> Vec<Stmt> {
+        let in_shape = self.shape(input).to_vec();
+        let (n, h, w, c) = (in_shape[0], in_shape[1], in_shape[2], in_shape[3]);
+        let sum = self.local();
+        self.nest(&[("n", n), ("c", c)], |cx, nc| {
+            let mut body = vec![assign(sum, Expr::Const(0.0))];
+            body.extend(cx.nest(&[("h", h), ("w", w)], |_, hw| {
+                let index = [nc[0].clone(), hw[0].clone(), hw[1].clone(), nc[1].clone()];
+                let x = Expr::load(input, Affine::flat(&in_shape, &index));
+                vec![assign(sum, Expr::from(sum) + x)]
+            }));
+            let value = Expr::from(sum) / Expr::Const((h * w) as f32);
+            body.push(store(out, Affine::flat(&[n, c], nc), value));
+            body
+        })
+    }
+
+    fn transpose(&mut self, input: BufferId, attrs: &TransposeAttrs, out: BufferId) -> Vec<Stmt> {
+        let in_shape = self.shape(input).to_vec();
+        let out_shape = self.shape(out).to_vec();
+        self.nest(&dims("d", &out_shape), |_, o| {
+            // y[i] = x[j] with j[perm[d]] = i[d]
+            let mut x_index = vec![Affine::constant(0); in_shape.len()];
+            for (d, &axis) in attrs.perm.iter().enumerate() {
+                x_index[axis] = o[d].clone();
+            }
+            let x = Expr::load(input, Affine::flat(&in_shape, &x_index));
+            vec![store(out, Affine::flat(&out_shape, o), x)]
+        })
+    }
+
+    fn concat(&mut self, args: &[BufferId], attrs: &ConcatAttrs, out: BufferId) -> Vec<Stmt> {
+        let out_shape = self.shape(out).to_vec();
+        let mut offset = 0;
+        let mut body = Vec::new();
+        for (j, &input) in args.iter().enumerate() {
+            let in_shape = self.shape(input).to_vec();
+            body.extend(self.nest(&dims(&format!("x{}_d", j), &in_shape), |_, i| {
+                let mut o = i.to_vec();
+                o[attrs.axis] = o[attrs.axis].clone() + offset as i64;
+                let x = Expr::load(input, Affine::flat(&in_shape, i));
+                vec![store(out, Affine::flat(&out_shape, &o), x)]
+            }));
+            offset += in_shape[attrs.axis];
+        }
+        body
+    }
+}
+
+/// Loops `<prefix>0`, `<prefix>1`, ... over each dim of `shape`
+fn dims(prefix: &str, shape: &[usize]) -> Vec<(String, usize)> {
+    shape
+        .iter()
+        .enumerate()
+        .map(|(d, &extent)| (format!("{}{}", prefix, d), extent))
+        .collect()
+}
+
+fn store(buffer: BufferId, index: Affine, value: Expr) -> Stmt {
+    Stmt::Store {
+        buffer,
+        index,
+        value,
+    }
+}
+
+fn assign(local: Local, value: Expr) -> Stmt {
+    Stmt::Assign { local, value }
+}
+
+/// `body` where `0 <= index < extent`
+fn guard(index: Affine, extent: usize, body: Vec<Stmt>) -> Stmt {
+    Stmt::If {
+        bounds: vec![Bound { index, extent }],
+        body,
+    }
+}
+
+/// `op` applied to its operands `arg(0)`, `arg(1)`, ...
+fn elementwise(op: ElementwiseOp, mut arg: impl FnMut(usize) -> Expr) -> Expr {
+    match op {
+        ElementwiseOp::Add => arg(0) + arg(1),
+        ElementwiseOp::Mul => arg(0) * arg(1),
+        ElementwiseOp::Relu => activation(Activation::Relu, arg(0)),
+        ElementwiseOp::Relu6 => activation(Activation::Relu6, arg(0)),
+        ElementwiseOp::HardSwish => activation(Activation::HardSwish, arg(0)),
+    }
+}
+
+fn activation(act: Activation, x: Expr) -> Expr {
+    let relu6 = |x: Expr| x.max(Expr::Const(0.0)).min(Expr::Const(6.0));
+    match act {
+        Activation::Relu => x.max(Expr::Const(0.0)),
+        Activation::Relu6 => relu6(x),
+        // x * relu6(x + 3) / 6
+        Activation::HardSwish => x.clone() * relu6(x + Expr::Const(3.0)) / Expr::Const(6.0),
+    }
+}
+
+fn activate(act: Option<Activation>, acc: Local) -> Expr {
+    match act {
+        Some(act) => activation(act, acc.into()),
+        None => acc.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{constant, desc, node, tensor};
+    use crate::{Conv2DAttrs, CpuBackend, DepthwiseConv2DAttrs, FusedStep, ReshapeAttrs, Tensor};
+
+    #[test]
+    fn matches_cpu_backend() {
+        let fused = FusedElementwiseAttrs {
+            steps: vec![
+                FusedStep {
+                    op: ElementwiseOp::Mul,
+                    args: vec![FusedArg::Input(0), FusedArg::Input(1)],
+                },
+                FusedStep {
+                    op: ElementwiseOp::HardSwish,
+                    args: vec![FusedArg::Step(0)],
+                },
+                FusedStep {
+                    op: ElementwiseOp::Add,
+                    args: vec![FusedArg::Step(1), FusedArg::Step(0)],
+                },
+            ],
+        };
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![3, 3, 1, 4], 1), &[], 1),
+                node(constant(vec![4], 2), &[], 2),
+                node(
+                    OpKind::Conv2D(Conv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [2, 1],
+                        pads: [1, 0, 1, 2],
+                        dilations: [1, 2],
+                        group: 2,
+                        activation: Some(Activation::Relu6),
+                    }),
+                    &[0, 1, 2],
+                    3,
+                ),
+                node(constant(vec![3, 3, 4, 2], 4), &[], 4),
+                node(
+                    OpKind::DepthwiseConv2D(DepthwiseConv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [1, 1],
+                        pads: [1, 1, 1, 1],
+                        dilations: [1, 1],
+                        depth_multiplier: 2,
+                        activation: Some(Activation::HardSwish),
+                    }),
+                    &[3, 4],
+                    5,
+                ),
+                node(constant(vec![8], 6), &[], 6),
+                node(constant(vec![8], 7), &[], 7),
+                node(constant(vec![8], 8), &[], 8),
+                node(OpKind::Constant(Tensor::full(vec![8], 0.5).into()), &[], 9),
+                node(OpKind::BatchNorm(None), &[5, 6, 7, 8, 9], 10),
+                node(OpKind::Relu, &[10], 11),
+                node(OpKind::FusedElementwise(fused), &[10, 11], 12),
+                node(
+                    OpKind::Transpose(TransposeAttrs {
+                        perm: vec![0, 3, 1, 2],
+                    }),
+                    &[12],
+                    13,
+                ),
+                node(
+                    OpKind::Reshape(ReshapeAttrs {
+                        shape: vec![8, -1],
+                        allowzero: false,
+                    }),
+                    &[13],
+                    14,
+                ),
+                node(constant(vec![9, 5], 15), &[], 15),
+                node(
+                    OpKind::MatMul(Some(MatMulAttrs {
+                        trans_a: false,
+                        trans_b: false,
+                        activation: Some(Activation::Relu),
+                    })),
+                    &[14, 15],
+                    16,
+                ),
+            ],
+            outputs: vec![ValueId(10), ValueId(16)],
+            value_types: HashMap::from([(ValueId(0), desc(vec![1, 6, 5, 2]))]),
+        };
+        let x = Tensor::from_fn(vec![1, 6, 5, 2], |i| {
+            (i[1] * 10 + i[2] * 2 + i[3]) as f32 / 10.0 - 3.0
+        });
+        let inputs = HashMap::from([(ValueId(0), x)]);
+
+        let program = lower(&graph, &HashMap::new()).unwrap();
+        let actual = program.run(&inputs).unwrap();
+        let expected = CpuBackend::new().run(&graph, &inputs).unwrap();
+        for v in &graph.outputs {
+            assert_eq!(actual[v].desc.shape, expected[v].desc.shape);
+            assert!(actual[v].approx_eq(&expected[v], 1e-5), "{:?}", v);
+        }
+    }
+
+    #[test]
+    fn lowers_pools_and_concat() {
+        let pool = |count_include_pad| {
+            OpKind::AveragePool(AveragePoolAttrs {
+                kernel_shape: [2, 2],
+                strides: [2, 2],
+                pads: [1, 1, 1, 1],
+                count_include_pad,
+            })
+        };
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(pool(false), &[0], 1),
+                node(pool(true), &[0], 2),
+                node(OpKind::GlobalAveragePool, &[0], 3),
+                node(OpKind::Concat(ConcatAttrs { axis: 2 }), &[1, 2], 4),
+            ],
+            outputs: vec![ValueId(3), ValueId(4)],
+            value_types: HashMap::new(),
+        };
+        // x[0, h, w, 0] = 2h + w on a 2x2 image
+        let x = Tensor::from_fn(vec![1, 2, 2, 1], |i| (i[1] * 2 + i[2]) as f32);
+        let types = HashMap::from([(ValueId(0), x.desc.clone())]);
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let program = lower(&graph, &types).unwrap();
+        let out = program.run(&inputs).unwrap();
+
+        assert_eq!(out[&ValueId(3)].data, vec![1.5]);
+        assert_eq!(out[&ValueId(4)].desc.shape, vec![1, 2, 4, 1]);
+        assert_eq!(
+            out[&ValueId(4)].data,
+            vec![0.0, 1.0, 0.0, 0.25, 2.0, 3.0, 0.5, 0.75]
+        );
+
+        let expected = CpuBackend::new().run(&graph, &inputs).unwrap();
+        for v in &graph.outputs {
+            assert_eq!(out[v].data, expected[v].data, "{:?}", v);
+        }
+    }
+
+    #[test]
+    fn transposed_matmul_matches_cpu_backend() {
+        let matmul = |trans_a, trans_b| {
+            OpKind::MatMul(Some(MatMulAttrs {
+                trans_a,
+                trans_b,
+                activation: None,
+            }))
+        };
+        // Non-square operands, so reading the wrong index order would
+        // mismatch the shapes or the values
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![4, 3], 1), &[], 1),
+                node(constant(vec![2, 4], 2), &[], 2),
+                node(matmul(true, true), &[0, 1], 3),
+                node(matmul(false, true), &[3, 2], 4),
+                node(matmul(true, false), &[2, 3], 5),
+            ],
+            outputs: vec![ValueId(3), ValueId(4), ValueId(5)],
+            value_types: HashMap::new(),
+        };
+        let inputs = HashMap::from([(ValueId(0), tensor(vec![3, 2], 3))]);
+        let types = HashMap::from([(ValueId(0), desc(vec![3, 2]))]);
+
+        let program = lower(&graph, &types).unwrap();
+        let actual = program.run(&inputs).unwrap();
+        let expected = CpuBackend::new().run(&graph, &inputs).unwrap();
+        for v in &graph.outputs {
+            assert_eq!(actual[v].desc.shape, expected[v].desc.shape);
+            assert!(actual[v].approx_eq(&expected[v], 1e-5), "{:?}", v);
+        }
+    }
+}
@@ -0,0 +1,544 @@
+//! Loop IR: a graph lowered to explicit loop nests over flat f32 buffers.
+//!
+//! Every value of the graph gets a row-major [`Buffer`], and every node a
+//! [`Kernel`] of loop nests whose loads and stores index buffers with
+//! [`Affine`] expressions of the enclosing loop variables. [`lower`] builds
+//! a [`Program`] from a graph; [`Program::run`] interprets it and serves as
+//! the reference for schedules ([`Program::schedule`]) and code generators.
+//!
+//! ```text
+//! kernel Relu_1 {
+//!   for i in 0..12 {
+//!     v1[i] = max(v0[i], 0.0)
+//!   }
+//! }
+//! ```
+
+mod interp;
+mod lower;
+mod schedule;
+
+use std::fmt;
+use std::ops;
+use std::sync::Arc;
+
+use crate::{NodeId, Tensor, ValueId};
+
+pub use lower::lower;
+pub use schedule::Schedule;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BufferId(pub u32);
+
+/// Loop variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Var(pub u32);
+
+/// Scalar f32 variable local to a kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Local(pub u32);
+
+#[derive(Debug, Clone)]
+pub enum BufferKind {
+    /// Provided by the caller
+    Input,
+    /// Embedded data, never written
+    Constant(Arc<Tensor>),
+    /// Written by exactly one kernel
+    Temp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Buffer {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub kind: BufferKind,
+}
+
+impl Buffer {
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// `constant + sum(coefficient * var)`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Affine {
+    pub terms: Vec<(Var, i64)>,
+    pub constant: i64,
+}
+
+impl Affine {
+    pub fn constant(c: i64) -> Self {
+        Affine {
+            terms: Vec::new(),
+            constant: c,
+        }
+    }
+
+    /// Value with `vars[v.0]` bound to each variable.
+    pub fn eval(&self, vars: &[i64]) -> i64 {
+        self.terms
+            .iter()
+            .fold(self.constant, |acc, &(v, k)| acc + k * vars[v.0 as usize])
+    }
+
+    /// Row-major flat index of `index` into an array of `shape`.
+    pub fn flat(shape: &[usize], index: &[Affine]) -> Affine {
+        debug_assert_eq!(shape.len(), index.len());
+        let mut flat = Affine::constant(0);
+        let mut stride = 1i64;
+        for (&dim, i) in shape.iter().zip(index).rev() {
+            flat = flat + i.clone() * stride;
+            stride *= dim as i64;
+        }
+        flat
+    }
+}
+
+impl From<Var> for Affine {
+    fn from(v: Var) -> Self {
+        Affine {
+            terms: vec![(v, 1)],
+            constant: 0,
+        }
+    }
+}
+
+impl ops::Add for Affine {
+    type Output = Affine;
+
+    fn add(mut self, other: Affine) -> Affine {
+        for (v, k) in other.terms {
+            match self.terms.iter_mut().find(|(u, _)| *u == v) {
+                Some(term) => term.1 += k,
+                None => self.terms.push((v, k)),
+            }
+        }
+        self.terms.retain(|&(_, k)| k != 0);
+        self.constant += other.constant;
+        self
+    }
+}
+
+impl ops::Add<i64> for Affine {
+    type Output = Affine;
+
+    fn add(mut self, c: i64) -> Affine {
+        self.constant += c;
+        self
+    }
+}
+
+impl ops::Mul<i64> for Affine {
+    type Output = Affine;
+
+    fn mul(mut self, k: i64) -> Affine {
+        if k == 0 {
+            return Affine::constant(0);
+        }
+        for term in &mut self.terms {
+            term.1 *= k;
+        }
+        self.constant *= k;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Sqrt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// `if a < b { b } else { a }`, so a NaN in `a` propagates like Relu
+    Max,
+    /// `if b < a { b } else { a }`
+    Min,
+}
+
+impl BinaryOp {
+    pub fn eval(self, a: f32, b: f32) -> f32 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+            BinaryOp::Max => {
+                if a < b {
+                    b
+                } else {
+                    a
+                }
+            }
+            BinaryOp::Min => {
+                if b < a {
+                    b
+                } else {
+                    a
+                }
+            }
+        }
+    }
+
+    pub(crate) fn symbol(self) -> Option<&'static str> {
+        match self {
+            BinaryOp::Add => Some("+"),
+            BinaryOp::Sub => Some("-"),
+            BinaryOp::Mul => Some("*"),
+            BinaryOp::Div => Some("/"),
+            BinaryOp::Max | BinaryOp::Min => None,
+        }
+    }
+}
+
+/// Scalar f32 expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Const(f32),
+    Local(Local),
+    Load(BufferId, Affine),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn load(buffer: BufferId, index: Affine) -> Self {
+        Expr::Load(buffer, index)
+    }
+
+    pub fn binary(op: BinaryOp, a: Expr, b: Expr) -> Self {
+        Expr::Binary(op, Box::new(a), Box::new(b))
+    }
+
+    pub fn max(self, other: Expr) -> Self {
+        Expr::binary(BinaryOp::Max, self, other)
+    }
+
+    pub fn min(self, other: Expr) -> Self {
+        Expr::binary(BinaryOp::Min, self, other)
+    }
+
+    pub fn sqrt(self) -> Self {
+        Expr::Unary(UnaryOp::Sqrt, Box::new(self))
+    }
+}
+
+impl From<f32> for Expr {
+    fn from(c: f32) -> Self {
+        Expr::Const(c)
+    }
+}
+
+impl From<Local> for Expr {
+    fn from(l: Local) -> Self {
+        Expr::Local(l)
+    }
+}
+
+macro_rules! expr_op {
+    ($trait:ident, $method:ident, $op:ident) => {
+        impl ops::$trait for Expr {
+            type Output = Expr;
+
+            fn $method(self, other: Expr) -> Expr {
+                Expr::binary(BinaryOp::$op, self, other)
+            }
+        }
+    };
+}
+
+expr_op!(Add, add, Add);
+expr_op!(Sub, sub, Sub);
+expr_op!(Mul, mul, Mul);
+expr_op!(Div, div, Div);
+
+/// `0 <= index < extent`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bound {
+    pub index: Affine,
+    pub extent: usize,
+}
+
+/// How a code generator should emit a loop; every kind runs the same
+/// iterations, so the interpreter ignores it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopKind {
+    Serial,
+    /// Iterations run concurrently
+    Parallel,
+    /// Iterations run in SIMD lanes
+    Vectorized,
+    /// The body is replicated `extent` times
+    Unrolled,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    pub var: Var,
+    /// The body runs for `var` in `0..extent`
+    pub extent: usize,
+    pub kind: LoopKind,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    For(Loop),
+    /// Runs `body` only where every bound holds
+    If {
+        bounds: Vec<Bound>,
+        body: Vec<Stmt>,
+    },
+    Assign {
+        local: Local,
+        value: Expr,
+    },
+    Store {
+        buffer: BufferId,
+        index: Affine,
+        value: Expr,
+    },
+}
+
+/// The loop nests computing one node's output. Each element of the output
+/// is stored once, and no other buffer is written.
+#[derive(Debug, Clone)]
+pub struct Kernel {
+    /// `<op>_<node id>`
+    pub name: String,
+    pub node: NodeId,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub buffers: Vec<Buffer>,
+    pub inputs: Vec<(ValueId, BufferId)>,
+    pub outputs: Vec<(ValueId, BufferId)>,
+    /// Run in order
+    pub kernels: Vec<Kernel>,
+    /// Name of each [`Var`], which is also the name of its loop
+    pub var_names: Vec<String>,
+    /// Number of distinct [`Local`]s
+    pub locals: u32,
+}
+
+impl Program {
+    pub fn buffer(&self, id: BufferId) -> &Buffer {
+        &self.buffers[id.0 as usize]
+    }
+
+    pub fn var_name(&self, v: Var) -> &str {
+        &self.var_names[v.0 as usize]
+    }
+
+    pub fn kernel(&self, name: &str) -> Option<&Kernel> {
+        self.kernels.iter().find(|k| k.name == name)
+    }
+}
+
+// ---------- Printing ----------
+
+impl fmt::Display for Local {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a{}", self.0)
+    }
+}
+
+/// Printing needs buffer and loop names, so expressions and statements
+/// print through their program.
+struct Named<'a, T> {
+    program: &'a Program,
+    item: &'a T,
+}
+
+fn named<'a>(program: &'a Program, item: &'a Expr) -> Named<'a, Expr> {
+    Named { program, item }
+}
+
+impl Program {
+    fn affine<'a>(&'a self, item: &'a Affine) -> Named<'a, Affine> {
+        Named {
+            program: self,
+            item,
+        }
+    }
+}
+
+impl fmt::Display for Named<'_, Affine> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Largest strides first, as in a row-major index
+        let mut terms = self.item.terms.clone();
+        terms.sort_by_key(|&(_, k)| std::cmp::Reverse(k.abs()));
+        let mut first = true;
+        for (v, k) in terms {
+            let sign = match (first, k < 0) {
+                (true, false) => "",
+                (true, true) => "-",
+                (false, false) => " + ",
+                (false, true) => " - ",
+            };
+            match k.abs() {
+                1 => write!(f, "{}{}", sign, self.program.var_name(v))?,
+                k => write!(f, "{}{}*{}", sign, k, self.program.var_name(v))?,
+            }
+            first = false;
+        }
+        match (first, self.item.constant) {
+            (true, c) => write!(f, "{}", c),
+            (false, 0) => Ok(()),
+            (false, c) if c < 0 => write!(f, " - {}", -c),
+            (false, c) => write!(f, " + {}", c),
+        }
+    }
+}
+
+impl fmt::Display for Named<'_, Expr> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.item {
+            Expr::Const(c) => write!(f, "{:?}", c),
+            Expr::Local(l) => write!(f, "{}", l),
+            Expr::Load(b, index) => {
+                let name = &self.program.buffer(*b).name;
+                write!(f, "{}[{}]", name, self.program.affine(index))
+            }
+            Expr::Unary(UnaryOp::Sqrt, x) => write!(f, "sqrt({})", named(self.program, x)),
+            Expr::Binary(op, a, b) => match op.symbol() {
+                Some(symbol) => {
+                    let operand = |x: &Expr| match x {
+                        Expr::Binary(op, ..) if op.symbol().is_some() => {
+                            format!("({})", named(self.program, x))
+                        }
+                        _ => named(self.program, x).to_string(),
+                    };
+                    write!(f, "{} {} {}", operand(a), symbol, operand(b))
+                }
+                None => {
+                    let name = if *op == BinaryOp::Max { "max" } else { "min" };
+                    write!(
+                        f,
+                        "{}({}, {})",
+                        name,
+                        named(self.program, a),
+                        named(self.program, b)
+                    )
+                }
+            },
+        }
+    }
+}
+
+impl Program {
+    fn fmt_stmts(&self, f: &mut fmt::Formatter<'_>, stmts: &[Stmt], depth: usize) -> fmt::Result {
+        let pad = "  ".repeat(depth);
+        let named = |x| named(self, x);
+        for stmt in stmts {
+            match stmt {
+                Stmt::For(l) => {
+                    let kind = match l.kind {
+                        LoopKind::Serial => "",
+                        LoopKind::Parallel => "parallel ",
+                        LoopKind::Vectorized => "vectorized ",
+                        LoopKind::Unrolled => "unrolled ",
+                    };
+                    let var = self.var_name(l.var);
+                    writeln!(f, "{}{}for {} in 0..{} {{", pad, kind, var, l.extent)?;
+                    self.fmt_stmts(f, &l.body, depth + 1)?;
+                    writeln!(f, "{}}}", pad)?;
+                }
+                Stmt::If { bounds, body } => {
+                    let conds: Vec<String> = bounds
+                        .iter()
+                        .map(|b| format!("0 <= {} < {}", self.affine(&b.index), b.extent))
+                        .collect();
+                    writeln!(f, "{}if {} {{", pad, conds.join(" && "))?;
+                    self.fmt_stmts(f, body, depth + 1)?;
+                    writeln!(f, "{}}}", pad)?;
+                }
+                Stmt::Assign { local, value } => {
+                    writeln!(f, "{}{} = {}", pad, local, named(value))?;
+                }
+                Stmt::Store {
+                    buffer,
+                    index,
+                    value,
+                } => {
+                    let name = &self.buffer(*buffer).name;
+                    let index = self.affine(index);
+                    writeln!(f, "{}{}[{}] = {}", pad, name, index, named(value))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.buffers {
+            let kind = match b.kind {
+                BufferKind::Input => "input",
+                BufferKind::Constant(_) => "const",
+                BufferKind::Temp => "temp",
+            };
+            writeln!(f, "{} {}: f32{:?}", kind, b.name, b.shape)?;
+        }
+        let outputs: Vec<&str> = self
+            .outputs
+            .iter()
+            .map(|&(_, b)| self.buffer(b).name.as_str())
+            .collect();
+        writeln!(f, "outputs {}", outputs.join(", "))?;
+        for k in &self.kernels {
+            writeln!(f, "kernel {} {{", k.name)?;
+            self.fmt_stmts(f, &k.body, 1)?;
+            writeln!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::test_util::{desc, node};
+    use crate::{Graph, OpKind};
+
+    #[test]
+    fn prints_program() {
+        let graph = Graph {
+            nodes: vec![node(OpKind::Input, &[], 0), node(OpKind::Relu, &[0], 1)],
+            outputs: vec![ValueId(1)],
+            value_types: HashMap::new(),
+        };
+        let program = lower(&graph, &HashMap::from([(ValueId(0), desc(vec![3, 4]))])).unwrap();
+        assert_eq!(
+            program.to_string(),
+            "input v0: f32[3, 4]\n\
+             temp v1: f32[3, 4]\n\
+             outputs v1\n\
+             kernel Relu_1 {\n\
+             \x20 for i in 0..12 {\n\
+             \x20   v1[i] = max(v0[i], 0.0)\n\
+             \x20 }\n\
+             }\n"
+        );
+
+        let i = Affine::from(Var(0));
+        let index = i.clone() * -4 + i * 2 + -3;
+        assert_eq!(program.affine(&index).to_string(), "-2*i - 3");
+        assert_eq!(index.eval(&[2]), -7);
+    }
+}
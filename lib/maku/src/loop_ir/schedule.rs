@@ -0,0 +1,535 @@
+//! Halide-style schedule transforms on the named loops of one kernel.
+//!
+//! Every transform keeps the kernel's results, up to float reassociation
+//! of reductions. A lowered kernel stores each element of its output once
+//! and reads only other buffers, which splits and reorders preserve, so
+//! only the locals carry values between iterations:
+//!
+//! - `reorder` needs the loops perfectly nested, apart from bound checks,
+//!   so that locals are set and read at the same place.
+//! - `parallelize` and `vectorize` need every iteration to assign each
+//!   local before reading it.
+
+use std::collections::HashSet;
+
+use super::{Affine, Bound, Expr, Local, Loop, LoopKind, Program, Stmt, Var};
+use crate::error::{Result, bail, ensure, err};
+
+/// Transforms of one kernel's loops, from [`Program::schedule`]. Methods
+/// chain: `s.tile("oh", "ow", 4, 4)?.parallelize("oh_o")?`.
+pub struct Schedule<'a> {
+    program: &'a mut Program,
+    kernel: usize,
+}
+
+impl Program {
+    /// Schedule the loops of the kernel named `kernel`.
+    pub fn schedule(&mut self, kernel: &str) -> Result<Schedule<'_>> {
+        let index = self
+            .kernels
+            .iter()
+            .position(|k| k.name == kernel)
+            .ok_or_else(|| err!(InvalidAttr, "no kernel named {}", kernel))?;
+        Ok(Schedule {
+            program: self,
+            kernel: index,
+        })
+    }
+}
+
+impl Loop {
+    /// A local that iterations read before assigning it, so that it carries
+    /// a value from one iteration to the next.
+    pub fn carried_local(&self) -> Option<Local> {
+        carried_local(&self.body, &mut HashSet::new())
+    }
+}
+
+impl Schedule<'_> {
+    /// Split `name` into `<name>_o` and `<name>_i` with `name = factor *
+    /// <name>_o + <name>_i`; a factor that does not divide the extent adds
+    /// a bound check.
+    pub fn split(&mut self, name: &str, factor: usize) -> Result<&mut Self> {
+        self.split_loop(name, factor)?;
+        Ok(self)
+    }
+
+    /// Split `x` and `y` (with `y` directly inside `x`) and order the loops
+    /// `x_o, y_o, x_i, y_i`.
+    pub fn tile(
+        &mut self,
+        x: &str,
+        y: &str,
+        x_factor: usize,
+        y_factor: usize,
+    ) -> Result<&mut Self> {
+        let (x_o, x_i) = self.split_loop(x, x_factor)?;
+        let (y_o, y_i) = self.split_loop(y, y_factor)?;
+        self.reorder(&[&x_o, &y_o, &x_i, &y_i])
+    }
+
+    /// Nest the named loops in `order`, outermost first. Loops between them
+    /// that are not named keep their position.
+    pub fn reorder(&mut self, order: &[&str]) -> Result<&mut Self> {
+        let vars = order
+            .iter()
+            .map(|name| self.var(name))
+            .collect::<Result<Vec<Var>>>()?;
+        let unique: HashSet<Var> = vars.iter().copied().collect();
+        ensure!(
+            unique.len() == vars.len(),
+            InvalidAttr,
+            "reorder lists a loop twice: {:?}",
+            order
+        );
+        let Some(top) = self.loops().into_iter().find(|l| unique.contains(&l.var)) else {
+            return Ok(self);
+        };
+        let top_var = top.var;
+        let reordered = self.reordered(top, &vars)?;
+        *self.loop_mut(top_var)? = reordered;
+        Ok(self)
+    }
+
+    /// Mark `name` to run on SIMD lanes, split by `width` first unless
+    /// that is its extent. The loop must be innermost.
+    pub fn vectorize(&mut self, name: &str, width: usize) -> Result<&mut Self> {
+        let l = self.find(name)?;
+        ensure!(
+            !l.body.iter().any(contains_loop),
+            UnsupportedOp,
+            "cannot vectorize {}: it is not an innermost loop",
+            name
+        );
+        self.check_independent(l, "vectorize")?;
+        self.mark(name, width, LoopKind::Vectorized)
+    }
+
+    /// Mark `name` to be replicated, split by `factor` first unless that
+    /// is its extent.
+    pub fn unroll(&mut self, name: &str, factor: usize) -> Result<&mut Self> {
+        self.mark(name, factor, LoopKind::Unrolled)
+    }
+
+    /// Mark `name` to run its iterations concurrently.
+    pub fn parallelize(&mut self, name: &str) -> Result<&mut Self> {
+        let l = self.find(name)?;
+        self.check_independent(l, "parallelize")?;
+        let var = l.var;
+        self.loop_mut(var)?.kind = LoopKind::Parallel;
+        Ok(self)
+    }
+
+    // ---------- Helpers ----------
+
+    fn body(&self) -> &[Stmt] {
+        &self.program.kernels[self.kernel].body
+    }
+
+    fn loops(&self) -> Vec<&Loop> {
+        let mut loops = Vec::new();
+        collect_loops(self.body(), &mut loops);
+        loops
+    }
+
+    fn find(&self, name: &str) -> Result<&Loop> {
+        self.loops()
+            .into_iter()
+            .find(|l| self.program.var_name(l.var) == name)
+            .ok_or_else(|| {
+                let kernel = &self.program.kernels[self.kernel].name;
+                err!(InvalidAttr, "kernel {} has no loop named {}", kernel, name)
+            })
+    }
+
+    fn var(&self, name: &str) -> Result<Var> {
+        self.find(name).map(|l| l.var)
+    }
+
+    fn loop_mut(&mut self, var: Var) -> Result<&mut Loop> {
+        find_mut(&mut self.program.kernels[self.kernel].body, var)
+            .ok_or_else(|| err!(Internal, "loop {:?} vanished", var))
+    }
+
+    /// A new loop variable, named uniquely within the kernel.
+    fn fresh_var(&mut self, name: String) -> Result<Var> {
+        ensure!(
+            self.find(&name).is_err(),
+            InvalidAttr,
+            "kernel {} already has a loop named {}",
+            self.program.kernels[self.kernel].name,
+            name
+        );
+        self.program.var_names.push(name);
+        Ok(Var(self.program.var_names.len() as u32 - 1))
+    }
+
+    /// [`Schedule::split`], returning the new loop names.
+    fn split_loop(&mut self, name: &str, factor: usize) -> Result<(String, String)> {
+        ensure!(factor > 0, InvalidAttr, "cannot split {} by 0", name);
+        let l = self.find(name)?;
+        ensure!(
+            l.kind == LoopKind::Serial,
+            UnsupportedOp,
+            "cannot split {}: it is already {:?}",
+            name,
+            l.kind
+        );
+        let (var, extent) = (l.var, l.extent);
+        let factor = factor.min(extent.max(1));
+        let (outer, inner) = (format!("{}_o", name), format!("{}_i", name));
+        let vo = self.fresh_var(outer.clone())?;
+        let vi = self.fresh_var(inner.clone())?;
+
+        let l = self.loop_mut(var)?;
+        let index = Affine::from(vo) * factor as i64 + Affine::from(vi);
+        let mut body = std::mem::take(&mut l.body);
+        substitute(&mut body, var, &index);
+        if !extent.is_multiple_of(factor) {
+            body = vec![Stmt::If {
+                bounds: vec![Bound { index, extent }],
+                body,
+            }];
+        }
+        l.var = vo;
+        l.extent = extent.div_ceil(factor);
+        l.body = vec![Stmt::For(Loop {
+            var: vi,
+            extent: factor,
+            kind: LoopKind::Serial,
+            body,
+        })];
+        Ok((outer, inner))
+    }
+
+    /// Mark `name` as `kind`, splitting off an inner loop of `factor`
+    /// iterations unless that is the whole loop.
+    fn mark(&mut self, name: &str, factor: usize, kind: LoopKind) -> Result<&mut Self> {
+        ensure!(
+            factor > 0,
+            InvalidAttr,
+            "{:?} factor of {} must be positive",
+            kind,
+            name
+        );
+        let l = self.find(name)?;
+        let name = if factor >= l.extent {
+            name.to_string()
+        } else {
+            self.split_loop(name, factor)?.1
+        };
+        let var = self.var(&name)?;
+        self.loop_mut(var)?.kind = kind;
+        Ok(self)
+    }
+
+    /// `top` with the loops of `vars` permuted into that order, or an
+    /// error if the permutation may change results.
+    fn reordered(&self, top: &Loop, vars: &[Var]) -> Result<Loop> {
+        let name = |v: Var| self.program.var_name(v).to_string();
+        let mut chain: Vec<&Loop> = Vec::new();
+        let mut guards: Vec<Bound> = Vec::new();
+        let mut l = top;
+        let mut inner: &[Stmt];
+        let mut found = 0;
+        // Peel loops and bound checks until every named loop is found
+        loop {
+            chain.push(l);
+            if vars.contains(&l.var) {
+                found += 1;
+            }
+            inner = &l.body;
+            while let [Stmt::If { bounds, body }] = inner {
+                guards.extend(bounds.iter().cloned());
+                inner = body;
+            }
+            if found == vars.len() {
+                break;
+            }
+            match inner {
+                [Stmt::For(next)] => l = next,
+                _ => bail!(
+                    UnsupportedOp,
+                    "cannot reorder {}: the body of {} is not a single loop",
+                    vars.iter().map(|&v| name(v)).collect::<Vec<_>>().join(", "),
+                    name(l.var)
+                ),
+            }
+        }
+
+        // Named loops take the chain positions of named loops in order;
+        // bound checks move innermost, where every variable is bound
+        let mut order = vars.iter();
+        let headers: Vec<&Loop> = chain
+            .iter()
+            .map(|&l| {
+                if vars.contains(&l.var) {
+                    let v = order.next().expect("every named loop is in the chain");
+                    *chain.iter().find(|c| c.var == *v).expect("chain loop")
+                } else {
+                    l
+                }
+            })
+            .collect();
+        let moved_vector = headers[..headers.len() - 1]
+            .iter()
+            .find(|l| l.kind == LoopKind::Vectorized);
+        if let Some(l) = moved_vector {
+            bail!(
+                UnsupportedOp,
+                "cannot reorder {}: a vectorized loop must stay innermost",
+                name(l.var)
+            );
+        }
+        let mut body = inner.to_vec();
+        if !guards.is_empty() {
+            body = vec![Stmt::If {
+                bounds: guards,
+                body,
+            }];
+        }
+        for l in headers.iter().rev() {
+            body = vec![Stmt::For(Loop {
+                var: l.var,
+                extent: l.extent,
+                kind: l.kind,
+                body,
+            })];
+        }
+        match body.pop() {
+            Some(Stmt::For(l)) => Ok(l),
+            _ => Err(err!(Internal, "reorder produced no loop")),
+        }
+    }
+
+    /// Iterations of `l` must not read locals set by an earlier iteration.
+    fn check_independent(&self, l: &Loop, action: &str) -> Result<()> {
+        match l.carried_local() {
+            Some(local) => bail!(
+                UnsupportedOp,
+                "cannot {} {}: iterations share local a{}",
+                action,
+                self.program.var_name(l.var),
+                local.0
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+fn contains_loop(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::For(_) => true,
+        Stmt::If { body, .. } => body.iter().any(contains_loop),
+        Stmt::Assign { .. } | Stmt::Store { .. } => false,
+    }
+}
+
+fn collect_loops<'a>(stmts: &'a [Stmt], out: &mut Vec<&'a Loop>) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::For(l) => {
+                out.push(l);
+                collect_loops(&l.body, out);
+            }
+            Stmt::If { body, .. } => collect_loops(body, out),
+            Stmt::Assign { .. } | Stmt::Store { .. } => {}
+        }
+    }
+}
+
+fn find_mut(stmts: &mut [Stmt], var: Var) -> Option<&mut Loop> {
+    stmts.iter_mut().find_map(|stmt| match stmt {
+        Stmt::For(l) => match l.var == var {
+            true => Some(l),
+            false => find_mut(&mut l.body, var),
+        },
+        Stmt::If { body, .. } => find_mut(body, var),
+        Stmt::Assign { .. } | Stmt::Store { .. } => None,
+    })
+}
+
+/// A local read before this iteration assigns it. Assignments inside
+/// loops and bound checks may not run, so they only count within them.
+fn carried_local(stmts: &[Stmt], assigned: &mut HashSet<Local>) -> Option<Local> {
+    for stmt in stmts {
+        match stmt {
+            Stmt::For(Loop { body, .. }) | Stmt::If { body, .. } => {
+                if let Some(l) = carried_local(body, &mut assigned.clone()) {
+                    return Some(l);
+                }
+            }
+            Stmt::Assign { local, value } => {
+                if let Some(l) = unassigned_read(value, assigned) {
+                    return Some(l);
+                }
+                assigned.insert(*local);
+            }
+            Stmt::Store { value, .. } => {
+                if let Some(l) = unassigned_read(value, assigned) {
+                    return Some(l);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn unassigned_read(expr: &Expr, assigned: &HashSet<Local>) -> Option<Local> {
+    match expr {
+        Expr::Local(l) if !assigned.contains(l) => Some(*l),
+        Expr::Const(_) | Expr::Local(_) | Expr::Load(..) => None,
+        Expr::Unary(_, x) => unassigned_read(x, assigned),
+        Expr::Binary(_, a, b) => {
+            unassigned_read(a, assigned).or_else(|| unassigned_read(b, assigned))
+        }
+    }
+}
+
+/// Replace `var` by `with` in every index below `stmts`.
+fn substitute(stmts: &mut [Stmt], var: Var, with: &Affine) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::For(Loop { body, .. }) => substitute(body, var, with),
+            Stmt::If { bounds, body } => {
+                for b in bounds {
+                    substitute_affine(&mut b.index, var, with);
+                }
+                substitute(body, var, with);
+            }
+            Stmt::Assign { value, .. } => substitute_expr(value, var, with),
+            Stmt::Store { index, value, .. } => {
+                substitute_affine(index, var, with);
+                substitute_expr(value, var, with);
+            }
+        }
+    }
+}
+
+fn substitute_expr(expr: &mut Expr, var: Var, with: &Affine) {
+    match expr {
+        Expr::Const(_) | Expr::Local(_) => {}
+        Expr::Load(_, index) => substitute_affine(index, var, with),
+        Expr::Unary(_, x) => substitute_expr(x, var, with),
+        Expr::Binary(_, a, b) => {
+            substitute_expr(a, var, with);
+            substitute_expr(b, var, with);
+        }
+    }
+}
+
+fn substitute_affine(index: &mut Affine, var: Var, with: &Affine) {
+    if let Some(pos) = index.terms.iter().position(|&(v, _)| v == var) {
+        let (_, k) = index.terms.remove(pos);
+        *index = std::mem::take(index) + with.clone() * k;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::loop_ir::lower;
+    use crate::test_util::node;
+    use crate::{Activation, Conv2DAttrs, Graph, OpKind, Tensor, ValueId};
+
+    /// Conv2D (node 2) into Relu (node 3) on a 1x5x5x3 input
+    fn conv_relu() -> (Program, HashMap<ValueId, Tensor>) {
+        let kernel = Tensor::from_fn(vec![3, 3, 3, 4], |i| {
+            ((i[0] * 7 + i[1] * 5 + i[2] * 3 + i[3]) % 11) as f32 / 5.0 - 1.0
+        });
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Constant(kernel.into()), &[], 1),
+                node(
+                    OpKind::Conv2D(Conv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [1, 1],
+                        pads: [1, 1, 1, 1],
+                        dilations: [1, 1],
+                        group: 1,
+                        activation: Some(Activation::Relu6),
+                    }),
+                    &[0, 1],
+                    2,
+                ),
+                node(OpKind::Relu, &[2], 3),
+            ],
+            outputs: vec![ValueId(3)],
+            value_types: HashMap::new(),
+        };
+        let x = Tensor::from_fn(vec![1, 5, 5, 3], |i| {
+            (i[1] * 15 + i[2] * 3 + i[3]) as f32 / 20.0 - 1.5
+        });
+        let types = HashMap::from([(ValueId(0), x.desc.clone())]);
+        let program = lower(&graph, &types).unwrap();
+        (program, HashMap::from([(ValueId(0), x)]))
+    }
+
+    #[test]
+    fn transforms_keep_results() {
+        let (mut program, inputs) = conv_relu();
+        let expected = program.run(&inputs).unwrap();
+
+        program
+            .schedule("Conv2D_2")
+            .unwrap()
+            .tile("oh", "ow", 2, 4)
+            .unwrap()
+            .reorder(&["ic", "kh", "kw"])
+            .unwrap()
+            .unroll("kw", 3)
+            .unwrap()
+            .parallelize("oh_o")
+            .unwrap();
+        program
+            .schedule("Relu_3")
+            .unwrap()
+            .vectorize("i", 8)
+            .unwrap();
+
+        let text = program.to_string();
+        for line in [
+            "parallel for oh_o in 0..3 {",
+            "for ow_i in 0..4 {",
+            "if 0 <= 2*oh_o + oh_i < 5 && 0 <= 4*ow_o + ow_i < 5 {",
+            "for ic in 0..3 {",
+            "unrolled for kw in 0..3 {",
+            "if 0 <= 2*oh_o + kh + oh_i - 1 < 5 && 0 <= 4*ow_o + kw + ow_i - 1 < 5 {",
+            "vectorized for i_i in 0..8 {",
+            "v3[8*i_o + i_i] = max(v2[8*i_o + i_i], 0.0)",
+        ] {
+            assert!(text.contains(line), "missing {:?} in\n{}", line, text);
+        }
+        let actual = program.run(&inputs).unwrap();
+        assert!(actual[&ValueId(3)].approx_eq(&expected[&ValueId(3)], 1e-5));
+    }
+
+    #[test]
+    fn rejects_illegal_transforms() {
+        let (mut program, _) = conv_relu();
+        let mut s = program.schedule("Conv2D_2").unwrap();
+        let kind = |r: Result<&mut Schedule>| r.err().map(|e| e.kind());
+
+        assert_eq!(kind(s.split("nope", 2)), Some("InvalidAttr"));
+        assert_eq!(kind(s.split("oh", 0)), Some("InvalidAttr"));
+        // ic accumulates into a local across iterations
+        assert_eq!(kind(s.vectorize("ic", 3)), Some("UnsupportedOp"));
+        assert_eq!(kind(s.parallelize("kh")), Some("UnsupportedOp"));
+        assert_eq!(kind(s.vectorize("oc", 4)), Some("UnsupportedOp"));
+        // The accumulator is set between oc and kh
+        assert_eq!(kind(s.reorder(&["kh", "oc"])), Some("UnsupportedOp"));
+        assert_eq!(kind(s.reorder(&["ow", "ow"])), Some("InvalidAttr"));
+
+        assert!(s.parallelize("oc").is_ok());
+        assert!(s.reorder(&["ow", "oh"]).is_ok());
+        assert_eq!(kind(s.split("oc", 2)), Some("UnsupportedOp"));
+        assert!(program.schedule("Conv2D_9").is_err());
+
+        let mut s = program.schedule("Relu_3").unwrap();
+        s.split("i", 4).unwrap().vectorize("i_i", 4).unwrap();
+        assert_eq!(kind(s.reorder(&["i_i", "i_o"])), Some("UnsupportedOp"));
+    }
+}
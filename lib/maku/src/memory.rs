@@ -0,0 +1,345 @@
+//! Static memory planning.
+//!
+//! Liveness analysis computes, from the node order, where each intermediate
+//! value is produced and last read. The planner then packs those values into
+//! a single arena, handing a dead value's space to later ones (best-fit).
+//! Caller inputs and constants live outside the arena.
+//!
+//! Elementwise nodes whose input dies at that node write their output over
+//! the input instead of allocating (see [`in_place_inputs`]).
+
+use std::collections::HashMap;
+
+use crate::error::{Result, err};
+use crate::{Graph, OpKind, TensorDesc, ValueId};
+
+/// Node indices (into `graph.nodes`) where a value is produced and last read.
+///
+/// Graph outputs have `last_use == graph.nodes.len()`, i.e. they outlive
+/// every node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lifetime {
+    pub def: usize,
+    pub last_use: usize,
+}
+
+/// Placement of one value in the arena, in f32 elements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub offset: usize,
+    pub len: usize,
+    pub lifetime: Lifetime,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPlan {
+    /// Arena placement of every intermediate value
+    pub buffers: HashMap<ValueId, Buffer>,
+    /// Arena size in f32 elements
+    pub arena_len: usize,
+    /// Largest total size of values live at the same time (lower bound for `arena_len`)
+    pub peak_live_len: usize,
+    /// Total size if every value had its own buffer
+    pub naive_len: usize,
+    /// Outputs written in place over a dead input (output -> input); both
+    /// share one buffer
+    pub aliases: HashMap<ValueId, ValueId>,
+}
+
+impl MemoryPlan {
+    /// Peak memory of the planned arena in bytes.
+    pub fn peak_bytes(&self) -> usize {
+        self.arena_len * size_of::<f32>()
+    }
+
+    /// Memory in bytes without buffer reuse.
+    pub fn naive_bytes(&self) -> usize {
+        self.naive_len * size_of::<f32>()
+    }
+}
+
+/// Index of the last node reading each value. Graph outputs map to
+/// `graph.nodes.len()`; values nobody reads are absent.
+pub fn last_uses(graph: &Graph) -> HashMap<ValueId, usize> {
+    let mut last = HashMap::new();
+    for (i, node) in graph.nodes.iter().enumerate() {
+        for &id in &node.inputs {
+            last.insert(id, i);
+        }
+    }
+    for &id in &graph.outputs {
+        last.insert(id, graph.nodes.len());
+    }
+    last
+}
+
+/// Lifetime of every value computed by a node (not Input or Constant).
+/// Values nobody reads die at their definition.
+pub fn lifetimes(graph: &Graph) -> HashMap<ValueId, Lifetime> {
+    let last = last_uses(graph);
+    graph
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| !matches!(node.op, OpKind::Input | OpKind::Constant(_)))
+        .map(|(def, node)| {
+            let last_use = last.get(&node.output).copied().unwrap_or(def).max(def);
+            (node.output, Lifetime { def, last_use })
+        })
+        .collect()
+}
+
+/// Elementwise nodes that may overwrite one of their inputs: node index ->
+/// input slot.
+///
+/// The input must be computed by an earlier node (caller inputs and
+/// constants are never written), read for the last time by this node (so
+/// not a graph output), and appear only once among the node's inputs.
+pub fn in_place_inputs(graph: &Graph) -> HashMap<usize, usize> {
+    let lifetimes = lifetimes(graph);
+    let mut in_place = HashMap::new();
+    for (i, node) in graph.nodes.iter().enumerate() {
+        if !node.op.is_elementwise() {
+            continue;
+        }
+        let slot = node.inputs.iter().position(|id| {
+            let dies_here = lifetimes
+                .get(id)
+                .is_some_and(|lt| lt.def < i && lt.last_use == i);
+            dies_here && node.inputs.iter().filter(|&other| other == id).count() == 1
+        });
+        if let Some(slot) = slot {
+            in_place.insert(i, slot);
+        }
+    }
+    in_place
+}
+
+/// Assign every intermediate value an arena offset, reusing space of values
+/// that are dead. `types` must describe every computed value (see
+/// [`crate::shape::infer_shapes`]).
+pub fn plan_memory(graph: &Graph, types: &HashMap<ValueId, TensorDesc>) -> Result<MemoryPlan> {
+    let lifetimes = lifetimes(graph);
+    let in_place = in_place_inputs(graph);
+
+    // Values to release after each node
+    let mut dies_at: Vec<Vec<ValueId>> = vec![Vec::new(); graph.nodes.len()];
+    for (&id, lt) in &lifetimes {
+        if lt.last_use < graph.nodes.len() {
+            dies_at[lt.last_use].push(id);
+        }
+    }
+
+    let mut plan = MemoryPlan::default();
+    let mut arena = Arena::default();
+    for (i, node) in graph.nodes.iter().enumerate() {
+        if let Some(&lifetime) = lifetimes.get(&node.output) {
+            let desc = types
+                .get(&node.output)
+                .ok_or_else(|| err!(Internal, "no type for {:?}", node.output))?;
+            let len = desc.shape.iter().product();
+            let offset = match in_place.get(&i) {
+                // Hand the dying input's buffer over to the output
+                Some(&slot) => {
+                    let input = node.inputs[slot];
+                    plan.aliases.insert(node.output, input);
+                    arena
+                        .rename(input, node.output)
+                        .ok_or_else(|| err!(Internal, "{:?} is not in the arena", input))?
+                }
+                // Otherwise the output is allocated while the inputs are
+                // still live, so a node never writes over what it reads
+                None => arena.alloc(node.output, len),
+            };
+            plan.buffers.insert(
+                node.output,
+                Buffer {
+                    offset,
+                    len,
+                    lifetime,
+                },
+            );
+            plan.naive_len += len;
+            plan.peak_live_len = plan.peak_live_len.max(arena.live_len());
+        }
+        for id in &dies_at[i] {
+            arena.free(*id);
+        }
+    }
+
+    plan.arena_len = arena.len;
+    Ok(plan)
+}
+
+/// Live intervals of the arena, kept sorted by offset
+#[derive(Default)]
+struct Arena {
+    live: Vec<(usize, usize, ValueId)>,
+    len: usize,
+}
+
+impl Arena {
+    /// Best-fit: the smallest gap between live buffers that holds `len`,
+    /// otherwise the end of the arena.
+    fn alloc(&mut self, id: ValueId, len: usize) -> usize {
+        let mut best: Option<(usize, usize)> = None;
+        let mut prev_end = 0;
+        for &(offset, size, _) in &self.live {
+            let gap = offset - prev_end;
+            if gap >= len && best.is_none_or(|(_, g)| gap < g) {
+                best = Some((prev_end, gap));
+            }
+            prev_end = prev_end.max(offset + size);
+        }
+        let offset = best.map_or(prev_end, |(offset, _)| offset);
+
+        let at = self.live.partition_point(|&(o, _, _)| o <= offset);
+        self.live.insert(at, (offset, len, id));
+        self.len = self.len.max(offset + len);
+        offset
+    }
+
+    /// Transfer `from`'s interval to `to`, returning its offset.
+    fn rename(&mut self, from: ValueId, to: ValueId) -> Option<usize> {
+        let entry = self.live.iter_mut().find(|(_, _, v)| *v == from)?;
+        entry.2 = to;
+        Some(entry.0)
+    }
+
+    fn free(&mut self, id: ValueId) {
+        self.live.retain(|&(_, _, v)| v != id);
+    }
+
+    fn live_len(&self) -> usize {
+        self.live.iter().map(|&(_, len, _)| len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DType;
+    use crate::shape::infer_shapes;
+    use crate::test_util::node;
+
+    fn plan(graph: &Graph, input_shape: Vec<usize>) -> MemoryPlan {
+        let inputs = HashMap::from([(
+            ValueId(0),
+            TensorDesc {
+                dtype: DType::F32,
+                shape: input_shape,
+            },
+        )]);
+        plan_memory(graph, &infer_shapes(graph, &inputs).unwrap()).unwrap()
+    }
+
+    /// Intervals of buffers live at the same time never overlap, except an
+    /// in-place output and the input it overwrites.
+    fn assert_no_conflicts(plan: &MemoryPlan) {
+        let buffers: Vec<_> = plan.buffers.iter().collect();
+        for (i, (a_id, a)) in buffers.iter().enumerate() {
+            for (b_id, b) in &buffers[i + 1..] {
+                if plan.aliases.get(a_id) == Some(b_id) || plan.aliases.get(b_id) == Some(a_id) {
+                    continue;
+                }
+                let live_together =
+                    a.lifetime.def <= b.lifetime.last_use && b.lifetime.def <= a.lifetime.last_use;
+                let overlap = a.offset < b.offset + b.len && b.offset < a.offset + a.len;
+                assert!(
+                    !(live_together && overlap),
+                    "{:?} {:?} and {:?} {:?} collide",
+                    a_id,
+                    a,
+                    b_id,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn chain_runs_in_one_buffer() {
+        // x -> Relu -> Relu6 -> HardSwish -> Relu -> y
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Relu, &[0], 1),
+                node(OpKind::Relu6, &[1], 2),
+                node(OpKind::HardSwish, &[2], 3),
+                node(OpKind::Relu, &[3], 4),
+            ],
+            outputs: vec![ValueId(4)],
+            value_types: HashMap::new(),
+        };
+        let plan = plan(&graph, vec![1000]);
+
+        assert_eq!(plan.naive_len, 4000);
+        // Relu6, HardSwish and the last Relu overwrite their input
+        assert_eq!(plan.arena_len, 1000);
+        assert_eq!(plan.peak_bytes(), 4000);
+        assert_eq!(plan.aliases.len(), 3);
+        assert!(plan.buffers.values().all(|b| b.offset == 0));
+        assert_eq!(
+            plan.buffers[&ValueId(1)].lifetime,
+            Lifetime {
+                def: 1,
+                last_use: 2
+            }
+        );
+        assert_eq!(plan.buffers[&ValueId(4)].lifetime.last_use, 5);
+        assert_no_conflicts(&plan);
+    }
+
+    #[test]
+    fn branches_stay_live_until_joined() {
+        // a = Relu(x), b = Relu6(x), c = Relu(a), d = Add(b, c), e = Relu(d)
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Relu, &[0], 1),
+                node(OpKind::Relu6, &[0], 2),
+                node(OpKind::Relu, &[1], 3),
+                node(OpKind::Add, &[2, 3], 4),
+                node(OpKind::Relu, &[4], 5),
+                node(OpKind::HardSwish, &[5], 6),
+            ],
+            outputs: vec![ValueId(6), ValueId(2)],
+            value_types: HashMap::new(),
+        };
+        let plan = plan(&graph, vec![64]);
+
+        assert_no_conflicts(&plan);
+        assert!(plan.arena_len < plan.naive_len);
+        assert!(plan.arena_len >= plan.peak_live_len);
+        // b is a graph output and must survive to the end
+        assert_eq!(plan.buffers[&ValueId(2)].lifetime.last_use, 7);
+    }
+
+    #[test]
+    fn in_place_only_over_dead_computed_values() {
+        // a = Relu(x), b = Relu(a), c = Add(a, b), d = Mul(c, c), e = Relu6(d)
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Relu, &[0], 1),
+                node(OpKind::Relu, &[1], 2),
+                node(OpKind::Add, &[1, 2], 3),
+                node(OpKind::Mul, &[3, 3], 4),
+                node(OpKind::Relu6, &[4], 5),
+            ],
+            outputs: vec![ValueId(5)],
+            value_types: HashMap::new(),
+        };
+        // Relu(x): caller input; Relu(a): a is read later; Mul(c, c): c is
+        // read twice by the same node
+        assert_eq!(in_place_inputs(&graph), HashMap::from([(3, 0), (5, 0)]));
+
+        let plan = plan(&graph, vec![16]);
+        assert_no_conflicts(&plan);
+        assert_eq!(plan.aliases[&ValueId(3)], ValueId(1));
+        assert_eq!(
+            plan.buffers[&ValueId(3)].offset,
+            plan.buffers[&ValueId(1)].offset
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Intra-op parallelism for the CPU backend.
+//!
+//! Large kernels split their output into contiguous row tiles that run on a
+//! [`ThreadPool`]. Every output element is computed by the same code path no
+//! matter which tile it lands in, so results are bit-identical for any
+//! thread count.
+
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use crate::error::{Result, ensure, err};
+
+/// Kernels doing less work than this (roughly multiply-adds) stay on the calling thread.
+const MIN_PARALLEL_WORK: usize = 1 << 16;
+
+/// Tiles handed out per worker, so uneven rows still balance.
+const TILES_PER_THREAD: usize = 4;
+
+/// Row length used to tile elementwise kernels. A multiple of every SIMD
+/// width, so the vector body / scalar tail split matches the serial kernel.
+pub(crate) const ELEMENTWISE_ROW: usize = 256;
+
+/// Worker threads shared by all kernels of a `CpuBackend`
+pub struct ThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> Result<Self> {
+        ensure!(threads > 0, InvalidAttr, "thread count must be positive");
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("maku-worker-{}", i))
+            .build()
+            .map_err(|e| err!(Internal, "failed to start thread pool: {}", e))?;
+        Ok(ThreadPool { pool })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Run `f` inside the pool, so nested `for_each_rows` calls use its workers.
+    pub(crate) fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        self.pool.install(f)
+    }
+
+    /// Run `f` in a scope whose spawned tasks execute on the pool and all
+    /// finish before this returns.
+    pub(crate) fn scope<'s, R: Send>(&self, f: impl FnOnce(&rayon::Scope<'s>) -> R + Send) -> R {
+        self.pool.scope(f)
+    }
+}
+
+/// Split `out` into rows of `row_len` elements and call `f(rows, out_rows)`
+/// for contiguous row ranges, in parallel when `pool` is set and the kernel
+/// is large enough. The last row may be shorter than `row_len`.
+///
+/// `work_per_row` is a rough cost estimate used to skip tiny kernels.
+pub(crate) fn for_each_rows<F>(
+    pool: Option<&ThreadPool>,
+    out: &mut [f32],
+    row_len: usize,
+    work_per_row: usize,
+    f: F,
+) where
+    F: Fn(Range<usize>, &mut [f32]) + Sync,
+{
+    let rows = out.len().div_ceil(row_len.max(1));
+    let pool = match pool {
+        Some(pool) if rows > 1 && rows.saturating_mul(work_per_row) >= MIN_PARALLEL_WORK => pool,
+        _ => return f(0..rows, out),
+    };
+
+    let tiles = (pool.threads() * TILES_PER_THREAD).min(rows);
+    let rows_per_tile = rows.div_ceil(tiles);
+    pool.install(|| {
+        out.par_chunks_mut(rows_per_tile * row_len)
+            .enumerate()
+            .for_each(|(i, chunk)| {
+                let start = i * rows_per_tile;
+                f(start..start + chunk.len().div_ceil(row_len), chunk)
+            })
+    });
+}
@@ -0,0 +1,158 @@
+//! Fuse standalone activations into the Conv2D / DepthwiseConv2D / MatMul
+//! feeding them, so the activation is applied while the output is written
+//! instead of in a separate pass over memory.
+
+use std::collections::{HashMap, HashSet};
+
+use super::use_counts;
+use crate::{Activation, Graph, MatMulAttrs, NodeId, OpKind, Result, ValueId};
+
+/// Rewrites Conv2D / DepthwiseConv2D / MatMul -> Relu / Relu6 / HardSwish
+/// into the producer with an activation epilogue.
+///
+/// Applies when the activation is the only reader of the producer's output
+/// and the producer has no epilogue yet. The producer takes over the
+/// activation's output value.
+#[derive(Debug, Clone, Default)]
+pub struct ActivationFusion;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActivationFusionReport {
+    /// (producer node, removed activation node)
+    pub fused: Vec<(NodeId, NodeId)>,
+}
+
+impl ActivationFusion {
+    pub fn run(&self, graph: &mut Graph) -> Result<ActivationFusionReport> {
+        let uses = use_counts(graph);
+        let mut producers: HashMap<ValueId, usize> = HashMap::new();
+        let mut removed = HashSet::new();
+        let mut report = ActivationFusionReport::default();
+
+        for j in 0..graph.nodes.len() {
+            let act = &graph.nodes[j];
+            let fusable = Activation::from_op(&act.op).and_then(|activation| {
+                let &i = producers.get(act.inputs.first()?)?;
+                let single_use = uses.get(&graph.nodes[i].output) == Some(&1);
+                single_use.then_some((i, activation))
+            });
+            let Some((i, activation)) = fusable else {
+                producers.insert(graph.nodes[j].output, j);
+                continue;
+            };
+
+            let (act_id, act_output) = (act.id, act.output);
+            let slot = match &mut graph.nodes[i].op {
+                OpKind::Conv2D(attrs) => &mut attrs.activation,
+                OpKind::DepthwiseConv2D(attrs) => &mut attrs.activation,
+                OpKind::MatMul(attrs) => {
+                    let attrs = attrs.get_or_insert(MatMulAttrs {
+                        trans_a: false,
+                        trans_b: false,
+                        activation: None,
+                    });
+                    &mut attrs.activation
+                }
+                _ => {
+                    producers.insert(act_output, j);
+                    continue;
+                }
+            };
+            if slot.is_some() {
+                producers.insert(act_output, j);
+                continue;
+            }
+            *slot = Some(activation);
+
+            let producer = &mut graph.nodes[i];
+            graph.value_types.remove(&producer.output);
+            producer.output = act_output;
+            producers.insert(act_output, i);
+            removed.insert(act_id);
+            report.fused.push((producer.id, act_id));
+        }
+
+        graph.nodes.retain(|n| !removed.contains(&n.id));
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{constant, node, tensor};
+    use crate::{Conv2DAttrs, CpuBackend};
+
+    /// r = Relu(Relu6(Conv(x, w))); y = HardSwish(MatMul(Reshape(r), m));
+    /// z = Relu(MatMul(y, m2)) where the MatMul is also an output
+    fn graph() -> Graph {
+        let conv = Conv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [1, 1],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        };
+        let reshape = crate::ReshapeAttrs {
+            shape: vec![16, 8],
+            allowzero: false,
+        };
+        Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![3, 3, 4, 8], 1), &[], 1),
+                node(OpKind::Conv2D(conv), &[0, 1], 2),
+                node(OpKind::Relu6, &[2], 3),
+                node(OpKind::Relu, &[3], 4),
+                node(OpKind::Reshape(reshape), &[4], 5),
+                node(constant(vec![8, 5], 2), &[], 6),
+                node(OpKind::MatMul(None), &[5, 6], 7),
+                node(OpKind::HardSwish, &[7], 8),
+                node(constant(vec![5, 3], 3), &[], 9),
+                node(OpKind::MatMul(None), &[8, 9], 10),
+                node(OpKind::Relu, &[10], 11),
+            ],
+            outputs: vec![ValueId(11), ValueId(10)],
+            value_types: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn fuses_single_use_activations() {
+        let mut g = graph();
+        let report = ActivationFusion.run(&mut g).unwrap();
+
+        // Relu after the fused Relu6 and Relu after a MatMul that is also
+        // an output stay
+        assert_eq!(
+            report.fused,
+            vec![(NodeId(2), NodeId(3)), (NodeId(7), NodeId(8))]
+        );
+        assert_eq!(g.nodes.len(), 10);
+        let conv = &g.nodes[2];
+        assert_eq!(conv.output, ValueId(3));
+        assert!(matches!(
+            &conv.op,
+            OpKind::Conv2D(a) if a.activation == Some(Activation::Relu6)
+        ));
+
+        let x = tensor(vec![1, 4, 4, 4], 4);
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let cpu = CpuBackend::new();
+        let expected = cpu.run(&graph(), &inputs).unwrap();
+        let actual = cpu.run(&g, &inputs).unwrap();
+        for id in [ValueId(10), ValueId(11)] {
+            assert_eq!(actual[&id].data, expected[&id].data);
+        }
+
+        let mut session = CpuBackend::new()
+            .compile(
+                &g,
+                &HashMap::from([(ValueId(0), inputs[&ValueId(0)].desc.clone())]),
+            )
+            .unwrap();
+        let compiled = session.run(&inputs).unwrap();
+        assert_eq!(compiled[&ValueId(11)].data, expected[&ValueId(11)].data);
+    }
+}
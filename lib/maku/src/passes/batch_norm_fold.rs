@@ -0,0 +1,292 @@
+//! Fold inference BatchNorm into the convolution feeding it.
+//!
+//! BatchNorm is a per-channel `y = x * mul + add`, and the output channel
+//! is the last axis of both conv kernels (`[Kh, Kw, C_in / group, C_out]`,
+//! depthwise `[Kh, Kw, C, M]` with channel `c * M + m`), so
+//! `BN(conv(x, W, b)) == conv(x, W * mul, b * mul + add)`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{IdAlloc, use_counts};
+use crate::{Graph, Node, NodeId, OpKind, Result, Tensor, ValueId};
+
+/// Rewrites Conv2D / DepthwiseConv2D -> BatchNorm into one convolution with
+/// new kernel and bias constants.
+///
+/// Applies when the kernel, bias (if any) and BatchNorm parameters are
+/// constants, the conv has no fused activation and the BatchNorm is the
+/// only reader of the conv output. The
+/// fused conv takes over the BatchNorm's output value; the original kernel
+/// and bias constants are left for dead code elimination.
+#[derive(Debug, Clone, Default)]
+pub struct BatchNormFolding;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchNormFoldReport {
+    /// (conv node, removed BatchNorm node)
+    pub folded: Vec<(NodeId, NodeId)>,
+}
+
+/// New weights for a conv, keyed by its node index
+struct Rewrite {
+    bn: usize,
+    kernel: Tensor,
+    bias: Tensor,
+}
+
+impl BatchNormFolding {
+    pub fn run(&self, graph: &mut Graph) -> Result<BatchNormFoldReport> {
+        let constants: HashMap<ValueId, Arc<Tensor>> = graph
+            .nodes
+            .iter()
+            .filter_map(|n| match &n.op {
+                OpKind::Constant(t) => Some((n.output, Arc::clone(t))),
+                _ => None,
+            })
+            .collect();
+        let producers: HashMap<ValueId, usize> = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.output, i))
+            .collect();
+        let uses = use_counts(graph);
+
+        let mut rewrites: HashMap<usize, Rewrite> = HashMap::new();
+        for (j, bn) in graph.nodes.iter().enumerate() {
+            let OpKind::BatchNorm(attrs) = &bn.op else {
+                continue;
+            };
+            let Some(&i) = bn.inputs.first().and_then(|x| producers.get(x)) else {
+                continue;
+            };
+            let conv = &graph.nodes[i];
+            if uses.get(&conv.output) != Some(&1) || rewrites.contains_key(&i) {
+                continue;
+            }
+            // BN(act(conv)) can't be folded into the conv's weights
+            let activation = match &conv.op {
+                OpKind::Conv2D(a) => a.activation,
+                OpKind::DepthwiseConv2D(a) => a.activation,
+                _ => continue,
+            };
+            if activation.is_some() {
+                continue;
+            }
+            let Some(params) = constant_inputs(bn, 1..5, &constants) else {
+                continue;
+            };
+            let Some(weights) = constant_inputs(conv, 1..conv.inputs.len(), &constants) else {
+                continue;
+            };
+
+            let out_c = params[0].len();
+            let kernel = weights[0];
+            let bias_ok = weights.get(1).is_none_or(|b| b.len() == out_c);
+            let params_ok = params.iter().all(|p| p.shape() == [out_c]);
+            if out_c == 0 || !kernel.len().is_multiple_of(out_c) || !bias_ok || !params_ok {
+                continue;
+            }
+
+            let (mul, add) = crate::batch_norm_affine(
+                &params[0].data,
+                &params[1].data,
+                &params[2].data,
+                &params[3].data,
+                attrs.as_ref(),
+            );
+            let kernel = Tensor::new(
+                kernel.desc.clone(),
+                kernel
+                    .data
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| w * mul[k % out_c])
+                    .collect(),
+            );
+            let bias = (0..out_c)
+                .map(|c| weights.get(1).map_or(0.0, |b| b.data[c]) * mul[c] + add[c])
+                .collect();
+            let bias = Tensor::new(params[0].desc.clone(), bias);
+            rewrites.insert(
+                i,
+                Rewrite {
+                    bn: j,
+                    kernel,
+                    bias,
+                },
+            );
+        }
+
+        let mut report = BatchNormFoldReport::default();
+        if rewrites.is_empty() {
+            return Ok(report);
+        }
+
+        let removed: Vec<usize> = rewrites.values().map(|r| r.bn).collect();
+        let mut ids = IdAlloc::new(graph);
+        let old = std::mem::take(&mut graph.nodes);
+        for (i, mut node) in old.iter().cloned().enumerate() {
+            if removed.contains(&i) {
+                continue;
+            }
+            if let Some(rewrite) = rewrites.remove(&i) {
+                let bn = &old[rewrite.bn];
+                let mut inputs = vec![node.inputs[0]];
+                for t in [rewrite.kernel, rewrite.bias] {
+                    let value = ids.value();
+                    graph.value_types.insert(value, t.desc.clone());
+                    graph.nodes.push(Node {
+                        id: ids.node(),
+                        op: OpKind::Constant(t.into()),
+                        inputs: vec![],
+                        output: value,
+                    });
+                    inputs.push(value);
+                }
+                graph.value_types.remove(&node.output);
+                node.inputs = inputs;
+                node.output = bn.output;
+                report.folded.push((node.id, bn.id));
+            }
+            graph.nodes.push(node);
+        }
+        Ok(report)
+    }
+}
+
+/// The constant tensors read by `node.inputs[range]`, if all are constant.
+fn constant_inputs<'c>(
+    node: &Node,
+    range: std::ops::Range<usize>,
+    constants: &'c HashMap<ValueId, Arc<Tensor>>,
+) -> Option<Vec<&'c Tensor>> {
+    node.inputs
+        .get(range)?
+        .iter()
+        .map(|id| constants.get(id).map(|t| t.as_ref()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{constant, node};
+    use crate::{Activation, BatchNormAttrs, Conv2DAttrs, CpuBackend, DepthwiseConv2DAttrs};
+
+    /// Positive BatchNorm variance
+    fn variance(c: usize) -> OpKind {
+        OpKind::Constant(Tensor::from_fn(vec![c], |i| 0.5 + i[0] as f32 * 0.25).into())
+    }
+
+    fn batch_norm(inputs: &[u32], output: u32) -> Node {
+        let attrs = BatchNormAttrs {
+            epsilon: 1e-3,
+            momentum: 0.9,
+        };
+        node(OpKind::BatchNorm(Some(attrs)), inputs, output)
+    }
+
+    /// y = Relu6(BN(DepthwiseConv(BN(Conv(x, w, b)), dw)))
+    fn graph() -> Graph {
+        let conv = Conv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [1, 1],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        };
+        let depthwise = DepthwiseConv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [2, 2],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            depth_multiplier: 2,
+            activation: None,
+        };
+        Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(vec![3, 3, 3, 4], 1), &[], 1),
+                node(constant(vec![4], 2), &[], 2),
+                node(OpKind::Conv2D(conv), &[0, 1, 2], 3),
+                node(constant(vec![4], 3), &[], 4),
+                node(constant(vec![4], 4), &[], 5),
+                node(constant(vec![4], 5), &[], 6),
+                node(variance(4), &[], 7),
+                batch_norm(&[3, 4, 5, 6, 7], 8),
+                node(constant(vec![3, 3, 4, 2], 6), &[], 9),
+                node(OpKind::DepthwiseConv2D(depthwise), &[8, 9], 10),
+                node(constant(vec![8], 7), &[], 11),
+                node(constant(vec![8], 8), &[], 12),
+                node(constant(vec![8], 9), &[], 13),
+                node(variance(8), &[], 14),
+                batch_norm(&[10, 11, 12, 13, 14], 15),
+                node(OpKind::Relu6, &[15], 16),
+            ],
+            outputs: vec![ValueId(16)],
+            value_types: HashMap::new(),
+        }
+    }
+
+    fn run(graph: &Graph) -> Tensor {
+        let x = Tensor::from_fn(vec![1, 6, 6, 3], |i| (i[1] * 6 + i[2] + i[3]) as f32 * 0.1);
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let mut out = CpuBackend::new().run(graph, &inputs).unwrap();
+        out.remove(&ValueId(16)).unwrap()
+    }
+
+    #[test]
+    fn folds_into_conv_and_depthwise() {
+        let mut g = graph();
+        let report = BatchNormFolding.run(&mut g).unwrap();
+
+        assert_eq!(
+            report.folded,
+            vec![(NodeId(3), NodeId(8)), (NodeId(10), NodeId(15))]
+        );
+        assert!(!g.nodes.iter().any(|n| matches!(n.op, OpKind::BatchNorm(_))));
+        let conv = g.nodes.iter().find(|n| n.id == NodeId(3)).unwrap();
+        assert_eq!(conv.output, ValueId(8));
+        assert_eq!(conv.inputs.len(), 3);
+
+        let (expected, actual) = (run(&graph()), run(&g));
+        assert!(
+            actual.approx_eq(&expected, 1e-5),
+            "max diff {:?}",
+            actual.max_abs_diff(&expected)
+        );
+    }
+
+    #[test]
+    fn keeps_conv_output_read_elsewhere() {
+        let mut g = graph();
+        g.outputs.push(ValueId(3));
+        let report = BatchNormFolding.run(&mut g).unwrap();
+        assert_eq!(report.folded, vec![(NodeId(10), NodeId(15))]);
+        assert!(g.nodes.iter().any(|n| n.id == NodeId(8)));
+    }
+
+    #[test]
+    fn keeps_conv_with_fused_activation() {
+        let mut g = graph();
+        let OpKind::Conv2D(attrs) = &mut g.nodes[3].op else {
+            unreachable!()
+        };
+        attrs.activation = Some(Activation::Relu);
+        let original = g.clone();
+
+        let report = BatchNormFolding.run(&mut g).unwrap();
+        assert_eq!(report.folded, vec![(NodeId(10), NodeId(15))]);
+        assert!(g.nodes.iter().any(|n| n.id == NodeId(8)));
+
+        let (expected, actual) = (run(&original), run(&g));
+        assert!(
+            actual.approx_eq(&expected, 1e-5),
+            "max diff {:?}",
+            actual.max_abs_diff(&expected)
+        );
+    }
+}
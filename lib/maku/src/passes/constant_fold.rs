@@ -0,0 +1,167 @@
+//! Constant folding: evaluate nodes whose inputs are all constants once, at
+//! rewrite time, with the CPU kernels.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{
+    CpuBackend, Graph, NodeId, OpKind, Result, Tensor, TensorDesc, TensorView, ValueId, shape,
+};
+
+/// Replaces every node whose inputs are all constants with a Constant
+/// holding its result.
+///
+/// Folding cascades: a folded node counts as a constant for the nodes after
+/// it. Constants that only fed folded nodes are left in the graph for dead
+/// code elimination.
+#[derive(Debug, Clone)]
+pub struct ConstantFolding {
+    /// Results with more elements than this stay computed at run time
+    pub max_len: usize,
+}
+
+impl Default for ConstantFolding {
+    fn default() -> Self {
+        ConstantFolding { max_len: 1 << 20 }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FoldReport {
+    /// Nodes now holding a Constant
+    pub folded: Vec<NodeId>,
+    /// Foldable nodes left alone because the result exceeds `max_len`
+    pub too_large: Vec<NodeId>,
+}
+
+impl ConstantFolding {
+    pub fn run(&self, graph: &mut Graph) -> Result<FoldReport> {
+        let cpu = CpuBackend::new();
+        let mut report = FoldReport::default();
+        let mut constants: HashMap<ValueId, Arc<Tensor>> = HashMap::new();
+
+        for node in &mut graph.nodes {
+            match &node.op {
+                OpKind::Constant(t) => {
+                    constants.insert(node.output, Arc::clone(t));
+                    continue;
+                }
+                OpKind::Input => continue,
+                _ => {}
+            }
+            let args: Option<Vec<TensorView>> = node
+                .inputs
+                .iter()
+                .map(|id| constants.get(id).map(|t| t.view()))
+                .collect();
+            let Some(args) = args.filter(|a| !a.is_empty()) else {
+                continue;
+            };
+
+            // Check the size before computing anything
+            let descs: Vec<&TensorDesc> =
+                node.inputs.iter().map(|id| &constants[id].desc).collect();
+            let desc = shape::infer_node(node, &descs).map_err(|e| e.at(node))?;
+            let len = desc.shape.iter().try_fold(1usize, |n, &d| n.checked_mul(d));
+            if len.is_none_or(|len| len > self.max_len) {
+                report.too_large.push(node.id);
+                continue;
+            }
+
+            let refs: Vec<&TensorView> = args.iter().collect();
+            let result = cpu.eval_node(node, &refs).map_err(|e| e.at(node))?;
+
+            let tensor = Arc::new(result.to_tensor());
+            graph.value_types.insert(node.output, tensor.desc.clone());
+            constants.insert(node.output, Arc::clone(&tensor));
+            node.op = OpKind::Constant(tensor);
+            node.inputs.clear();
+            report.folded.push(node.id);
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransposeAttrs;
+    use crate::test_util::node;
+
+    fn constant(t: Tensor) -> OpKind {
+        OpKind::Constant(t.into())
+    }
+
+    /// y = Add(x, Relu(Transpose(Mul(a, b)))) with a, b constant
+    fn graph() -> Graph {
+        Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(
+                    constant(Tensor::arange(-3.0, 3.0, 1.0).reshape(vec![2, 3]).unwrap()),
+                    &[],
+                    1,
+                ),
+                node(constant(Tensor::full(vec![2, 3], 2.0)), &[], 2),
+                node(OpKind::Mul, &[1, 2], 3),
+                node(
+                    OpKind::Transpose(TransposeAttrs { perm: vec![1, 0] }),
+                    &[3],
+                    4,
+                ),
+                node(OpKind::Relu, &[4], 5),
+                node(OpKind::Add, &[0, 5], 6),
+            ],
+            outputs: vec![ValueId(6)],
+            value_types: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn folds_constant_chains() {
+        let original = graph();
+        let mut folded = graph();
+        let report = ConstantFolding::default().run(&mut folded).unwrap();
+
+        assert_eq!(report.folded, vec![NodeId(3), NodeId(4), NodeId(5)]);
+        assert!(matches!(folded.nodes[6].op, OpKind::Add));
+        let OpKind::Constant(t) = &folded.nodes[5].op else {
+            panic!("Relu was not folded");
+        };
+        assert_eq!(t.shape(), &[3, 2]);
+        assert_eq!(t.data, vec![0.0, 0.0, 0.0, 2.0, 0.0, 4.0]);
+        assert_eq!(folded.value_types[&ValueId(5)].shape, vec![3, 2]);
+
+        let inputs = HashMap::from([(ValueId(0), Tensor::ones(vec![3, 2]))]);
+        let cpu = CpuBackend::new();
+        let expected = cpu.run(&original, &inputs).unwrap();
+        let actual = cpu.run(&folded, &inputs).unwrap();
+        assert_eq!(actual[&ValueId(6)].data, expected[&ValueId(6)].data);
+    }
+
+    #[test]
+    fn respects_size_cap() {
+        let mut g = graph();
+        let report = ConstantFolding { max_len: 5 }.run(&mut g).unwrap();
+        assert!(report.folded.is_empty());
+        assert_eq!(report.too_large, vec![NodeId(3)]);
+        assert!(matches!(g.nodes[3].op, OpKind::Mul));
+    }
+
+    #[test]
+    fn checks_size_cap_before_evaluating() {
+        // [n, 1] x [1, n] would materialize 2^32 elements
+        let n = 1 << 16;
+        let mut g = Graph {
+            nodes: vec![
+                node(constant(Tensor::full(vec![n, 1], 1.0)), &[], 0),
+                node(constant(Tensor::full(vec![1, n], 1.0)), &[], 1),
+                node(OpKind::MatMul(None), &[0, 1], 2),
+            ],
+            outputs: vec![ValueId(2)],
+            value_types: HashMap::new(),
+        };
+        let report = ConstantFolding::default().run(&mut g).unwrap();
+        assert_eq!(report.too_large, vec![NodeId(2)]);
+    }
+}
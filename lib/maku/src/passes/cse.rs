@@ -0,0 +1,154 @@
+//! Common subexpression elimination.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::{Graph, NodeId, OpKind, Result, ValueId};
+
+/// Merges nodes that apply the same op with the same attributes to the same
+/// inputs; readers of the duplicate are pointed at the first occurrence.
+///
+/// Constants are merged when their shapes and bits match. Input nodes and
+/// duplicates whose output is a graph output are never removed.
+#[derive(Debug, Clone, Default)]
+pub struct CommonSubexpressionElimination;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CseReport {
+    /// (kept node, removed duplicate)
+    pub merged: Vec<(NodeId, NodeId)>,
+}
+
+impl CommonSubexpressionElimination {
+    pub fn run(&self, graph: &mut Graph) -> Result<CseReport> {
+        let outputs: HashSet<ValueId> = graph.outputs.iter().copied().collect();
+        let mut renamed: HashMap<ValueId, ValueId> = HashMap::new();
+        // (op name, inputs) -> indices of kept nodes with that signature
+        let mut seen: HashMap<(&'static str, Vec<ValueId>), Vec<usize>> = HashMap::new();
+        let mut removed = HashSet::new();
+        let mut report = CseReport::default();
+
+        for j in 0..graph.nodes.len() {
+            for input in &mut graph.nodes[j].inputs {
+                if let Some(&v) = renamed.get(input) {
+                    *input = v;
+                }
+            }
+            let node = &graph.nodes[j];
+            if matches!(node.op, OpKind::Input) {
+                continue;
+            }
+            let key = (node.op.name(), node.inputs.clone());
+            let candidates = seen.entry(key).or_default();
+            let original = candidates
+                .iter()
+                .find(|&&i| same_op(&graph.nodes[i].op, &node.op));
+            match original {
+                Some(&i) if !outputs.contains(&node.output) => {
+                    renamed.insert(node.output, graph.nodes[i].output);
+                    removed.insert(node.id);
+                    report.merged.push((graph.nodes[i].id, node.id));
+                }
+                _ => candidates.push(j),
+            }
+        }
+
+        graph.nodes.retain(|n| !removed.contains(&n.id));
+        graph.value_types.retain(|v, _| !renamed.contains_key(v));
+        Ok(report)
+    }
+}
+
+/// Same op with equal attributes; constants compare by shape and bit pattern.
+fn same_op(a: &OpKind, b: &OpKind) -> bool {
+    match (a, b) {
+        (OpKind::Input, _) | (_, OpKind::Input) => false,
+        (OpKind::Constant(x), OpKind::Constant(y)) => {
+            Arc::ptr_eq(x, y)
+                || (x.desc.shape == y.desc.shape
+                    && x.data
+                        .iter()
+                        .zip(&y.data)
+                        .all(|(p, q)| p.to_bits() == q.to_bits()))
+        }
+        (OpKind::MatMul(x), OpKind::MatMul(y)) => x == y,
+        (OpKind::Conv2D(x), OpKind::Conv2D(y)) => x == y,
+        (OpKind::DepthwiseConv2D(x), OpKind::DepthwiseConv2D(y)) => x == y,
+        (OpKind::BatchNorm(x), OpKind::BatchNorm(y)) => x == y,
+        (OpKind::AveragePool(x), OpKind::AveragePool(y)) => x == y,
+        (OpKind::Reshape(x), OpKind::Reshape(y)) => x == y,
+        (OpKind::Transpose(x), OpKind::Transpose(y)) => x == y,
+        (OpKind::Concat(x), OpKind::Concat(y)) => x == y,
+        (OpKind::FusedElementwise(x), OpKind::FusedElementwise(y)) => x == y,
+        (OpKind::Add, OpKind::Add)
+        | (OpKind::Mul, OpKind::Mul)
+        | (OpKind::Relu, OpKind::Relu)
+        | (OpKind::Relu6, OpKind::Relu6)
+        | (OpKind::HardSwish, OpKind::HardSwish)
+        | (OpKind::GlobalAveragePool, OpKind::GlobalAveragePool) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::node;
+    use crate::{CpuBackend, Tensor, TransposeAttrs};
+
+    fn transpose(perm: Vec<usize>) -> OpKind {
+        OpKind::Transpose(TransposeAttrs { perm })
+    }
+
+    #[test]
+    fn merges_duplicate_subgraphs() {
+        // Two copies of Relu(Mul(x, c)) with separately built but equal
+        // constants, summed; Transposes with different perms stay apart.
+        let c = || {
+            OpKind::Constant(
+                Tensor::arange(0.0, 4.0, 1.0)
+                    .reshape(vec![2, 2])
+                    .unwrap()
+                    .into(),
+            )
+        };
+        let mut g = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(c(), &[], 1),
+                node(OpKind::Mul, &[0, 1], 2),
+                node(OpKind::Relu, &[2], 3),
+                node(c(), &[], 4),
+                node(OpKind::Mul, &[0, 4], 5),
+                node(OpKind::Relu, &[5], 6),
+                node(OpKind::Add, &[3, 6], 7),
+                node(transpose(vec![1, 0]), &[7], 8),
+                node(transpose(vec![0, 1]), &[7], 9),
+                node(OpKind::Add, &[8, 9], 10),
+                node(OpKind::Relu, &[2], 11),
+            ],
+            outputs: vec![ValueId(10), ValueId(11)],
+            value_types: HashMap::new(),
+        };
+        let x = Tensor::arange(-2.0, 2.0, 1.0).reshape(vec![2, 2]).unwrap();
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let expected = CpuBackend::new().run(&g, &inputs).unwrap();
+
+        let report = CommonSubexpressionElimination.run(&mut g).unwrap();
+        // Relu 11 duplicates Relu 3 but is a graph output
+        assert_eq!(
+            report.merged,
+            vec![
+                (NodeId(1), NodeId(4)),
+                (NodeId(2), NodeId(5)),
+                (NodeId(3), NodeId(6))
+            ]
+        );
+        assert_eq!(g.nodes[4].inputs, vec![ValueId(3), ValueId(3)]);
+
+        let actual = CpuBackend::new().run(&g, &inputs).unwrap();
+        for id in [ValueId(10), ValueId(11)] {
+            assert_eq!(actual[&id].data, expected[&id].data);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Dead code elimination.
+
+use std::collections::HashSet;
+
+use crate::{Graph, NodeId, OpKind, Result};
+
+/// Removes nodes whose output no graph output depends on. Input nodes are
+/// kept, since they describe what the caller passes in.
+#[derive(Debug, Clone, Default)]
+pub struct DeadCodeElimination;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DceReport {
+    pub removed: Vec<NodeId>,
+}
+
+impl DeadCodeElimination {
+    pub fn run(&self, graph: &mut Graph) -> Result<DceReport> {
+        let mut live: HashSet<_> = graph.outputs.iter().copied().collect();
+        let mut keep = vec![false; graph.nodes.len()];
+        for (i, node) in graph.nodes.iter().enumerate().rev() {
+            if matches!(node.op, OpKind::Input) || live.contains(&node.output) {
+                keep[i] = true;
+                // also covers unread inputs, whose types must survive
+                live.insert(node.output);
+                live.extend(&node.inputs);
+            }
+        }
+
+        let mut report = DceReport::default();
+        let mut keep = keep.into_iter();
+        graph.nodes.retain(|node| {
+            let kept = keep.next().unwrap_or(true);
+            if !kept {
+                report.removed.push(node.id);
+            }
+            kept
+        });
+        graph.value_types.retain(|id, _| live.contains(id));
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::node;
+    use crate::{Tensor, ValueId};
+    use std::collections::HashMap;
+
+    #[test]
+    fn removes_nodes_outputs_do_not_need() {
+        // y = Relu(x); dangling: Mul(x, c), Relu6(Mul); unused input z
+        let mut g = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Constant(Tensor::ones(vec![2]).into()), &[], 1),
+                node(OpKind::Mul, &[0, 1], 2),
+                node(OpKind::Relu6, &[2], 3),
+                node(OpKind::Relu, &[0], 4),
+                node(OpKind::Input, &[], 5),
+            ],
+            outputs: vec![ValueId(4)],
+            value_types: HashMap::from([
+                (ValueId(1), Tensor::ones(vec![2]).desc),
+                (ValueId(5), Tensor::ones(vec![3]).desc),
+            ]),
+        };
+        let report = DeadCodeElimination.run(&mut g).unwrap();
+
+        assert_eq!(report.removed, vec![NodeId(1), NodeId(2), NodeId(3)]);
+        let ids: Vec<NodeId> = g.nodes.iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![NodeId(0), NodeId(4), NodeId(5)]);
+        let types: Vec<ValueId> = g.value_types.keys().copied().collect();
+        assert_eq!(types, vec![ValueId(5)]);
+    }
+}
@@ -0,0 +1,209 @@
+//! Fuse chains of elementwise ops into one FusedElementwise node, so
+//! `Mul -> Add -> Relu6` makes one pass over memory without intermediate
+//! tensors.
+//!
+//! Add and Mul take operands of one shape in this IR, so there are no
+//! broadcasting ops to fuse and fused nodes never broadcast.
+
+use std::collections::{HashMap, HashSet};
+
+use super::use_counts;
+use crate::{
+    ElementwiseOp, FusedArg, FusedElementwiseAttrs, FusedStep, Graph, NodeId, OpKind, Result,
+    ValueId,
+};
+
+/// Merges each Add / Mul / Relu / Relu6 / HardSwish (or already fused)
+/// node into the elementwise node reading it, when that is its only reader.
+///
+/// The consumer keeps its id and output and takes over the producer's
+/// program and inputs. Results are bit-identical to the unfused graph.
+#[derive(Debug, Clone, Default)]
+pub struct ElementwiseFusion;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ElementwiseFusionReport {
+    /// (fused node, nodes merged into it)
+    pub fused: Vec<(NodeId, Vec<NodeId>)>,
+}
+
+/// A node's computation as a program over its inputs
+struct Program {
+    steps: Vec<FusedStep>,
+    inputs: Vec<ValueId>,
+}
+
+impl Program {
+    fn of(op: &OpKind, inputs: &[ValueId]) -> Option<Program> {
+        let steps = match op {
+            OpKind::FusedElementwise(attrs) => attrs.steps.clone(),
+            op => {
+                let op = ElementwiseOp::from_op(op)?;
+                let args = (0..op.arity()).map(FusedArg::Input).collect();
+                vec![FusedStep { op, args }]
+            }
+        };
+        Some(Program {
+            steps,
+            inputs: inputs.to_vec(),
+        })
+    }
+
+    /// Inline `producer`, which computes `value`, in front of `self`.
+    fn inline(self, value: ValueId, producer: Program) -> Program {
+        let mut inputs: Vec<ValueId> = Vec::new();
+        let mut intern = |v: ValueId| match inputs.iter().position(|&u| u == v) {
+            Some(i) => i,
+            None => {
+                inputs.push(v);
+                inputs.len() - 1
+            }
+        };
+
+        let base = producer.steps.len();
+        let mut steps = Vec::with_capacity(base + self.steps.len());
+        for step in producer.steps {
+            let args = step
+                .args
+                .iter()
+                .map(|&arg| match arg {
+                    FusedArg::Input(i) => FusedArg::Input(intern(producer.inputs[i])),
+                    step => step,
+                })
+                .collect();
+            steps.push(FusedStep { op: step.op, args });
+        }
+        for step in self.steps {
+            let args = step
+                .args
+                .iter()
+                .map(|&arg| match arg {
+                    FusedArg::Input(i) if self.inputs[i] == value => FusedArg::Step(base - 1),
+                    FusedArg::Input(i) => FusedArg::Input(intern(self.inputs[i])),
+                    FusedArg::Step(t) => FusedArg::Step(base + t),
+                })
+                .collect();
+            steps.push(FusedStep { op: step.op, args });
+        }
+        Program { steps, inputs }
+    }
+}
+
+impl ElementwiseFusion {
+    pub fn run(&self, graph: &mut Graph) -> Result<ElementwiseFusionReport> {
+        let uses = use_counts(graph);
+        let mut producers: HashMap<ValueId, usize> = HashMap::new();
+        let mut removed = HashSet::new();
+        let mut report = ElementwiseFusionReport::default();
+
+        for j in 0..graph.nodes.len() {
+            let node = &graph.nodes[j];
+            let Some(mut program) = Program::of(&node.op, &node.inputs) else {
+                producers.insert(node.output, j);
+                continue;
+            };
+            let mut merged = Vec::new();
+            let mut k = 0;
+            while k < program.inputs.len() {
+                let value = program.inputs[k];
+                let producer = producers
+                    .get(&value)
+                    .filter(|_| uses.get(&value) == Some(&1))
+                    .and_then(|&i| {
+                        let p = &graph.nodes[i];
+                        Some((i, Program::of(&p.op, &p.inputs)?))
+                    });
+                match producer {
+                    Some((i, p)) => {
+                        let id = graph.nodes[i].id;
+                        program = program.inline(value, p);
+                        removed.insert(id);
+                        merged.push(id);
+                        // A fused producer's own group moves into this one
+                        if let Some(pos) = report.fused.iter().position(|(f, _)| *f == id) {
+                            merged.extend(report.fused.remove(pos).1);
+                        }
+                        k = 0;
+                    }
+                    None => k += 1,
+                }
+            }
+
+            let node = &mut graph.nodes[j];
+            if !merged.is_empty() {
+                node.op = OpKind::FusedElementwise(FusedElementwiseAttrs {
+                    steps: program.steps,
+                });
+                node.inputs = program.inputs;
+                report.fused.push((node.id, merged));
+            }
+            producers.insert(node.output, j);
+        }
+
+        graph.nodes.retain(|n| !removed.contains(&n.id));
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::node;
+    use crate::{CpuBackend, Tensor, TensorDesc};
+
+    /// y = Relu6(x * a + b); z = HardSwish(y) * (y + x), with `y` read
+    /// twice so it ends one group and starts another
+    fn graph() -> Graph {
+        Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Input, &[], 1),
+                node(OpKind::Input, &[], 2),
+                node(OpKind::Mul, &[0, 1], 3),
+                node(OpKind::Add, &[3, 2], 4),
+                node(OpKind::Relu6, &[4], 5),
+                node(OpKind::HardSwish, &[5], 6),
+                node(OpKind::Add, &[5, 0], 7),
+                node(OpKind::Mul, &[6, 7], 8),
+            ],
+            outputs: vec![ValueId(8)],
+            value_types: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn fuses_chains_with_identical_results() {
+        let shape = vec![3, 1000];
+        let inputs: HashMap<ValueId, Tensor> = (0..3)
+            .map(|v| {
+                let t = Tensor::from_fn(shape.clone(), |i| {
+                    ((i[1] * 7 + i[0] * 3 + v * 5) % 23) as f32 * 0.37 - 4.0
+                });
+                (ValueId(v as u32), t)
+            })
+            .collect();
+        let cpu = CpuBackend::new();
+        let expected = cpu.run(&graph(), &inputs).unwrap();
+
+        let mut g = graph();
+        let report = ElementwiseFusion.run(&mut g).unwrap();
+        assert_eq!(
+            report.fused,
+            vec![
+                (NodeId(5), vec![NodeId(4), NodeId(3)]),
+                (NodeId(8), vec![NodeId(6), NodeId(7)]),
+            ]
+        );
+        assert_eq!(g.nodes.len(), 5);
+        assert_eq!(g.nodes[3].inputs, vec![ValueId(0), ValueId(1), ValueId(2)]);
+
+        let actual = cpu.run(&g, &inputs).unwrap();
+        assert_eq!(actual[&ValueId(8)].data, expected[&ValueId(8)].data);
+
+        let descs: HashMap<ValueId, TensorDesc> =
+            inputs.iter().map(|(&id, t)| (id, t.desc.clone())).collect();
+        let mut session = cpu.compile(&g, &descs).unwrap();
+        let compiled = session.run(&inputs).unwrap();
+        assert_eq!(compiled[&ValueId(8)].data, expected[&ValueId(8)].data);
+    }
+}
@@ -0,0 +1,482 @@
+//! Layout propagation for graphs exported in NCHW (PyTorch / ONNX style):
+//! the kernels run NHWC, so 4-D activations are kept in NHWC between
+//! layout-aware ops and converted back only where an op or a graph output
+//! needs the original layout.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::IdAlloc;
+use crate::error::{Result, ensure};
+use crate::{Graph, Node, NodeId, OpKind, ReshapeAttrs, Tensor, TransposeAttrs, ValueId};
+
+/// NCHW -> NHWC
+const TO_NHWC: [usize; 4] = [0, 2, 3, 1];
+/// NHWC -> NCHW
+const TO_NCHW: [usize; 4] = [0, 3, 1, 2];
+/// Conv kernel `[C_out, C_in / group, Kh, Kw]` -> `[Kh, Kw, C_in / group, C_out]`
+const CONV_KERNEL: [usize; 4] = [2, 3, 1, 0];
+/// Depthwise kernel `[C, M, Kh, Kw]` -> `[Kh, Kw, C, M]`
+const DEPTHWISE_KERNEL: [usize; 4] = [2, 3, 0, 1];
+
+/// Rewrites a graph whose layout-sensitive ops follow NCHW conventions into
+/// the NHWC conventions the kernels use.
+///
+/// Before the pass, Conv2D / DepthwiseConv2D / AveragePool /
+/// GlobalAveragePool read and write NCHW, BatchNorm normalizes axis 1 of
+/// 4-D inputs, Conv2D kernels are `[C_out, C_in / group, Kh, Kw]` and
+/// DepthwiseConv2D kernels `[C * M, 1, Kh, Kw]`. Graph inputs and outputs
+/// stay NCHW.
+///
+/// Activations stay NHWC through elementwise ops, BatchNorm and Concat;
+/// ops that interpret the layout otherwise (Reshape, MatMul, ...) get NCHW
+/// inputs, and a Transpose reading NHWC has its permutation composed
+/// instead. Each value is converted at most once per layout, and constants
+/// are permuted here instead of by a Transpose node. The original weight
+/// constants are left for dead code elimination.
+///
+/// Shape inference assumes NHWC, so add this to a
+/// [`PassManager`](super::PassManager) with
+/// [`with_pre_pass`](super::PassManager::with_pre_pass), which runs it
+/// before validation.
+#[derive(Debug, Clone, Default)]
+pub struct NchwToNhwc;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayoutReport {
+    /// Inserted Transpose nodes
+    pub transposes: Vec<NodeId>,
+    /// Inserted Constant nodes holding permuted constants
+    pub constants: Vec<NodeId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Form {
+    Nchw,
+    Nhwc,
+    ConvKernel,
+    /// With the depth multiplier
+    DepthwiseKernel(usize),
+}
+
+/// Where an original value's data is held in the rewritten graph
+#[derive(Clone, Copy)]
+struct Held {
+    id: ValueId,
+    nhwc: bool,
+}
+
+struct Rewriter {
+    ids: IdAlloc,
+    nodes: Vec<Node>,
+    held: HashMap<ValueId, Held>,
+    /// Rank of each original value, where known
+    ranks: HashMap<ValueId, usize>,
+    constants: HashMap<ValueId, Arc<Tensor>>,
+    converted: HashMap<(ValueId, Form), ValueId>,
+    report: LayoutReport,
+}
+
+impl Rewriter {
+    /// `value` (an id of the original graph) in `form`, inserting the
+    /// conversion on first use.
+    fn get(&mut self, value: ValueId, form: Form) -> Result<ValueId> {
+        let held = self.held.get(&value).copied().unwrap_or(Held {
+            id: value,
+            nhwc: false,
+        });
+        match form {
+            Form::Nchw if !held.nhwc => return Ok(held.id),
+            Form::Nhwc if held.nhwc => return Ok(held.id),
+            _ => {}
+        }
+        if let Some(&id) = self.converted.get(&(value, form)) {
+            return Ok(id);
+        }
+
+        let id = match form {
+            Form::Nchw => self.permute(held.id, None, &TO_NCHW)?,
+            Form::Nhwc => self.permute(held.id, None, &TO_NHWC)?,
+            Form::ConvKernel => {
+                let src = self.get(value, Form::Nchw)?;
+                self.permute(src, None, &CONV_KERNEL)?
+            }
+            Form::DepthwiseKernel(m) => {
+                let src = self.get(value, Form::Nchw)?;
+                self.permute(src, Some(m), &DEPTHWISE_KERNEL)?
+            }
+        };
+        self.converted.insert((value, form), id);
+        Ok(id)
+    }
+
+    /// Permute `src` (an id of the rewritten graph) by `perm`, after
+    /// splitting axis 0 into `[-1, m]` and dropping axis 1 when `split` is
+    /// given.
+    fn permute(&mut self, src: ValueId, split: Option<usize>, perm: &[usize]) -> Result<ValueId> {
+        if let Some(t) = self.constants.get(&src) {
+            let mut view = t.view();
+            if let Some(m) = split {
+                let s = view.shape().to_vec();
+                ensure!(
+                    s.len() == 4 && s[1] == 1 && m > 0 && s[0] % m == 0,
+                    ShapeMismatch,
+                    "depthwise kernel {:?} is not [C * {}, 1, Kh, Kw]",
+                    s,
+                    m
+                );
+                view = view.reshape(vec![s[0] / m, m, s[2], s[3]])?;
+            }
+            let tensor = Arc::new(view.permute(perm)?.to_tensor());
+            let (id, output) = (self.ids.node(), self.ids.value());
+            self.constants.insert(output, Arc::clone(&tensor));
+            self.push(id, OpKind::Constant(tensor), vec![], output);
+            self.report.constants.push(id);
+            return Ok(output);
+        }
+
+        let mut src = src;
+        if let Some(m) = split {
+            let reshape = ReshapeAttrs {
+                shape: vec![-1, m as isize, 0, 0],
+                allowzero: false,
+            };
+            let (id, output) = (self.ids.node(), self.ids.value());
+            self.push(id, OpKind::Reshape(reshape), vec![src], output);
+            src = output;
+        }
+        let (id, output) = (self.ids.node(), self.ids.value());
+        let attrs = TransposeAttrs {
+            perm: perm.to_vec(),
+        };
+        self.push(id, OpKind::Transpose(attrs), vec![src], output);
+        self.report.transposes.push(id);
+        Ok(output)
+    }
+
+    fn push(&mut self, id: NodeId, op: OpKind, inputs: Vec<ValueId>, output: ValueId) {
+        self.nodes.push(Node {
+            id,
+            op,
+            inputs,
+            output,
+        });
+    }
+
+    /// `values` converted to the forms `form(i)` picks for each position.
+    fn gather(&mut self, values: &[ValueId], form: impl Fn(usize) -> Form) -> Result<Vec<ValueId>> {
+        let mut ids = Vec::with_capacity(values.len());
+        for (i, &v) in values.iter().enumerate() {
+            ids.push(self.get(v, form(i))?);
+        }
+        Ok(ids)
+    }
+
+    fn is_nhwc(&self, value: &ValueId) -> bool {
+        self.held.get(value).is_some_and(|h| h.nhwc)
+    }
+
+    /// The rewritten op of `node`, its inputs in the forms it needs, and
+    /// whether it writes NHWC.
+    fn rewrite(&mut self, node: &Node) -> Result<(OpKind, Vec<ValueId>, bool)> {
+        let any_nhwc = node.inputs.iter().any(|v| self.is_nhwc(v));
+        let first_nhwc = node.inputs.first().is_some_and(|v| self.is_nhwc(v));
+        let mut op = node.op.clone();
+        let (inputs, nhwc) = match &mut op {
+            OpKind::Input | OpKind::Constant(_) => (vec![], false),
+            OpKind::Conv2D(_) => {
+                let form = |i: usize| [Form::Nhwc, Form::ConvKernel, Form::Nchw][i.min(2)];
+                (self.gather(&node.inputs, form)?, true)
+            }
+            OpKind::DepthwiseConv2D(attrs) => {
+                let kernel = Form::DepthwiseKernel(attrs.depth_multiplier);
+                let form = |i: usize| [Form::Nhwc, kernel, Form::Nchw][i.min(2)];
+                (self.gather(&node.inputs, form)?, true)
+            }
+            OpKind::AveragePool(_) | OpKind::GlobalAveragePool => {
+                (self.gather(&node.inputs, |_| Form::Nhwc)?, true)
+            }
+            // Axis 1 is the channel axis of 4-D inputs and already the last
+            // one of [N, C]
+            OpKind::BatchNorm(_) => {
+                let rank = node.inputs.first().and_then(|v| self.ranks.get(v));
+                let x_nhwc = first_nhwc || rank == Some(&4);
+                ensure!(
+                    x_nhwc || rank == Some(&2),
+                    UnsupportedOp,
+                    "BatchNorm over axis 1 needs a 4-D or 2-D input of known rank, got {:?}",
+                    rank
+                );
+                let form = |i| {
+                    if x_nhwc && i == 0 {
+                        Form::Nhwc
+                    } else {
+                        Form::Nchw
+                    }
+                };
+                (self.gather(&node.inputs, form)?, x_nhwc)
+            }
+            // Operands of one shape: NHWC if any of them already is
+            OpKind::Add
+            | OpKind::Mul
+            | OpKind::Relu
+            | OpKind::Relu6
+            | OpKind::HardSwish
+            | OpKind::FusedElementwise(_) => {
+                let form = if any_nhwc { Form::Nhwc } else { Form::Nchw };
+                (self.gather(&node.inputs, |_| form)?, any_nhwc)
+            }
+            OpKind::Concat(attrs) => {
+                if any_nhwc && attrs.axis < 4 {
+                    attrs.axis = TO_NCHW[attrs.axis];
+                }
+                let form = if any_nhwc { Form::Nhwc } else { Form::Nchw };
+                (self.gather(&node.inputs, |_| form)?, any_nhwc)
+            }
+            // The input is permute(held, TO_NCHW), so axis `p` of it is axis
+            // TO_NCHW[p] of the held value
+            OpKind::Transpose(attrs)
+                if first_nhwc && attrs.perm.len() == 4 && attrs.perm.iter().all(|&a| a < 4) =>
+            {
+                attrs.perm = attrs.perm.iter().map(|&a| TO_NCHW[a]).collect();
+                (vec![self.held[&node.inputs[0]].id], false)
+            }
+            OpKind::MatMul(_) | OpKind::Reshape(_) | OpKind::Transpose(_) => {
+                (self.gather(&node.inputs, |_| Form::Nchw)?, false)
+            }
+        };
+        Ok((op, inputs, nhwc))
+    }
+}
+
+/// Rank of the output of `node`; layout conversions never change it.
+fn rank(node: &Node, graph: &Graph, ranks: &HashMap<ValueId, usize>) -> Option<usize> {
+    match &node.op {
+        OpKind::Input => graph.value_types.get(&node.output).map(|d| d.shape.len()),
+        OpKind::Constant(t) => Some(t.desc.shape.len()),
+        OpKind::Conv2D(_)
+        | OpKind::DepthwiseConv2D(_)
+        | OpKind::AveragePool(_)
+        | OpKind::GlobalAveragePool => Some(4),
+        OpKind::MatMul(_) => Some(2),
+        OpKind::Reshape(attrs) => Some(attrs.shape.len()),
+        OpKind::Transpose(attrs) => Some(attrs.perm.len()),
+        OpKind::Add
+        | OpKind::Mul
+        | OpKind::Relu
+        | OpKind::Relu6
+        | OpKind::HardSwish
+        | OpKind::BatchNorm(_)
+        | OpKind::Concat(_)
+        | OpKind::FusedElementwise(_) => node.inputs.first().and_then(|v| ranks.get(v)).copied(),
+    }
+}
+
+impl NchwToNhwc {
+    pub fn run(&self, graph: &mut Graph) -> Result<LayoutReport> {
+        let outputs: HashSet<ValueId> = graph.outputs.iter().copied().collect();
+        let mut r = Rewriter {
+            ids: IdAlloc::new(graph),
+            nodes: Vec::with_capacity(graph.nodes.len()),
+            held: HashMap::new(),
+            ranks: HashMap::new(),
+            constants: HashMap::new(),
+            converted: HashMap::new(),
+            report: LayoutReport::default(),
+        };
+
+        for node in std::mem::take(&mut graph.nodes) {
+            let (op, inputs, nhwc) = r.rewrite(&node).map_err(|e| e.at(&node))?;
+            if let OpKind::Constant(t) = &op {
+                r.constants.insert(node.output, Arc::clone(t));
+            }
+            if let Some(rank) = rank(&node, graph, &r.ranks) {
+                r.ranks.insert(node.output, rank);
+            }
+            // A graph output keeps its id and NCHW layout, so an NHWC
+            // result is written to a fresh value and converted back
+            let output = if nhwc && outputs.contains(&node.output) {
+                r.ids.value()
+            } else {
+                node.output
+            };
+            r.held.insert(node.output, Held { id: output, nhwc });
+            r.push(node.id, op, inputs, output);
+            if output != node.output {
+                let (id, attrs) = (
+                    r.ids.node(),
+                    TransposeAttrs {
+                        perm: TO_NCHW.to_vec(),
+                    },
+                );
+                r.push(id, OpKind::Transpose(attrs), vec![output], node.output);
+                r.report.transposes.push(id);
+                r.converted.insert((node.output, Form::Nchw), node.output);
+            }
+        }
+
+        // Types of values now holding NHWC data are stale
+        let inputs: HashSet<ValueId> = r
+            .nodes
+            .iter()
+            .filter(|n| matches!(n.op, OpKind::Input))
+            .map(|n| n.output)
+            .collect();
+        graph.value_types.retain(|v, _| inputs.contains(v));
+        graph.nodes = r.nodes;
+        Ok(r.report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::{AlgebraicSimplification, OptLevel, PassManager};
+    use crate::test_util::{node, tensor};
+    use crate::{BatchNormAttrs, Conv2DAttrs, CpuBackend, DType, DepthwiseConv2DAttrs, TensorDesc};
+
+    fn constant(t: &Tensor) -> OpKind {
+        OpKind::Constant(Arc::new(t.clone()))
+    }
+
+    /// r = Relu(BN(Conv(x)));
+    /// y = MatMul(Reshape(Transpose(DWConv(r) + c, to NHWC)), m),
+    /// in NCHW conventions when `nchw`, otherwise the hand-written NHWC
+    /// equivalent
+    fn graph(nchw: bool) -> Graph {
+        let w = tensor(vec![4, 3, 3, 3], 1);
+        let dw = tensor(vec![8, 1, 3, 3], 2);
+        let c = tensor(vec![1, 8, 3, 3], 3);
+        let (w, dw, c) = if nchw {
+            (w, dw, c)
+        } else {
+            (
+                Tensor::from_fn(vec![3, 3, 3, 4], |i| w.get(&[i[3], i[2], i[0], i[1]])),
+                Tensor::from_fn(vec![3, 3, 4, 2], |i| {
+                    dw.get(&[i[2] * 2 + i[3], 0, i[0], i[1]])
+                }),
+                Tensor::from_fn(vec![1, 3, 3, 8], |i| c.get(&[0, i[3], i[1], i[2]])),
+            )
+        };
+        let conv = Conv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [1, 1],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        };
+        let depthwise = DepthwiseConv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [2, 2],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            depth_multiplier: 2,
+            activation: None,
+        };
+        let bn = BatchNormAttrs {
+            epsilon: 1e-3,
+            momentum: 0.9,
+        };
+        let reshape = ReshapeAttrs {
+            shape: vec![9, 8],
+            allowzero: false,
+        };
+        let perm = if nchw {
+            vec![0, 2, 3, 1]
+        } else {
+            vec![0, 1, 2, 3]
+        };
+        let x_shape = if nchw {
+            vec![1, 3, 6, 6]
+        } else {
+            vec![1, 6, 6, 3]
+        };
+        let x_desc = TensorDesc {
+            dtype: DType::F32,
+            shape: x_shape,
+        };
+        Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(&w), &[], 1),
+                node(constant(&tensor(vec![4], 4)), &[], 2),
+                node(OpKind::Conv2D(conv), &[0, 1, 2], 3),
+                node(constant(&tensor(vec![4], 5)), &[], 4),
+                node(constant(&tensor(vec![4], 6)), &[], 5),
+                node(constant(&tensor(vec![4], 7)), &[], 6),
+                node(constant(&tensor(vec![4], 8).map(|v| v + 1.0)), &[], 7),
+                node(OpKind::BatchNorm(Some(bn)), &[3, 4, 5, 6, 7], 8),
+                node(OpKind::Relu, &[8], 9),
+                node(constant(&dw), &[], 10),
+                node(OpKind::DepthwiseConv2D(depthwise), &[9, 10], 11),
+                node(constant(&c), &[], 12),
+                node(OpKind::Add, &[11, 12], 13),
+                node(OpKind::Transpose(TransposeAttrs { perm }), &[13], 14),
+                node(OpKind::Reshape(reshape), &[14], 15),
+                node(constant(&tensor(vec![8, 2], 9)), &[], 16),
+                node(OpKind::MatMul(None), &[15, 16], 17),
+            ],
+            outputs: vec![ValueId(17), ValueId(9)],
+            value_types: HashMap::from([(ValueId(0), x_desc)]),
+        }
+    }
+
+    #[test]
+    fn converts_nchw_graph_to_nhwc() {
+        let x = tensor(vec![1, 3, 6, 6], 10);
+        let x_nhwc = Tensor::from_fn(vec![1, 6, 6, 3], |i| x.get(&[0, i[3], i[1], i[2]]));
+        let cpu = CpuBackend::new();
+        let expected = cpu
+            .run(&graph(false), &HashMap::from([(ValueId(0), x_nhwc)]))
+            .unwrap();
+
+        let mut g = graph(true);
+        let report = NchwToNhwc.run(&mut g).unwrap();
+        // Input in and Relu output back out; the explicit Transpose absorbs
+        // the conversion, and the kernels and `c` are permuted constants
+        assert_eq!(report.transposes.len(), 2);
+        assert_eq!(report.constants.len(), 3);
+
+        let simplified = AlgebraicSimplification.run(&mut g).unwrap();
+        assert_eq!(
+            simplified.rewrites,
+            vec![(NodeId(14), "transpose-identity")]
+        );
+
+        let actual = cpu.run(&g, &HashMap::from([(ValueId(0), x)])).unwrap();
+        assert!(actual[&ValueId(17)].approx_eq(&expected[&ValueId(17)], 1e-5));
+        let r = &actual[&ValueId(9)];
+        assert_eq!(r.shape(), [1, 4, 6, 6]);
+        let r_nhwc = Tensor::from_fn(vec![1, 6, 6, 4], |i| r.get(&[0, i[3], i[1], i[2]]));
+        assert!(r_nhwc.approx_eq(&expected[&ValueId(9)], 1e-5));
+    }
+
+    #[test]
+    fn runs_before_validation_as_a_pre_pass() {
+        let x = tensor(vec![1, 3, 6, 6], 10);
+        let cpu = CpuBackend::new();
+        let inputs = HashMap::from([(ValueId(0), x.clone())]);
+        let types = HashMap::from([(ValueId(0), x.desc.clone())]);
+
+        // Shape inference reads the NCHW conv kernel as NHWC
+        assert!(
+            PassManager::with_level(OptLevel::O1)
+                .run(&mut graph(true), &types)
+                .is_err()
+        );
+
+        let pm = PassManager::with_level(OptLevel::O1).with_pre_pass(NchwToNhwc);
+        assert_eq!(pm.pass_names()[0], "NchwToNhwc");
+        let mut g = graph(true);
+        let log = pm.run(&mut g, &types).unwrap();
+        assert_eq!(log.passes[0].changes.len(), 5);
+
+        let x_nhwc = Tensor::from_fn(vec![1, 6, 6, 3], |i| x.get(&[0, i[3], i[1], i[2]]));
+        let expected = cpu
+            .run(&graph(false), &HashMap::from([(ValueId(0), x_nhwc)]))
+            .unwrap();
+        let actual = cpu.run(&g, &inputs).unwrap();
+        assert!(actual[&ValueId(17)].approx_eq(&expected[&ValueId(17)], 1e-5));
+    }
+}
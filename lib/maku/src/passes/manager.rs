@@ -0,0 +1,540 @@
+//! Ordered pass pipelines with validation between passes.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::{
+    ActivationFusion, AlgebraicSimplification, BatchNormFolding, CommonSubexpressionElimination,
+    ConstantFolding, DeadCodeElimination, ElementwiseFusion, NchwToNhwc,
+};
+use crate::error::{Result, bail, ensure, err};
+use crate::{Graph, TensorDesc, ValueId, shape};
+
+/// A graph rewrite the [`PassManager`] can run.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    /// Rewrite `graph` in place, returning one line per change made.
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>>;
+}
+
+/// Preset pipelines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    /// No rewrites
+    O0,
+    /// Rewrites within the existing ops: constant folding, algebraic
+    /// simplification, CSE and DCE
+    O1,
+    /// O1 plus folding BatchNorm and activations into the ops before them
+    /// and fusing elementwise chains
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "O0" => Ok(OptLevel::O0),
+            "O1" => Ok(OptLevel::O1),
+            "O2" => Ok(OptLevel::O2),
+            _ => Err(err!(
+                InvalidAttr,
+                "unknown optimization level {:?} (expected O0, O1 or O2)",
+                s
+            )),
+        }
+    }
+}
+
+/// What one pass did in a [`PassManager::run`].
+#[derive(Debug, Clone)]
+pub struct PassRecord {
+    pub pass: &'static str,
+    pub elapsed: Duration,
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    pub changes: Vec<String>,
+}
+
+/// Change log of a pipeline run, one record per pass in order.
+#[derive(Debug, Clone, Default)]
+pub struct PassLog {
+    pub passes: Vec<PassRecord>,
+}
+
+impl PassLog {
+    pub fn total_changes(&self) -> usize {
+        self.passes.iter().map(|p| p.changes.len()).sum()
+    }
+}
+
+impl fmt::Display for PassLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for p in &self.passes {
+            writeln!(
+                f,
+                "{}: {} changes, {} -> {} nodes, {:.3} ms",
+                p.pass,
+                p.changes.len(),
+                p.nodes_before,
+                p.nodes_after,
+                p.elapsed.as_secs_f64() * 1e3
+            )?;
+            for change in &p.changes {
+                writeln!(f, "  {}", change)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs passes in order over a graph, checking after each one that the
+/// graph is still well formed and its outputs keep their shapes.
+pub struct PassManager {
+    /// Run before the input graph is validated
+    pre_passes: Vec<Box<dyn Pass>>,
+    passes: Vec<Box<dyn Pass>>,
+    clock: fn() -> Duration,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        PassManager::new()
+    }
+}
+
+impl PassManager {
+    /// Empty pipeline.
+    pub fn new() -> Self {
+        PassManager {
+            pre_passes: Vec::new(),
+            passes: Vec::new(),
+            clock: default_clock,
+        }
+    }
+
+    /// The preset pipeline for `level`.
+    pub fn with_level(level: OptLevel) -> Self {
+        let pm = PassManager::new();
+        match level {
+            OptLevel::O0 => pm,
+            OptLevel::O1 => pm
+                .with_pass(ConstantFolding::default())
+                .with_pass(AlgebraicSimplification)
+                .with_pass(CommonSubexpressionElimination)
+                .with_pass(DeadCodeElimination),
+            OptLevel::O2 => pm
+                .with_pass(ConstantFolding::default())
+                .with_pass(AlgebraicSimplification)
+                .with_pass(CommonSubexpressionElimination)
+                .with_pass(BatchNormFolding)
+                .with_pass(ActivationFusion)
+                .with_pass(ElementwiseFusion)
+                .with_pass(DeadCodeElimination),
+        }
+    }
+
+    /// Append `pass` to the pipeline.
+    pub fn with_pass(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Run `pass` on the input graph before it is validated, for rewrites
+    /// such as [`NchwToNhwc`] that turn an imported graph into one shape
+    /// inference accepts. Pre-passes run in the order added, ahead of the
+    /// pipeline, and are recorded in the [`PassLog`] like the others.
+    pub fn with_pre_pass(mut self, pass: impl Pass + 'static) -> Self {
+        self.pre_passes.push(Box::new(pass));
+        self
+    }
+
+    /// Time source for [`PassRecord::elapsed`]: any monotonic time since a
+    /// fixed origin. `std` has no clock on wasm32, where the default always
+    /// reads zero.
+    pub fn with_clock(mut self, clock: fn() -> Duration) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Names of the passes, in run order.
+    pub fn pass_names(&self) -> Vec<&'static str> {
+        self.pre_passes
+            .iter()
+            .chain(&self.passes)
+            .map(|p| p.name())
+            .collect()
+    }
+
+    /// Run every pass over `graph`. `inputs` describes the graph inputs as
+    /// for [`CpuBackend::compile`](crate::CpuBackend::compile).
+    ///
+    /// The `inputs` types are recorded in `graph.value_types` so passes can
+    /// use shapes. Errors in the input graph (after any pre-passes) are
+    /// returned as is; a pass that leaves the graph invalid or changes an
+    /// output's shape gives an `Internal` error naming the pass.
+    ///
+    /// The passes work on a copy of `graph`, which replaces it only once the
+    /// whole pipeline succeeds, so on error `graph` is left as it was.
+    pub fn run(&self, graph: &mut Graph, inputs: &HashMap<ValueId, TensorDesc>) -> Result<PassLog> {
+        let mut work = graph.clone();
+        for (id, desc) in inputs {
+            work.value_types.insert(*id, desc.clone());
+        }
+        let mut log = PassLog::default();
+        for pass in &self.pre_passes {
+            log.passes.push(self.apply(pass.as_ref(), &mut work)?);
+        }
+        let expected = validate(&work, inputs)?;
+
+        for pass in &self.passes {
+            let record = self.apply(pass.as_ref(), &mut work)?;
+            let types = validate(&work, inputs)
+                .map_err(|e| err!(Internal, "{} left an invalid graph: {}", pass.name(), e))?;
+            for id in &work.outputs {
+                let (before, after) = (&expected[id].shape, &types[id].shape);
+                ensure!(
+                    before == after,
+                    Internal,
+                    "{} changed the shape of output {:?} from {:?} to {:?}",
+                    pass.name(),
+                    id,
+                    before,
+                    after
+                );
+            }
+
+            log.passes.push(record);
+        }
+        *graph = work;
+        Ok(log)
+    }
+
+    /// Run `pass` over `graph`, timing it.
+    fn apply(&self, pass: &dyn Pass, graph: &mut Graph) -> Result<PassRecord> {
+        let nodes_before = graph.nodes.len();
+        let start = (self.clock)();
+        let changes = pass.apply(graph)?;
+        Ok(PassRecord {
+            pass: pass.name(),
+            elapsed: (self.clock)().saturating_sub(start),
+            nodes_before,
+            nodes_after: graph.nodes.len(),
+            changes,
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_clock() -> Duration {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_clock() -> Duration {
+    Duration::ZERO
+}
+
+/// Structural checks (unique ids, every read value produced by an earlier
+/// node or given in `inputs`) plus shape inference; returns the type of
+/// every value.
+///
+/// Like [`CpuBackend::run`](crate::CpuBackend::run), a value in `inputs`
+/// needs no Input node.
+fn validate(
+    graph: &Graph,
+    inputs: &HashMap<ValueId, TensorDesc>,
+) -> Result<HashMap<ValueId, TensorDesc>> {
+    let mut node_ids = HashSet::new();
+    let mut produced = HashSet::new();
+    for node in &graph.nodes {
+        if !node_ids.insert(node.id) {
+            bail!(InvalidGraph, "duplicate node id {:?}", node.id);
+        }
+        let missing = |v: &&ValueId| !produced.contains(*v) && !inputs.contains_key(*v);
+        if let Some(&value) = node.inputs.iter().find(missing) {
+            return Err(crate::Error::MissingInput {
+                node: Some(node.into()),
+                value,
+            });
+        }
+        if !produced.insert(node.output) {
+            bail!(InvalidGraph, "value {:?} is produced twice", node.output);
+        }
+    }
+    let types = shape::infer_shapes(graph, inputs)?;
+    for &id in &graph.outputs {
+        if !types.contains_key(&id) {
+            return Err(crate::Error::MissingOutput { value: id });
+        }
+    }
+    Ok(types)
+}
+
+// ---------- Pass implementations ----------
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "ConstantFolding"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        Ok(report
+            .folded
+            .iter()
+            .map(|id| format!("folded node {}", id.0))
+            .collect())
+    }
+}
+
+impl Pass for CommonSubexpressionElimination {
+    fn name(&self) -> &'static str {
+        "CommonSubexpressionElimination"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        Ok(report
+            .merged
+            .iter()
+            .map(|(kept, removed)| format!("merged node {} into node {}", removed.0, kept.0))
+            .collect())
+    }
+}
+
+impl Pass for AlgebraicSimplification {
+    fn name(&self) -> &'static str {
+        "AlgebraicSimplification"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        Ok(report
+            .rewrites
+            .iter()
+            .map(|(id, rule)| format!("{} at node {}", rule, id.0))
+            .collect())
+    }
+}
+
+impl Pass for ElementwiseFusion {
+    fn name(&self) -> &'static str {
+        "ElementwiseFusion"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        Ok(report
+            .fused
+            .iter()
+            .map(|(id, merged)| {
+                let merged: Vec<String> = merged.iter().map(|m| m.0.to_string()).collect();
+                format!("fused nodes {} into node {}", merged.join(", "), id.0)
+            })
+            .collect())
+    }
+}
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "DeadCodeElimination"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        Ok(report
+            .removed
+            .iter()
+            .map(|id| format!("removed node {}", id.0))
+            .collect())
+    }
+}
+
+impl Pass for BatchNormFolding {
+    fn name(&self) -> &'static str {
+        "BatchNormFolding"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        Ok(report
+            .folded
+            .iter()
+            .map(|(conv, bn)| format!("folded BatchNorm node {} into node {}", bn.0, conv.0))
+            .collect())
+    }
+}
+
+impl Pass for ActivationFusion {
+    fn name(&self) -> &'static str {
+        "ActivationFusion"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        Ok(report
+            .fused
+            .iter()
+            .map(|(producer, act)| format!("fused node {} into node {}", act.0, producer.0))
+            .collect())
+    }
+}
+
+impl Pass for NchwToNhwc {
+    fn name(&self) -> &'static str {
+        "NchwToNhwc"
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+        let report = self.run(graph)?;
+        let transposes = report
+            .transposes
+            .iter()
+            .map(|id| format!("inserted Transpose node {}", id.0));
+        let constants = report
+            .constants
+            .iter()
+            .map(|id| format!("permuted constant into node {}", id.0));
+        Ok(transposes.chain(constants).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{constant, desc, node, tensor};
+    use crate::{Conv2DAttrs, CpuBackend, OpKind, Tensor};
+
+    /// y = Relu(BN(Conv(x, w))) with every BN parameter built from
+    /// constant Adds, plus a duplicated and a dangling branch
+    fn graph() -> Graph {
+        let conv = Conv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [1, 1],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        };
+        let mut nodes = vec![
+            node(OpKind::Input, &[], 0),
+            node(constant(vec![3, 3, 2, 4], 1), &[], 1),
+            node(OpKind::Conv2D(conv), &[0, 1], 2),
+        ];
+        for (i, seed) in [2, 3, 4, 5].into_iter().enumerate() {
+            let c = 10 + 3 * i as u32;
+            // Shifted positive so the summed BN variance stays positive
+            let positive = |seed| OpKind::Constant(tensor(vec![4], seed).map(|v| v + 1.5).into());
+            nodes.push(node(positive(seed), &[], c));
+            nodes.push(node(positive(seed + 10), &[], c + 1));
+            nodes.push(node(OpKind::Add, &[c, c + 1], c + 2));
+        }
+        nodes.extend([
+            node(OpKind::BatchNorm(None), &[2, 12, 15, 18, 21], 30),
+            node(OpKind::Relu, &[30], 31),
+            node(OpKind::Relu, &[30], 32),
+            node(OpKind::Add, &[31, 32], 33),
+            node(OpKind::Relu6, &[0], 34),
+        ]);
+        Graph {
+            nodes,
+            outputs: vec![ValueId(33)],
+            value_types: HashMap::new(),
+        }
+    }
+
+    fn input_types() -> HashMap<ValueId, TensorDesc> {
+        HashMap::from([(ValueId(0), desc(vec![1, 5, 5, 2]))])
+    }
+
+    #[test]
+    fn levels_rewrite_and_log() {
+        let x = Tensor::from_fn(vec![1, 5, 5, 2], |i| i[1] as f32 - i[2] as f32 * 0.5);
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let cpu = CpuBackend::new();
+        let expected = cpu.run(&graph(), &inputs).unwrap();
+
+        let mut g = graph();
+        let log = PassManager::with_level(OptLevel::O0)
+            .run(&mut g, &input_types())
+            .unwrap();
+        assert!(log.passes.is_empty());
+        assert_eq!(g.nodes.len(), graph().nodes.len());
+
+        let mut counts = Vec::new();
+        for level in [OptLevel::O1, OptLevel::O2] {
+            let pm = PassManager::with_level(level);
+            let mut g = graph();
+            let log = pm.run(&mut g, &input_types()).unwrap();
+            assert_eq!(
+                log.passes.iter().map(|p| p.pass).collect::<Vec<_>>(),
+                pm.pass_names()
+            );
+            assert!(log.to_string().contains("ConstantFolding: 4 changes"));
+            let actual = cpu.run(&g, &inputs).unwrap();
+            assert!(actual[&ValueId(33)].approx_eq(&expected[&ValueId(33)], 1e-5));
+            counts.push(g.nodes.len());
+        }
+        // O1: Input, kernel, Conv, 4 folded BN params, BN, Relu, Add;
+        // O2 folds the BN and the Relu into the Conv
+        assert_eq!(counts, vec![10, 5]);
+    }
+
+    /// Removes the conv kernel, which the conv still reads
+    struct DropKernel;
+
+    impl Pass for DropKernel {
+        fn name(&self) -> &'static str {
+            "DropKernel"
+        }
+
+        fn apply(&self, graph: &mut Graph) -> Result<Vec<String>> {
+            graph.nodes.remove(1);
+            Ok(vec!["dropped".to_string()])
+        }
+    }
+
+    #[test]
+    fn rejects_passes_that_break_the_graph() {
+        let pm = PassManager::new()
+            .with_pass(DeadCodeElimination)
+            .with_pass(DropKernel);
+        let mut g = graph();
+        let err = pm.run(&mut g, &input_types()).unwrap_err();
+        assert_eq!(err.kind(), "Internal");
+        assert!(
+            err.to_string()
+                .starts_with("DropKernel left an invalid graph")
+        );
+        // DCE's rewrite is dropped along with the broken one
+        assert_eq!(format!("{:?}", g), format!("{:?}", graph()));
+
+        let err = pm.run(&mut graph(), &HashMap::new()).unwrap_err();
+        assert_eq!(err.kind(), "MissingType");
+
+        assert_eq!("O2".parse::<OptLevel>().unwrap(), OptLevel::O2);
+        assert_eq!("O3".parse::<OptLevel>().unwrap_err().kind(), "InvalidAttr");
+    }
+
+    #[test]
+    fn accepts_inputs_without_input_nodes() {
+        let mut g = graph();
+        g.nodes.remove(0);
+        let x = Tensor::from_fn(vec![1, 5, 5, 2], |i| i[3] as f32 - i[1] as f32 * 0.25);
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let cpu = CpuBackend::new();
+        let expected = cpu.run(&g, &inputs).unwrap();
+
+        PassManager::with_level(OptLevel::O2)
+            .run(&mut g, &input_types())
+            .unwrap();
+        let actual = cpu.run(&g, &inputs).unwrap();
+        assert!(actual[&ValueId(33)].approx_eq(&expected[&ValueId(33)], 1e-5));
+    }
+}
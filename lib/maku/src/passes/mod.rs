@@ -0,0 +1,113 @@
+//! Graph rewrites run before execution.
+//!
+//! Passes edit a [`Graph`](crate::Graph) in place and return a report of
+//! what they changed. Nodes keep their ids and graph outputs keep their
+//! value ids, so callers can still address them afterwards.
+
+mod activation_fusion;
+mod batch_norm_fold;
+mod constant_fold;
+mod cse;
+mod dce;
+mod elementwise_fusion;
+mod layout;
+mod manager;
+mod pattern;
+mod simplify;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{Graph, NodeId, OpKind, TensorDesc, ValueId, shape};
+
+pub use activation_fusion::{ActivationFusion, ActivationFusionReport};
+pub use batch_norm_fold::{BatchNormFoldReport, BatchNormFolding};
+pub use constant_fold::{ConstantFolding, FoldReport};
+pub use cse::{CommonSubexpressionElimination, CseReport};
+pub use dce::{DceReport, DeadCodeElimination};
+pub use elementwise_fusion::{ElementwiseFusion, ElementwiseFusionReport};
+pub use layout::{LayoutReport, NchwToNhwc};
+pub use manager::{OptLevel, Pass, PassLog, PassManager, PassRecord};
+pub use simplify::{AlgebraicSimplification, SimplifyReport};
+
+/// Hands out node and value ids unused by a graph, for nodes a pass inserts.
+///
+/// Ids count up from the largest one in use, wrapping around and skipping
+/// used ones, since hashed ids from the editor can sit near `u32::MAX`.
+pub(crate) struct IdAlloc {
+    nodes: HashSet<u32>,
+    values: HashSet<u32>,
+    next_node: u32,
+    next_value: u32,
+}
+
+impl IdAlloc {
+    pub(crate) fn new(graph: &Graph) -> Self {
+        let nodes: HashSet<u32> = graph.nodes.iter().map(|n| n.id.0).collect();
+        let values: HashSet<u32> = graph
+            .nodes
+            .iter()
+            .flat_map(|n| n.inputs.iter().chain([&n.output]))
+            .chain(&graph.outputs)
+            .chain(graph.value_types.keys())
+            .map(|v| v.0)
+            .collect();
+        IdAlloc {
+            next_node: nodes.iter().max().map_or(0, |&id| id.wrapping_add(1)),
+            next_value: values.iter().max().map_or(0, |&id| id.wrapping_add(1)),
+            nodes,
+            values,
+        }
+    }
+
+    pub(crate) fn node(&mut self) -> NodeId {
+        NodeId(next_unused(&mut self.nodes, &mut self.next_node))
+    }
+
+    pub(crate) fn value(&mut self) -> ValueId {
+        ValueId(next_unused(&mut self.values, &mut self.next_value))
+    }
+}
+
+fn next_unused(used: &mut HashSet<u32>, next: &mut u32) -> u32 {
+    loop {
+        let id = *next;
+        *next = next.wrapping_add(1);
+        if used.insert(id) {
+            return id;
+        }
+    }
+}
+
+/// Number of times each value is read, counting each graph output as a read.
+pub(crate) fn use_counts(graph: &Graph) -> HashMap<ValueId, usize> {
+    let mut counts = HashMap::new();
+    for id in graph
+        .nodes
+        .iter()
+        .flat_map(|n| &n.inputs)
+        .chain(&graph.outputs)
+    {
+        *counts.entry(*id).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Types of the values derivable from constants and the Input types in
+/// `graph.value_types`; values depending on an unknown one are left out.
+pub(crate) fn known_types(graph: &Graph) -> HashMap<ValueId, TensorDesc> {
+    let mut types = HashMap::new();
+    for node in &graph.nodes {
+        let desc = match &node.op {
+            OpKind::Input => graph.value_types.get(&node.output).cloned(),
+            _ => {
+                let args: Option<Vec<&TensorDesc>> =
+                    node.inputs.iter().map(|v| types.get(v)).collect();
+                args.and_then(|args| shape::infer_node(node, &args).ok())
+            }
+        };
+        if let Some(desc) = desc {
+            types.insert(node.output, desc);
+        }
+    }
+    types
+}
@@ -0,0 +1,166 @@
+//! Declarative tree patterns over the producers of a node's inputs, for
+//! peephole rewrites.
+//!
+//! ```text
+//! // Relu6(Relu(x))
+//! const P: Pattern = node(is_relu6, &[node(is_relu, &[ANY])]);
+//! ```
+
+use std::collections::HashMap;
+
+use crate::{Graph, OpKind, ValueId};
+
+#[derive(Clone, Copy)]
+pub(crate) enum Pattern {
+    /// Any value, bound in [`Match::values`]
+    Any,
+    /// A value produced by a node whose op satisfies `op` and whose inputs
+    /// match `inputs`, bound in [`Match::nodes`]. Commutative binary ops
+    /// also match with their inputs swapped.
+    Node {
+        op: fn(&OpKind) -> bool,
+        inputs: &'static [Pattern],
+        commutative: bool,
+    },
+}
+
+pub(crate) const ANY: Pattern = Pattern::Any;
+
+pub(crate) const fn node(op: fn(&OpKind) -> bool, inputs: &'static [Pattern]) -> Pattern {
+    Pattern::Node {
+        op,
+        inputs,
+        commutative: false,
+    }
+}
+
+pub(crate) const fn commutative(op: fn(&OpKind) -> bool, inputs: &'static [Pattern]) -> Pattern {
+    Pattern::Node {
+        op,
+        inputs,
+        commutative: true,
+    }
+}
+
+/// Bindings of a successful match, in pattern pre-order; `nodes[0]` is the
+/// root.
+#[derive(Debug, Default)]
+pub(crate) struct Match {
+    /// Indices into `graph.nodes`
+    pub nodes: Vec<usize>,
+    pub values: Vec<ValueId>,
+}
+
+impl Pattern {
+    /// Match with `graph.nodes[root]` as the root. `producers` maps each
+    /// value to the index of the node computing it.
+    pub(crate) fn match_node(
+        &self,
+        graph: &Graph,
+        producers: &HashMap<ValueId, usize>,
+        root: usize,
+    ) -> Option<Match> {
+        let mut m = Match::default();
+        self.match_at(graph, producers, root, &mut m).then_some(m)
+    }
+
+    fn match_at(
+        &self,
+        graph: &Graph,
+        producers: &HashMap<ValueId, usize>,
+        index: usize,
+        m: &mut Match,
+    ) -> bool {
+        let Pattern::Node {
+            op,
+            inputs,
+            commutative,
+        } = *self
+        else {
+            return false;
+        };
+        let node = &graph.nodes[index];
+        if !op(&node.op) || node.inputs.len() != inputs.len() {
+            return false;
+        }
+        let orders: &[[usize; 2]] = if commutative && inputs.len() == 2 {
+            &[[0, 1], [1, 0]]
+        } else {
+            &[[0, 1]]
+        };
+        let (nodes, values) = (m.nodes.len(), m.values.len());
+        for order in orders {
+            m.nodes.push(index);
+            let matched = inputs.iter().enumerate().all(|(i, pat)| {
+                let input = node.inputs[if i < 2 { order[i] } else { i }];
+                pat.bind(graph, producers, input, m)
+            });
+            if matched {
+                return true;
+            }
+            m.nodes.truncate(nodes);
+            m.values.truncate(values);
+        }
+        false
+    }
+
+    fn bind(
+        &self,
+        graph: &Graph,
+        producers: &HashMap<ValueId, usize>,
+        value: ValueId,
+        m: &mut Match,
+    ) -> bool {
+        match self {
+            Pattern::Any => {
+                m.values.push(value);
+                true
+            }
+            Pattern::Node { .. } => producers
+                .get(&value)
+                .is_some_and(|&i| self.match_at(graph, producers, i, m)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Node, NodeId, Tensor};
+
+    fn is_mul(op: &OpKind) -> bool {
+        matches!(op, OpKind::Mul)
+    }
+
+    fn is_constant(op: &OpKind) -> bool {
+        matches!(op, OpKind::Constant(_))
+    }
+
+    #[test]
+    fn binds_in_pattern_order() {
+        let graph_node = |op, inputs: &[u32], output| Node {
+            id: NodeId(output),
+            op,
+            inputs: inputs.iter().map(|&v| ValueId(v)).collect(),
+            output: ValueId(output),
+        };
+        let graph = Graph {
+            nodes: vec![
+                graph_node(OpKind::Input, &[], 0),
+                graph_node(OpKind::Constant(Tensor::ones(vec![1]).into()), &[], 1),
+                graph_node(OpKind::Mul, &[1, 0], 2),
+            ],
+            outputs: vec![ValueId(2)],
+            value_types: HashMap::new(),
+        };
+        let producers = HashMap::from([(ValueId(0), 0), (ValueId(1), 1), (ValueId(2), 2)]);
+
+        const P: Pattern = commutative(is_mul, &[ANY, node(is_constant, &[])]);
+        let m = P.match_node(&graph, &producers, 2).unwrap();
+        assert_eq!(m.nodes, vec![2, 1]);
+        assert_eq!(m.values, vec![ValueId(0)]);
+
+        const Q: Pattern = node(is_mul, &[ANY, node(is_constant, &[])]);
+        assert!(Q.match_node(&graph, &producers, 2).is_none());
+    }
+}
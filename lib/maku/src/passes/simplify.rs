@@ -0,0 +1,323 @@
+//! Algebraic simplification: peephole rewrites of identities that
+//! editor-built graphs often contain, written as [`Pattern`] rules.
+
+use std::collections::{HashMap, HashSet};
+
+use super::known_types;
+use super::pattern::{ANY, Match, Pattern, commutative, node};
+use crate::{Graph, NodeId, OpKind, ReshapeAttrs, Result, TensorDesc, ValueId};
+
+/// Applies the rules below to every node, repeatedly until none matches.
+///
+/// A node whose result equals an existing value is removed and its readers
+/// use that value instead, unless its output is a graph output. Nodes that
+/// lose their last reader are left for dead code elimination. Rules that
+/// compare shapes apply only where shapes are known from constants and the
+/// Input types in `graph.value_types`.
+#[derive(Debug, Clone, Default)]
+pub struct AlgebraicSimplification;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimplifyReport {
+    /// (rewritten node, rule name), in the order applied
+    pub rewrites: Vec<(NodeId, &'static str)>,
+}
+
+enum Rewrite {
+    /// The node computes this existing value
+    Forward(ValueId),
+    /// Replace the node's op and inputs
+    Replace(OpKind, Vec<ValueId>),
+}
+
+struct Ctx<'a> {
+    graph: &'a Graph,
+    types: &'a HashMap<ValueId, TensorDesc>,
+}
+
+impl Ctx<'_> {
+    fn op(&self, m: &Match, i: usize) -> &OpKind {
+        &self.graph.nodes[m.nodes[i]].op
+    }
+
+    fn output(&self, m: &Match, i: usize) -> ValueId {
+        self.graph.nodes[m.nodes[i]].output
+    }
+
+    fn same_shape(&self, a: ValueId, b: ValueId) -> bool {
+        match (self.types.get(&a), self.types.get(&b)) {
+            (Some(a), Some(b)) => a.shape == b.shape,
+            _ => false,
+        }
+    }
+}
+
+struct Rule {
+    name: &'static str,
+    pattern: Pattern,
+    rewrite: fn(&Match, &Ctx) -> Option<Rewrite>,
+}
+
+// ---------- Rules ----------
+
+const RULES: &[Rule] = &[
+    Rule {
+        name: "mul-one",
+        pattern: commutative(|op| matches!(op, OpKind::Mul), &[ANY, node(is_ones, &[])]),
+        rewrite: forward_same_shape,
+    },
+    Rule {
+        name: "add-zero",
+        pattern: commutative(|op| matches!(op, OpKind::Add), &[ANY, node(is_zeros, &[])]),
+        rewrite: forward_same_shape,
+    },
+    Rule {
+        name: "transpose-inverse",
+        pattern: node(is_transpose, &[node(is_transpose, &[ANY])]),
+        rewrite: |m, ctx| {
+            let (OpKind::Transpose(outer), OpKind::Transpose(inner)) = (ctx.op(m, 0), ctx.op(m, 1))
+            else {
+                return None;
+            };
+            // y[i] = x[inner[outer[i]]]
+            let identity = outer.perm.len() == inner.perm.len()
+                && outer
+                    .perm
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &a)| inner.perm.get(a) == Some(&i));
+            identity.then_some(Rewrite::Forward(m.values[0]))
+        },
+    },
+    Rule {
+        name: "transpose-identity",
+        pattern: node(is_transpose, &[ANY]),
+        rewrite: |m, ctx| {
+            let OpKind::Transpose(attrs) = ctx.op(m, 0) else {
+                return None;
+            };
+            let identity = attrs.perm.iter().enumerate().all(|(i, &a)| i == a);
+            identity.then_some(Rewrite::Forward(m.values[0]))
+        },
+    },
+    Rule {
+        name: "reshape-same-shape",
+        pattern: node(is_reshape, &[ANY]),
+        rewrite: forward_same_shape,
+    },
+    Rule {
+        name: "reshape-reshape",
+        pattern: node(is_reshape, &[node(is_reshape, &[ANY])]),
+        rewrite: |m, ctx| {
+            let OpKind::Reshape(outer) = ctx.op(m, 0) else {
+                return None;
+            };
+            // A 0 copies a dim of the inner Reshape's result; spell out
+            // the result shape instead when it is known
+            let copies_dims = !outer.allowzero && outer.shape.contains(&0);
+            let attrs = if !copies_dims {
+                outer.clone()
+            } else {
+                let shape = &ctx.types.get(&ctx.output(m, 0))?.shape;
+                ReshapeAttrs {
+                    shape: shape.iter().map(|&d| d as isize).collect(),
+                    allowzero: true,
+                }
+            };
+            Some(Rewrite::Replace(OpKind::Reshape(attrs), vec![m.values[0]]))
+        },
+    },
+    Rule {
+        name: "relu-relu",
+        pattern: node(is_relu, &[node(is_relu, &[ANY])]),
+        rewrite: |m, ctx| Some(Rewrite::Forward(ctx.output(m, 1))),
+    },
+    Rule {
+        name: "relu6-relu",
+        pattern: node(|op| matches!(op, OpKind::Relu6), &[node(is_relu, &[ANY])]),
+        rewrite: |m, _| Some(Rewrite::Replace(OpKind::Relu6, vec![m.values[0]])),
+    },
+];
+
+fn is_relu(op: &OpKind) -> bool {
+    matches!(op, OpKind::Relu)
+}
+
+fn is_reshape(op: &OpKind) -> bool {
+    matches!(op, OpKind::Reshape(_))
+}
+
+fn is_transpose(op: &OpKind) -> bool {
+    matches!(op, OpKind::Transpose(_))
+}
+
+fn is_ones(op: &OpKind) -> bool {
+    matches!(op, OpKind::Constant(t) if t.data.iter().all(|&x| x == 1.0))
+}
+
+fn is_zeros(op: &OpKind) -> bool {
+    matches!(op, OpKind::Constant(t) if t.data.iter().all(|&x| x == 0.0))
+}
+
+/// The root is the identity on `values[0]` when both have the same shape.
+fn forward_same_shape(m: &Match, ctx: &Ctx) -> Option<Rewrite> {
+    let x = m.values[0];
+    ctx.same_shape(x, ctx.output(m, 0))
+        .then_some(Rewrite::Forward(x))
+}
+
+impl AlgebraicSimplification {
+    pub fn run(&self, graph: &mut Graph) -> Result<SimplifyReport> {
+        let types = known_types(graph);
+        let outputs: HashSet<ValueId> = graph.outputs.iter().copied().collect();
+        let mut producers: HashMap<ValueId, usize> = HashMap::new();
+        let mut renamed: HashMap<ValueId, ValueId> = HashMap::new();
+        let mut removed = HashSet::new();
+        let mut report = SimplifyReport::default();
+
+        for j in 0..graph.nodes.len() {
+            for input in &mut graph.nodes[j].inputs {
+                if let Some(&v) = renamed.get(input) {
+                    *input = v;
+                }
+            }
+            while let Some((name, rewrite)) = apply_first(graph, &types, &producers, j) {
+                let node = &mut graph.nodes[j];
+                match rewrite {
+                    Rewrite::Forward(_) if outputs.contains(&node.output) => break,
+                    Rewrite::Forward(value) => {
+                        renamed.insert(node.output, value);
+                        removed.insert(node.id);
+                        report.rewrites.push((node.id, name));
+                        break;
+                    }
+                    Rewrite::Replace(op, inputs) => {
+                        node.op = op;
+                        node.inputs = inputs;
+                        report.rewrites.push((node.id, name));
+                    }
+                }
+            }
+            if !removed.contains(&graph.nodes[j].id) {
+                producers.insert(graph.nodes[j].output, j);
+            }
+        }
+
+        graph.nodes.retain(|n| !removed.contains(&n.id));
+        graph.value_types.retain(|v, _| !renamed.contains_key(v));
+        Ok(report)
+    }
+}
+
+/// The first rule matching at `graph.nodes[j]` and its rewrite.
+fn apply_first(
+    graph: &Graph,
+    types: &HashMap<ValueId, TensorDesc>,
+    producers: &HashMap<ValueId, usize>,
+    j: usize,
+) -> Option<(&'static str, Rewrite)> {
+    let ctx = Ctx { graph, types };
+    RULES.iter().find_map(|rule| {
+        let m = rule.pattern.match_node(graph, producers, j)?;
+        (rule.rewrite)(&m, &ctx).map(|r| (rule.name, r))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::node;
+    use crate::{CpuBackend, DType, Tensor, TransposeAttrs};
+
+    fn transpose(perm: Vec<usize>) -> OpKind {
+        OpKind::Transpose(TransposeAttrs { perm })
+    }
+
+    fn reshape(shape: Vec<isize>) -> OpKind {
+        OpKind::Reshape(ReshapeAttrs {
+            shape,
+            allowzero: false,
+        })
+    }
+
+    fn constant(t: Tensor) -> OpKind {
+        OpKind::Constant(t.into())
+    }
+
+    #[test]
+    fn simplifies_identities() {
+        let x_desc = TensorDesc {
+            dtype: DType::F32,
+            shape: vec![2, 3, 4],
+        };
+        let mut g = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(constant(Tensor::ones(vec![2, 3, 4])), &[], 1),
+                node(OpKind::Mul, &[1, 0], 2),
+                node(constant(Tensor::zeros(vec![2, 3, 4])), &[], 3),
+                node(OpKind::Add, &[2, 3], 4),
+                node(transpose(vec![1, 2, 0]), &[4], 5),
+                node(transpose(vec![2, 0, 1]), &[5], 6),
+                node(reshape(vec![6, 4]), &[6], 7),
+                node(reshape(vec![0, 2, 2]), &[7], 8),
+                node(reshape(vec![6, 2, 2]), &[8], 9),
+                node(OpKind::Relu, &[9], 10),
+                node(OpKind::Relu, &[10], 11),
+                node(OpKind::Relu6, &[11], 12),
+                node(constant(Tensor::full(vec![6, 2, 2], 0.5)), &[], 13),
+                node(OpKind::Add, &[12, 13], 14),
+            ],
+            outputs: vec![ValueId(14)],
+            value_types: HashMap::from([(ValueId(0), x_desc)]),
+        };
+        let x = Tensor::from_fn(vec![2, 3, 4], |i| {
+            (i[0] * 12 + i[1] * 4 + i[2]) as f32 - 11.5
+        });
+        let inputs = HashMap::from([(ValueId(0), x)]);
+        let expected = CpuBackend::new().run(&g, &inputs).unwrap();
+
+        let report = AlgebraicSimplification.run(&mut g).unwrap();
+        assert_eq!(
+            report.rewrites,
+            vec![
+                (NodeId(2), "mul-one"),
+                (NodeId(4), "add-zero"),
+                (NodeId(6), "transpose-inverse"),
+                (NodeId(8), "reshape-reshape"),
+                (NodeId(9), "reshape-same-shape"),
+                (NodeId(11), "relu-relu"),
+                (NodeId(12), "relu6-relu"),
+            ]
+        );
+        let find = |id| g.nodes.iter().find(|n| n.id == NodeId(id)).unwrap();
+        assert_eq!(find(12).inputs, vec![ValueId(8)]);
+        assert_eq!(find(8).inputs, vec![ValueId(0)]);
+        assert!(matches!(
+            &find(8).op,
+            OpKind::Reshape(a) if a.shape == [6, 2, 2] && a.allowzero
+        ));
+
+        let actual = CpuBackend::new().run(&g, &inputs).unwrap();
+        assert_eq!(actual[&ValueId(14)].data, expected[&ValueId(14)].data);
+    }
+
+    #[test]
+    fn keeps_graph_outputs_and_unknown_shapes() {
+        // The Reshape's input type is unknown, so it cannot be shown to be
+        // a no-op; Relu 2 duplicates Relu 1 but is a graph output
+        let mut g = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Relu, &[0], 1),
+                node(OpKind::Relu, &[1], 2),
+                node(reshape(vec![-1]), &[0], 3),
+            ],
+            outputs: vec![ValueId(2), ValueId(3)],
+            value_types: HashMap::new(),
+        };
+        let report = AlgebraicSimplification.run(&mut g).unwrap();
+        assert!(report.rewrites.is_empty());
+        assert_eq!(g.nodes.len(), 4);
+    }
+}
@@ -0,0 +1,379 @@
+//! Dependency-driven inter-op scheduler.
+//!
+//! A node becomes ready once every value it reads has been produced, and
+//! ready nodes are spawned onto the backend's [`ThreadPool`], so independent
+//! branches (e.g. Inception-style parallel convs) run concurrently. Each node
+//! is evaluated by the same `CpuBackend::eval_node` as the sequential
+//! executor, so outputs are identical. Constants are read from the graph in
+//! place and never scheduled, and intermediates are dropped once their last
+//! reader has run.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::error::{Result, ensure, err};
+use crate::{CpuBackend, Error, Graph, OpKind, Tensor, TensorView, ThreadPool, ValueId};
+
+/// Where a value comes from
+#[derive(Clone, Copy)]
+enum Source {
+    /// Output of `graph.nodes[i]`
+    Node(usize),
+    /// Constant held by `graph.nodes[i]`
+    Const(usize),
+    /// Caller-provided input tensor
+    Input,
+}
+
+/// `'a` borrows the graph and inputs; `'c` the backend, which may be shorter
+struct State<'c, 'a> {
+    cpu: &'c CpuBackend,
+    graph: &'a Graph,
+    inputs: &'a HashMap<ValueId, Tensor>,
+    sources: HashMap<ValueId, Source>,
+    /// Nodes reading each node's output (one entry per input slot)
+    consumers: Vec<Vec<usize>>,
+    /// Unproduced inputs left per node
+    pending: Vec<AtomicUsize>,
+    /// Reads of each node's output still to run; graph outputs hold one
+    /// extra that is never released
+    readers: Vec<AtomicUsize>,
+    /// Output of each node from when it has run until its last reader has
+    results: Vec<Mutex<Option<TensorView<'a>>>>,
+    completed: AtomicUsize,
+    failed: AtomicBool,
+    /// First error by node order, so reporting doesn't depend on timing
+    error: Mutex<Option<(usize, Error)>>,
+}
+
+impl<'a> State<'_, 'a> {
+    fn value(&self, id: ValueId) -> TensorView<'a> {
+        match self.sources[&id] {
+            Source::Node(i) => self.results[i]
+                .lock()
+                .unwrap()
+                .clone()
+                .expect("scheduled before its inputs"),
+            Source::Const(i) => constant(self.graph, i).view(),
+            Source::Input => self.inputs[&id].view(),
+        }
+    }
+
+    /// One read of `graph.nodes[i]`'s output has finished.
+    fn release(&self, i: usize) {
+        if self.readers[i].fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.results[i].lock().unwrap().take();
+        }
+    }
+
+    fn fail(&self, node: usize, err: Error) {
+        self.failed.store(true, Ordering::SeqCst);
+        let mut slot = self.error.lock().unwrap();
+        if slot.as_ref().is_none_or(|(i, _)| node < *i) {
+            *slot = Some((node, err));
+        }
+    }
+}
+
+/// Execute `graph` with ready nodes running concurrently on `pool`.
+pub(crate) fn run<'a>(
+    cpu: &CpuBackend,
+    pool: &ThreadPool,
+    graph: &'a Graph,
+    inputs: &'a HashMap<ValueId, Tensor>,
+) -> Result<HashMap<ValueId, TensorView<'a>>> {
+    let n = graph.nodes.len();
+
+    let mut sources: HashMap<ValueId, Source> =
+        inputs.keys().map(|&id| (id, Source::Input)).collect();
+    let mut computed = 0;
+    for (i, node) in graph.nodes.iter().enumerate() {
+        let source = match node.op {
+            OpKind::Input => continue,
+            OpKind::Constant(_) => Source::Const(i),
+            _ => {
+                computed += 1;
+                Source::Node(i)
+            }
+        };
+        if let Some(Source::Node(j) | Source::Const(j)) = sources.insert(node.output, source) {
+            let e = err!(
+                InvalidGraph,
+                "{:?} is produced by both {:?} and {:?}",
+                node.output,
+                graph.nodes[j].id,
+                node.id
+            );
+            return Err(e.at(node));
+        }
+    }
+
+    let mut consumers = vec![Vec::new(); n];
+    let mut pending = Vec::with_capacity(n);
+    for (i, node) in graph.nodes.iter().enumerate() {
+        let mut count = 0;
+        if runs(node) {
+            for id in &node.inputs {
+                match sources.get(id) {
+                    Some(Source::Node(j)) => {
+                        consumers[*j].push(i);
+                        count += 1;
+                    }
+                    Some(Source::Const(_) | Source::Input) => {}
+                    None => {
+                        return Err(Error::MissingInput {
+                            node: Some(node.into()),
+                            value: *id,
+                        });
+                    }
+                }
+            }
+        }
+        pending.push(AtomicUsize::new(count));
+    }
+    let outputs: HashSet<ValueId> = graph.outputs.iter().copied().collect();
+    let readers = graph
+        .nodes
+        .iter()
+        .zip(&consumers)
+        .map(|(node, c)| AtomicUsize::new(c.len() + outputs.contains(&node.output) as usize))
+        .collect();
+
+    let state = State {
+        cpu,
+        graph,
+        inputs,
+        sources,
+        consumers,
+        pending,
+        readers,
+        results: (0..n).map(|_| Mutex::new(None)).collect(),
+        completed: AtomicUsize::new(0),
+        failed: AtomicBool::new(false),
+        error: Mutex::new(None),
+    };
+
+    pool.scope(|scope| {
+        let state = &state;
+        for (i, node) in graph.nodes.iter().enumerate() {
+            if runs(node) && state.pending[i].load(Ordering::SeqCst) == 0 {
+                scope.spawn(move |scope| execute(scope, state, i));
+            }
+        }
+    });
+
+    if let Some((_, err)) = state.error.lock().unwrap().take() {
+        return Err(err);
+    }
+    let completed = state.completed.load(Ordering::SeqCst);
+    ensure!(
+        completed == computed,
+        InvalidGraph,
+        "graph has a cycle: {} of {} nodes never became ready",
+        computed - completed,
+        computed
+    );
+
+    // Move computed outputs out of their slots instead of copying them
+    let State {
+        sources, results, ..
+    } = state;
+    let mut results: Vec<Option<TensorView>> = results
+        .into_iter()
+        .map(|r| r.into_inner().unwrap())
+        .collect();
+    let mut outputs = HashMap::new();
+    for &vid in &graph.outputs {
+        let t = match sources.get(&vid) {
+            _ if outputs.contains_key(&vid) => continue,
+            Some(Source::Node(i)) => results[*i].take().expect("node completed"),
+            Some(Source::Const(i)) => constant(graph, *i).view(),
+            Some(Source::Input) => inputs[&vid].view(),
+            None => return Err(Error::MissingOutput { value: vid }),
+        };
+        outputs.insert(vid, t);
+    }
+    Ok(outputs)
+}
+
+fn constant(graph: &Graph, i: usize) -> &Tensor {
+    match &graph.nodes[i].op {
+        OpKind::Constant(t) => t,
+        _ => unreachable!("Source::Const points at a Constant node"),
+    }
+}
+
+/// Input and Constant nodes provide values without being evaluated
+fn runs(node: &crate::Node) -> bool {
+    !matches!(node.op, OpKind::Input | OpKind::Constant(_))
+}
+
+fn execute<'s, 'a: 's>(scope: &rayon::Scope<'s>, state: &'s State<'s, 'a>, i: usize) {
+    if state.failed.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let node = &state.graph.nodes[i];
+    let views: Vec<TensorView> = node.inputs.iter().map(|&id| state.value(id)).collect();
+    let args: Vec<&TensorView> = views.iter().collect();
+    let out = match state.cpu.eval_node(node, &args) {
+        Ok(out) => out,
+        Err(err) => return state.fail(i, err.at(node)),
+    };
+    drop(views);
+    for id in &node.inputs {
+        if let Source::Node(j) = state.sources[id] {
+            state.release(j);
+        }
+    }
+    // Readers only start after this, so a zero count means there are none
+    if state.readers[i].load(Ordering::SeqCst) > 0 {
+        *state.results[i].lock().unwrap() = Some(out);
+    }
+    state.completed.fetch_add(1, Ordering::SeqCst);
+
+    for &c in &state.consumers[i] {
+        if state.pending[c].fetch_sub(1, Ordering::SeqCst) == 1 {
+            scope.spawn(move |scope| execute(scope, state, c));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConcatAttrs, Conv2DAttrs, DType, Node, NodeId, TensorDesc};
+
+    fn node(id: u32, op: OpKind, inputs: &[u32], output: u32) -> Node {
+        Node {
+            id: NodeId(id),
+            op,
+            inputs: inputs.iter().map(|&v| ValueId(v)).collect(),
+            output: ValueId(output),
+        }
+    }
+
+    fn tensor(shape: Vec<usize>, seed: usize) -> Tensor {
+        let len = shape.iter().product();
+        let data = (0..len)
+            .map(|i| ((i * 31 + seed * 7) % 23) as f32 * 0.05 - 0.5)
+            .collect();
+        Tensor::new(
+            TensorDesc {
+                dtype: DType::F32,
+                shape,
+            },
+            data,
+        )
+    }
+
+    fn conv(k: usize) -> OpKind {
+        OpKind::Conv2D(Conv2DAttrs {
+            kernel_shape: [k, k],
+            strides: [1, 1],
+            pads: [k / 2; 4],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        })
+    }
+
+    /// Inception-style block: three parallel conv branches merged by an Add
+    /// and a channel Concat.
+    fn branchy_graph() -> Graph {
+        Graph {
+            nodes: vec![
+                node(0, OpKind::Input, &[], 0),
+                node(
+                    1,
+                    OpKind::Constant(tensor(vec![1, 1, 8, 8], 1).into()),
+                    &[],
+                    1,
+                ),
+                node(
+                    2,
+                    OpKind::Constant(tensor(vec![3, 3, 8, 8], 2).into()),
+                    &[],
+                    2,
+                ),
+                node(
+                    3,
+                    OpKind::Constant(tensor(vec![5, 5, 8, 8], 3).into()),
+                    &[],
+                    3,
+                ),
+                node(4, conv(1), &[0, 1], 4),
+                node(5, conv(3), &[0, 2], 5),
+                node(6, conv(5), &[0, 3], 6),
+                node(7, OpKind::Relu, &[5], 7),
+                node(8, OpKind::Add, &[4, 7], 8),
+                node(9, OpKind::Concat(ConcatAttrs { axis: 3 }), &[8, 6], 9),
+                node(10, OpKind::HardSwish, &[9], 10),
+            ],
+            outputs: vec![ValueId(10), ValueId(6)],
+            value_types: HashMap::new(),
+        }
+    }
+
+    fn inputs() -> HashMap<ValueId, Tensor> {
+        HashMap::from([(ValueId(0), tensor(vec![2, 16, 16, 8], 4))])
+    }
+
+    #[test]
+    fn matches_sequential_executor() {
+        let graph = branchy_graph();
+        let expected = CpuBackend::new().run(&graph, &inputs()).unwrap();
+        for threads in [2, 3, 4] {
+            let backend = CpuBackend::new()
+                .with_threads(threads)
+                .unwrap()
+                .with_inter_op(true);
+            let actual = backend.run(&graph, &inputs()).unwrap();
+            assert_eq!(actual.len(), expected.len());
+            for (id, t) in &expected {
+                assert_eq!(actual[id].data, t.data, "threads={} {:?}", threads, id);
+            }
+        }
+    }
+
+    #[test]
+    fn runs_nodes_listed_out_of_order() {
+        let mut graph = branchy_graph();
+        graph.nodes.reverse();
+        let backend = CpuBackend::new()
+            .with_threads(2)
+            .unwrap()
+            .with_inter_op(true);
+        let actual = backend.run(&graph, &inputs()).unwrap();
+        let expected = CpuBackend::new().run(&branchy_graph(), &inputs()).unwrap();
+        assert_eq!(actual[&ValueId(10)].data, expected[&ValueId(10)].data);
+    }
+
+    #[test]
+    fn reports_missing_inputs_and_cycles() {
+        let backend = CpuBackend::new()
+            .with_threads(2)
+            .unwrap()
+            .with_inter_op(true);
+
+        let missing = Graph {
+            nodes: vec![node(0, OpKind::Relu, &[42], 1)],
+            outputs: vec![ValueId(1)],
+            value_types: HashMap::new(),
+        };
+        let err = backend.run(&missing, &HashMap::new()).unwrap_err();
+        assert!(err.to_string().contains("missing input"), "{}", err);
+
+        let cycle = Graph {
+            nodes: vec![
+                node(0, OpKind::Relu, &[2], 1),
+                node(1, OpKind::Relu, &[1], 2),
+            ],
+            outputs: vec![ValueId(2)],
+            value_types: HashMap::new(),
+        };
+        let err = backend.run(&cycle, &HashMap::new()).unwrap_err();
+        assert!(err.to_string().contains("cycle"), "{}", err);
+    }
+}
@@ -0,0 +1,505 @@
+//! Compiled execution plans.
+//!
+//! [`CpuBackend::compile`] validates a graph, infers every shape and plans
+//! memory once, lowering the graph to a flat list of instructions whose
+//! operands are pre-resolved: session inputs and constants by index,
+//! intermediates by arena range. [`Session::run`] then executes that list
+//! without hashing value ids or allocating intermediates, so running the same
+//! model on every video frame only pays for the kernels.
+//!
+//! Compiling does not rewrite the graph: optimize it first with a
+//! [`PassManager`](crate::passes::PassManager), as `WasmSession` does.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::error::{Result, bail, ensure, err};
+use crate::memory::{self, MemoryPlan};
+use crate::simd::{self, ConvParams, SimdLevel};
+use crate::{
+    Activation, CpuBackend, Error, FusedStep, Graph, Node, OpKind, Tensor, TensorDesc, TensorView,
+    ValueId, shape,
+};
+
+/// Where an instruction reads a value from
+#[derive(Debug, Clone)]
+enum Operand {
+    /// `i`-th session input
+    Input(usize),
+    /// `i`-th constant
+    Const(usize),
+    /// Slice of the arena
+    Arena(Range<usize>),
+}
+
+enum Kernel {
+    Binary(fn(SimdLevel, &[f32], &[f32], &mut [f32])),
+    /// Output overwrites one operand; `args[0]` is the other
+    BinaryInPlace(fn(SimdLevel, &mut [f32], &[f32])),
+    Unary(fn(SimdLevel, &[f32], &mut [f32])),
+    /// Output overwrites the operand; no `args`
+    UnaryInPlace(fn(SimdLevel, &mut [f32])),
+    MatMul {
+        m: usize,
+        k: usize,
+        n: usize,
+        activation: Option<Activation>,
+    },
+    Conv2D(ConvParams),
+    DepthwiseConv2D(ConvParams),
+    FusedElementwise(Vec<FusedStep>),
+    /// Ops without an arena kernel: evaluated by `CpuBackend::eval_node` on
+    /// views of the arena, the result copied back
+    Node {
+        node: Node,
+        args: Vec<TensorDesc>,
+    },
+}
+
+struct Instr {
+    kernel: Kernel,
+    args: Vec<Operand>,
+    out: Range<usize>,
+}
+
+/// A graph compiled for fixed input shapes, ready to run repeatedly.
+pub struct Session {
+    backend: CpuBackend,
+    instrs: Vec<Instr>,
+    inputs: Vec<(ValueId, TensorDesc)>,
+    outputs: Vec<(ValueId, Operand, TensorDesc)>,
+    /// Shared with the graph, not copied
+    consts: Vec<Arc<Tensor>>,
+    arena: Vec<f32>,
+    plan: MemoryPlan,
+}
+
+impl Session {
+    /// Lower `graph` for `backend`. Input shapes come from `inputs`, falling
+    /// back to `graph.value_types`.
+    pub(crate) fn compile(
+        backend: CpuBackend,
+        graph: &Graph,
+        inputs: &HashMap<ValueId, TensorDesc>,
+    ) -> Result<Session> {
+        let types = shape::infer_shapes(graph, inputs)?;
+        let plan = memory::plan_memory(graph, &types)?;
+
+        let mut session = Session {
+            backend,
+            instrs: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            consts: Vec::new(),
+            arena: vec![0.0; plan.arena_len],
+            plan: MemoryPlan::default(),
+        };
+        let mut operands: HashMap<ValueId, Operand> = HashMap::new();
+
+        for node in &graph.nodes {
+            let operand = match &node.op {
+                OpKind::Input => {
+                    session
+                        .inputs
+                        .push((node.output, types[&node.output].clone()));
+                    Operand::Input(session.inputs.len() - 1)
+                }
+                OpKind::Constant(t) => {
+                    session.consts.push(Arc::clone(t));
+                    Operand::Const(session.consts.len() - 1)
+                }
+                _ => {
+                    let args = node
+                        .inputs
+                        .iter()
+                        .map(|id| {
+                            operands.get(id).cloned().ok_or(Error::MissingInput {
+                                node: Some(node.into()),
+                                value: *id,
+                            })
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    let buffer = plan.buffers[&node.output];
+                    let out = buffer.offset..buffer.offset + buffer.len;
+                    let alias = plan.aliases.get(&node.output);
+                    let slot = alias.and_then(|a| node.inputs.iter().position(|id| id == a));
+                    let (kernel, args) = lower(node, args, slot, &types).map_err(|e| e.at(node))?;
+                    session.instrs.push(Instr {
+                        kernel,
+                        args,
+                        out: out.clone(),
+                    });
+                    Operand::Arena(out)
+                }
+            };
+            if operands.insert(node.output, operand).is_some() {
+                let e = err!(InvalidGraph, "{:?} is produced more than once", node.output);
+                return Err(e.at(node));
+            }
+        }
+
+        for &id in &graph.outputs {
+            let operand = operands
+                .get(&id)
+                .cloned()
+                .ok_or(Error::MissingOutput { value: id })?;
+            session.outputs.push((id, operand, types[&id].clone()));
+        }
+
+        session.plan = plan;
+        Ok(session)
+    }
+
+    /// Session inputs in the order `run_ordered` expects them
+    pub fn inputs(&self) -> &[(ValueId, TensorDesc)] {
+        &self.inputs
+    }
+
+    /// Graph outputs in the order `run_ordered` returns them
+    pub fn output_ids(&self) -> Vec<ValueId> {
+        self.outputs.iter().map(|(id, _, _)| *id).collect()
+    }
+
+    pub fn memory_plan(&self) -> &MemoryPlan {
+        &self.plan
+    }
+
+    /// Execute with inputs keyed by value id, like [`CpuBackend::run`].
+    pub fn run(&mut self, inputs: &HashMap<ValueId, Tensor>) -> Result<HashMap<ValueId, Tensor>> {
+        let ordered = self
+            .inputs
+            .iter()
+            .map(|(id, _)| {
+                inputs.get(id).ok_or(Error::MissingInput {
+                    node: None,
+                    value: *id,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let outputs = self.run_ordered(&ordered)?;
+        Ok(self.output_ids().into_iter().zip(outputs).collect())
+    }
+
+    /// Execute with inputs in [`Session::inputs`] order, returning outputs in
+    /// [`Session::output_ids`] order.
+    pub fn run_ordered(&mut self, inputs: &[&Tensor]) -> Result<Vec<Tensor>> {
+        let outputs = self.run_borrowed(inputs)?;
+        Ok(outputs.iter().map(TensorRef::to_tensor).collect())
+    }
+
+    /// Like [`Session::run_ordered`], but outputs borrow the session arena
+    /// (or the inputs and constants) instead of being copied. They stay
+    /// valid until the next run.
+    pub fn run_borrowed<'s>(&'s mut self, inputs: &[&'s Tensor]) -> Result<Vec<TensorRef<'s>>> {
+        ensure!(
+            inputs.len() == self.inputs.len(),
+            ShapeMismatch,
+            "expected {} inputs, got {}",
+            self.inputs.len(),
+            inputs.len()
+        );
+        for ((id, desc), t) in self.inputs.iter().zip(inputs) {
+            ensure!(
+                t.desc.shape == desc.shape,
+                ShapeMismatch,
+                "input {:?} has shape {:?}, compiled for {:?}",
+                id,
+                t.desc.shape,
+                desc.shape
+            );
+        }
+
+        let cpu = &self.backend;
+        for instr in &self.instrs {
+            let (head, rest) = self.arena.split_at_mut(instr.out.start);
+            let (out, tail) = rest.split_at_mut(instr.out.len());
+            let frame = Frame {
+                inputs,
+                consts: &self.consts,
+                head,
+                tail,
+                out: instr.out.clone(),
+            };
+            let arg = |i: usize| frame.get(&instr.args[i]);
+
+            match &instr.kernel {
+                Kernel::Binary(kernel) => crate::binary(cpu, *kernel, arg(0)?, arg(1)?, out),
+                Kernel::BinaryInPlace(kernel) => crate::binary_inplace(cpu, *kernel, out, arg(0)?),
+                Kernel::Unary(kernel) => crate::unary(cpu, *kernel, arg(0)?, out),
+                Kernel::UnaryInPlace(kernel) => crate::unary_inplace(cpu, *kernel, out),
+                Kernel::MatMul {
+                    m,
+                    k,
+                    n,
+                    activation,
+                } => crate::matmul_into(cpu, arg(0)?, arg(1)?, out, *m, *k, *n, *activation),
+                Kernel::Conv2D(p) => {
+                    let bias = instr.args.get(2).map(|a| frame.get(a)).transpose()?;
+                    crate::conv2d_into(cpu, arg(0)?, arg(1)?, bias, out, p)
+                }
+                Kernel::DepthwiseConv2D(p) => {
+                    let bias = instr.args.get(2).map(|a| frame.get(a)).transpose()?;
+                    crate::depthwise_conv2d_into(cpu, arg(0)?, arg(1)?, bias, out, p)
+                }
+                Kernel::FusedElementwise(steps) => {
+                    let args = (0..instr.args.len()).map(arg).collect::<Result<Vec<_>>>()?;
+                    crate::fused_elementwise_into(cpu, steps, &args, out)
+                }
+                Kernel::Node { node, args } => {
+                    let views = args
+                        .iter()
+                        .enumerate()
+                        .map(|(i, desc)| TensorView::from_slice(arg(i)?, desc.shape.clone()))
+                        .collect::<Result<Vec<_>>>()?;
+                    let refs: Vec<&TensorView> = views.iter().collect();
+                    let result = cpu.eval_node(node, &refs).map_err(|e| e.at(node))?;
+                    ensure!(
+                        result.len() == out.len(),
+                        Internal,
+                        "{} produced {} elements, planned {}",
+                        node.op.name(),
+                        result.len(),
+                        out.len()
+                    );
+                    out.copy_from_slice(&result.contiguous());
+                }
+            }
+        }
+
+        let frame = Frame {
+            inputs,
+            consts: &self.consts,
+            head: &self.arena,
+            tail: &[],
+            out: self.arena.len()..self.arena.len(),
+        };
+        self.outputs
+            .iter()
+            .map(|(_, operand, desc)| {
+                Ok(TensorRef {
+                    desc,
+                    data: frame.get(operand)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A session output borrowed instead of copied
+#[derive(Debug, Clone, Copy)]
+pub struct TensorRef<'a> {
+    pub desc: &'a TensorDesc,
+    pub data: &'a [f32],
+}
+
+impl TensorRef<'_> {
+    /// Copy into an owned tensor.
+    pub fn to_tensor(&self) -> Tensor {
+        Tensor::new(self.desc.clone(), self.data.to_vec())
+    }
+}
+
+/// Choose the kernel for `node` and the operands it reads. `slot` is the
+/// input the output overwrites, if the memory plan runs the node in place.
+fn lower(
+    node: &Node,
+    mut args: Vec<Operand>,
+    slot: Option<usize>,
+    types: &HashMap<ValueId, TensorDesc>,
+) -> Result<(Kernel, Vec<Operand>)> {
+    let shape = |i: usize| types[&node.inputs[i]].shape.as_slice();
+    if let Some(slot) = slot {
+        args.remove(slot);
+    }
+
+    let kernel = match (&node.op, slot) {
+        (OpKind::Add, None) => Kernel::Binary(simd::add),
+        (OpKind::Add, Some(_)) => Kernel::BinaryInPlace(simd::add_inplace),
+        (OpKind::Mul, None) => Kernel::Binary(simd::mul),
+        (OpKind::Mul, Some(_)) => Kernel::BinaryInPlace(simd::mul_inplace),
+        (OpKind::Relu, None) => Kernel::Unary(simd::relu),
+        (OpKind::Relu, Some(_)) => Kernel::UnaryInPlace(simd::relu_inplace),
+        (OpKind::Relu6, None) => Kernel::Unary(simd::relu6),
+        (OpKind::Relu6, Some(_)) => Kernel::UnaryInPlace(simd::relu6_inplace),
+        (OpKind::HardSwish, None) => Kernel::Unary(simd::hard_swish),
+        (OpKind::HardSwish, Some(_)) => Kernel::UnaryInPlace(simd::hard_swish_inplace),
+        // Transposed MatMul reads strided views through `Kernel::Node`
+        (OpKind::MatMul(attrs), None)
+            if attrs.as_ref().is_none_or(|a| !a.trans_a && !a.trans_b) =>
+        {
+            Kernel::MatMul {
+                activation: attrs.as_ref().and_then(|a| a.activation),
+                m: shape(0)[0],
+                k: shape(0)[1],
+                n: shape(1)[1],
+            }
+        }
+        (OpKind::Conv2D(attrs), None) => {
+            let bias = (node.inputs.len() > 2).then(|| shape(2));
+            Kernel::Conv2D(crate::conv2d_params(shape(0), shape(1), bias, attrs)?)
+        }
+        (OpKind::DepthwiseConv2D(attrs), None) => {
+            let bias = (node.inputs.len() > 2).then(|| shape(2));
+            Kernel::DepthwiseConv2D(crate::depthwise_conv2d_params(
+                shape(0),
+                shape(1),
+                bias,
+                attrs,
+            )?)
+        }
+        (OpKind::FusedElementwise(attrs), None) => Kernel::FusedElementwise(attrs.steps.clone()),
+        (op, Some(_)) => bail!(UnsupportedOp, "{} cannot run in place", op.name()),
+        (_, None) => Kernel::Node {
+            node: node.clone(),
+            args: node.inputs.iter().map(|id| types[id].clone()).collect(),
+        },
+    };
+    Ok((kernel, args))
+}
+
+/// Read access to every operand while one instruction writes `out`, the
+/// arena range between `head` and `tail`.
+struct Frame<'a, 'i> {
+    inputs: &'i [&'a Tensor],
+    consts: &'a [Arc<Tensor>],
+    head: &'a [f32],
+    tail: &'a [f32],
+    out: Range<usize>,
+}
+
+impl<'a> Frame<'a, '_> {
+    fn get(&self, operand: &Operand) -> Result<&'a [f32]> {
+        Ok(match operand {
+            Operand::Input(i) => &self.inputs[*i].data,
+            Operand::Const(i) => &self.consts[*i].data,
+            // The memory plan never places a live operand over the output
+            Operand::Arena(r) if r.end <= self.out.start => &self.head[r.clone()],
+            Operand::Arena(r) => {
+                ensure!(
+                    r.start >= self.out.end,
+                    Internal,
+                    "operand {:?} overlaps output {:?}",
+                    r,
+                    self.out
+                );
+                &self.tail[r.start - self.out.end..r.end - self.out.end]
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{node, tensor};
+    use crate::{Conv2DAttrs, DType};
+
+    /// Conv -> HardSwish, a residual Add with a Relu branch, and a MatMul head.
+    fn graph() -> Graph {
+        let conv = OpKind::Conv2D(Conv2DAttrs {
+            kernel_shape: [3, 3],
+            strides: [1, 1],
+            pads: [1, 1, 1, 1],
+            dilations: [1, 1],
+            group: 1,
+            activation: None,
+        });
+        Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Constant(tensor(vec![3, 3, 4, 4], 1).into()), &[], 1),
+                node(OpKind::Constant(tensor(vec![4], 2).into()), &[], 2),
+                node(conv, &[0, 1, 2], 3),
+                node(OpKind::HardSwish, &[3], 4),
+                node(OpKind::Relu, &[4], 5),
+                node(OpKind::Add, &[4, 5], 6),
+                node(OpKind::Mul, &[6, 0], 7),
+                node(
+                    OpKind::Reshape(crate::ReshapeAttrs {
+                        shape: vec![-1, 4],
+                        allowzero: false,
+                    }),
+                    &[7],
+                    8,
+                ),
+                node(OpKind::Input, &[], 9),
+                node(OpKind::MatMul(None), &[8, 9], 10),
+            ],
+            outputs: vec![ValueId(7), ValueId(10)],
+            value_types: HashMap::new(),
+        }
+    }
+
+    fn input_descs() -> HashMap<ValueId, TensorDesc> {
+        [(0, vec![1, 5, 5, 4]), (9, vec![4, 3])]
+            .into_iter()
+            .map(|(id, shape)| {
+                (
+                    ValueId(id),
+                    TensorDesc {
+                        dtype: DType::F32,
+                        shape,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_backend_run_across_frames() {
+        let g = graph();
+        let mut session = CpuBackend::new().compile(&g, &input_descs()).unwrap();
+        assert_eq!(session.inputs().len(), 2);
+        assert!(!session.memory_plan().aliases.is_empty());
+
+        for seed in 0..3 {
+            let inputs = HashMap::from([
+                (ValueId(0), tensor(vec![1, 5, 5, 4], seed)),
+                (ValueId(9), tensor(vec![4, 3], seed + 10)),
+            ]);
+            let expected = CpuBackend::new().run(&g, &inputs).unwrap();
+            let actual = session.run(&inputs).unwrap();
+            for id in [ValueId(7), ValueId(10)] {
+                assert_eq!(actual[&id].data, expected[&id].data, "{:?}", id);
+            }
+        }
+    }
+
+    #[test]
+    fn validates_at_compile_and_run() {
+        let mut session = CpuBackend::new().compile(&graph(), &input_descs()).unwrap();
+        assert_eq!(session.output_ids(), vec![ValueId(7), ValueId(10)]);
+
+        let x = tensor(vec![1, 5, 5, 4], 0);
+        let w = tensor(vec![4, 4], 0);
+        let err = session.run_ordered(&[&x, &w]).unwrap_err();
+        assert_eq!(err.kind(), "ShapeMismatch");
+        assert!(err.to_string().contains("compiled for"), "{}", err);
+
+        let err = CpuBackend::new()
+            .compile(&graph(), &HashMap::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "MissingType");
+        assert!(err.to_string().contains("no type for Input"), "{}", err);
+
+        let mut bad = graph();
+        bad.nodes[6].inputs[1] = ValueId(42);
+        let err = CpuBackend::new()
+            .compile(&bad, &input_descs())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("missing input"), "{}", err);
+        match err {
+            Error::MissingInput {
+                node: Some(node),
+                value,
+            } => {
+                assert_eq!(value, ValueId(42));
+                assert_eq!(node.output, bad.nodes[6].output);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Shape inference: derives the `TensorDesc` of every value in a graph from
+//! the caller's input descriptions, without running any kernels.
+
+use std::collections::HashMap;
+
+use crate::error::{Result, bail, ensure, err};
+use crate::{
+    AveragePoolAttrs, ConcatAttrs, DType, Error, Graph, Node, OpKind, ReshapeAttrs, TensorDesc,
+    TransposeAttrs, ValueId,
+};
+
+/// Infer the type of every value in `graph`.
+///
+/// Input values are taken from `inputs`, falling back to
+/// `graph.value_types` for Input nodes the caller did not describe. Nodes
+/// are visited in `graph.nodes` order (MVP assumes topologically sorted).
+pub fn infer_shapes(
+    graph: &Graph,
+    inputs: &HashMap<ValueId, TensorDesc>,
+) -> Result<HashMap<ValueId, TensorDesc>> {
+    let mut types = inputs.clone();
+
+    for node in &graph.nodes {
+        let desc = match &node.op {
+            OpKind::Input => match types.get(&node.output) {
+                Some(_) => continue,
+                None => graph
+                    .value_types
+                    .get(&node.output)
+                    .cloned()
+                    .ok_or(Error::MissingType {
+                        node: Some(node.into()),
+                        value: node.output,
+                    })?,
+            },
+            _ => {
+                let args = node
+                    .inputs
+                    .iter()
+                    .map(|id| {
+                        types.get(id).ok_or(Error::MissingInput {
+                            node: Some(node.into()),
+                            value: *id,
+                        })
+                    })
+                    .collect::<Result<Vec<&TensorDesc>>>()?;
+                infer_node(node, &args).map_err(|e| e.at(node))?
+            }
+        };
+        types.insert(node.output, desc);
+    }
+
+    Ok(types)
+}
+
+/// Output type of a single node given the types of its inputs.
+pub fn infer_node(node: &Node, args: &[&TensorDesc]) -> Result<TensorDesc> {
+    let op = node.op.name();
+    let arg = |i: usize| -> Result<&[usize]> {
+        args.get(i)
+            .map(|d| d.shape.as_slice())
+            .ok_or_else(|| err!(InvalidGraph, "{} missing input {}", op, i))
+    };
+
+    let shape = match &node.op {
+        OpKind::Input => bail!(
+            UnsupportedOp,
+            "Input {:?} has no inferable type",
+            node.output
+        ),
+        OpKind::Constant(t) => t.desc.shape.clone(),
+        OpKind::Add | OpKind::Mul => {
+            let (a, b) = (arg(0)?, arg(1)?);
+            ensure!(
+                a == b,
+                ShapeMismatch,
+                "{} shape mismatch: {:?} vs {:?}",
+                op,
+                a,
+                b
+            );
+            a.to_vec()
+        }
+        OpKind::MatMul(attrs) => {
+            let (a, b) = (arg(0)?, arg(1)?);
+            ensure!(
+                a.len() == 2 && b.len() == 2,
+                ShapeMismatch,
+                "MatMul expects 2D tensors, got {:?} and {:?}",
+                a,
+                b
+            );
+            let (trans_a, trans_b) = attrs
+                .as_ref()
+                .map_or((false, false), |a| (a.trans_a, a.trans_b));
+            let (m, k1) = if trans_a { (a[1], a[0]) } else { (a[0], a[1]) };
+            let (k2, n) = if trans_b { (b[1], b[0]) } else { (b[0], b[1]) };
+            ensure!(
+                k1 == k2,
+                ShapeMismatch,
+                "MatMul inner dim mismatch: {} vs {}",
+                k1,
+                k2
+            );
+            vec![m, n]
+        }
+        OpKind::Relu | OpKind::Relu6 | OpKind::HardSwish => arg(0)?.to_vec(),
+        OpKind::FusedElementwise(attrs) => {
+            attrs.validate(args.len())?;
+            let shape = arg(0)?;
+            for i in 1..args.len() {
+                ensure!(
+                    arg(i)? == shape,
+                    ShapeMismatch,
+                    "{} shape mismatch: {:?} vs {:?}",
+                    op,
+                    shape,
+                    arg(i)?
+                );
+            }
+            shape.to_vec()
+        }
+        OpKind::Conv2D(attrs) => {
+            let bias = if args.len() > 2 { Some(arg(2)?) } else { None };
+            let p = crate::conv2d_params(arg(0)?, arg(1)?, bias, attrs)?;
+            vec![p.batch, p.out_h, p.out_w, p.out_c]
+        }
+        OpKind::DepthwiseConv2D(attrs) => {
+            let bias = if args.len() > 2 { Some(arg(2)?) } else { None };
+            let p = crate::depthwise_conv2d_params(arg(0)?, arg(1)?, bias, attrs)?;
+            vec![p.batch, p.out_h, p.out_w, p.out_c]
+        }
+        OpKind::BatchNorm(_) => {
+            let input = arg(0)?;
+            let c = input.last().copied().unwrap_or(0);
+            for (i, name) in [(1, "scale"), (2, "bias"), (3, "mean"), (4, "var")] {
+                let param = arg(i)?;
+                ensure!(
+                    param == [c],
+                    ShapeMismatch,
+                    "BatchNorm {} shape {:?} does not match {} channels",
+                    name,
+                    param,
+                    c
+                );
+            }
+            input.to_vec()
+        }
+        OpKind::AveragePool(attrs) => average_pool_shape(arg(0)?, attrs)?,
+        OpKind::GlobalAveragePool => {
+            let input = arg(0)?;
+            ensure!(
+                input.len() == 4,
+                ShapeMismatch,
+                "GlobalAveragePool expects NHWC input, got {:?}",
+                input
+            );
+            vec![input[0], 1, 1, input[3]]
+        }
+        OpKind::Reshape(attrs) => reshape_shape(arg(0)?, attrs)?,
+        OpKind::Transpose(attrs) => transpose_shape(arg(0)?, attrs)?,
+        OpKind::Concat(attrs) => {
+            let shapes = (0..args.len()).map(arg).collect::<Result<Vec<_>>>()?;
+            concat_shape(&shapes, attrs)?
+        }
+    };
+
+    Ok(TensorDesc {
+        dtype: DType::F32,
+        shape,
+    })
+}
+
+pub(crate) fn average_pool_shape(input: &[usize], attrs: &AveragePoolAttrs) -> Result<Vec<usize>> {
+    ensure!(
+        input.len() == 4,
+        ShapeMismatch,
+        "AveragePool expects NHWC input, got {:?}",
+        input
+    );
+    let [k_h, k_w] = attrs.kernel_shape;
+    let pads = attrs.pads;
+    let out_h = crate::conv_out_dim(
+        "AveragePool",
+        input[1],
+        pads[0] + pads[2],
+        k_h,
+        attrs.strides[0],
+        1,
+    )?;
+    let out_w = crate::conv_out_dim(
+        "AveragePool",
+        input[2],
+        pads[1] + pads[3],
+        k_w,
+        attrs.strides[1],
+        1,
+    )?;
+    Ok(vec![input[0], out_h, out_w, input[3]])
+}
+
+/// Resolve a Reshape target: `-1` is inferred from the element count, and
+/// `0` copies the input dimension unless `allowzero` is set.
+pub(crate) fn reshape_shape(input: &[usize], attrs: &ReshapeAttrs) -> Result<Vec<usize>> {
+    let len: usize = input.iter().product();
+    let mut infer = None;
+    let mut shape = Vec::with_capacity(attrs.shape.len());
+
+    for (i, &d) in attrs.shape.iter().enumerate() {
+        let dim = match d {
+            -1 => {
+                ensure!(
+                    infer.is_none(),
+                    InvalidAttr,
+                    "Reshape shape {:?} has more than one -1",
+                    attrs.shape
+                );
+                infer = Some(i);
+                1
+            }
+            0 if !attrs.allowzero => *input.get(i).ok_or_else(|| {
+                err!(
+                    InvalidAttr,
+                    "Reshape shape {:?} copies missing dim {} of {:?}",
+                    attrs.shape,
+                    i,
+                    input
+                )
+            })?,
+            d if d >= 0 => d as usize,
+            _ => bail!(
+                InvalidAttr,
+                "Reshape shape {:?} has invalid dim {}",
+                attrs.shape,
+                d
+            ),
+        };
+        shape.push(dim);
+    }
+
+    let known: usize = shape.iter().product();
+    if let Some(i) = infer {
+        ensure!(
+            known > 0 && len.is_multiple_of(known),
+            ShapeMismatch,
+            "Reshape cannot infer -1 in {:?} for {:?}",
+            attrs.shape,
+            input
+        );
+        shape[i] = len / known;
+    }
+    ensure!(
+        shape.iter().product::<usize>() == len,
+        ShapeMismatch,
+        "Reshape {:?} to {:?} changes the element count",
+        input,
+        shape
+    );
+    Ok(shape)
+}
+
+pub(crate) fn transpose_shape(input: &[usize], attrs: &TransposeAttrs) -> Result<Vec<usize>> {
+    let mut seen = vec![false; input.len()];
+    for &axis in &attrs.perm {
+        ensure!(
+            axis < input.len() && !seen[axis],
+            InvalidAttr,
+            "Transpose perm {:?} is not a permutation of {} axes",
+            attrs.perm,
+            input.len()
+        );
+        seen[axis] = true;
+    }
+    ensure!(
+        attrs.perm.len() == input.len(),
+        InvalidAttr,
+        "Transpose perm {:?} is not a permutation of {} axes",
+        attrs.perm,
+        input.len()
+    );
+    Ok(attrs.perm.iter().map(|&axis| input[axis]).collect())
+}
+
+pub(crate) fn concat_shape(inputs: &[&[usize]], attrs: &ConcatAttrs) -> Result<Vec<usize>> {
+    let first = *inputs
+        .first()
+        .ok_or_else(|| err!(InvalidGraph, "Concat needs at least one input"))?;
+    ensure!(
+        attrs.axis < first.len(),
+        InvalidAttr,
+        "Concat axis {} out of range for {:?}",
+        attrs.axis,
+        first
+    );
+
+    let mut shape = first.to_vec();
+    for other in &inputs[1..] {
+        let compatible = other.len() == first.len()
+            && (0..first.len()).all(|d| d == attrs.axis || other[d] == first[d]);
+        ensure!(
+            compatible,
+            ShapeMismatch,
+            "Concat shape mismatch on axis {}: {:?} vs {:?}",
+            attrs.axis,
+            first,
+            other
+        );
+        shape[attrs.axis] += other[attrs.axis];
+    }
+    Ok(shape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{desc, node};
+    use crate::{Conv2DAttrs, Tensor};
+
+    #[test]
+    fn infers_through_conv_and_layout_ops() {
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(
+                    OpKind::Constant(Tensor::zeros(vec![3, 3, 3, 16]).into()),
+                    &[],
+                    1,
+                ),
+                node(
+                    OpKind::Conv2D(Conv2DAttrs {
+                        kernel_shape: [3, 3],
+                        strides: [2, 2],
+                        pads: [1, 1, 1, 1],
+                        dilations: [1, 1],
+                        group: 1,
+                        activation: None,
+                    }),
+                    &[0, 1],
+                    2,
+                ),
+                node(OpKind::GlobalAveragePool, &[2], 3),
+                node(
+                    OpKind::Reshape(ReshapeAttrs {
+                        shape: vec![0, -1],
+                        allowzero: false,
+                    }),
+                    &[3],
+                    4,
+                ),
+                node(
+                    OpKind::Transpose(TransposeAttrs { perm: vec![1, 0] }),
+                    &[4],
+                    5,
+                ),
+                node(OpKind::Concat(ConcatAttrs { axis: 1 }), &[5, 5], 6),
+            ],
+            outputs: vec![ValueId(6)],
+            value_types: HashMap::from([(ValueId(0), desc(vec![1, 224, 224, 3]))]),
+        };
+
+        let types = infer_shapes(&graph, &HashMap::new()).unwrap();
+        assert_eq!(types[&ValueId(2)].shape, vec![1, 112, 112, 16]);
+        assert_eq!(types[&ValueId(3)].shape, vec![1, 1, 1, 16]);
+        assert_eq!(types[&ValueId(4)].shape, vec![1, 16]);
+        assert_eq!(types[&ValueId(5)].shape, vec![16, 1]);
+        assert_eq!(types[&ValueId(6)].shape, vec![16, 2]);
+    }
+
+    #[test]
+    fn rejects_mismatched_shapes() {
+        let graph = Graph {
+            nodes: vec![
+                node(OpKind::Input, &[], 0),
+                node(OpKind::Input, &[], 1),
+                node(OpKind::Add, &[0, 1], 2),
+            ],
+            outputs: vec![ValueId(2)],
+            value_types: HashMap::new(),
+        };
+        let inputs = HashMap::from([
+            (ValueId(0), desc(vec![2, 3])),
+            (ValueId(1), desc(vec![3, 2])),
+        ]);
+        let err = infer_shapes(&graph, &inputs).unwrap_err();
+        assert!(err.to_string().contains("Add shape mismatch"), "{}", err);
+    }
+}
@@ -0,0 +1,1175 @@
+//! SIMD kernels for the CPU backend.
+//!
+//! Every kernel has a scalar reference implementation and a vectorized
+//! implementation written once against the [`Lanes`] trait. The vector
+//! width is picked at runtime by [`SimdLevel::detect`]:
+//!
+//! * x86_64: SSE (always available) or AVX2 + FMA
+//! * wasm32: simd128 (when the module is built with `+simd128`)
+//!
+//! All kernels work on flat row-major / NHWC slices; shape checking is
+//! done by the callers in `lib.rs`.
+
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// Instruction set used by the vectorized kernels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimdLevel {
+    /// Plain scalar loops (reference implementation)
+    Scalar,
+    /// x86_64 SSE, 4 lanes
+    Sse,
+    /// x86_64 AVX2 + FMA, 8 lanes
+    Avx2,
+    /// wasm32 simd128, 4 lanes
+    Simd128,
+}
+
+impl SimdLevel {
+    /// Best level supported by the running CPU (cached after the first call).
+    pub fn detect() -> SimdLevel {
+        static LEVEL: OnceLock<SimdLevel> = OnceLock::new();
+        *LEVEL.get_or_init(detect_uncached)
+    }
+
+    /// Whether this level can run on the current CPU.
+    pub fn is_supported(self) -> bool {
+        match self {
+            SimdLevel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Sse => true,
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 => is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma"),
+            #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+            SimdLevel::Simd128 => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+fn detect_uncached() -> SimdLevel {
+    [SimdLevel::Avx2, SimdLevel::Simd128, SimdLevel::Sse]
+        .into_iter()
+        .find(|level| level.is_supported())
+        .unwrap_or(SimdLevel::Scalar)
+}
+
+/// Geometry of an NHWC convolution (shared by Conv2D and DepthwiseConv2D)
+#[derive(Debug, Clone, Copy)]
+pub struct ConvParams {
+    pub batch: usize,
+    pub in_h: usize,
+    pub in_w: usize,
+    pub in_c: usize,
+    pub out_h: usize,
+    pub out_w: usize,
+    pub out_c: usize,
+    pub k_h: usize,
+    pub k_w: usize,
+    pub stride_h: usize,
+    pub stride_w: usize,
+    pub dilation_h: usize,
+    pub dilation_w: usize,
+    pub pad_top: usize,
+    pub pad_left: usize,
+    /// Conv2D: number of groups. DepthwiseConv2D: always `in_c`.
+    pub group: usize,
+    /// Epilogue applied by the backend after each tile of rows
+    pub activation: Option<crate::Activation>,
+}
+
+impl ConvParams {
+    /// All output rows, indexed over `batch * out_h`.
+    pub fn rows(&self) -> Range<usize> {
+        0..self.batch * self.out_h
+    }
+
+    /// Input row for output row `oh` and kernel row `kh`, or None if it falls into padding.
+    #[inline(always)]
+    fn in_row(&self, oh: usize, kh: usize) -> Option<usize> {
+        (oh * self.stride_h + kh * self.dilation_h)
+            .checked_sub(self.pad_top)
+            .filter(|&ih| ih < self.in_h)
+    }
+
+    /// Input column for output column `ow` and kernel column `kw`, or None if it falls into padding.
+    #[inline(always)]
+    fn in_col(&self, ow: usize, kw: usize) -> Option<usize> {
+        (ow * self.stride_w + kw * self.dilation_w)
+            .checked_sub(self.pad_left)
+            .filter(|&iw| iw < self.in_w)
+    }
+}
+
+// ---------- Public dispatch entry points ----------
+
+macro_rules! dispatch {
+    ($level:expr, $generic:ident ( $($arg:expr),* )) => {{
+        let level = $level;
+        assert!(level.is_supported(), "{:?} is not supported on this CPU", level);
+        match level {
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Sse => unsafe { x86::sse::$generic($($arg),*) },
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 => unsafe { x86::avx2::$generic($($arg),*) },
+            #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+            SimdLevel::Simd128 => unsafe { generic::$generic::<wasm::F32x4>($($arg),*) },
+            _ => scalar::$generic($($arg),*),
+        }
+    }};
+}
+
+/// out = a + b
+pub fn add(level: SimdLevel, a: &[f32], b: &[f32], out: &mut [f32]) {
+    assert!(a.len() == out.len() && b.len() == out.len());
+    dispatch!(level, add(a, b, out))
+}
+
+/// out = a * b
+pub fn mul(level: SimdLevel, a: &[f32], b: &[f32], out: &mut [f32]) {
+    assert!(a.len() == out.len() && b.len() == out.len());
+    dispatch!(level, mul(a, b, out))
+}
+
+/// out = max(x, 0)
+pub fn relu(level: SimdLevel, x: &[f32], out: &mut [f32]) {
+    assert_eq!(x.len(), out.len());
+    dispatch!(level, relu(x, out))
+}
+
+/// out = clamp(x, 0, 6)
+pub fn relu6(level: SimdLevel, x: &[f32], out: &mut [f32]) {
+    assert_eq!(x.len(), out.len());
+    dispatch!(level, relu6(x, out))
+}
+
+/// out = x * relu6(x + 3) / 6
+pub fn hard_swish(level: SimdLevel, x: &[f32], out: &mut [f32]) {
+    assert_eq!(x.len(), out.len());
+    dispatch!(level, hard_swish(x, out))
+}
+
+/// acc += b
+pub fn add_inplace(level: SimdLevel, acc: &mut [f32], b: &[f32]) {
+    assert_eq!(acc.len(), b.len());
+    dispatch!(level, add_inplace(acc, b))
+}
+
+/// acc *= b
+pub fn mul_inplace(level: SimdLevel, acc: &mut [f32], b: &[f32]) {
+    assert_eq!(acc.len(), b.len());
+    dispatch!(level, mul_inplace(acc, b))
+}
+
+/// x = max(x, 0)
+pub fn relu_inplace(level: SimdLevel, x: &mut [f32]) {
+    dispatch!(level, relu_inplace(x))
+}
+
+/// x = clamp(x, 0, 6)
+pub fn relu6_inplace(level: SimdLevel, x: &mut [f32]) {
+    dispatch!(level, relu6_inplace(x))
+}
+
+/// x = x * relu6(x + 3) / 6
+pub fn hard_swish_inplace(level: SimdLevel, x: &mut [f32]) {
+    dispatch!(level, hard_swish_inplace(x))
+}
+
+/// out[m, n] = a[m, k] x b[k, n]
+pub fn matmul(
+    level: SimdLevel,
+    a: &[f32],
+    b: &[f32],
+    out: &mut [f32],
+    m: usize,
+    k: usize,
+    n: usize,
+) {
+    assert!(a.len() == m * k && b.len() == k * n && out.len() == m * n);
+    dispatch!(level, matmul(a, b, out, m, k, n))
+}
+
+/// NHWC convolution with kernel `[Kh, Kw, C_in / group, C_out]`.
+///
+/// Computes the output rows in `rows` (indexed over `batch * out_h`) into
+/// `out`, which holds exactly those rows.
+pub fn conv2d_nhwc(
+    level: SimdLevel,
+    input: &[f32],
+    kernel: &[f32],
+    bias: Option<&[f32]>,
+    out: &mut [f32],
+    p: &ConvParams,
+    rows: Range<usize>,
+) {
+    check_conv(input, kernel, bias, out, p, p.in_c / p.group, &rows);
+    dispatch!(level, conv2d_nhwc(input, kernel, bias, out, p, rows))
+}
+
+/// NHWC depthwise convolution with kernel `[Kh, Kw, C, depth_multiplier]`.
+///
+/// `rows` / `out` work as in [`conv2d_nhwc`].
+pub fn depthwise_conv2d_nhwc(
+    level: SimdLevel,
+    input: &[f32],
+    kernel: &[f32],
+    bias: Option<&[f32]>,
+    out: &mut [f32],
+    p: &ConvParams,
+    rows: Range<usize>,
+) {
+    check_conv(input, kernel, bias, out, p, 1, &rows);
+    dispatch!(
+        level,
+        depthwise_conv2d_nhwc(input, kernel, bias, out, p, rows)
+    )
+}
+
+fn check_conv(
+    input: &[f32],
+    kernel: &[f32],
+    bias: Option<&[f32]>,
+    out: &[f32],
+    p: &ConvParams,
+    k_in_c: usize,
+    rows: &Range<usize>,
+) {
+    assert!(p.group > 0 && p.in_c.is_multiple_of(p.group) && p.out_c.is_multiple_of(p.group));
+    assert!(rows.start <= rows.end && rows.end <= p.batch * p.out_h);
+    assert_eq!(input.len(), p.batch * p.in_h * p.in_w * p.in_c);
+    assert_eq!(kernel.len(), p.k_h * p.k_w * k_in_c * p.out_c);
+    assert_eq!(out.len(), rows.len() * p.out_w * p.out_c);
+    if let Some(bias) = bias {
+        assert_eq!(bias.len(), p.out_c);
+    }
+}
+
+// ---------- Scalar reference implementation ----------
+
+pub(crate) mod scalar {
+    use std::ops::Range;
+
+    use super::ConvParams;
+
+    pub fn add(a: &[f32], b: &[f32], out: &mut [f32]) {
+        for i in 0..out.len() {
+            out[i] = a[i] + b[i];
+        }
+    }
+
+    pub fn mul(a: &[f32], b: &[f32], out: &mut [f32]) {
+        for i in 0..out.len() {
+            out[i] = a[i] * b[i];
+        }
+    }
+
+    pub fn relu(x: &[f32], out: &mut [f32]) {
+        for (o, &v) in out.iter_mut().zip(x) {
+            *o = if v < 0.0 { 0.0 } else { v };
+        }
+    }
+
+    pub fn relu6(x: &[f32], out: &mut [f32]) {
+        for (o, &v) in out.iter_mut().zip(x) {
+            *o = v.clamp(0.0, 6.0);
+        }
+    }
+
+    pub fn hard_swish(x: &[f32], out: &mut [f32]) {
+        for (o, &v) in out.iter_mut().zip(x) {
+            *o = v * (v + 3.0).clamp(0.0, 6.0) / 6.0;
+        }
+    }
+
+    pub fn add_inplace(acc: &mut [f32], b: &[f32]) {
+        for (a, &b) in acc.iter_mut().zip(b) {
+            *a += b;
+        }
+    }
+
+    pub fn mul_inplace(acc: &mut [f32], b: &[f32]) {
+        for (a, &b) in acc.iter_mut().zip(b) {
+            *a *= b;
+        }
+    }
+
+    pub fn relu_inplace(x: &mut [f32]) {
+        for v in x {
+            *v = if *v < 0.0 { 0.0 } else { *v };
+        }
+    }
+
+    pub fn relu6_inplace(x: &mut [f32]) {
+        for v in x {
+            *v = v.clamp(0.0, 6.0);
+        }
+    }
+
+    pub fn hard_swish_inplace(x: &mut [f32]) {
+        for v in x {
+            *v = *v * (*v + 3.0).clamp(0.0, 6.0) / 6.0;
+        }
+    }
+
+    pub fn matmul(a: &[f32], b: &[f32], out: &mut [f32], m: usize, k: usize, n: usize) {
+        for i in 0..m {
+            for j in 0..n {
+                let mut sum = 0.0;
+                for kk in 0..k {
+                    sum += a[i * k + kk] * b[kk * n + j];
+                }
+                out[i * n + j] = sum;
+            }
+        }
+    }
+
+    pub fn conv2d_nhwc(
+        input: &[f32],
+        kernel: &[f32],
+        bias: Option<&[f32]>,
+        out: &mut [f32],
+        p: &ConvParams,
+        rows: Range<usize>,
+    ) {
+        let in_c_g = p.in_c / p.group;
+        let out_c_g = p.out_c / p.group;
+
+        for row in rows.clone() {
+            let (n, oh) = (row / p.out_h, row % p.out_h);
+            for ow in 0..p.out_w {
+                let out_base = ((row - rows.start) * p.out_w + ow) * p.out_c;
+                for oc in 0..p.out_c {
+                    let g = oc / out_c_g;
+                    let mut acc = bias.map(|b| b[oc]).unwrap_or(0.0);
+                    for kh in 0..p.k_h {
+                        let Some(ih) = p.in_row(oh, kh) else { continue };
+                        for kw in 0..p.k_w {
+                            let Some(iw) = p.in_col(ow, kw) else { continue };
+                            let in_base = ((n * p.in_h + ih) * p.in_w + iw) * p.in_c + g * in_c_g;
+                            for ic in 0..in_c_g {
+                                let k_idx = ((kh * p.k_w + kw) * in_c_g + ic) * p.out_c + oc;
+                                acc += input[in_base + ic] * kernel[k_idx];
+                            }
+                        }
+                    }
+                    out[out_base + oc] = acc;
+                }
+            }
+        }
+    }
+
+    pub fn depthwise_conv2d_nhwc(
+        input: &[f32],
+        kernel: &[f32],
+        bias: Option<&[f32]>,
+        out: &mut [f32],
+        p: &ConvParams,
+        rows: Range<usize>,
+    ) {
+        let multiplier = p.out_c / p.in_c;
+
+        for row in rows.clone() {
+            let (n, oh) = (row / p.out_h, row % p.out_h);
+            for ow in 0..p.out_w {
+                let out_base = ((row - rows.start) * p.out_w + ow) * p.out_c;
+                for oc in 0..p.out_c {
+                    let c = oc / multiplier;
+                    let mut acc = bias.map(|b| b[oc]).unwrap_or(0.0);
+                    for kh in 0..p.k_h {
+                        let Some(ih) = p.in_row(oh, kh) else { continue };
+                        for kw in 0..p.k_w {
+                            let Some(iw) = p.in_col(ow, kw) else { continue };
+                            let in_idx = ((n * p.in_h + ih) * p.in_w + iw) * p.in_c + c;
+                            let k_idx = (kh * p.k_w + kw) * p.out_c + oc;
+                            acc += input[in_idx] * kernel[k_idx];
+                        }
+                    }
+                    out[out_base + oc] = acc;
+                }
+            }
+        }
+    }
+}
+
+// ---------- Vectorized implementation ----------
+
+/// A register of `LANES` f32 values.
+///
+/// Methods are `unsafe` because they may only be called when the
+/// corresponding instruction set is available, and `load`/`store` take
+/// raw pointers that must be valid for `LANES` elements.
+#[allow(dead_code)]
+trait Lanes: Copy {
+    const LANES: usize;
+    unsafe fn splat(v: f32) -> Self;
+    unsafe fn load(p: *const f32) -> Self;
+    unsafe fn store(self, p: *mut f32);
+    unsafe fn add(self, o: Self) -> Self;
+    unsafe fn mul(self, o: Self) -> Self;
+    unsafe fn div(self, o: Self) -> Self;
+    /// Lane-wise max; NaN lanes of `self` propagate, like the scalar kernels
+    unsafe fn max(self, o: Self) -> Self;
+    /// Lane-wise min; NaN lanes of `self` propagate, like the scalar kernels
+    unsafe fn min(self, o: Self) -> Self;
+    /// self * b + c (fused when the target has FMA)
+    unsafe fn mul_add(self, b: Self, c: Self) -> Self;
+}
+
+#[allow(dead_code)]
+mod generic {
+    use std::ops::Range;
+
+    use super::{ConvParams, Lanes, scalar};
+
+    #[inline(always)]
+    unsafe fn binary<V: Lanes>(
+        a: &[f32],
+        b: &[f32],
+        out: &mut [f32],
+        f: impl Fn(V, V) -> V,
+        s: impl Fn(f32, f32) -> f32,
+    ) {
+        let len = out.len();
+        let mut i = 0;
+        unsafe {
+            while i + V::LANES <= len {
+                let r = f(V::load(a.as_ptr().add(i)), V::load(b.as_ptr().add(i)));
+                r.store(out.as_mut_ptr().add(i));
+                i += V::LANES;
+            }
+        }
+        for j in i..len {
+            out[j] = s(a[j], b[j]);
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn unary<V: Lanes>(
+        x: &[f32],
+        out: &mut [f32],
+        f: impl Fn(V) -> V,
+        s: impl Fn(&[f32], &mut [f32]),
+    ) {
+        let len = out.len();
+        let mut i = 0;
+        unsafe {
+            while i + V::LANES <= len {
+                f(V::load(x.as_ptr().add(i))).store(out.as_mut_ptr().add(i));
+                i += V::LANES;
+            }
+        }
+        s(&x[i..], &mut out[i..]);
+    }
+
+    /// `binary` with the output aliasing the first operand
+    #[inline(always)]
+    unsafe fn binary_inplace<V: Lanes>(
+        acc: &mut [f32],
+        b: &[f32],
+        f: impl Fn(V, V) -> V,
+        s: impl Fn(&mut [f32], &[f32]),
+    ) {
+        let len = acc.len();
+        let mut i = 0;
+        unsafe {
+            while i + V::LANES <= len {
+                let p = acc.as_mut_ptr().add(i);
+                f(V::load(p), V::load(b.as_ptr().add(i))).store(p);
+                i += V::LANES;
+            }
+        }
+        s(&mut acc[i..], &b[i..]);
+    }
+
+    #[inline(always)]
+    unsafe fn unary_inplace<V: Lanes>(x: &mut [f32], f: impl Fn(V) -> V, s: impl Fn(&mut [f32])) {
+        let len = x.len();
+        let mut i = 0;
+        unsafe {
+            while i + V::LANES <= len {
+                let p = x.as_mut_ptr().add(i);
+                f(V::load(p)).store(p);
+                i += V::LANES;
+            }
+        }
+        s(&mut x[i..]);
+    }
+
+    #[inline(always)]
+    pub unsafe fn add<V: Lanes>(a: &[f32], b: &[f32], out: &mut [f32]) {
+        unsafe { binary::<V>(a, b, out, |x, y| x.add(y), |x, y| x + y) }
+    }
+
+    #[inline(always)]
+    pub unsafe fn mul<V: Lanes>(a: &[f32], b: &[f32], out: &mut [f32]) {
+        unsafe { binary::<V>(a, b, out, |x, y| x.mul(y), |x, y| x * y) }
+    }
+
+    #[inline(always)]
+    pub unsafe fn relu<V: Lanes>(x: &[f32], out: &mut [f32]) {
+        unsafe {
+            let zero = V::splat(0.0);
+            unary::<V>(x, out, |v| v.max(zero), scalar::relu)
+        }
+    }
+
+    #[inline(always)]
+    pub unsafe fn relu6<V: Lanes>(x: &[f32], out: &mut [f32]) {
+        unsafe {
+            let (zero, six) = (V::splat(0.0), V::splat(6.0));
+            unary::<V>(x, out, |v| v.max(zero).min(six), scalar::relu6)
+        }
+    }
+
+    #[inline(always)]
+    pub unsafe fn hard_swish<V: Lanes>(x: &[f32], out: &mut [f32]) {
+        unsafe {
+            let (zero, three, six) = (V::splat(0.0), V::splat(3.0), V::splat(6.0));
+            unary::<V>(
+                x,
+                out,
+                |v| v.mul(v.add(three).max(zero).min(six)).div(six),
+                scalar::hard_swish,
+            )
+        }
+    }
+
+    #[inline(always)]
+    pub unsafe fn add_inplace<V: Lanes>(acc: &mut [f32], b: &[f32]) {
+        unsafe { binary_inplace::<V>(acc, b, |x, y| x.add(y), scalar::add_inplace) }
+    }
+
+    #[inline(always)]
+    pub unsafe fn mul_inplace<V: Lanes>(acc: &mut [f32], b: &[f32]) {
+        unsafe { binary_inplace::<V>(acc, b, |x, y| x.mul(y), scalar::mul_inplace) }
+    }
+
+    #[inline(always)]
+    pub unsafe fn relu_inplace<V: Lanes>(x: &mut [f32]) {
+        unsafe {
+            let zero = V::splat(0.0);
+            unary_inplace::<V>(x, |v| v.max(zero), scalar::relu_inplace)
+        }
+    }
+
+    #[inline(always)]
+    pub unsafe fn relu6_inplace<V: Lanes>(x: &mut [f32]) {
+        unsafe {
+            let (zero, six) = (V::splat(0.0), V::splat(6.0));
+            unary_inplace::<V>(x, |v| v.max(zero).min(six), scalar::relu6_inplace)
+        }
+    }
+
+    #[inline(always)]
+    pub unsafe fn hard_swish_inplace<V: Lanes>(x: &mut [f32]) {
+        unsafe {
+            let (zero, three, six) = (V::splat(0.0), V::splat(3.0), V::splat(6.0));
+            unary_inplace::<V>(
+                x,
+                |v| v.mul(v.add(three).max(zero).min(six)).div(six),
+                scalar::hard_swish_inplace,
+            )
+        }
+    }
+
+    /// i-k-j loop order: broadcast a[i, k] and stream rows of b into the output row.
+    #[inline(always)]
+    pub unsafe fn matmul<V: Lanes>(
+        a: &[f32],
+        b: &[f32],
+        out: &mut [f32],
+        m: usize,
+        k: usize,
+        n: usize,
+    ) {
+        let n_vec = n - n % V::LANES;
+        for i in 0..m {
+            let row = &mut out[i * n..(i + 1) * n];
+            let a_row = &a[i * k..(i + 1) * k];
+            let mut j = 0;
+            unsafe {
+                while j < n_vec {
+                    let mut acc = V::splat(0.0);
+                    for (kk, &av) in a_row.iter().enumerate() {
+                        acc = V::splat(av).mul_add(V::load(b.as_ptr().add(kk * n + j)), acc);
+                    }
+                    acc.store(row.as_mut_ptr().add(j));
+                    j += V::LANES;
+                }
+            }
+            for j in n_vec..n {
+                let mut sum = 0.0;
+                for (kk, &av) in a_row.iter().enumerate() {
+                    sum += av * b[kk * n + j];
+                }
+                row[j] = sum;
+            }
+        }
+    }
+
+    /// Vectorized over output channels within each group.
+    #[inline(always)]
+    pub unsafe fn conv2d_nhwc<V: Lanes>(
+        input: &[f32],
+        kernel: &[f32],
+        bias: Option<&[f32]>,
+        out: &mut [f32],
+        p: &ConvParams,
+        rows: Range<usize>,
+    ) {
+        let in_c_g = p.in_c / p.group;
+        let out_c_g = p.out_c / p.group;
+        let oc_vec = out_c_g - out_c_g % V::LANES;
+
+        for row in rows.clone() {
+            let (n, oh) = (row / p.out_h, row % p.out_h);
+            for ow in 0..p.out_w {
+                let out_base = ((row - rows.start) * p.out_w + ow) * p.out_c;
+                for g in 0..p.group {
+                    let oc0 = g * out_c_g;
+                    let in_c0 = g * in_c_g;
+
+                    let mut oc = 0;
+                    while oc < oc_vec {
+                        unsafe {
+                            let mut acc = match bias {
+                                Some(b) => V::load(b.as_ptr().add(oc0 + oc)),
+                                None => V::splat(0.0),
+                            };
+                            for kh in 0..p.k_h {
+                                let Some(ih) = p.in_row(oh, kh) else { continue };
+                                for kw in 0..p.k_w {
+                                    let Some(iw) = p.in_col(ow, kw) else { continue };
+                                    let in_base =
+                                        ((n * p.in_h + ih) * p.in_w + iw) * p.in_c + in_c0;
+                                    let k_base = (kh * p.k_w + kw) * in_c_g;
+                                    for ic in 0..in_c_g {
+                                        let k_ptr =
+                                            kernel.as_ptr().add((k_base + ic) * p.out_c + oc0 + oc);
+                                        acc = V::splat(input[in_base + ic])
+                                            .mul_add(V::load(k_ptr), acc);
+                                    }
+                                }
+                            }
+                            acc.store(out.as_mut_ptr().add(out_base + oc0 + oc));
+                        }
+                        oc += V::LANES;
+                    }
+
+                    for oc in oc0 + oc_vec..oc0 + out_c_g {
+                        let mut acc = bias.map(|b| b[oc]).unwrap_or(0.0);
+                        for kh in 0..p.k_h {
+                            let Some(ih) = p.in_row(oh, kh) else { continue };
+                            for kw in 0..p.k_w {
+                                let Some(iw) = p.in_col(ow, kw) else { continue };
+                                let in_base = ((n * p.in_h + ih) * p.in_w + iw) * p.in_c + in_c0;
+                                for ic in 0..in_c_g {
+                                    let k_idx = ((kh * p.k_w + kw) * in_c_g + ic) * p.out_c + oc;
+                                    acc += input[in_base + ic] * kernel[k_idx];
+                                }
+                            }
+                        }
+                        out[out_base + oc] = acc;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Vectorized over channels when depth_multiplier == 1 (input and
+    /// output channels line up); other multipliers use the scalar kernel.
+    #[inline(always)]
+    pub unsafe fn depthwise_conv2d_nhwc<V: Lanes>(
+        input: &[f32],
+        kernel: &[f32],
+        bias: Option<&[f32]>,
+        out: &mut [f32],
+        p: &ConvParams,
+        rows: Range<usize>,
+    ) {
+        if p.out_c != p.in_c {
+            scalar::depthwise_conv2d_nhwc(input, kernel, bias, out, p, rows);
+            return;
+        }
+        let c_vec = p.out_c - p.out_c % V::LANES;
+
+        for row in rows.clone() {
+            let (n, oh) = (row / p.out_h, row % p.out_h);
+            for ow in 0..p.out_w {
+                let out_base = ((row - rows.start) * p.out_w + ow) * p.out_c;
+
+                let mut c = 0;
+                while c < c_vec {
+                    unsafe {
+                        let mut acc = match bias {
+                            Some(b) => V::load(b.as_ptr().add(c)),
+                            None => V::splat(0.0),
+                        };
+                        for kh in 0..p.k_h {
+                            let Some(ih) = p.in_row(oh, kh) else { continue };
+                            for kw in 0..p.k_w {
+                                let Some(iw) = p.in_col(ow, kw) else { continue };
+                                let in_ptr = input
+                                    .as_ptr()
+                                    .add(((n * p.in_h + ih) * p.in_w + iw) * p.in_c + c);
+                                let k_ptr = kernel.as_ptr().add((kh * p.k_w + kw) * p.out_c + c);
+                                acc = V::load(in_ptr).mul_add(V::load(k_ptr), acc);
+                            }
+                        }
+                        acc.store(out.as_mut_ptr().add(out_base + c));
+                    }
+                    c += V::LANES;
+                }
+
+                for c in c_vec..p.out_c {
+                    let mut acc = bias.map(|b| b[c]).unwrap_or(0.0);
+                    for kh in 0..p.k_h {
+                        let Some(ih) = p.in_row(oh, kh) else { continue };
+                        for kw in 0..p.k_w {
+                            let Some(iw) = p.in_col(ow, kw) else { continue };
+                            let in_idx = ((n * p.in_h + ih) * p.in_w + iw) * p.in_c + c;
+                            acc += input[in_idx] * kernel[(kh * p.k_w + kw) * p.out_c + c];
+                        }
+                    }
+                    out[out_base + c] = acc;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::Lanes;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4(__m128);
+
+    impl Lanes for F32x4 {
+        const LANES: usize = 4;
+        #[inline(always)]
+        unsafe fn splat(v: f32) -> Self {
+            unsafe { F32x4(_mm_set1_ps(v)) }
+        }
+        #[inline(always)]
+        unsafe fn load(p: *const f32) -> Self {
+            unsafe { F32x4(_mm_loadu_ps(p)) }
+        }
+        #[inline(always)]
+        unsafe fn store(self, p: *mut f32) {
+            unsafe { _mm_storeu_ps(p, self.0) }
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            unsafe { F32x4(_mm_add_ps(self.0, o.0)) }
+        }
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            unsafe { F32x4(_mm_mul_ps(self.0, o.0)) }
+        }
+        #[inline(always)]
+        unsafe fn div(self, o: Self) -> Self {
+            unsafe { F32x4(_mm_div_ps(self.0, o.0)) }
+        }
+        // maxps/minps return the second operand when either one is NaN
+        #[inline(always)]
+        unsafe fn max(self, o: Self) -> Self {
+            unsafe { F32x4(_mm_max_ps(o.0, self.0)) }
+        }
+        #[inline(always)]
+        unsafe fn min(self, o: Self) -> Self {
+            unsafe { F32x4(_mm_min_ps(o.0, self.0)) }
+        }
+        #[inline(always)]
+        unsafe fn mul_add(self, b: Self, c: Self) -> Self {
+            unsafe { F32x4(_mm_add_ps(_mm_mul_ps(self.0, b.0), c.0)) }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct F32x8(__m256);
+
+    impl Lanes for F32x8 {
+        const LANES: usize = 8;
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn splat(v: f32) -> Self {
+            F32x8(_mm256_set1_ps(v))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn load(p: *const f32) -> Self {
+            unsafe { F32x8(_mm256_loadu_ps(p)) }
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn store(self, p: *mut f32) {
+            unsafe { _mm256_storeu_ps(p, self.0) }
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn add(self, o: Self) -> Self {
+            F32x8(_mm256_add_ps(self.0, o.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn mul(self, o: Self) -> Self {
+            F32x8(_mm256_mul_ps(self.0, o.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn div(self, o: Self) -> Self {
+            F32x8(_mm256_div_ps(self.0, o.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn max(self, o: Self) -> Self {
+            F32x8(_mm256_max_ps(o.0, self.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn min(self, o: Self) -> Self {
+            F32x8(_mm256_min_ps(o.0, self.0))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2,fma")]
+        unsafe fn mul_add(self, b: Self, c: Self) -> Self {
+            F32x8(_mm256_fmadd_ps(self.0, b.0, c.0))
+        }
+    }
+
+    /// Instantiates every generic kernel for one lane type inside a
+    /// `#[target_feature]` function so the intrinsics get inlined.
+    macro_rules! instantiate {
+        ($name:ident, $lanes:ty, $($feature:literal)?) => {
+            pub mod $name {
+                use std::ops::Range;
+
+                use super::super::{generic, ConvParams};
+
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn add(a: &[f32], b: &[f32], out: &mut [f32]) {
+                    unsafe { generic::add::<$lanes>(a, b, out) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn mul(a: &[f32], b: &[f32], out: &mut [f32]) {
+                    unsafe { generic::mul::<$lanes>(a, b, out) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn relu(x: &[f32], out: &mut [f32]) {
+                    unsafe { generic::relu::<$lanes>(x, out) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn relu6(x: &[f32], out: &mut [f32]) {
+                    unsafe { generic::relu6::<$lanes>(x, out) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn hard_swish(x: &[f32], out: &mut [f32]) {
+                    unsafe { generic::hard_swish::<$lanes>(x, out) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn add_inplace(acc: &mut [f32], b: &[f32]) {
+                    unsafe { generic::add_inplace::<$lanes>(acc, b) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn mul_inplace(acc: &mut [f32], b: &[f32]) {
+                    unsafe { generic::mul_inplace::<$lanes>(acc, b) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn relu_inplace(x: &mut [f32]) {
+                    unsafe { generic::relu_inplace::<$lanes>(x) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn relu6_inplace(x: &mut [f32]) {
+                    unsafe { generic::relu6_inplace::<$lanes>(x) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn hard_swish_inplace(x: &mut [f32]) {
+                    unsafe { generic::hard_swish_inplace::<$lanes>(x) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn matmul(a: &[f32], b: &[f32], out: &mut [f32], m: usize, k: usize, n: usize) {
+                    unsafe { generic::matmul::<$lanes>(a, b, out, m, k, n) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn conv2d_nhwc(input: &[f32], kernel: &[f32], bias: Option<&[f32]>, out: &mut [f32], p: &ConvParams, rows: Range<usize>) {
+                    unsafe { generic::conv2d_nhwc::<$lanes>(input, kernel, bias, out, p, rows) }
+                }
+                $(#[target_feature(enable = $feature)])?
+                pub unsafe fn depthwise_conv2d_nhwc(input: &[f32], kernel: &[f32], bias: Option<&[f32]>, out: &mut [f32], p: &ConvParams, rows: Range<usize>) {
+                    unsafe { generic::depthwise_conv2d_nhwc::<$lanes>(input, kernel, bias, out, p, rows) }
+                }
+            }
+        };
+    }
+
+    instantiate!(sse, super::F32x4,);
+    instantiate!(avx2, super::F32x8, "avx2,fma");
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+mod wasm {
+    use std::arch::wasm32::*;
+
+    use super::Lanes;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4(v128);
+
+    impl Lanes for F32x4 {
+        const LANES: usize = 4;
+        #[inline(always)]
+        unsafe fn splat(v: f32) -> Self {
+            F32x4(f32x4_splat(v))
+        }
+        #[inline(always)]
+        unsafe fn load(p: *const f32) -> Self {
+            unsafe { F32x4(v128_load(p as *const v128)) }
+        }
+        #[inline(always)]
+        unsafe fn store(self, p: *mut f32) {
+            unsafe { v128_store(p as *mut v128, self.0) }
+        }
+        #[inline(always)]
+        unsafe fn add(self, o: Self) -> Self {
+            F32x4(f32x4_add(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn mul(self, o: Self) -> Self {
+            F32x4(f32x4_mul(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn div(self, o: Self) -> Self {
+            F32x4(f32x4_div(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn max(self, o: Self) -> Self {
+            F32x4(f32x4_pmax(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn min(self, o: Self) -> Self {
+            F32x4(f32x4_pmin(self.0, o.0))
+        }
+        #[inline(always)]
+        unsafe fn mul_add(self, b: Self, c: Self) -> Self {
+            F32x4(f32x4_add(f32x4_mul(self.0, b.0), c.0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random data in [-4, 4)
+    fn data(len: usize, seed: u32) -> Vec<f32> {
+        let mut s = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                s ^= s << 13;
+                s ^= s >> 17;
+                s ^= s << 5;
+                (s % 8000) as f32 / 1000.0 - 4.0
+            })
+            .collect()
+    }
+
+    fn levels() -> Vec<SimdLevel> {
+        [SimdLevel::Sse, SimdLevel::Avx2, SimdLevel::Simd128]
+            .into_iter()
+            .filter(|l| l.is_supported())
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], tol: f32, what: &str) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!(
+                (a - e).abs() <= tol * (1.0 + e.abs()),
+                "{what}[{i}]: {a} vs {e}"
+            );
+        }
+    }
+
+    #[test]
+    fn elementwise_matches_scalar() {
+        // 37 = several full vectors plus a tail for both 4 and 8 lanes
+        let a = data(37, 1);
+        let b = data(37, 2);
+        for level in levels() {
+            let mut expected = vec![0.0; 37];
+            let mut actual = vec![0.0; 37];
+
+            scalar::add(&a, &b, &mut expected);
+            add(level, &a, &b, &mut actual);
+            assert_eq!(actual, expected, "{level:?} add");
+
+            scalar::mul(&a, &b, &mut expected);
+            mul(level, &a, &b, &mut actual);
+            assert_eq!(actual, expected, "{level:?} mul");
+
+            scalar::relu(&a, &mut expected);
+            relu(level, &a, &mut actual);
+            assert_eq!(actual, expected, "{level:?} relu");
+
+            let wide: Vec<f32> = a.iter().map(|v| v * 3.0).collect();
+            scalar::relu6(&wide, &mut expected);
+            relu6(level, &wide, &mut actual);
+            assert_eq!(actual, expected, "{level:?} relu6");
+
+            scalar::hard_swish(&a, &mut expected);
+            hard_swish(level, &a, &mut actual);
+            assert_eq!(actual, expected, "{level:?} hard_swish");
+        }
+    }
+
+    #[test]
+    fn inplace_matches_out_of_place() {
+        let a = data(37, 1);
+        let b = data(37, 2);
+        for level in [SimdLevel::Scalar].into_iter().chain(levels()) {
+            let mut expected = vec![0.0; 37];
+
+            add(level, &a, &b, &mut expected);
+            let mut actual = a.clone();
+            add_inplace(level, &mut actual, &b);
+            assert_eq!(actual, expected, "{level:?} add_inplace");
+
+            mul(level, &a, &b, &mut expected);
+            let mut actual = a.clone();
+            mul_inplace(level, &mut actual, &b);
+            assert_eq!(actual, expected, "{level:?} mul_inplace");
+
+            relu(level, &a, &mut expected);
+            let mut actual = a.clone();
+            relu_inplace(level, &mut actual);
+            assert_eq!(actual, expected, "{level:?} relu_inplace");
+
+            let wide: Vec<f32> = a.iter().map(|v| v * 3.0).collect();
+            relu6(level, &wide, &mut expected);
+            let mut actual = wide.clone();
+            relu6_inplace(level, &mut actual);
+            assert_eq!(actual, expected, "{level:?} relu6_inplace");
+
+            hard_swish(level, &a, &mut expected);
+            let mut actual = a.clone();
+            hard_swish_inplace(level, &mut actual);
+            assert_eq!(actual, expected, "{level:?} hard_swish_inplace");
+        }
+    }
+
+    #[test]
+    fn activations_propagate_nan() {
+        // NaN both in a full vector and in the scalar tail
+        let mut x = data(37, 5);
+        for i in [0, 9, 36] {
+            x[i] = f32::NAN;
+        }
+        type Kernel = fn(SimdLevel, &[f32], &mut [f32]);
+        let kernels: [(&str, Kernel); 3] =
+            [("relu", relu), ("relu6", relu6), ("hard_swish", hard_swish)];
+        for level in [SimdLevel::Scalar].into_iter().chain(levels()) {
+            for (name, kernel) in kernels {
+                let mut out = vec![0.0; 37];
+                kernel(level, &x, &mut out);
+                for (i, (o, v)) in out.iter().zip(&x).enumerate() {
+                    assert_eq!(o.is_nan(), v.is_nan(), "{level:?} {name}[{i}]");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matmul_matches_scalar() {
+        let (m, k, n) = (5, 19, 21);
+        let a = data(m * k, 3);
+        let b = data(k * n, 4);
+        let mut expected = vec![0.0; m * n];
+        scalar::matmul(&a, &b, &mut expected, m, k, n);
+        for level in levels() {
+            let mut actual = vec![0.0; m * n];
+            matmul(level, &a, &b, &mut actual, m, k, n);
+            assert_close(&actual, &expected, 1e-5, &format!("{level:?} matmul"));
+        }
+    }
+
+    fn conv_params(group: usize, in_c: usize, out_c: usize) -> ConvParams {
+        ConvParams {
+            batch: 2,
+            in_h: 7,
+            in_w: 6,
+            in_c,
+            out_h: 4,
+            out_w: 3,
+            out_c,
+            k_h: 3,
+            k_w: 3,
+            stride_h: 2,
+            stride_w: 2,
+            dilation_h: 1,
+            dilation_w: 1,
+            pad_top: 1,
+            pad_left: 1,
+            group,
+            activation: None,
+        }
+    }
+
+    #[test]
+    fn conv2d_matches_scalar() {
+        for (group, in_c, out_c) in [(1, 3, 11), (1, 5, 16), (2, 6, 18)] {
+            let p = conv_params(group, in_c, out_c);
+            let input = data(p.batch * p.in_h * p.in_w * in_c, 5);
+            let kernel = data(p.k_h * p.k_w * (in_c / group) * out_c, 6);
+            let bias = data(out_c, 7);
+            let mut expected = vec![0.0; p.batch * p.out_h * p.out_w * out_c];
+            scalar::conv2d_nhwc(&input, &kernel, Some(&bias), &mut expected, &p, p.rows());
+            for level in levels() {
+                let mut actual = vec![0.0; expected.len()];
+                conv2d_nhwc(
+                    level,
+                    &input,
+                    &kernel,
+                    Some(&bias),
+                    &mut actual,
+                    &p,
+                    p.rows(),
+                );
+                assert_close(
+                    &actual,
+                    &expected,
+                    1e-5,
+                    &format!("{level:?} conv2d group={group}"),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn depthwise_conv2d_matches_scalar() {
+        for (in_c, multiplier) in [(13, 1), (16, 1), (4, 3)] {
+            let mut p = conv_params(in_c, in_c, in_c * multiplier);
+            p.dilation_h = 2;
+            p.out_h = 3;
+            let input = data(p.batch * p.in_h * p.in_w * in_c, 8);
+            let kernel = data(p.k_h * p.k_w * p.out_c, 9);
+            let mut expected = vec![0.0; p.batch * p.out_h * p.out_w * p.out_c];
+            scalar::depthwise_conv2d_nhwc(&input, &kernel, None, &mut expected, &p, p.rows());
+            for level in levels() {
+                let mut actual = vec![0.0; expected.len()];
+                depthwise_conv2d_nhwc(level, &input, &kernel, None, &mut actual, &p, p.rows());
+                assert_close(
+                    &actual,
+                    &expected,
+                    1e-5,
+                    &format!("{level:?} depthwise c={in_c} m={multiplier}"),
+                );
+            }
+        }
+    }
+}
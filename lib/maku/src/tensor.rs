@@ -0,0 +1,328 @@
+//! Convenience API on [`Tensor`] for pre/post-processing and tests:
+//! constructors, multi-dimensional indexing, elementwise maps and
+//! approximate comparisons.
+
+use std::fmt;
+
+use crate::error::{Result, ensure};
+use crate::view::contiguous_strides;
+use crate::{DType, Tensor, TensorDesc};
+
+/// Data that does not fit the shape it is given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TensorError {
+    /// `data.len()` differs from the element count of `shape`
+    DataLength {
+        shape: Vec<usize>,
+        expected: usize,
+        actual: usize,
+    },
+    /// The element count of `shape` does not fit in `usize`
+    ShapeOverflow { shape: Vec<usize> },
+}
+
+impl fmt::Display for TensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TensorError::DataLength {
+                shape,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "tensor data length {} does not match shape {:?} ({} elements expected)",
+                actual, shape, expected
+            ),
+            TensorError::ShapeOverflow { shape } => {
+                write!(f, "tensor shape {:?} has too many elements", shape)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TensorError {}
+
+fn f32_desc(shape: Vec<usize>) -> TensorDesc {
+    TensorDesc {
+        dtype: DType::F32,
+        shape,
+    }
+}
+
+impl Tensor {
+    pub fn ones(shape: Vec<usize>) -> Self {
+        Tensor::full(shape, 1.0)
+    }
+
+    /// Every element set to `value`.
+    pub fn full(shape: Vec<usize>, value: f32) -> Self {
+        let len: usize = shape.iter().product();
+        Tensor::new(f32_desc(shape), vec![value; len])
+    }
+
+    /// Element at each multi-dimensional index is `f(index)`, filled in
+    /// row-major order.
+    pub fn from_fn(shape: Vec<usize>, mut f: impl FnMut(&[usize]) -> f32) -> Self {
+        let len: usize = shape.iter().product();
+        let mut data = Vec::with_capacity(len);
+        let mut index = vec![0; shape.len()];
+        for _ in 0..len {
+            data.push(f(&index));
+            for d in (0..shape.len()).rev() {
+                index[d] += 1;
+                if index[d] < shape[d] {
+                    break;
+                }
+                index[d] = 0;
+            }
+        }
+        Tensor::new(f32_desc(shape), data)
+    }
+
+    /// 1-D tensor `start, start + step, ...` up to but excluding `end`.
+    pub fn arange(start: f32, end: f32, step: f32) -> Self {
+        assert!(step != 0.0, "arange step must be non-zero");
+        let len = ((end - start) / step).ceil().max(0.0) as usize;
+        let data: Vec<f32> = (0..len).map(|i| start + i as f32 * step).collect();
+        Tensor::new(f32_desc(vec![len]), data)
+    }
+
+    /// `n x n` identity matrix.
+    pub fn eye(n: usize) -> Self {
+        Tensor::from_fn(vec![n, n], |i| if i[0] == i[1] { 1.0 } else { 0.0 })
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.desc.shape
+    }
+
+    /// Flat position of a multi-dimensional index; panics when out of bounds.
+    fn position(&self, index: &[usize]) -> usize {
+        let shape = &self.desc.shape;
+        assert!(
+            index.len() == shape.len() && index.iter().zip(shape).all(|(i, d)| i < d),
+            "index {:?} out of bounds for shape {:?}",
+            index,
+            shape
+        );
+        index
+            .iter()
+            .zip(contiguous_strides(shape))
+            .map(|(i, s)| i * s)
+            .sum()
+    }
+
+    /// Element at a multi-dimensional index.
+    pub fn get(&self, index: &[usize]) -> f32 {
+        self.data[self.position(index)]
+    }
+
+    pub fn set(&mut self, index: &[usize], value: f32) {
+        let pos = self.position(index);
+        self.data[pos] = value;
+    }
+
+    /// Same data with a new shape of the same element count.
+    pub fn reshape(self, shape: Vec<usize>) -> Result<Tensor> {
+        ensure!(
+            shape.iter().product::<usize>() == self.len(),
+            ShapeMismatch,
+            "cannot reshape {:?} to {:?}",
+            self.desc.shape,
+            shape
+        );
+        Ok(Tensor::new(f32_desc(shape), self.data))
+    }
+
+    /// Apply `f` to every element.
+    pub fn map(&self, f: impl Fn(f32) -> f32) -> Tensor {
+        Tensor::new(self.desc.clone(), self.data.iter().map(|&x| f(x)).collect())
+    }
+
+    /// Combine two tensors of the same shape element by element.
+    pub fn zip_map(&self, other: &Tensor, f: impl Fn(f32, f32) -> f32) -> Result<Tensor> {
+        ensure!(
+            self.shape() == other.shape(),
+            ShapeMismatch,
+            "shape mismatch: {:?} vs {:?}",
+            self.shape(),
+            other.shape()
+        );
+        let data = self.data.iter().zip(&other.data).map(|(&a, &b)| f(a, b));
+        Ok(Tensor::new(self.desc.clone(), data.collect()))
+    }
+
+    /// Largest `|self - other|` over all elements, or `None` if the shapes
+    /// differ. NaN anywhere gives NaN.
+    pub fn max_abs_diff(&self, other: &Tensor) -> Option<f32> {
+        if self.shape() != other.shape() {
+            return None;
+        }
+        Some(self.data.iter().zip(&other.data).fold(0.0, |max, (a, b)| {
+            let diff = (a - b).abs();
+            if diff.is_nan() || max < diff {
+                diff
+            } else {
+                max
+            }
+        }))
+    }
+
+    /// NumPy-style `|self - other| <= atol + rtol * |other|` for every
+    /// element; shapes must match and NaN never compares close.
+    pub fn allclose(&self, other: &Tensor, rtol: f32, atol: f32) -> bool {
+        self.shape() == other.shape()
+            && self
+                .data
+                .iter()
+                .zip(&other.data)
+                .all(|(a, b)| (a - b).abs() <= atol + rtol * b.abs())
+    }
+
+    /// `allclose` with one tolerance used as both relative and absolute
+    /// bound, i.e. `|self - other| <= tol * (1 + |other|)`.
+    pub fn approx_eq(&self, other: &Tensor, tol: f32) -> bool {
+        self.allclose(other, tol, tol)
+    }
+}
+
+/// Tensors with more elements than this print only the first and last
+/// `EDGE_ITEMS` entries of each axis.
+const SUMMARY_THRESHOLD: usize = 1000;
+const EDGE_ITEMS: usize = 3;
+
+/// Nested brackets in the style of NumPy; format flags such as precision
+/// apply to each element (`{:.2}`).
+impl fmt::Display for Tensor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let summarize = self.len() > SUMMARY_THRESHOLD;
+        write_axis(f, self, 0, 0, summarize)
+    }
+}
+
+fn write_axis(
+    f: &mut fmt::Formatter<'_>,
+    t: &Tensor,
+    axis: usize,
+    offset: usize,
+    summarize: bool,
+) -> fmt::Result {
+    let shape = t.shape();
+    if axis == shape.len() {
+        return fmt::Display::fmt(&t.data[offset], f);
+    }
+    let stride: usize = shape[axis + 1..].iter().product();
+    let n = shape[axis];
+    // Rows of higher-rank axes go on their own lines, indented past the
+    // opening brackets; blank lines separate matrices.
+    let sep = if axis + 1 == shape.len() {
+        ", ".to_string()
+    } else {
+        let blank_lines = shape.len() - axis - 2;
+        format!(",\n{}{}", "\n".repeat(blank_lines), " ".repeat(axis + 1))
+    };
+
+    f.write_str("[")?;
+    for i in 0..n {
+        if summarize && n > 2 * EDGE_ITEMS && i == EDGE_ITEMS {
+            f.write_str("...")?;
+            f.write_str(&sep)?;
+            continue;
+        }
+        if summarize && n > 2 * EDGE_ITEMS && i > EDGE_ITEMS && i < n - EDGE_ITEMS {
+            continue;
+        }
+        write_axis(f, t, axis + 1, offset + i * stride, summarize)?;
+        if i + 1 < n {
+            f.write_str(&sep)?;
+        }
+    }
+    f.write_str("]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constructors_and_indexing() {
+        let t = Tensor::from_fn(vec![2, 3], |i| (i[0] * 10 + i[1]) as f32);
+        assert_eq!(t.data, vec![0.0, 1.0, 2.0, 10.0, 11.0, 12.0]);
+        assert_eq!(t.get(&[1, 2]), 12.0);
+
+        let mut eye = Tensor::eye(3);
+        assert_eq!(eye.data, vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]);
+        eye.set(&[0, 2], 5.0);
+        assert_eq!(eye.data[2], 5.0);
+
+        assert_eq!(
+            Tensor::arange(0.0, 1.0, 0.25).data,
+            vec![0.0, 0.25, 0.5, 0.75]
+        );
+        assert!(Tensor::arange(1.0, 0.0, 1.0).is_empty());
+        assert_eq!(Tensor::full(vec![2, 2], 7.0).data, vec![7.0; 4]);
+        assert_eq!(Tensor::ones(vec![3]).map(|x| x * 2.0).data, vec![2.0; 3]);
+
+        let r = Tensor::arange(0.0, 6.0, 1.0).reshape(vec![3, 2]).unwrap();
+        assert_eq!(r.get(&[2, 1]), 5.0);
+        assert!(r.reshape(vec![4]).is_err());
+    }
+
+    #[test]
+    fn try_new_reports_bad_data() {
+        let err = Tensor::try_new(f32_desc(vec![2, 3]), vec![0.0; 5]).unwrap_err();
+        assert_eq!(
+            err,
+            TensorError::DataLength {
+                shape: vec![2, 3],
+                expected: 6,
+                actual: 5
+            }
+        );
+        assert!(err.to_string().contains("does not match shape [2, 3]"));
+
+        let huge = f32_desc(vec![usize::MAX, 2]);
+        assert!(matches!(
+            Tensor::try_new(huge, vec![]),
+            Err(TensorError::ShapeOverflow { .. })
+        ));
+        assert!(Tensor::try_new(f32_desc(vec![0, 4]), vec![]).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn get_checks_bounds() {
+        Tensor::zeros(vec![2, 2]).get(&[0, 2]);
+    }
+
+    #[test]
+    fn approximate_comparisons() {
+        let a = Tensor::arange(0.0, 4.0, 1.0);
+        let b = a.map(|x| x + 1e-3);
+        assert!((a.max_abs_diff(&b).unwrap() - 1e-3).abs() < 1e-5);
+        assert!(a.approx_eq(&b, 1e-2));
+        assert!(!a.approx_eq(&b, 1e-4));
+        assert!(a.allclose(&b, 0.0, 2e-3));
+        assert!(!a.allclose(&a.clone().reshape(vec![2, 2]).unwrap(), 1.0, 1.0));
+        assert!(!a.allclose(&a.map(|_| f32::NAN), 1.0, 1.0));
+        assert_eq!(a.zip_map(&b, |x, y| y - x).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn display_nests_and_summarizes() {
+        let t = Tensor::arange(0.0, 8.0, 1.0)
+            .reshape(vec![2, 2, 2])
+            .unwrap();
+        assert_eq!(
+            t.to_string(),
+            "[[[0, 1],\n  [2, 3]],\n\n [[4, 5],\n  [6, 7]]]"
+        );
+        assert_eq!(
+            format!("{:.1}", Tensor::eye(2)),
+            "[[1.0, 0.0],\n [0.0, 1.0]]"
+        );
+
+        let long = Tensor::arange(0.0, 2000.0, 1.0).to_string();
+        assert_eq!(long, "[0, 1, 2, ..., 1997, 1998, 1999]");
+    }
+}
@@ -0,0 +1,36 @@
+//! Graph and tensor fixtures shared by the unit tests.
+
+use crate::{DType, Node, NodeId, OpKind, Tensor, TensorDesc, ValueId};
+
+/// Node computing `output` from `inputs`, with the node id equal to the
+/// output value id
+pub(crate) fn node(op: OpKind, inputs: &[u32], output: u32) -> Node {
+    Node {
+        id: NodeId(output),
+        op,
+        inputs: inputs.iter().map(|&v| ValueId(v)).collect(),
+        output: ValueId(output),
+    }
+}
+
+pub(crate) fn desc(shape: Vec<usize>) -> TensorDesc {
+    TensorDesc {
+        dtype: DType::F32,
+        shape,
+    }
+}
+
+/// Deterministic values in [-1, 1) hashed from `seed` and each element's
+/// position, so tensors built with different seeds differ
+pub(crate) fn tensor(shape: Vec<usize>, seed: usize) -> Tensor {
+    let len = shape.iter().product();
+    let data = (0..len)
+        .map(|i| ((i * 31 + seed * 7) % 17) as f32 / 8.0 - 1.0)
+        .collect();
+    Tensor::new(desc(shape), data)
+}
+
+/// Constant node op holding [`tensor`]`(shape, seed)`
+pub(crate) fn constant(shape: Vec<usize>, seed: usize) -> OpKind {
+    OpKind::Constant(tensor(shape, seed).into())
+}
@@ -0,0 +1,413 @@
+//! Strided tensor views.
+//!
+//! A [`TensorView`] is a shape, strides and offset over storage that is
+//! either borrowed (caller inputs, constants) or shared behind an `Arc`
+//! (computed values). Reshape, Transpose, Slice and broadcast Expand only
+//! rewrite that metadata; kernels read contiguous views in place and fall
+//! back to [`TensorView::contiguous`] otherwise.
+
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::error::{Result, ensure};
+use crate::{DType, Tensor, TensorDesc};
+
+#[derive(Debug, Clone)]
+enum Storage<'a> {
+    Borrowed(&'a [f32]),
+    Shared(Arc<Vec<f32>>),
+}
+
+impl Storage<'_> {
+    fn as_slice(&self) -> &[f32] {
+        match self {
+            Storage::Borrowed(data) => data,
+            Storage::Shared(data) => data,
+        }
+    }
+}
+
+/// Row-major strides of `shape`
+pub fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for d in (1..shape.len()).rev() {
+        strides[d - 1] = strides[d] * shape[d];
+    }
+    strides
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorView<'a> {
+    storage: Storage<'a>,
+    shape: Vec<usize>,
+    /// Elements to skip per step along each axis; 0 for broadcast axes
+    strides: Vec<usize>,
+    offset: usize,
+}
+
+impl<'a> TensorView<'a> {
+    /// Contiguous view over borrowed data.
+    pub fn from_slice(data: &'a [f32], shape: Vec<usize>) -> Result<Self> {
+        let len: usize = shape.iter().product();
+        ensure!(
+            len == data.len(),
+            ShapeMismatch,
+            "view of {} elements does not match shape {:?}",
+            data.len(),
+            shape
+        );
+        Ok(TensorView {
+            storage: Storage::Borrowed(data),
+            strides: contiguous_strides(&shape),
+            shape,
+            offset: 0,
+        })
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn desc(&self) -> TensorDesc {
+        TensorDesc {
+            dtype: DType::F32,
+            shape: self.shape.clone(),
+        }
+    }
+
+    /// Elements are laid out row-major without gaps (axes of size 1 may
+    /// have any stride).
+    pub fn is_contiguous(&self) -> bool {
+        let expected = contiguous_strides(&self.shape);
+        self.is_empty()
+            || (0..self.shape.len()).all(|d| self.shape[d] == 1 || self.strides[d] == expected[d])
+    }
+
+    /// The elements as one slice, if the view is contiguous.
+    pub fn as_slice(&self) -> Option<&[f32]> {
+        if self.is_empty() {
+            return Some(&[]);
+        }
+        self.is_contiguous()
+            .then(|| &self.storage.as_slice()[self.offset..self.offset + self.len()])
+    }
+
+    /// The elements in row-major order, copied only if the view is strided.
+    pub fn contiguous(&self) -> Cow<'_, [f32]> {
+        match self.as_slice() {
+            Some(data) => Cow::Borrowed(data),
+            None => Cow::Owned(self.gather()),
+        }
+    }
+
+    /// Element at a multi-dimensional index.
+    pub fn get(&self, index: &[usize]) -> f32 {
+        assert!(
+            index.len() == self.shape.len() && index.iter().zip(&self.shape).all(|(i, d)| i < d),
+            "index {:?} out of bounds for shape {:?}",
+            index,
+            self.shape
+        );
+        let pos: usize = index.iter().zip(&self.strides).map(|(i, s)| i * s).sum();
+        self.storage.as_slice()[self.offset + pos]
+    }
+
+    /// Same elements with a new shape. Metadata-only for contiguous views;
+    /// strided views are copied first.
+    pub fn reshape(&self, shape: Vec<usize>) -> Result<TensorView<'a>> {
+        ensure!(
+            shape.iter().product::<usize>() == self.len(),
+            ShapeMismatch,
+            "cannot reshape {:?} to {:?}",
+            self.shape,
+            shape
+        );
+        let base = if self.is_contiguous() {
+            self.clone()
+        } else {
+            TensorView::from(Tensor::new(self.desc(), self.gather()))
+        };
+        Ok(TensorView {
+            strides: contiguous_strides(&shape),
+            shape,
+            ..base
+        })
+    }
+
+    /// Reorder axes: axis `i` of the result is axis `perm[i]` of `self`.
+    pub fn permute(&self, perm: &[usize]) -> Result<TensorView<'a>> {
+        let mut seen = vec![false; self.shape.len()];
+        for &axis in perm {
+            ensure!(
+                axis < seen.len() && !seen[axis],
+                InvalidAttr,
+                "{:?} is not a permutation of {} axes",
+                perm,
+                seen.len()
+            );
+            seen[axis] = true;
+        }
+        ensure!(
+            perm.len() == seen.len(),
+            InvalidAttr,
+            "{:?} is not a permutation of {} axes",
+            perm,
+            seen.len()
+        );
+        Ok(TensorView {
+            storage: self.storage.clone(),
+            shape: perm.iter().map(|&a| self.shape[a]).collect(),
+            strides: perm.iter().map(|&a| self.strides[a]).collect(),
+            offset: self.offset,
+        })
+    }
+
+    /// Restrict `axis` to `range`.
+    pub fn slice(&self, axis: usize, range: Range<usize>) -> Result<TensorView<'a>> {
+        ensure!(
+            axis < self.shape.len() && range.start <= range.end && range.end <= self.shape[axis],
+            InvalidAttr,
+            "cannot slice axis {} of {:?} to {:?}",
+            axis,
+            self.shape,
+            range
+        );
+        let mut view = self.clone();
+        view.offset += range.start * self.strides[axis];
+        view.shape[axis] = range.len();
+        Ok(view)
+    }
+
+    /// Broadcast to `shape` (NumPy rules: axes are aligned from the right,
+    /// and size-1 or missing axes repeat with stride 0).
+    pub fn expand(&self, shape: &[usize]) -> Result<TensorView<'a>> {
+        ensure!(
+            shape.len() >= self.shape.len(),
+            ShapeMismatch,
+            "cannot expand {:?} to {:?}",
+            self.shape,
+            shape
+        );
+        let lead = shape.len() - self.shape.len();
+        let mut strides = vec![0; shape.len()];
+        for (d, &src) in self.shape.iter().enumerate() {
+            let dst = shape[lead + d];
+            ensure!(
+                src == dst || src == 1,
+                ShapeMismatch,
+                "cannot expand {:?} to {:?}",
+                self.shape,
+                shape
+            );
+            if src == dst {
+                strides[lead + d] = self.strides[d];
+            }
+        }
+        Ok(TensorView {
+            storage: self.storage.clone(),
+            shape: shape.to_vec(),
+            strides,
+            offset: self.offset,
+        })
+    }
+
+    /// Both views read the same underlying buffer.
+    pub fn shares_storage(&self, other: &TensorView) -> bool {
+        std::ptr::eq(self.storage.as_slice(), other.storage.as_slice())
+    }
+
+    /// Copy into an owned contiguous tensor.
+    pub fn to_tensor(&self) -> Tensor {
+        Tensor::new(self.desc(), self.contiguous().into_owned())
+    }
+
+    /// Convert into an owned tensor, without copying when this view is the
+    /// only owner of a buffer it covers exactly.
+    pub fn into_tensor(self) -> Tensor {
+        if self.is_unique() {
+            let desc = self.desc();
+            if let Storage::Shared(data) = self.storage
+                && let Ok(data) = Arc::try_unwrap(data)
+            {
+                return Tensor::new(desc, data);
+            }
+            unreachable!("unique views own their storage")
+        }
+        self.to_tensor()
+    }
+
+    /// Sole owner of a contiguous buffer it covers exactly, so it may be
+    /// written in place.
+    pub(crate) fn is_unique(&self) -> bool {
+        match &self.storage {
+            Storage::Shared(data) => {
+                Arc::strong_count(data) == 1
+                    && Arc::weak_count(data) == 0
+                    && self.offset == 0
+                    && self.len() == data.len()
+                    && self.is_contiguous()
+            }
+            Storage::Borrowed(_) => false,
+        }
+    }
+
+    /// Mutable access to the elements of a unique view.
+    pub(crate) fn make_mut(&mut self) -> Option<&mut [f32]> {
+        if !self.is_unique() {
+            return None;
+        }
+        match &mut self.storage {
+            Storage::Shared(data) => Arc::get_mut(data).map(|data| data.as_mut_slice()),
+            Storage::Borrowed(_) => None,
+        }
+    }
+
+    /// Walk the view in row-major order.
+    fn gather(&self) -> Vec<f32> {
+        let data = self.storage.as_slice();
+        let mut out = Vec::with_capacity(self.len());
+        if self.is_empty() {
+            return out;
+        }
+        let mut index = vec![0; self.shape.len()];
+        let mut pos = self.offset;
+        loop {
+            out.push(data[pos]);
+            // Odometer increment, last axis fastest
+            let mut d = self.shape.len();
+            loop {
+                if d == 0 {
+                    return out;
+                }
+                d -= 1;
+                index[d] += 1;
+                pos += self.strides[d];
+                if index[d] < self.shape[d] {
+                    break;
+                }
+                pos -= self.strides[d] * self.shape[d];
+                index[d] = 0;
+            }
+        }
+    }
+}
+
+impl From<Tensor> for TensorView<'static> {
+    /// Take ownership of the tensor's buffer without copying it.
+    fn from(t: Tensor) -> Self {
+        TensorView {
+            strides: contiguous_strides(&t.desc.shape),
+            shape: t.desc.shape,
+            storage: Storage::Shared(Arc::new(t.data)),
+            offset: 0,
+        }
+    }
+}
+
+impl<'a> From<&'a Tensor> for TensorView<'a> {
+    fn from(t: &'a Tensor) -> Self {
+        TensorView {
+            storage: Storage::Borrowed(&t.data),
+            shape: t.desc.shape.clone(),
+            strides: contiguous_strides(&t.desc.shape),
+            offset: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arange(shape: Vec<usize>) -> Tensor {
+        let len = shape.iter().product();
+        Tensor::new(
+            TensorDesc {
+                dtype: DType::F32,
+                shape,
+            },
+            (0..len).map(|i| i as f32).collect(),
+        )
+    }
+
+    #[test]
+    fn layout_ops_share_storage() {
+        let t = arange(vec![2, 3, 4]);
+        let v = TensorView::from(&t);
+
+        let r = v.reshape(vec![6, 4]).unwrap();
+        assert!(r.shares_storage(&v) && r.is_contiguous());
+        assert_eq!(r.get(&[5, 3]), 23.0);
+
+        let p = v.permute(&[2, 0, 1]).unwrap();
+        assert!(p.shares_storage(&v) && !p.is_contiguous());
+        assert_eq!(p.shape(), [4, 2, 3]);
+        assert_eq!(p.get(&[3, 1, 2]), t.data[23]);
+
+        let s = v.slice(1, 1..3).unwrap();
+        assert!(s.shares_storage(&v));
+        assert_eq!(
+            s.contiguous().as_ref(),
+            [
+                4., 5., 6., 7., 8., 9., 10., 11., 16., 17., 18., 19., 20., 21., 22., 23.
+            ]
+        );
+        // Slicing the outermost axis keeps the view contiguous
+        assert!(v.slice(0, 1..2).unwrap().as_slice().is_some());
+
+        let row = TensorView::from(&t)
+            .slice(0, 0..1)
+            .unwrap()
+            .slice(1, 0..1)
+            .unwrap();
+        let e = row.reshape(vec![4]).unwrap().expand(&[3, 4]).unwrap();
+        assert!(e.shares_storage(&v));
+        assert_eq!(e.strides(), [0, 1]);
+        assert_eq!(
+            e.contiguous().as_ref(),
+            [0., 1., 2., 3., 0., 1., 2., 3., 0., 1., 2., 3.]
+        );
+    }
+
+    #[test]
+    fn reshape_of_strided_view_copies() {
+        let t = arange(vec![2, 3]);
+        let v = TensorView::from(&t).permute(&[1, 0]).unwrap();
+        let r = v.reshape(vec![6]).unwrap();
+        assert!(!r.shares_storage(&v));
+        assert_eq!(r.as_slice().unwrap(), [0., 3., 1., 4., 2., 5.]);
+        assert!(v.reshape(vec![4]).is_err());
+        assert!(v.expand(&[2, 2]).is_err());
+    }
+
+    #[test]
+    fn unique_views_convert_without_copy() {
+        let t = arange(vec![4]);
+        let ptr = t.data.as_ptr();
+        let mut v = TensorView::from(t);
+        assert!(v.make_mut().is_some());
+
+        let alias = v.clone();
+        assert!(v.make_mut().is_none());
+        drop(alias);
+
+        assert_eq!(v.into_tensor().data.as_ptr(), ptr);
+    }
+}
@@ -2,9 +2,10 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
 
-use maku::{CpuBackend, DType, Graph, Node, NodeId, OpKind, Tensor, TensorDesc, ValueId};
+use maku::passes::{NchwToNhwc, OptLevel, PassManager};
+use maku::{CpuBackend, DType, Graph, Node, NodeId, OpKind, Session, Tensor, TensorDesc, ValueId};
 
-/// ---------- Types for communication with JS ----------
+// ---------- Types for communication with JS ----------
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JsTensor {
@@ -140,7 +141,103 @@ type JsInputs = HashMap<String, JsTensor>;
 /// Output tensor: valueId(string) -> JsTensor
 type JsOutputs = HashMap<String, JsTensor>;
 
-/// ---------- Helper for ID conversion ----------
+/// Input shape: valueId(string) -> shape
+type JsInputShapes = HashMap<String, Vec<usize>>;
+
+/// Error thrown to JS. `node` and `value` are ids from the JS graph, so the
+/// editor can highlight the failing node.
+#[derive(Debug, Clone, Serialize)]
+pub struct JsError {
+    /// `maku::Error` variant name, or `ParseError` / `InvalidTensor`
+    pub kind: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub op: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// Value the error is about (a missing or malformed tensor)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+// ---------- Errors thrown to JS ----------
+
+impl JsError {
+    fn new(kind: &str, message: String) -> Self {
+        JsError {
+            kind: kind.to_string(),
+            message,
+            node: None,
+            op: None,
+            inputs: Vec::new(),
+            output: None,
+            value: None,
+        }
+    }
+}
+
+impl From<JsError> for JsValue {
+    fn from(err: JsError) -> JsValue {
+        serde_wasm_bindgen::to_value(&err).unwrap_or_else(|_| JsValue::from_str(&err.message))
+    }
+}
+
+fn parse_error<E: std::fmt::Display>(what: &'static str) -> impl Fn(E) -> JsValue {
+    move |e| JsError::new("ParseError", format!("{} parse error: {}", what, e)).into()
+}
+
+/// JS ids of a graph's nodes and values, to report core errors in the
+/// caller's terms.
+struct JsIds {
+    nodes: HashMap<NodeId, String>,
+    values: HashMap<ValueId, String>,
+}
+
+impl JsIds {
+    fn new(js_graph: &JsGraph) -> Self {
+        let mut ids = JsIds {
+            nodes: HashMap::new(),
+            values: HashMap::new(),
+        };
+        for n in &js_graph.nodes {
+            ids.nodes.insert(str_to_node_id(&n.id), n.id.clone());
+            for v in n.inputs.iter().chain([&n.output]) {
+                ids.values.insert(str_to_value_id(v), v.clone());
+            }
+        }
+        ids
+    }
+
+    fn value(&self, id: ValueId) -> String {
+        self.values.get(&id).cloned().unwrap_or_else(|| id.0.to_string())
+    }
+
+    fn error(&self, err: maku::Error) -> JsValue {
+        let mut js = JsError::new(err.kind(), err.to_string());
+        if let Some(node) = err.node() {
+            let id = self.nodes.get(&node.id).cloned();
+            js.node = Some(id.unwrap_or_else(|| node.id.0.to_string()));
+            js.op = Some(node.op.to_string());
+            js.inputs = node.inputs.iter().map(|&v| self.value(v)).collect();
+            js.output = Some(self.value(node.output));
+        }
+        match err {
+            maku::Error::MissingInput { value, .. }
+            | maku::Error::MissingType { value, .. }
+            | maku::Error::MissingOutput { value } => {
+                js.value = Some(self.value(value));
+            }
+            _ => {}
+        }
+        js.into()
+    }
+}
+
+// ---------- Helper for ID conversion ----------
 
 fn str_to_value_id(s: &str) -> ValueId {
     // MVP: Can use hash-like approach, or simply parse to u32
@@ -169,14 +266,21 @@ fn str_to_node_id(s: &str) -> NodeId {
     }
 }
 
-fn js_tensor_to_core(t: &JsTensor) -> Tensor {
-    Tensor::new(
+/// `role` and `key` (e.g. `input`, `"x"`) name the tensor in the error
+/// thrown to JS when its data does not match its shape.
+fn js_tensor_to_core(t: &JsTensor, role: &str, key: &str) -> Result<Tensor, JsValue> {
+    Tensor::try_new(
         TensorDesc {
             dtype: DType::F32,
             shape: t.shape.clone(),
         },
         t.data.clone(),
     )
+    .map_err(|e| {
+        let mut err = JsError::new("InvalidTensor", format!("{} {:?}: {}", role, key, e));
+        err.value = Some(key.to_string());
+        err.into()
+    })
 }
 
 fn core_tensor_to_js(t: &Tensor) -> JsTensor {
@@ -186,9 +290,9 @@ fn core_tensor_to_js(t: &Tensor) -> JsTensor {
     }
 }
 
-/// ---------- JsGraph -> Graph conversion ----------
+// ---------- JsGraph -> Graph conversion ----------
 
-fn build_core_graph(js_graph: &JsGraph) -> Graph {
+fn build_core_graph(js_graph: &JsGraph) -> Result<Graph, JsValue> {
     let mut nodes = Vec::new();
     let mut value_types = HashMap::new();
 
@@ -200,10 +304,10 @@ fn build_core_graph(js_graph: &JsGraph) -> Graph {
         let op = match &js_node.op {
             JsOpKind::Input => OpKind::Input,
             JsOpKind::Constant { tensor } => {
-                let core_t = js_tensor_to_core(tensor);
+                let core_t = js_tensor_to_core(tensor, "constant", &js_node.output)?;
                 // Register Constant's output type since it's determined from tensor
                 value_types.insert(output_id, core_t.desc.clone());
-                OpKind::Constant(core_t)
+                OpKind::Constant(core_t.into())
             }
             JsOpKind::Add => OpKind::Add,
             JsOpKind::Mul => OpKind::Mul,
@@ -211,6 +315,7 @@ fn build_core_graph(js_graph: &JsGraph) -> Graph {
                 OpKind::MatMul(attrs.as_ref().map(|a| maku::MatMulAttrs {
                     trans_a: a.trans_a,
                     trans_b: a.trans_b,
+                    activation: None,
                 }))
             }
             JsOpKind::Relu => OpKind::Relu,
@@ -223,6 +328,7 @@ fn build_core_graph(js_graph: &JsGraph) -> Graph {
                     pads: attrs.pads,
                     dilations: attrs.dilations,
                     group: attrs.group,
+                    activation: None,
                 })
             }
             JsOpKind::DepthwiseConv2D { attrs } => {
@@ -232,6 +338,7 @@ fn build_core_graph(js_graph: &JsGraph) -> Graph {
                     pads: attrs.pads,
                     dilations: attrs.dilations,
                     depth_multiplier: attrs.depth_multiplier,
+                    activation: None,
                 })
             }
             JsOpKind::BatchNorm { attrs } => {
@@ -284,20 +391,26 @@ fn build_core_graph(js_graph: &JsGraph) -> Graph {
         .map(|s| str_to_value_id(s))
         .collect();
 
-    Graph {
+    Ok(Graph {
         nodes,
         outputs,
         value_types,
-    }
+    })
 }
 
-/// ---------- Engine exposed to WASM ----------
+// ---------- Engine exposed to WASM ----------
 
 #[wasm_bindgen]
 pub struct WasmEngine {
     backend: CpuBackend,
 }
 
+impl Default for WasmEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl WasmEngine {
     #[wasm_bindgen(constructor)]
@@ -314,38 +427,319 @@ impl WasmEngine {
     /// Returns: JS object of { [valueId: string]: JsTensor }
     #[wasm_bindgen]
     pub fn run(&mut self, graph: JsValue, inputs: JsValue) -> Result<JsValue, JsValue> {
-        // JsValue -> Rust構造体 (serde_wasm_bindgen)
-        let js_graph: JsGraph = serde_wasm_bindgen::from_value(graph)
-            .map_err(|e| JsValue::from_str(&format!("graph parse error: {}", e)))?;
-        let js_inputs: JsInputs = serde_wasm_bindgen::from_value(inputs)
-            .map_err(|e| JsValue::from_str(&format!("inputs parse error: {}", e)))?;
-
-        // JsGraph -> core Graph
-        let core_graph = build_core_graph(&js_graph);
-
-        // Convert input tensor to core HashMap<ValueId, Tensor>
-        let mut core_inputs = HashMap::new();
-        for (key, js_t) in js_inputs {
-            let vid = str_to_value_id(&key);
-            let t = js_tensor_to_core(&js_t);
-            core_inputs.insert(vid, t);
-        }
+        let (core_graph, ids, core_inputs) = parse_run_args(graph, inputs)?;
 
         // Execute
         let core_outputs = self
             .backend
             .run(&core_graph, &core_inputs)
-            .map_err(|e| JsValue::from_str(&format!("run error: {}", e)))?;
+            .map_err(|e| ids.error(e))?;
+
+        outputs_to_js(core_outputs)
+    }
+}
+
+/// Core graph, its JS ids and core inputs from the arguments of `run`.
+fn parse_run_args(
+    graph: JsValue,
+    inputs: JsValue,
+) -> Result<(Graph, JsIds, HashMap<ValueId, Tensor>), JsValue> {
+    // JsValue -> Rust構造体 (serde_wasm_bindgen)
+    let js_graph: JsGraph = serde_wasm_bindgen::from_value(graph)
+        .map_err(parse_error("graph"))?;
+    let js_inputs: JsInputs = serde_wasm_bindgen::from_value(inputs)
+        .map_err(parse_error("inputs"))?;
+
+    // JsGraph -> core Graph
+    let core_graph = build_core_graph(&js_graph)?;
+    let ids = JsIds::new(&js_graph);
+
+    // Convert input tensor to core HashMap<ValueId, Tensor>
+    let mut core_inputs = HashMap::new();
+    for (key, js_t) in js_inputs {
+        let vid = str_to_value_id(&key);
+        let t = js_tensor_to_core(&js_t, "input", &key)?;
+        core_inputs.insert(vid, t);
+    }
+    Ok((core_graph, ids, core_inputs))
+}
+
+fn outputs_to_js(core_outputs: HashMap<ValueId, Tensor>) -> Result<JsValue, JsValue> {
+    // Convert return value to JsOutputs
+    let mut js_outputs: JsOutputs = HashMap::new();
+    for (vid, t) in core_outputs {
+        let key = format!("{}", vid.0); // ValueId(u32) -> "0", "1", ...
+        js_outputs.insert(key, core_tensor_to_js(&t));
+    }
 
-        // Convert return value to JsOutputs
-        let mut js_outputs: JsOutputs = HashMap::new();
-        for (vid, t) in core_outputs {
-            let key = format!("{}", vid.0); // ValueId(u32) -> "0", "1", ...
-            js_outputs.insert(key, core_tensor_to_js(&t));
+    // Rust struct -> JsValue
+    serde_wasm_bindgen::to_value(&js_outputs)
+        .map_err(|e| JsError::new("Internal", format!("to_value error: {}", e)).into())
+}
+
+// ---------- WebGPU engine exposed to WASM ----------
+
+/// Runs graphs on WebGPU, falling back to the CPU for ops the GPU backend
+/// cannot run. Create with `await WasmGpuEngine.create()`.
+#[cfg(feature = "gpu")]
+#[wasm_bindgen]
+pub struct WasmGpuEngine {
+    backend: std::rc::Rc<maku::GpuBackend>,
+}
+
+#[cfg(feature = "gpu")]
+#[wasm_bindgen]
+impl WasmGpuEngine {
+    /// Resolves to an engine on the browser's default adapter; rejects with
+    /// a `Device` error when WebGPU is unavailable.
+    pub async fn create() -> Result<WasmGpuEngine, JsValue> {
+        console_error_panic_hook::set_once();
+        let backend = maku::GpuBackend::new()
+            .await
+            .map_err(|e| JsValue::from(JsError::new(e.kind(), e.to_string())))?;
+        Ok(WasmGpuEngine {
+            backend: std::rc::Rc::new(backend),
+        })
+    }
+
+    /// Same arguments as `WasmEngine.run`; returns a Promise of the outputs.
+    #[wasm_bindgen]
+    pub fn run(&self, graph: JsValue, inputs: JsValue) -> js_sys::Promise {
+        let backend = self.backend.clone();
+        wasm_bindgen_futures::future_to_promise(async move {
+            let (core_graph, ids, core_inputs) = parse_run_args(graph, inputs)?;
+            let core_outputs = backend
+                .run(&core_graph, &core_inputs)
+                .await
+                .map_err(|e| ids.error(e))?;
+            outputs_to_js(core_outputs)
+        })
+    }
+}
+
+// ---------- Graph compiled to a standalone WASM module ----------
+
+/// Where a tensor lives in the memory of a compiled module: `len` f32s at
+/// byte `offset`
+#[derive(Debug, Clone, Serialize)]
+pub struct JsRegion {
+    pub offset: u32,
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct JsWasmLayout {
+    inputs: HashMap<String, JsRegion>,
+    outputs: HashMap<String, JsRegion>,
+}
+
+/// Compile a graph for fixed input shapes into its own WebAssembly module,
+/// with shapes and loop bounds baked in and simd128 kernels.
+///
+/// graph: JS object representing JsGraph
+/// input_shapes: JS object of { [valueId: string]: number[] }
+/// layout: "NHWC" (default) or "NCHW" for PyTorch / ONNX style graphs
+///
+/// Returns: { wasm: Uint8Array, inputs, outputs }, where inputs and outputs
+/// map value ids to { offset, len } in the module's exported `memory`.
+/// Write the inputs there, call the exported `run()` and read the outputs.
+#[wasm_bindgen(js_name = compileWasm)]
+pub fn compile_wasm(
+    graph: JsValue,
+    input_shapes: JsValue,
+    layout: Option<String>,
+) -> Result<JsValue, JsValue> {
+    let js_graph: JsGraph = serde_wasm_bindgen::from_value(graph)
+        .map_err(parse_error("graph"))?;
+    let js_shapes: JsInputShapes = serde_wasm_bindgen::from_value(input_shapes)
+        .map_err(parse_error("input shapes"))?;
+
+    let mut core_graph = build_core_graph(&js_graph)?;
+    let ids = JsIds::new(&js_graph);
+    let descs = input_descs(js_shapes);
+    if is_nchw(layout.as_deref())? {
+        PassManager::new()
+            .with_pre_pass(NchwToNhwc)
+            .run(&mut core_graph, &descs)
+            .map_err(|e| ids.error(e))?;
+    }
+    let program = maku::loop_ir::lower(&core_graph, &descs).map_err(|e| ids.error(e))?;
+    let module = maku::codegen::wasm::generate(&program).map_err(|e| ids.error(e))?;
+
+    let regions = |list: &[(ValueId, maku::codegen::wasm::Region)]| {
+        list.iter()
+            .map(|&(value, r)| {
+                let region = JsRegion {
+                    offset: r.offset,
+                    len: r.len,
+                };
+                (ids.value(value), region)
+            })
+            .collect()
+    };
+    let layout = JsWasmLayout {
+        inputs: regions(&module.inputs),
+        outputs: regions(&module.outputs),
+    };
+    let to_js = |e: serde_wasm_bindgen::Error| {
+        JsValue::from(JsError::new("Internal", format!("to_value error: {}", e)))
+    };
+    let js = serde_wasm_bindgen::to_value(&layout).map_err(to_js)?;
+    let wasm = js_sys::Uint8Array::from(module.bytes.as_slice());
+    js_sys::Reflect::set(&js, &JsValue::from_str("wasm"), &wasm)?;
+    Ok(js)
+}
+
+/// Whether the graph's layout-sensitive ops follow NCHW conventions
+/// (PyTorch / ONNX exports), from "NHWC" (the default) or "NCHW". NCHW
+/// graphs are converted by [`NchwToNhwc`] before anything else runs; their
+/// inputs and outputs stay NCHW.
+fn is_nchw(layout: Option<&str>) -> Result<bool, JsValue> {
+    match layout.unwrap_or("NHWC") {
+        "NHWC" => Ok(false),
+        "NCHW" => Ok(true),
+        other => Err(JsError::new(
+            "InvalidAttr",
+            format!("unknown layout {:?} (expected NHWC or NCHW)", other),
+        )
+        .into()),
+    }
+}
+
+/// Core input types from { [valueId: string]: number[] }
+fn input_descs(js_shapes: JsInputShapes) -> HashMap<ValueId, TensorDesc> {
+    js_shapes
+        .into_iter()
+        .map(|(key, shape)| {
+            let desc = TensorDesc {
+                dtype: DType::F32,
+                shape,
+            };
+            (str_to_value_id(&key), desc)
+        })
+        .collect()
+}
+
+// ---------- Compiled session exposed to WASM ----------
+
+/// A graph compiled once for fixed input shapes, for running every frame
+/// without re-parsing or re-planning.
+#[wasm_bindgen]
+pub struct WasmSession {
+    session: Session,
+    /// JS key of each session input, in `Session::inputs` order
+    input_keys: Vec<String>,
+    /// JS key of each graph output, in `Session::output_ids` order
+    output_keys: Vec<String>,
+    ids: JsIds,
+    /// Change log of the optimization passes run at compile time
+    pass_log: String,
+}
+
+#[wasm_bindgen]
+impl WasmSession {
+    /// graph: JS object representing JsGraph
+    /// input_shapes: JS object of { [valueId: string]: number[] }
+    /// opt_level: "O0" (default), "O1" or "O2"
+    /// layout: "NHWC" (default) or "NCHW" for PyTorch / ONNX style graphs
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        graph: JsValue,
+        input_shapes: JsValue,
+        opt_level: Option<String>,
+        layout: Option<String>,
+    ) -> Result<WasmSession, JsValue> {
+        console_error_panic_hook::set_once();
+        let js_graph: JsGraph = serde_wasm_bindgen::from_value(graph)
+            .map_err(parse_error("graph"))?;
+        let js_shapes: JsInputShapes = serde_wasm_bindgen::from_value(input_shapes)
+            .map_err(parse_error("input shapes"))?;
+
+        let mut core_graph = build_core_graph(&js_graph)?;
+        let ids = JsIds::new(&js_graph);
+        let level: OptLevel = opt_level
+            .as_deref()
+            .unwrap_or("O0")
+            .parse()
+            .map_err(|e| ids.error(e))?;
+        let descs = input_descs(js_shapes);
+        let mut passes = PassManager::with_level(level);
+        if is_nchw(layout.as_deref())? {
+            passes = passes.with_pre_pass(NchwToNhwc);
         }
+        let pass_log = passes
+            .with_clock(|| std::time::Duration::from_secs_f64(js_sys::Date::now() / 1e3))
+            .run(&mut core_graph, &descs)
+            .map_err(|e| ids.error(e))?;
+        let session = CpuBackend::new()
+            .compile(&core_graph, &descs)
+            .map_err(|e| ids.error(e))?;
+
+        let keys: HashMap<ValueId, &String> = js_graph
+            .nodes
+            .iter()
+            .map(|n| (str_to_value_id(&n.output), &n.output))
+            .collect();
+        let input_keys = session
+            .inputs()
+            .iter()
+            .map(|(id, _)| keys[id].clone())
+            .collect();
+
+        Ok(WasmSession {
+            session,
+            input_keys,
+            output_keys: js_graph.outputs,
+            ids,
+            pass_log: pass_log.to_string(),
+        })
+    }
 
-        // Rust struct -> JsValue
+    /// One line per optimization pass run at compile time, followed by the
+    /// changes it made.
+    #[wasm_bindgen(getter, js_name = passLog)]
+    pub fn pass_log(&self) -> String {
+        self.pass_log.clone()
+    }
+
+    /// inputs: JS object of { [valueId: string]: JsTensor }
+    ///
+    /// Returns: JS object of { [valueId: string]: JsTensor }
+    #[wasm_bindgen]
+    pub fn run(&mut self, inputs: JsValue) -> Result<JsValue, JsValue> {
+        let js_inputs: JsInputs = serde_wasm_bindgen::from_value(inputs)
+            .map_err(parse_error("inputs"))?;
+
+        let tensors = self
+            .input_keys
+            .iter()
+            .map(|key| {
+                let js_t = js_inputs.get(key).ok_or_else(|| {
+                    let mut err = JsError::new("MissingInput", format!("missing input {}", key));
+                    err.value = Some(key.clone());
+                    JsValue::from(err)
+                })?;
+                js_tensor_to_core(js_t, "input", key)
+            })
+            .collect::<Result<Vec<Tensor>, JsValue>>()?;
+        let refs: Vec<&Tensor> = tensors.iter().collect();
+
+        // Outputs are borrowed from the session arena, not copied out first
+        let core_outputs = self
+            .session
+            .run_borrowed(&refs)
+            .map_err(|e| self.ids.error(e))?;
+
+        let js_outputs: JsOutputs = self
+            .output_keys
+            .iter()
+            .cloned()
+            .zip(core_outputs.iter().map(|t| JsTensor {
+                shape: t.desc.shape.clone(),
+                data: t.data.to_vec(),
+            }))
+            .collect();
         serde_wasm_bindgen::to_value(&js_outputs)
-            .map_err(|e| JsValue::from_str(&format!("to_value error: {}", e)))
+            .map_err(|e| JsError::new("Internal", format!("to_value error: {}", e)).into())
     }
 }